@@ -0,0 +1,85 @@
+//! Optional shared-secret auth for the WebSocket upgrade, so the measurement
+//! endpoint can't be used by arbitrary clients. Off unless `AUTH_TOKEN` is
+//! set. The token may be sent as an `Authorization: Bearer` header or, since
+//! browsers can't set headers on a WebSocket, as a `token` query parameter.
+
+use axum::http::{header, HeaderMap};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    token: Option<Arc<str>>,
+}
+
+impl Auth {
+    /// Requires `token` on every upgrade.
+    pub fn new(token: &str) -> Self {
+        Self {
+            token: Some(token.into()),
+        }
+    }
+
+    /// Reads the token from `AUTH_TOKEN`. Unset or empty disables auth.
+    pub fn from_env() -> Self {
+        match std::env::var("AUTH_TOKEN") {
+            Ok(token) if !token.is_empty() => Self::new(&token),
+            _ => Self::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Whether an upgrade with these headers and `token` query parameter may
+    /// proceed. Always true with auth disabled.
+    pub fn check(&self, headers: &HeaderMap, query_token: Option<&str>) -> bool {
+        let Some(expected) = &self.token else {
+            return true;
+        };
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        [bearer, query_token]
+            .into_iter()
+            .flatten()
+            .any(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+    }
+}
+
+/// Compares without returning early, so response timing doesn't reveal how
+/// much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = HeaderValue::from_str(&format!("Bearer {token}")).unwrap();
+        headers.insert(header::AUTHORIZATION, value);
+        headers
+    }
+
+    #[test]
+    fn disabled_allows_everything() {
+        let auth = Auth::default();
+        assert!(!auth.enabled());
+        assert!(auth.check(&HeaderMap::new(), None));
+    }
+
+    #[test]
+    fn token_from_header_or_query() {
+        let auth = Auth::new("s3cret");
+        assert!(auth.check(&bearer("s3cret"), None));
+        assert!(auth.check(&HeaderMap::new(), Some("s3cret")));
+        assert!(!auth.check(&HeaderMap::new(), None));
+        assert!(!auth.check(&bearer("s3cre"), Some("wrong")));
+        assert!(!auth.check(&HeaderMap::new(), Some("s3cret ")));
+    }
+}
@@ -0,0 +1,61 @@
+//! Per-connection load state, so latency measured while the link is loaded
+//! is kept apart from idle latency.
+
+use shared_data::{LatencyTest, LoadDirection, FILLER_SIZE, MAGIC_NUMBER};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The load a connection is under, if any, and until when.
+#[derive(Default)]
+pub struct LoadPhase {
+    current: Mutex<Option<(LoadDirection, Instant)>>,
+}
+
+impl LoadPhase {
+    /// Loads the connection in `direction` until `until`. True if that only
+    /// extends a load already running in the same direction.
+    pub fn start(&self, direction: LoadDirection, until: Instant) -> bool {
+        let mut current = self.current.lock().unwrap();
+        let extended = matches!(
+            *current,
+            Some((running, end)) if running == direction && Instant::now() < end
+        );
+        *current = Some((direction, until));
+        extended
+    }
+
+    /// The direction being loaded at `now`, or `Idle` once the load ends.
+    pub fn current(&self, now: Instant) -> LoadDirection {
+        match *self.current.lock().unwrap() {
+            Some((direction, until)) if now < until => direction,
+            _ => LoadDirection::Idle,
+        }
+    }
+}
+
+/// A frame of filler for download loads.
+pub fn filler() -> LatencyTest {
+    LatencyTest::Filler {
+        magic: MAGIC_NUMBER,
+        id: 0,
+        bytes: vec![0; FILLER_SIZE],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn load_ends_on_time() {
+        let phase = LoadPhase::default();
+        let start = Instant::now();
+        assert_eq!(phase.current(start), LoadDirection::Idle);
+        assert!(!phase.start(LoadDirection::Upload, start + Duration::from_secs(1)));
+        assert_eq!(phase.current(start), LoadDirection::Upload);
+        assert!(phase.start(LoadDirection::Upload, start + Duration::from_secs(1)));
+        assert!(!phase.start(LoadDirection::Download, start + Duration::from_secs(1)));
+        assert_eq!(phase.current(start + Duration::from_secs(1)), LoadDirection::Idle);
+    }
+}
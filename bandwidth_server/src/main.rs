@@ -1,40 +1,122 @@
 use axum::body::StreamBody;
-use axum::extract::ws::{Message, WebSocket};
-use axum::extract::WebSocketUpgrade;
-use axum::http::{HeaderMap, header};
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use axum::extract::{Query, State, WebSocketUpgrade};
+use axum::http::{HeaderMap, StatusCode, header};
 use axum::response::Html;
-use axum::{response::IntoResponse, routing::get, Router};
-use shared_data::LatencyTest;
+use axum::{middleware, response::IntoResponse, response::Response, routing::get, Json, Router};
+use futures_util::{Sink, SinkExt};
+use serde::{Deserialize, Serialize};
+use shared_data::{
+    BinaryCodec, Codec, Direction, ErrorCode, FrameCapture, LatencySamples, LatencyTest,
+    LatencyTestRef, LoadDirection, MessageKind, Transport, MAX_FRAME_SIZE,
+};
+use shared_data::handshake::{process_frame, HandshakeState};
 use tokio_util::io::ReaderStream;
+use tracing::Instrument;
 use tracing_subscriber::fmt::format::FmtSpan;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufWriter;
 use std::net::SocketAddr;
-use tokio::sync::mpsc::Sender;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+mod auth;
+mod load;
+mod metrics;
+mod net;
+mod requests;
+mod sessions;
+mod sla;
+mod spans;
+mod state;
+mod summary;
+#[cfg(test)]
+mod test_util;
+use metrics::ConnectionMetrics;
+use sessions::{SessionHandle, SessionStore};
+use state::{AppState, Config, FrameCodec, UnexpectedFrames};
+
+#[cfg(feature = "statsd")]
+mod statsd;
+#[cfg(feature = "otel")]
+mod telemetry;
+#[cfg(feature = "webtransport")]
+mod webtransport;
+#[cfg(feature = "webhook")]
+mod webhook;
 
 #[tokio::main]
 async fn main() {
     // Start the logger
     set_console_logging().unwrap();
 
+    let config = Config::from_env();
+    #[cfg(feature = "webtransport")]
+    let (config, webtransport) = match webtransport::WebTransportConfig::from_env() {
+        Some(wt_config) => {
+            let addr = SocketAddr::from(([0, 0, 0, 0], wt_config.port));
+            let listener = webtransport::Listener::bind(addr, &wt_config).await.unwrap();
+            let webtransport = Some(listener.info());
+            (Config { webtransport, ..config }, Some(listener))
+        }
+        None => (config, None),
+    };
+    let state = AppState::new(config, SessionStore::from_env());
+    if state.config.drop_rate > 0.0 {
+        tracing::warn!(
+            "DROP_RATE is set: {:.1}% of replies will be dropped. This is for testing only!",
+            state.config.drop_rate * 100.0
+        );
+    }
+    if state.config.auth.enabled() {
+        tracing::info!("AUTH_TOKEN is set: WebSocket upgrades require the token");
+    }
+    if let Some(interval) = state.config.summary_interval {
+        summary::spawn(state.sessions.clone(), state.routes.clone(), interval);
+    }
+    #[cfg(feature = "webtransport")]
+    if let Some(listener) = webtransport {
+        tokio::spawn(listener.serve(state.clone()));
+    }
+
     // Start the webserver
-    let app = Router::new()
+    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
+    let incoming = net::bind(addr, &net::SocketConfig::from_env()).unwrap();
+    axum::Server::builder(incoming)
+        .serve(app(state).into_make_service())
+        .await
+        .unwrap();
+}
+
+fn app(state: AppState) -> Router {
+    Router::new()
         .route("/", get(index_page))
         .route("/app.js", get(js_bundle))
         .route("/app.js.map", get(js_map))
         .route("/style.css", get(css))
         .route("/style.css.map", get(css_map))
         .route("/wasm_client_bg.wasm", get(wasm_file))
-        .route("/ws", get(ws_handler));
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+        .route("/version", get(version))
+        .route("/webtransport", get(webtransport_info))
+        .route("/summary", get(summary_handler))
+        .route("/ws", get(ws_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), requests::track))
+        .with_state(state)
 }
 
 fn set_console_logging() -> anyhow::Result<()> {
-    // install global collector configured based on RUST_LOG env var.
+    // LOG_LEVEL=trace adds a log line for every handshake frame.
+    let level = std::env::var("LOG_LEVEL")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(tracing::Level::INFO);
     let subscriber = tracing_subscriber::fmt()
+        .with_max_level(level)
         // Use a more compact, abbreviated log format
         .compact()
         // Display source code file paths
@@ -50,6 +132,13 @@ fn set_console_logging() -> anyhow::Result<()> {
         // Build the subscriber
         .finish();
 
+    // Optionally export spans to OpenTelemetry
+    #[cfg(feature = "otel")]
+    let subscriber = {
+        use tracing_subscriber::layer::SubscriberExt;
+        subscriber.with(telemetry::otlp_layer()?)
+    };
+
     // Set the subscriber as the default
     tracing::subscriber::set_global_default(subscriber)?;
     Ok(())
@@ -117,26 +206,434 @@ async fn wasm_file() -> impl IntoResponse {
         .unwrap()
 }
 
+/// Build information, so clients can tag their results with the server
+/// build they were measured against.
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    git_hash: &'static str,
+    protocol_version: u16,
+}
+
+async fn version() -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GIT_HASH"),
+        protocol_version: shared_data::PROTOCOL_VERSION,
+    })
+}
+
+/// Where to find the WebTransport endpoint, for clients that support it.
+/// Not found if it isn't running, so clients stay on the WebSocket.
+async fn webtransport_info(State(state): State<AppState>) -> Response {
+    match &state.config.webtransport {
+        Some(info) => Json(info.clone()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
 
-pub async fn ws_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
+#[derive(Deserialize)]
+pub struct SummaryParams {
+    /// Only summarize the results reported under this campaign id.
+    campaign: Option<u64>,
+    /// The shared secret, as for `/ws`.
+    token: Option<String>,
+}
+
+/// Percentiles over the results clients have reported (`null` if there are
+/// none), for every session still held or only those tagged with a
+/// campaign. Needs the token if `AUTH_TOKEN` is set.
+async fn summary_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<SummaryParams>,
+) -> Response {
+    if !state.config.auth.check(&headers, params.token.as_deref()) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let reports = state.sessions.campaign_reports(params.campaign);
+    Json(summary::summarize(&reports)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct WsParams {
+    /// Token from a previous connection, to resume its session.
+    session: Option<u64>,
+    /// The shared secret, if `AUTH_TOKEN` is set and the client can't send
+    /// an `Authorization` header.
+    token: Option<String>,
+}
+
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<WsParams>,
+) -> Response {
     tracing::info!("WS Upgrade Called");
-    ws.on_upgrade(move |sock| handle_socket(sock))
+    if !state.config.auth.check(&headers, params.token.as_deref()) {
+        tracing::warn!("Rejecting WS upgrade without a valid token");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let span = connection_span(&headers);
+    ws.on_upgrade(move |sock| {
+        let token = state.sessions.attach(params.session, Instant::now());
+        let session = SessionHandle::new(state.sessions, token);
+        span.record("session", session.token);
+        handle_socket(sock, session, state.config).instrument(span)
+    })
+    .into_response()
+}
+
+/// A span for one connection. With the `otel` feature, a client can tie the
+/// server's spans into its own distributed trace by sending its W3C trace
+/// context in a `traceparent` header, which (unlike the query string) is
+/// never logged.
+#[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+fn connection_span(headers: &HeaderMap) -> tracing::Span {
+    let span = tracing::info_span!("connection", session = tracing::field::Empty);
+    #[cfg(feature = "otel")]
+    if let Some(traceparent) = headers.get("traceparent").and_then(|value| value.to_str().ok()) {
+        telemetry::set_parent(&span, traceparent);
+    }
+    span
+}
+
+/// The time now, for stamping frames. `None` (and logged) if the clock is
+/// set before the UNIX epoch, as any latency measured with it would be wrong.
+fn now_ms() -> Option<u128> {
+    let now = shared_data::unix_now_ms();
+    if now.is_none() {
+        tracing::error!("System clock is before the UNIX epoch; no time to stamp frames with");
+    }
+    now
+}
+
+type Capture = FrameCapture<BufWriter<File>>;
+
+/// Opens a capture file in `dir` for a new connection.
+fn open_capture(dir: &Path) -> Option<Capture> {
+    static CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+    let id = CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let path = dir.join(format!("{}-{id}.cap", now_ms().unwrap_or_default()));
+    match File::create(&path).and_then(|file| FrameCapture::new(BufWriter::new(file))) {
+        Ok(capture) => {
+            tracing::info!("Capturing frames to {path:?}");
+            Some(capture)
+        }
+        Err(e) => {
+            tracing::error!("Unable to open capture file {path:?}: {e}");
+            None
+        }
+    }
+}
+
+/// Records a frame if capture is enabled, disabling capture on error.
+fn capture_frame(capture: &mut Option<Capture>, direction: Direction, msg: &Message) {
+    let Some(writer) = capture else {
+        return;
+    };
+    let bytes = match msg {
+        Message::Binary(bytes) => bytes.as_slice(),
+        Message::Text(text) => text.as_bytes(),
+        _ => return,
+    };
+    let Some(now) = now_ms() else {
+        tracing::error!("Unable to timestamp capture, disabling it");
+        *capture = None;
+        return;
+    };
+    if let Err(e) = writer.record(now, direction, bytes) {
+        tracing::error!("Unable to write capture, disabling it: {e}");
+        *capture = None;
+    }
+}
+
+/// The `Busy` reply to a frame that arrived with no permit free, in the
+/// frame's transport, carrying the frame's id (or 0, if it can't be
+/// decoded). Filler needs no reply, so gets none.
+fn busy_reply(msg: &Message, config: &Config) -> Option<Outgoing> {
+    // Borrowed, so filler isn't copied just to be recognized
+    let reply_id = |bytes: &[u8]| match LatencyTestRef::decode(bytes) {
+        Ok(LatencyTestRef::Filler { .. }) => None,
+        Ok(frame) => Some(frame.id()),
+        Err(_) => Some(0),
+    };
+    let id = match msg {
+        Message::Binary(bytes) => reply_id(bytes),
+        Message::Text(text) => {
+            shared_data::decode_base64(text).map_or(Some(0), |bytes| reply_id(&bytes))
+        }
+        _ => return None,
+    };
+    let busy = LatencyTest::Busy {
+        magic: shared_data::MAGIC_NUMBER,
+        id: id?,
+        retry_after_ms: config.busy_retry_ms,
+    };
+    Some(Outgoing::Reply(Replier::new(msg, config), busy))
+}
+
+/// Decodes the frame `msg` carries, in either transport, and passes it to
+/// `inspect`.
+fn inspect_frame<T>(msg: &Message, inspect: impl FnOnce(LatencyTestRef) -> T) -> Option<T> {
+    match msg {
+        Message::Binary(bytes) => LatencyTestRef::decode(bytes).ok().map(inspect),
+        Message::Text(text) => {
+            let bytes = shared_data::decode_base64(text).ok()?;
+            LatencyTestRef::decode(&bytes).ok().map(inspect)
+        }
+        _ => None,
+    }
+}
+
+/// The kind of frame `msg` carries.
+fn frame_kind(msg: &Message) -> Option<MessageKind> {
+    inspect_frame(msg, |frame| frame.kind())
+}
+
+/// The server leg of the handshake `msg` completes, if it's a `SecondReply`.
+fn server_leg_ms(msg: &Message) -> Option<u128> {
+    inspect_frame(msg, |frame| match frame {
+        LatencyTestRef::Control(LatencyTest::SecondReply {
+            server_time,
+            server_ack_time,
+            ..
+        }) => server_ack_time.checked_sub(server_time),
+        _ => None,
+    })
+    .flatten()
+}
+
+/// Writes `msg` to the socket, flushing it if it's time-critical or nothing
+/// else is waiting to go.
+///
+/// Flush policy: probe replies (the handshake and keepalive acks) are timed
+/// by the client, so they're flushed as soon as they're written; any time
+/// they spent buffered would be measured as latency. Other frames (load
+/// filler, report acks, sessions) are only written, and go out together with
+/// whatever follows them, unless `more_queued` is false: then there's nothing
+/// to batch them with, and they're flushed too.
+async fn send_reply<S>(sink: &mut S, msg: Message, more_queued: bool) -> Result<(), S::Error>
+where
+    S: Sink<Message> + Unpin,
+{
+    let flush = !more_queued || frame_kind(&msg).is_some_and(MessageKind::is_probe);
+    sink.feed(msg).await?;
+    if flush {
+        sink.flush().await?;
+    }
+    Ok(())
+}
+
+/// Runs a frame handler in its own task, holding `permit` until it finishes.
+fn spawn_frame_task<F>(permit: OwnedSemaphorePermit, task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        task.await;
+        drop(permit);
+    });
+}
+
+/// The transport-independent half of a client connection: the session, the
+/// reply queue, frame capture and the per-connection frame permits.
+struct Connection {
+    session: SessionHandle,
+    config: Arc<Config>,
+    tx: Sender<Outgoing>,
+    capture: Option<Capture>,
+    session_announced: bool,
+    /// Totals for the log when the connection closes. Its handshakes count
+    /// towards `max_handshakes`.
+    metrics: ConnectionMetrics,
+    /// Each frame handler holds a permit. Frames arriving while none are
+    /// free get a `Busy` reply instead of a task, so a flood can't spawn
+    /// unbounded tasks, and the client can tell congestion from loss.
+    frame_limit: Arc<Semaphore>,
+    /// The number of the next reply sent, if the server numbers them.
+    next_reply: Option<u32>,
+    /// Once the last handshake allowed (`max_handshakes`) completes, when
+    /// to stop waiting for its report and close the connection anyway.
+    reconnect_at: Option<Instant>,
+    /// What the connection's handshakes have measured, and not yet filed.
+    handshake: HandshakeState,
+    /// Where to file what the reply being sent measured, once it's sent.
+    measured: Option<Measured>,
+}
+
+/// How long a connection that reached `max_handshakes` waits for the report
+/// of its last handshake before closing.
+const REPORT_GRACE: Duration = Duration::from_secs(2);
+
+/// The close frame asking a client that reached `max_handshakes` to
+/// reconnect.
+fn reconnect_close() -> CloseFrame<'static> {
+    CloseFrame {
+        code: shared_data::RECONNECT_CLOSE_CODE,
+        reason: "Handshake limit reached".into(),
+    }
+}
+
+/// Waits until `deadline`, or forever without one.
+async fn until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+impl Connection {
+    /// A new connection, and the queue its replies are sent from.
+    fn new(session: SessionHandle, config: Arc<Config>) -> (Self, Receiver<Outgoing>) {
+        let (tx, rx) = tokio::sync::mpsc::channel::<Outgoing>(10);
+        let connection = Self {
+            capture: config.capture_dir.as_deref().and_then(open_capture),
+            frame_limit: Arc::new(Semaphore::new(config.frame_concurrency)),
+            next_reply: config.sequence_replies.then_some(0),
+            session,
+            config,
+            tx,
+            session_announced: false,
+            metrics: ConnectionMetrics::new(Instant::now()),
+            reconnect_at: None,
+            handshake: HandshakeState::new(),
+            measured: None,
+        };
+        (connection, rx)
+    }
+
+    /// Queues the client's session token, if it hasn't been sent yet. The
+    /// connection loop mustn't wait on its own queue, so if it's full the
+    /// token goes with a later frame instead.
+    fn announce(&mut self, transport: Transport) {
+        if self.session_announced {
+            return;
+        }
+        let token = LatencyTest::Session {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            token: self.session.token,
+        };
+        let token = reply_message(&token, transport, &*self.config.codec);
+        self.session_announced = self.tx.try_send(token.into()).is_ok();
+    }
+
+    /// Handles a frame from the client in its own task. Returns a `Busy`
+    /// reply if there's no permit free; it must be sent directly, as the
+    /// queue may be full of replies from the busy handlers.
+    async fn received(&mut self, msg: Message) -> Option<Message> {
+        capture_frame(&mut self.capture, Direction::Inbound, &msg);
+        self.metrics.received(frame_len(&msg));
+        // Tell the client its session token, using the transport it chose
+        self.announce(match msg {
+            Message::Text(_) => Transport::Text,
+            _ => Transport::Binary,
+        });
+        // Spawn a new task, so we keep trucking in the meantime
+        let arrived = Instant::now();
+        match self.frame_limit.clone().try_acquire_owned() {
+            Ok(permit) => {
+                let last_report =
+                    self.reconnect_at.is_some() && frame_kind(&msg) == Some(MessageKind::Report);
+                let tx = self.tx.clone();
+                let task = handle_frame(
+                    msg,
+                    arrived,
+                    tx.clone(),
+                    self.session.clone(),
+                    self.config.clone(),
+                );
+                if last_report {
+                    // The last handshake's report: reconnect once it's handled
+                    // (and acked), rather than waiting out the grace period
+                    self.reconnect_at = None;
+                    let task = async move {
+                        task.await;
+                        let _ = tx.send(Outgoing::Close(reconnect_close())).await;
+                    };
+                    spawn_frame_task(permit, task.in_current_span());
+                } else {
+                    spawn_frame_task(permit, task.in_current_span());
+                }
+                None
+            }
+            Err(_) => {
+                let busy = busy_reply(&msg, &self.config)?;
+                tracing::debug!("Busy, asking client to back off");
+                let busy = self.sending(busy)?;
+                self.metrics.sent(frame_len(&busy), None);
+                Some(busy)
+            }
+        }
+    }
+
+    /// The frame to send for `outgoing`, numbered if it's a reply and the
+    /// server numbers them, and recorded if frames are captured. `None` if
+    /// there's nothing to send after all. Anything the reply measured waits
+    /// until it's `delivered`.
+    fn sending(&mut self, outgoing: Outgoing) -> Option<Message> {
+        let (msg, measured) = outgoing.stamp(&mut self.handshake, self.next_reply.as_mut())?;
+        capture_frame(&mut self.capture, Direction::Outbound, &msg);
+        self.measured = measured;
+        Some(msg)
+    }
+
+    /// Files what the frame just sent measured, if anything.
+    fn delivered(&mut self) {
+        if let Some(measured) = self.measured.take() {
+            measured.file(self.handshake.take_samples());
+        }
+    }
+
+    /// Counts a frame once it's sent. If it completed the last handshake
+    /// allowed (`max_handshakes`), the connection is closed with
+    /// `RECONNECT_CLOSE_CODE` once the client reports that handshake, or
+    /// after `REPORT_GRACE` if it doesn't.
+    fn sent(&mut self, msg: &Message) {
+        let server_leg = server_leg_ms(msg);
+        self.metrics.sent(frame_len(msg), server_leg);
+        let Some(max_handshakes) = self.config.max_handshakes else {
+            return;
+        };
+        if server_leg.is_none() || self.metrics.handshakes != max_handshakes {
+            return;
+        }
+        tracing::info!("Handshake limit reached, asking the client to reconnect");
+        self.reconnect_at = Some(Instant::now() + REPORT_GRACE);
+    }
+
+    /// Ends the session's connection, and logs (and returns) its metrics.
+    fn close(self) -> ConnectionMetrics {
+        let now = Instant::now();
+        self.session.store.detach(self.session.token, now);
+        if let Some(mut capture) = self.capture {
+            if let Err(e) = capture.flush() {
+                tracing::error!("Unable to flush capture: {e}");
+            }
+        }
+        self.metrics.close(now)
+    }
 }
 
-async fn handle_socket(mut socket: WebSocket) {
+async fn handle_socket(mut socket: WebSocket, session: SessionHandle, config: Arc<Config>) {
     tracing::info!("WebSocket Connected");
 
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(10);
+    let (mut connection, mut rx) = Connection::new(session, config);
 
     loop {
         tokio::select! {
             msg = socket.recv() => {
                 match msg {
-                    Some(Ok(Message::Binary(bytes))) => {
-                        // Spawn a new task, so we keep trucking in the meantime
-                        tokio::spawn(
-                            handle_socket_message(bytes, tx.clone())
-                        );
+                    Some(Ok(msg @ (Message::Binary(_) | Message::Text(_)))) => {
+                        if let Some(busy) = connection.received(msg).await {
+                            if let Err(e) = socket.send(busy).await {
+                                tracing::warn!("Error sending: {e}");
+                                break;
+                            }
+                        }
                     }
                     Some(Err(e)) => {
                         tracing::error!("Error receiving message: {:?}", e);
@@ -147,15 +644,27 @@ async fn handle_socket(mut socket: WebSocket) {
                         break;
                     }
                     _ => {
-                        tracing::error!("Message in non-binary format");
+                        tracing::error!("Message in unsupported format");
                         break;
                     }
                 }
             },
             msg = rx.recv() => {
                 match msg {
-                    Some(bytes) => {
-                        socket.send(Message::Binary(bytes)).await.unwrap();
+                    Some(reply) => {
+                        let Some(reply) = connection.sending(reply) else {
+                            continue;
+                        };
+                        connection.sent(&reply);
+                        let closing = matches!(reply, Message::Close(_));
+                        if let Err(e) = send_reply(&mut socket, reply, !rx.is_empty()).await {
+                            tracing::warn!("Error sending: {e}");
+                            break;
+                        }
+                        connection.delivered();
+                        if closing {
+                            break;
+                        }
                     }
                     None => {
                         tracing::info!("WebSocket Disconnected");
@@ -163,37 +672,1696 @@ async fn handle_socket(mut socket: WebSocket) {
                     }
                 }
             },
+            _ = until(connection.reconnect_at) => {
+                tracing::debug!("No report of the last handshake, reconnecting anyway");
+                let _ = socket.send(Message::Close(Some(reconnect_close()))).await;
+                break;
+            },
+        }
+    }
+
+    connection.close();
+}
+
+/// Randomly decides whether to drop a reply, with probability `rate`.
+fn should_drop(rate: f64) -> bool {
+    rate > 0.0 && rand::random::<f64>() < rate
+}
+
+/// Wraps an encoded reply in the same kind of frame the client used.
+fn reply_message(reply: &LatencyTest, transport: Transport, codec: &dyn Codec) -> Message {
+    let bytes = codec.encode(reply);
+    match transport {
+        Transport::Binary => Message::Binary(bytes),
+        Transport::Text => Message::Text(shared_data::encode_base64(&bytes)),
+    }
+}
+
+/// The size of a frame's payload.
+fn frame_len(msg: &Message) -> usize {
+    match msg {
+        Message::Binary(bytes) => bytes.len(),
+        Message::Text(text) => text.len(),
+        _ => 0,
+    }
+}
+
+/// Answers one request, in the transport it arrived in. Unless the cap is
+/// disabled, a reply may be at most `max_amplification` times the size of
+/// its request, so the server can't be used to turn small requests into
+/// much larger replies. A reply over the cap is refused with a `TooLarge`
+/// `ProtocolError` instead, so the client knows it won't come. (Download
+/// load is the one stream not capped, as it can't be: it's only started for
+/// a session that has completed a handshake, and is bounded by
+/// `MAX_LOAD_DURATION_MS`.)
+///
+/// If the server numbers its replies, each is wrapped in a `Sequenced`. The
+/// number is only given as the reply is sent (see `Outgoing`), and only once
+/// it's passed the cap, so the numbers follow the order replies go out in.
+#[derive(Clone)]
+struct Replier {
+    transport: Transport,
+    codec: FrameCodec,
+    max_len: Option<usize>,
+}
+
+impl Replier {
+    fn new(request: &Message, config: &Config) -> Self {
+        Self {
+            transport: match request {
+                Message::Text(_) => Transport::Text,
+                _ => Transport::Binary,
+            },
+            codec: config.codec.clone(),
+            max_len: config
+                .max_amplification
+                .map(|factor| frame_len(request).saturating_mul(factor as usize)),
+        }
+    }
+
+    /// `reply` as a frame, or the `ProtocolError` refusing it if it's over
+    /// the cap; `None` if even that is. If `next` holds the connection's next
+    /// reply number, the frame is numbered with it, and it's advanced.
+    fn reply(&self, reply: &LatencyTest, next: Option<&mut u32>) -> Option<Message> {
+        let seq = next.as_deref().copied();
+        let mut msg = self.frame(reply, seq);
+        if let Some(max_len) = self.max_len.filter(|&max_len| frame_len(&msg) > max_len) {
+            tracing::warn!(frame = %reply.short(), "Reply over the amplification cap, refused");
+            let detail = format!("{} byte reply, over the cap of {max_len}", frame_len(&msg));
+            let error = LatencyTest::protocol_error(ErrorCode::TooLarge, detail);
+            msg = self.frame(&error.with_id(reply.id()), seq);
+            if frame_len(&msg) > max_len {
+                return None;
+            }
+        }
+        if let Some(next) = next {
+            *next = next.wrapping_add(1);
+        }
+        Some(msg)
+    }
+
+    fn frame(&self, reply: &LatencyTest, seq: Option<u32>) -> Message {
+        match seq {
+            Some(seq) => {
+                let sequenced = LatencyTest::Sequenced {
+                    magic: shared_data::MAGIC_NUMBER,
+                    id: reply.id(),
+                    seq,
+                    frame: self.codec.encode(reply),
+                };
+                reply_message(&sequenced, self.transport, &BinaryCodec)
+            }
+            None => reply_message(reply, self.transport, &*self.codec),
+        }
+    }
+}
+
+/// A frame queued for the socket. Handshake replies carry the server's
+/// timestamp, which is only stamped as the reply is taken off the queue to
+/// be sent: otherwise time spent queued behind other frames would be counted
+/// as network latency. Replies are numbered then too, if the server numbers
+/// them, whichever task built them.
+enum Outgoing {
+    /// Sent as is, and never numbered: session announcements and filler.
+    Frame(Message),
+    /// A reply to one of the client's frames.
+    Reply(Replier, LatencyTest),
+    /// Builds the reply, given the time it's sent, with anything it
+    /// measured; or `None` if it mustn't be sent after all.
+    Stamped(Replier, Stamper),
+    /// A reply deliberately not sent (`DROP_RATE`). It still uses up a
+    /// number, so to the client it looks lost on the way. A handshake reply
+    /// is still built, so it's measured as of when it would have been sent.
+    Lost(Option<Stamper>),
+    /// Closes the connection, after sending everything queued before it.
+    Close(CloseFrame<'static>),
+}
+
+/// Builds a stamped reply, advancing the connection's handshakes.
+type Stamper = Box<dyn FnOnce(&mut HandshakeState, u128) -> Option<Stamped> + Send>;
+
+impl Outgoing {
+    fn stamped(
+        replier: Replier,
+        reply: impl FnOnce(&mut HandshakeState, u128) -> Option<Stamped> + Send + 'static,
+    ) -> Self {
+        Self::Stamped(replier, Box::new(reply))
+    }
+
+    fn lost(
+        reply: impl FnOnce(&mut HandshakeState, u128) -> Option<Stamped> + Send + 'static,
+    ) -> Self {
+        Self::Lost(Some(Box::new(reply)))
+    }
+
+    /// The frame to send now, stamping it if needed, and numbering it from
+    /// `next` if it's a reply and the server numbers them; with where to file
+    /// what a stamped reply measured in `state`, once it's sent. A reply that
+    /// can't be stamped isn't sent, and nor is what it measured filed.
+    fn stamp(
+        self,
+        state: &mut HandshakeState,
+        next: Option<&mut u32>,
+    ) -> Option<(Message, Option<Measured>)> {
+        let msg = match self {
+            Self::Frame(msg) => Some(msg),
+            Self::Reply(replier, reply) => replier.reply(&reply, next),
+            Self::Stamped(replier, reply) => {
+                let (reply, measured) = reply(state, now_ms()?)?;
+                let Some(msg) = replier.reply(&reply, next) else {
+                    state.take_samples();
+                    return None;
+                };
+                return Some((msg, measured));
+            }
+            Self::Lost(reply) => {
+                if let Some(next) = next {
+                    *next = next.wrapping_add(1);
+                }
+                let measured = reply.zip(now_ms()).and_then(|(reply, now)| reply(state, now));
+                if let Some((_, Some(measured))) = measured {
+                    measured.file(state.take_samples());
+                }
+                None
+            }
+            Self::Close(frame) => Some(Message::Close(Some(frame))),
+        };
+        Some((msg?, None))
+    }
+}
+
+/// A stamped reply, and what it measured.
+type Stamped = (LatencyTest, Option<Measured>);
+
+/// Where to file the results a handshake measured, which wait in the
+/// connection's `HandshakeState` until the reply completing it is sent:
+/// filing takes the session store's lock (and may send metrics), which
+/// mustn't hold up a reply already stamped.
+struct Measured {
+    session: SessionHandle,
+    id: u64,
+    /// The handshake's span, closed once the results are filed.
+    span: tracing::Span,
+}
+
+impl Measured {
+    fn file(self, samples: LatencySamples) {
+        let _entered = self.span.enter();
+        for sample in samples.iter() {
+            self.session.record(self.id, sample.timestamp_ms, sample.result);
+        }
+    }
+}
+
+impl From<Message> for Outgoing {
+    fn from(msg: Message) -> Self {
+        Self::Frame(msg)
+    }
+}
+
+/// A span tying together the frames of one handshake, identified by the
+/// server timestamp issued in `FirstReply`, which the client echoes back.
+/// Open spans are kept by probe id too (see `HandshakeSpans`).
+fn handshake_span(session: &SessionHandle, server_time: u128) -> tracing::Span {
+    tracing::info_span!(
+        "handshake",
+        session = session.token,
+        handshake_id = server_time as u64,
+        server_latency_ms = tracing::field::Empty,
+    )
+}
+
+/// Runs `frame` through the transport-independent handshake logic, in the
+/// connection's `state`, stamping the reply with `now`. If it measured
+/// anything, it's kept in `state` until the reply is sent, with where to
+/// file it in the session.
+fn process(
+    state: &mut HandshakeState,
+    session: &SessionHandle,
+    frame: LatencyTest,
+    now: u128,
+) -> Option<Stamped> {
+    let id = frame.id();
+    let reply = process_frame(state, frame, now)?;
+    let measured = (!state.samples().is_empty()).then(|| Measured {
+        session: session.clone(),
+        id,
+        span: tracing::Span::none(),
+    });
+    Some((reply, measured))
+}
+
+/// Handles a frame that arrived at `arrived`. A probe that waited longer
+/// than `max_frame_staleness` for its handler is answered `Busy` instead: its
+/// latency would measure the server's backlog, not the network.
+async fn handle_frame(
+    msg: Message,
+    arrived: Instant,
+    tx: Sender<Outgoing>,
+    session: SessionHandle,
+    config: Arc<Config>,
+) {
+    let stale = config.max_frame_staleness.is_some_and(|max| arrived.elapsed() > max);
+    if stale && frame_kind(&msg).is_some_and(MessageKind::is_probe) {
+        tracing::debug!(waited = ?arrived.elapsed(), "Probe stale, asking client to back off");
+        if let Some(busy) = busy_reply(&msg, &config) {
+            let _ = tx.send(busy).await;
         }
+        return;
     }
+    handle_socket_message(msg, tx, session, config).await;
 }
 
-async fn handle_socket_message(bytes: Vec<u8>, tx: Sender<Vec<u8>>) {
-    let decoded = LatencyTest::decode(&bytes).unwrap();
+/// Handles one frame from the client: an adapter between the socket and
+/// `process_frame`, adding what's specific to this server (load, reports,
+/// simulated loss, the amplification cap and tracing).
+async fn handle_socket_message(
+    msg: Message,
+    tx: Sender<Outgoing>,
+    session: SessionHandle,
+    config: Arc<Config>,
+) {
+    let codec = config.codec.clone();
+    let replier = Replier::new(&msg, &config);
+    let (bytes, transport) = match msg {
+        Message::Binary(bytes) => (Ok(bytes), Transport::Binary),
+        Message::Text(text) => (shared_data::decode_base64(&text), Transport::Text),
+        _ => return,
+    };
+    let bytes = match bytes {
+        Ok(bytes) if bytes.len() > MAX_FRAME_SIZE => {
+            tracing::warn!(len = bytes.len(), "Message too large");
+            let detail = format!("{} bytes, over {MAX_FRAME_SIZE}", bytes.len());
+            refuse(&tx, &replier, LatencyTest::protocol_error(ErrorCode::TooLarge, detail)).await;
+            return;
+        }
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::warn!("Unable to decode message: {e}");
+            let error = LatencyTest::protocol_error(ErrorCode::BadFrame, e.to_string());
+            refuse(&tx, &replier, error).await;
+            return;
+        }
+    };
+    let decoded = match codec.decode_ref(&bytes) {
+        // Upload load; nothing to do but receive it, so its payload isn't copied
+        Ok(LatencyTestRef::Filler { .. }) => return,
+        Ok(decoded) => decoded.into_owned(),
+        Err(e) => {
+            tracing::warn!("Unable to decode message: {e}");
+            let error = LatencyTest::protocol_error(ErrorCode::BadFrame, e.to_string());
+            refuse(&tx, &replier, error).await;
+            return;
+        }
+    };
     match decoded {
-        LatencyTest::InitialRequest { magic } => {
+        LatencyTest::InitialRequest { magic, .. } => {
+            assert_eq!(magic, shared_data::MAGIC_NUMBER);
+            // The handshake (and its span) starts when the reply is sent
+            let frame = decoded.short();
+            if should_drop(config.drop_rate) {
+                tracing::debug!(%frame, "Dropping reply (DROP_RATE)");
+                let _ = tx.send(Outgoing::Lost(None)).await;
+                return;
+            }
+            let reply = move |state: &mut HandshakeState, server_time| {
+                let handshake = handshake_span(&session, server_time);
+                handshake.in_scope(|| tracing::trace!(%frame, "frame received"));
+                session.handshakes.open(decoded.id(), server_time, handshake);
+                process(state, &session, decoded, server_time)
+            };
+            tx.send(Outgoing::stamped(replier, reply)).await.unwrap();
+        }
+        LatencyTest::FirstResponse { magic, server_time, .. } => {
             assert_eq!(magic, shared_data::MAGIC_NUMBER);
-            let reply = LatencyTest::FirstReply {
+            let handshake = session
+                .handshakes
+                .take(decoded.id(), server_time)
+                .unwrap_or_else(|| handshake_span(&session, server_time));
+            handshake.in_scope(|| tracing::trace!(frame = %decoded.short(), "frame received"));
+            let dropped = should_drop(config.drop_rate);
+            if dropped {
+                handshake.in_scope(|| tracing::debug!("Dropping reply (DROP_RATE)"));
+            }
+            let reply = move |state: &mut HandshakeState, server_ack_time: u128| {
+                // A client claiming a later server time gets no latency
+                if let Some(server_latency_ms) = server_ack_time.checked_sub(server_time) {
+                    handshake.record("server_latency_ms", server_latency_ms as f64);
+                }
+                let (reply, mut measured) = process(state, &session, decoded, server_ack_time)?;
+                // The handshake's span closes once its result is filed
+                if let Some(measured) = &mut measured {
+                    measured.span = handshake;
+                }
+                Some((reply, measured))
+            };
+            if dropped {
+                let _ = tx.send(Outgoing::lost(reply)).await;
+                return;
+            }
+            tx.send(Outgoing::stamped(replier, reply)).await.unwrap();
+        }
+        LatencyTest::Load {
+            direction,
+            duration_ms,
+            ..
+        } => {
+            // Filler is far larger than the request for it, so only a client
+            // known to be measuring (not just anyone who connects) gets it
+            let established = session.store.is_established(session.token);
+            if direction == LoadDirection::Download && !established {
+                tracing::warn!("Download load requested before any handshake, refused");
+                let reason = "Load before any handshake";
+                let error = LatencyTest::protocol_error(ErrorCode::Unexpected, reason);
+                refuse(&tx, &replier, error.with_id(decoded.id())).await;
+                return;
+            }
+            let duration_ms = duration_ms.min(shared_data::MAX_LOAD_DURATION_MS);
+            let until = Instant::now() + std::time::Duration::from_millis(duration_ms as u64);
+            tracing::debug!("Loading {} for {duration_ms}ms", direction.name());
+            let extended = session.load.start(direction, until);
+            if direction == LoadDirection::Download && !extended {
+                // In a task of its own, so it doesn't hold the frame's permit
+                let filler = reply_message(&load::filler(), transport, &*codec);
+                tokio::spawn(download(tx, filler, session.load.clone()).in_current_span());
+            }
+        }
+        LatencyTest::Filler { .. } => {
+            // Upload load; nothing to do but receive it
+        }
+        LatencyTest::KeepAlive { .. } => {
+            // Not a measurement: answered, but kept out of the stats
+            let reply = move |state: &mut HandshakeState, server_time| {
+                process(state, &session, decoded, server_time)
+            };
+            tx.send(Outgoing::stamped(replier, reply)).await.unwrap();
+        }
+        LatencyTest::Report {
+            result,
+            campaign_id,
+            ref metadata,
+            ..
+        } => {
+            tracing::debug!(frame = %decoded.short(), "Result reported");
+            let metadata = metadata.clone();
+            if let Some(now) = now_ms() {
+                session.report(now, result, campaign_id, metadata);
+            }
+            if config.echo_reports {
+                // The report carries its handshake's id
+                let server_leg = session.store.server_latency_ms(session.token, decoded.id());
+                if let Some(server_latency_ms) = server_leg {
+                    if !result.agrees_with_server(server_latency_ms) {
+                        tracing::warn!(
+                            reported = result.server_latency_ms,
+                            measured = server_latency_ms,
+                            "Reported server leg doesn't match the server's"
+                        );
+                    }
+                    let ack = LatencyTest::ReportAck {
+                        magic: shared_data::MAGIC_NUMBER,
+                        id: decoded.id(),
+                        server_latency_ms,
+                    };
+                    let _ = tx.send(Outgoing::Reply(replier, ack)).await;
+                }
+            }
+        }
+        LatencyTest::FirstReply { .. }
+        | LatencyTest::SecondReply { .. }
+        | LatencyTest::Final { .. } => {
+            // The handshake's own frames, from the wrong side: the client is
+            // broken (or not a client), so under UNEXPECTED_FRAMES=close, it's
+            // told so by closing the connection
+            tracing::warn!(frame = %decoded.short(), "Handshake frame not expected by server");
+            let reason = format!("Unexpected {:?}", decoded.kind());
+            let error = LatencyTest::protocol_error(ErrorCode::Unexpected, reason.clone());
+            refuse(&tx, &replier, error.with_id(decoded.id())).await;
+            if config.unexpected_frames == UnexpectedFrames::Close {
+                let close = CloseFrame {
+                    code: axum::extract::ws::close_code::POLICY,
+                    reason: reason.into(),
+                };
+                let _ = tx.send(Outgoing::Close(close)).await;
+            }
+        }
+        LatencyTest::ProtocolError { .. } => {
+            // The client refusing one of ours; answering it would only echo
+            tracing::debug!(frame = %decoded.short(), "Frame refused by client");
+        }
+        _ => {
+            // Frames a newer client may send, or the server's other frames:
+            // refused, so the client knows they did nothing, but harmless
+            tracing::debug!(frame = %decoded.short(), "Message not handled by server");
+            let reason = format!("Unexpected {:?}", decoded.kind());
+            let error = LatencyTest::protocol_error(ErrorCode::Unexpected, reason);
+            refuse(&tx, &replier, error.with_id(decoded.id())).await;
+        }
+    }
+}
+
+/// Saturates the downlink with `filler` for as long as the connection's
+/// download load lasts, including any extension of it.
+async fn download(tx: Sender<Outgoing>, filler: Message, load: Arc<load::LoadPhase>) {
+    while load.current(Instant::now()) == LoadDirection::Download {
+        if tx.send(filler.clone().into()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Tells the client why its frame was refused, with a `ProtocolError`.
+async fn refuse(tx: &Sender<Outgoing>, replier: &Replier, error: LatencyTest) {
+    let _ = tx.send(Outgoing::Reply(replier.clone(), error)).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::Auth;
+    use crate::sessions::SessionStore;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    impl Outgoing {
+        /// The frame as the socket loop sends it, filing anything it
+        /// measured straight away.
+        fn into_message(self, next: Option<&mut u32>) -> Option<Message> {
+            let mut state = HandshakeState::new();
+            let (msg, measured) = self.stamp(&mut state, next)?;
+            if let Some(measured) = measured {
+                measured.file(state.take_samples());
+            }
+            Some(msg)
+        }
+    }
+
+    fn test_state(auth: Auth) -> AppState {
+        let config = Config {
+            auth,
+            ..Config::default()
+        };
+        AppState::new(config, SessionStore::new(Duration::from_secs(60)))
+    }
+
+    fn test_app(auth: Auth) -> Router {
+        app(test_state(auth))
+    }
+
+    fn test_config() -> Arc<Config> {
+        Arc::new(Config::default())
+    }
+
+    #[tokio::test]
+    async fn version_reports_build_info() {
+        let response = test_app(Auth::default())
+            .oneshot(Request::builder().uri("/version").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["git_hash"], env!("GIT_HASH"));
+        assert_eq!(json["protocol_version"], shared_data::PROTOCOL_VERSION);
+    }
+
+    #[tokio::test]
+    async fn summary_filters_by_campaign() {
+        let state = test_state(Auth::default());
+        let now = Instant::now();
+        let reports = [(Some(7), 10.0), (Some(7), 20.0), (Some(8), 500.0), (None, 900.0)];
+        for (campaign_id, latency_ms) in reports {
+            let token = state.sessions.attach(None, now);
+            let result = shared_data::LatencyResult {
+                latency_ms,
+                server_latency_ms: latency_ms,
+                client_latency_ms: latency_ms,
+                approximate: false,
+                resolution_limited: false,
+            };
+            let metadata = shared_data::Metadata::new();
+            state.sessions.report(token, 1000, result, campaign_id, metadata);
+        }
+        let summary = |uri| {
+            let app = app(state.clone());
+            async move {
+                let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        let json = summary("/summary?campaign=7").await;
+        assert_eq!(json["clients"], 2);
+        assert_eq!(json["samples"], 2);
+        assert_eq!(json["p99_ms"], 20.0);
+        assert_eq!(summary("/summary").await["samples"], 4);
+        assert!(summary("/summary?campaign=9").await.is_null());
+    }
+
+    #[tokio::test]
+    async fn webtransport_is_advertised_only_when_running() {
+        let request = || Request::builder().uri("/webtransport").body(Body::empty()).unwrap();
+        let response = test_app(Auth::default()).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let config = Config {
+            webtransport: Some(state::WebTransportInfo {
+                port: 4433,
+                certificate_hash: Some("ab".repeat(32)),
+            }),
+            ..Config::default()
+        };
+        let state = AppState::new(config, SessionStore::new(Duration::from_secs(60)));
+        let response = app(state).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["port"], 4433);
+        assert_eq!(json["certificate_hash"], "ab".repeat(32));
+    }
+
+    /// Serves `app` on a local port, and attempts a WebSocket upgrade at
+    /// `path` (with any `extra` header lines). Returns the response's status.
+    async fn upgrade_status(app: Router, path: &str, extra: &str) -> u16 {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service());
+        tokio::spawn(server);
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: Upgrade\r\n\
+             Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{extra}\r\n"
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = [0; 12];
+        stream.read_exact(&mut response).await.unwrap();
+        // "HTTP/1.1 101"
+        std::str::from_utf8(&response[9..]).unwrap().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn upgrade_without_auth_is_accepted() {
+        assert_eq!(upgrade_status(test_app(Auth::default()), "/ws", "").await, 101);
+    }
+
+    #[tokio::test]
+    async fn upgrade_with_token_is_accepted() {
+        let auth = Auth::new("s3cret");
+        assert_eq!(upgrade_status(test_app(auth.clone()), "/ws?token=s3cret", "").await, 101);
+        let header = "Authorization: Bearer s3cret\r\n";
+        assert_eq!(upgrade_status(test_app(auth), "/ws", header).await, 101);
+    }
+
+    #[tokio::test]
+    async fn upgrade_without_token_is_rejected() {
+        let auth = Auth::new("s3cret");
+        assert_eq!(upgrade_status(test_app(auth.clone()), "/ws", "").await, 401);
+        assert_eq!(upgrade_status(test_app(auth), "/ws?token=guess", "").await, 401);
+    }
+
+    #[tokio::test]
+    async fn connection_closes_after_max_handshakes() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let config = Config {
+            max_handshakes: Some(2),
+            ..Config::default()
+        };
+        let state = AppState::new(config, SessionStore::new(Duration::from_secs(60)));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app(state.clone()).into_make_service()));
+        let url = format!("ws://{addr}/ws");
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        let initial = LatencyTest::InitialRequest {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+        };
+        let report = LatencyTest::Report {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            result: shared_data::LatencyResult {
+                latency_ms: 1.0,
+                server_latency_ms: 1.0,
+                client_latency_ms: 1.0,
+                approximate: false,
+                resolution_limited: false,
+            },
+            campaign_id: None,
+            metadata: shared_data::Metadata::new(),
+        };
+        for _ in 0..2 {
+            ws.send(WsMessage::Binary(initial.encode())).await.unwrap();
+            let server_time = loop {
+                let msg = ws.next().await.unwrap().unwrap();
+                match LatencyTest::decode(&msg.into_data()).unwrap() {
+                    LatencyTest::FirstReply { server_time, .. } => break server_time,
+                    _ => continue,
+                }
+            };
+            let response = LatencyTest::FirstResponse {
                 magic: shared_data::MAGIC_NUMBER,
-                server_time: shared_data::unix_now_ms(),
+                id: 0,
+                server_time,
+                client_time: server_time,
             };
-            tx.send(reply.encode()).await.unwrap();
+            ws.send(WsMessage::Binary(response.encode())).await.unwrap();
+            let msg = ws.next().await.unwrap().unwrap();
+            let reply = LatencyTest::decode(&msg.into_data()).unwrap();
+            assert_eq!(reply.kind(), MessageKind::SecondReply);
+            ws.send(WsMessage::Binary(report.encode())).await.unwrap();
         }
-        LatencyTest::FirstResponse {
-            magic,
+
+        // The second handshake is reported, and the server then asks for a
+        // reconnect, having recorded that report too
+        let Some(Ok(WsMessage::Close(Some(close)))) = ws.next().await else {
+            panic!("Expected a close frame");
+        };
+        assert_eq!(close.code, CloseCode::from(shared_data::RECONNECT_CLOSE_CODE));
+        let reports = state.sessions.campaign_reports(None);
+        assert_eq!(reports.iter().map(|reports| reports.len()).sum::<usize>(), 2);
+    }
+
+    #[tokio::test]
+    async fn connection_metrics_total_the_session() {
+        let (mut connection, _rx) = Connection::new(test_session(), test_config());
+        let request = LatencyTest::InitialRequest {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+        };
+        connection.received(Message::Binary(request.encode())).await;
+        connection.received(Message::Binary(request.encode())).await;
+        let second_reply = |server_time, server_ack_time| LatencyTest::SecondReply {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
             server_time,
-            client_time,
-        } => {
-            assert_eq!(magic, shared_data::MAGIC_NUMBER);
+            client_time: 5000,
+            server_ack_time,
+        };
+        connection.sent(&Message::Binary(second_reply(1000, 1010).encode()));
+        connection.sent(&Message::Text(second_reply(2000, 2030).encode_text()));
+        let filler = load::filler().encode();
+        connection.sent(&Message::Binary(filler.clone()));
+
+        let metrics = connection.close();
+        assert_eq!(metrics.handshakes, 2);
+        assert_eq!(metrics.mean_server_latency_ms(), Some(20.0));
+        assert_eq!(metrics.bytes_received, 28);
+        let sent = second_reply(0, 0).encode().len() + second_reply(0, 0).encode_text().len();
+        assert_eq!(metrics.bytes_sent, (sent + filler.len()) as u64);
+    }
+
+    #[tokio::test]
+    async fn echoed_report_agrees_with_the_client() {
+        use futures_util::{SinkExt, StreamExt};
+        use shared_data::handshake::{client_step, ClientStep};
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let config = Config {
+            echo_reports: true,
+            ..Config::default()
+        };
+        let state = AppState::new(config, SessionStore::new(Duration::from_secs(60)));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app(state).into_make_service()));
+        let url = format!("ws://{addr}/ws");
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        // The second handshake's server leg is held up, so the two differ
+        let mut results = Vec::new();
+        for (id, delay_ms) in [(1, 0), (2, 50)] {
+            let initial = LatencyTest::InitialRequest {
+                magic: shared_data::MAGIC_NUMBER,
+                id,
+            };
+            ws.send(WsMessage::Binary(initial.encode())).await.unwrap();
+            let result = loop {
+                let msg = ws.next().await.unwrap().unwrap();
+                let frame = LatencyTest::decode(&msg.into_data()).unwrap();
+                match client_step(frame, now_ms().unwrap()) {
+                    ClientStep::Reply(reply) => {
+                        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                        ws.send(WsMessage::Binary(reply.encode())).await.unwrap()
+                    }
+                    ClientStep::Complete { result, .. } => break result,
+                    ClientStep::Unexpected(_) => continue,
+                }
+            };
+            results.push(result);
+        }
+
+        // Reported late, the first is still acked with its own server leg
+        let report = LatencyTest::Report {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 1,
+            result: results[0],
+            campaign_id: None,
+            metadata: shared_data::Metadata::new(),
+        };
+        ws.send(WsMessage::Binary(report.encode())).await.unwrap();
+        let msg = ws.next().await.unwrap().unwrap();
+        let Ok(LatencyTest::ReportAck {
+            id,
+            server_latency_ms,
+            ..
+        }) = LatencyTest::decode(&msg.into_data())
+        else {
+            panic!("Expected a ReportAck");
+        };
+        assert_eq!(id, 1);
+        assert!(results[0].agrees_with_server(server_latency_ms));
+        assert!(!results[1].agrees_with_server(server_latency_ms));
+    }
+
+    #[tokio::test]
+    async fn app_states_are_independent() {
+        let open = test_state(Auth::default());
+        let locked = test_state(Auth::new("s3cret"));
+        assert_eq!(upgrade_status(app(open.clone()), "/ws", "").await, 101);
+        assert_eq!(upgrade_status(app(locked.clone()), "/ws", "").await, 401);
+
+        let token = open.sessions.attach(None, Instant::now());
+        let result = shared_data::LatencyResult {
+            latency_ms: 10.0,
+            server_latency_ms: 10.0,
+            client_latency_ms: 10.0,
+            approximate: false,
+            resolution_limited: false,
+        };
+        open.sessions.record(token, 0, 1000, result);
+        assert_eq!(open.sessions.samples(token).map(|samples| samples.len()), Some(1));
+        assert!(locked.sessions.samples(token).is_none());
+    }
+
+    fn test_session() -> SessionHandle {
+        let store = Arc::new(SessionStore::new(Duration::from_secs(60)));
+        let token = store.attach(None, Instant::now());
+        SessionHandle::new(store, token)
+    }
+
+    async fn reply_to(msg: Message) -> Message {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        handle_socket_message(msg, tx, test_session(), test_config()).await;
+        rx.recv().await.unwrap().into_message(None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replies_echo_the_request_id() {
+        let request = LatencyTest::InitialRequest {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 42,
+        };
+        let Message::Binary(bytes) = reply_to(Message::Binary(request.encode())).await else {
+            panic!("Expected a binary reply");
+        };
+        let reply = LatencyTest::decode(&bytes).unwrap();
+        assert_eq!(reply.kind(), MessageKind::FirstReply);
+        assert_eq!(reply.id(), 42);
+
+        let keepalive = LatencyTest::KeepAlive {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 43,
+            client_time: 1000,
+        };
+        let Message::Binary(bytes) = reply_to(Message::Binary(keepalive.encode())).await else {
+            panic!("Expected a binary reply");
+        };
+        assert_eq!(LatencyTest::decode(&bytes).unwrap().id(), 43);
+    }
+
+    #[tokio::test]
+    async fn unexpected_final_closes_the_connection() {
+        let unexpected = LatencyTest::Final {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 9,
+            server_time: 1000,
+            client_time: 5010,
+            server_ack_time: 1020,
+            client_ack_time: 5030,
+        };
+        // Refused under the frame's own id
+        let error =
+            LatencyTest::protocol_error(ErrorCode::Unexpected, "Unexpected Final").with_id(9);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let msg = Message::Binary(unexpected.encode());
+        handle_socket_message(msg, tx, test_session(), test_config()).await;
+        let reply = rx.recv().await.unwrap().into_message(None).unwrap();
+        assert_eq!(reply, Message::Binary(error.encode()));
+        let close = rx.recv().await.unwrap().into_message(None);
+        let Some(Message::Close(Some(close))) = close else {
+            panic!("Expected the connection to be closed");
+        };
+        assert_eq!(close.code, axum::extract::ws::close_code::POLICY);
+        assert_eq!(close.reason, "Unexpected Final");
+
+        // Or just refused, if so configured
+        let config = Config {
+            unexpected_frames: UnexpectedFrames::Warn,
+            ..Config::default()
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let msg = Message::Binary(unexpected.encode());
+        handle_socket_message(msg, tx, test_session(), Arc::new(config)).await;
+        let reply = rx.recv().await.unwrap().into_message(None).unwrap();
+        assert_eq!(reply, Message::Binary(error.encode()));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn other_unhandled_frames_are_refused_without_closing() {
+        let unhandled = [
+            LatencyTest::KeepAliveAck {
+                magic: shared_data::MAGIC_NUMBER,
+                id: 3,
+                client_time: 1000,
+                server_time: 1010,
+            },
+            LatencyTest::Busy {
+                magic: shared_data::MAGIC_NUMBER,
+                id: 3,
+                retry_after_ms: 100,
+            },
+            LatencyTest::Session {
+                magic: shared_data::MAGIC_NUMBER,
+                id: 3,
+                token: 42,
+            },
+        ];
+        for frame in unhandled {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+            let msg = Message::Binary(frame.encode());
+            handle_socket_message(msg, tx, test_session(), test_config()).await;
+            let reply = rx.recv().await.unwrap().into_message(None).unwrap();
+            let reason = format!("Unexpected {:?}", frame.kind());
+            let error = LatencyTest::protocol_error(ErrorCode::Unexpected, reason).with_id(3);
+            assert_eq!(reply, Message::Binary(error.encode()));
+            assert!(rx.recv().await.is_none(), "{frame:?}");
+        }
+
+        // A client's refusal isn't answered at all
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let refusal = LatencyTest::protocol_error(ErrorCode::BadFrame, "garbled");
+        handle_socket_message(Message::Binary(refusal.encode()), tx, test_session(), test_config())
+            .await;
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn bad_frames_are_refused_with_a_reason() {
+        let refusal = |reply: Message| {
+            let Message::Binary(bytes) = reply else {
+                panic!("Expected a binary reply, got {reply:?}");
+            };
+            match LatencyTest::decode(&bytes).unwrap() {
+                LatencyTest::ProtocolError { code, .. } => ErrorCode::from_u16(code),
+                other => panic!("Expected a ProtocolError, got {other:?}"),
+            }
+        };
+        let mut garbage = LatencyTest::InitialRequest {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+        }
+        .encode();
+        garbage.extend([0xff; 60]);
+        garbage[5] = 0xff;
+        assert_eq!(refusal(reply_to(Message::Binary(garbage)).await), Some(ErrorCode::BadFrame));
+
+        let mut oversized = LatencyTest::Filler {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            bytes: vec![0; MAX_FRAME_SIZE],
+        }
+        .encode();
+        oversized.truncate(MAX_FRAME_SIZE + 1);
+        let reply = reply_to(Message::Binary(oversized)).await;
+        assert_eq!(refusal(reply), Some(ErrorCode::TooLarge));
+    }
+
+    #[tokio::test]
+    async fn numbered_replies_count_dropped_ones() {
+        let session = test_session();
+        let config = |drop_rate| {
+            Arc::new(Config {
+                sequence_replies: true,
+                drop_rate,
+                ..Config::default()
+            })
+        };
+        let initial = LatencyTest::InitialRequest {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+        };
+        let keepalive = LatencyTest::KeepAlive {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            client_time: 1000,
+        };
+        let mut replies = Vec::new();
+        let mut next = 0;
+        for (request, drop_rate) in [(&initial, 0.0), (&initial, 1.0), (&keepalive, 1.0)] {
+            // Each reply is sent (and numbered) before the next request
+            let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+            let msg = Message::Binary(request.encode());
+            handle_socket_message(msg, tx, session.clone(), config(drop_rate)).await;
+            while let Some(outgoing) = rx.recv().await {
+                let Some(msg) = outgoing.into_message(Some(&mut next)) else {
+                    continue;
+                };
+                let Message::Binary(bytes) = msg else {
+                    panic!("Expected a binary reply");
+                };
+                let Ok(LatencyTest::Sequenced { seq, frame, .. }) = LatencyTest::decode(&bytes)
+                else {
+                    panic!("Expected a numbered reply");
+                };
+                replies.push((seq, LatencyTest::decode(&frame).unwrap().kind()));
+            }
+        }
+        // The dropped FirstReply's number is skipped, as if lost on the way
+        assert_eq!(
+            replies,
+            [(0, MessageKind::FirstReply), (2, MessageKind::KeepAliveAck)]
+        );
+    }
+
+    #[tokio::test]
+    async fn replies_are_numbered_as_they_are_sent() {
+        let config = Arc::new(Config {
+            sequence_replies: true,
+            max_amplification: Some(4),
+            ..Config::default()
+        });
+        let (mut connection, _rx) = Connection::new(test_session(), config.clone());
+        let replier = Replier::new(&Message::Binary(vec![0; 16]), &config);
+        let ack = |id| LatencyTest::ReportAck {
+            magic: shared_data::MAGIC_NUMBER,
+            id,
+            server_latency_ms: 10.0,
+        };
+        // Over the cap, and too small a cap for its refusal: never sent
+        let oversized = LatencyTest::protocol_error(ErrorCode::BadFrame, "x".repeat(100));
+        let queued = [
+            Outgoing::stamped(replier.clone(), move |_, _| Some((ack(1), None))),
+            Outgoing::Reply(replier.clone(), oversized),
+            Outgoing::Frame(Message::Binary(load::filler().encode())),
+            Outgoing::Reply(replier, ack(2)),
+        ];
+        let mut numbered = Vec::new();
+        for outgoing in queued {
+            let Some(Message::Binary(bytes)) = connection.sending(outgoing) else {
+                continue;
+            };
+            if let Ok(LatencyTest::Sequenced { seq, frame, .. }) = LatencyTest::decode(&bytes) {
+                numbered.push((seq, LatencyTest::decode(&frame).unwrap().id()));
+            }
+        }
+        // Numbered in the order sent, with no gap for the reply never sent
+        assert_eq!(numbered, [(0, 1), (1, 2)]);
+    }
+
+    #[tokio::test]
+    async fn text_request_gets_text_reply() {
+        let request = LatencyTest::InitialRequest {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+        };
+        match reply_to(Message::Text(request.encode_text())).await {
+            Message::Text(text) => assert!(matches!(
+                LatencyTest::decode_text(&text),
+                Ok(LatencyTest::FirstReply { .. })
+            )),
+            other => panic!("Expected a text reply, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn binary_request_gets_binary_reply() {
+        let request = LatencyTest::FirstResponse {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 1030,
+        };
+        match reply_to(Message::Binary(request.encode())).await {
+            Message::Binary(bytes) => assert!(matches!(
+                LatencyTest::decode(&bytes),
+                Ok(LatencyTest::SecondReply {
+                    server_time: 1000,
+                    client_time: 1030,
+                    ..
+                })
+            )),
+            other => panic!("Expected a binary reply, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn keepalive_is_acked_but_not_measured() {
+        let session = test_session();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let probe = LatencyTest::KeepAlive {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            client_time: 2000,
+        };
+        let before = shared_data::unix_now_ms().unwrap();
+        handle_socket_message(
+            Message::Binary(probe.encode()),
+            tx,
+            session.clone(),
+            test_config(),
+        )
+        .await;
+
+        match rx.recv().await.unwrap().into_message(None).unwrap() {
+            Message::Binary(bytes) => match LatencyTest::decode(&bytes) {
+                Ok(LatencyTest::KeepAliveAck {
+                    client_time,
+                    server_time,
+                    ..
+                }) => {
+                    assert_eq!(client_time, 2000);
+                    assert!(server_time >= before);
+                }
+                other => panic!("Expected a KeepAliveAck, got {other:?}"),
+            },
+            other => panic!("Expected a binary reply, got {other:?}"),
+        }
+        let samples = session.store.samples(session.token).unwrap();
+        assert!(samples.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replies_over_the_amplification_cap_are_not_sent() {
+        // A 14-byte InitialRequest would elicit a 30-byte FirstReply
+        let request = LatencyTest::InitialRequest {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+        }
+        .encode();
+        let reply = |max_amplification| {
+            let msg = Message::Binary(request.clone());
+            async move {
+                let config = Config {
+                    max_amplification,
+                    ..Config::default()
+                };
+                let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+                handle_socket_message(msg, tx, test_session(), Arc::new(config)).await;
+                rx.recv().await.unwrap().into_message(None)
+            }
+        };
+        // Too small a cap even for the refusal
+        assert!(reply(Some(2)).await.is_none());
+        assert!(reply(Some(3)).await.is_some());
+        assert!(reply(None).await.is_some());
+    }
+
+    #[test]
+    fn replies_over_the_amplification_cap_are_refused() {
+        let request = Message::Binary(vec![0; 10]);
+        let config = Config {
+            max_amplification: Some(10),
+            ..Config::default()
+        };
+        let replier = Replier::new(&request, &config);
+        let small = LatencyTest::protocol_error(ErrorCode::BadFrame, "x".repeat(50));
+        assert_eq!(replier.reply(&small, None), Some(Message::Binary(small.encode())));
+
+        let large = LatencyTest::protocol_error(ErrorCode::BadFrame, "x".repeat(200)).with_id(4);
+        let Some(Message::Binary(bytes)) = replier.reply(&large, None) else {
+            panic!("Expected a binary refusal");
+        };
+        assert!(bytes.len() <= 100);
+        let Ok(LatencyTest::ProtocolError { id, code, .. }) = LatencyTest::decode(&bytes) else {
+            panic!("Expected a ProtocolError");
+        };
+        assert_eq!((id, ErrorCode::from_u16(code)), (4, Some(ErrorCode::TooLarge)));
+    }
+
+    #[tokio::test]
+    async fn session_is_announced_without_waiting_on_a_full_queue() {
+        let (mut connection, mut rx) = Connection::new(test_session(), test_config());
+        while connection.tx.try_send(Outgoing::Lost(None)).is_ok() {}
+        connection.announce(Transport::Binary);
+        assert!(!connection.session_announced);
+
+        // Once there's room, the next frame announces it
+        rx.recv().await.unwrap();
+        connection.announce(Transport::Binary);
+        assert!(connection.session_announced);
+    }
+
+    #[tokio::test]
+    async fn frame_tasks_are_bounded_by_permits() {
+        use std::sync::atomic::AtomicUsize;
+
+        const PERMITS: usize = 3;
+        let limit = Arc::new(Semaphore::new(PERMITS));
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(AtomicUsize::new(0));
+        for _ in 0..50 {
+            let permit = limit.clone().acquire_owned().await.unwrap();
+            let (running, peak, finished) = (running.clone(), peak.clone(), finished.clone());
+            spawn_frame_task(permit, async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        // Once every permit is back, every task has finished
+        let _all = limit.acquire_many(PERMITS as u32).await.unwrap();
+        assert_eq!(finished.load(Ordering::SeqCst), 50);
+        assert!(peak.load(Ordering::SeqCst) <= PERMITS);
+    }
+
+    #[test]
+    fn drop_fraction_matches_rate() {
+        const FRAMES: usize = 20_000;
+        assert!(!(0..FRAMES).any(|_| should_drop(0.0)));
+        assert!((0..FRAMES).all(|_| should_drop(1.0)));
+        let dropped = (0..FRAMES).filter(|_| should_drop(0.25)).count();
+        let fraction = dropped as f64 / FRAMES as f64;
+        assert!((0.23..0.27).contains(&fraction), "dropped {fraction}");
+    }
+
+    #[tokio::test]
+    async fn reported_metadata_is_stored_with_results() {
+        let session = test_session();
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let result = shared_data::LatencyResult {
+            latency_ms: 21.0,
+            server_latency_ms: 20.0,
+            client_latency_ms: 22.0,
+            approximate: false,
+            resolution_limited: false,
+        };
+        let metadata = shared_data::Metadata::from([("isp".to_string(), "Example Fiber".to_string())]);
+        let report = LatencyTest::Report {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            result,
+            campaign_id: None,
+            metadata: metadata.clone(),
+        };
+        handle_socket_message(
+            Message::Binary(report.encode()),
+            tx,
+            session.clone(),
+            test_config(),
+        )
+        .await;
+
+        let (reports, stored) = session.store.reports(session.token).unwrap();
+        assert_eq!(reports.iter().map(|sample| sample.result).collect::<Vec<_>>(), [result]);
+        assert_eq!(stored, metadata);
+    }
+
+    #[tokio::test]
+    async fn latency_under_download_is_recorded_separately() {
+        let session = test_session();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+        let drain = tokio::spawn(async move {
+            let mut fillers = 0;
+            while let Some(outgoing) = rx.recv().await {
+                let Some(Message::Binary(bytes)) = Outgoing::into_message(outgoing, None) else {
+                    break;
+                };
+                if let Ok(LatencyTest::Filler { .. }) = LatencyTest::decode(&bytes) {
+                    fillers += 1;
+                }
+            }
+            fillers
+        });
+        let handshake = || {
+            let request = LatencyTest::FirstResponse {
+                magic: shared_data::MAGIC_NUMBER,
+                id: 0,
+                server_time: shared_data::unix_now_ms().unwrap(),
+                client_time: 1030,
+            };
+            handle_socket_message(
+                Message::Binary(request.encode()),
+                tx.clone(),
+                session.clone(),
+                test_config(),
+            )
+        };
+
+        let load = LatencyTest::Load {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            direction: LoadDirection::Download,
+            duration_ms: 200,
+        };
+        let start_load = || {
+            handle_socket_message(
+                Message::Binary(load.encode()),
+                tx.clone(),
+                session.clone(),
+                test_config(),
+            )
+        };
+        // Only loaded once the session is established, as it is when the
+        // reply is sent (and measured)
+        handshake().await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        start_load().await;
+        // The filler is sent from its own task, not the frame's
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        handshake().await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        handshake().await;
+        drop(tx);
+        assert!(drain.await.unwrap() > 0);
+
+        let store = &session.store;
+        let loaded = store.loaded_samples(session.token, LoadDirection::Download).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(store.samples(session.token).unwrap().len(), 2);
+        assert!(store
+            .loaded_samples(session.token, LoadDirection::Upload)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn download_before_any_handshake_is_refused() {
+        let load = LatencyTest::Load {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 5,
+            direction: LoadDirection::Download,
+            duration_ms: 1000,
+        };
+        let session = test_session();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let msg = Message::Binary(load.encode());
+        handle_socket_message(msg, tx, session.clone(), test_config()).await;
+        let Some(Message::Binary(bytes)) = rx.recv().await.unwrap().into_message(None) else {
+            panic!("Expected a binary reply");
+        };
+        let refusal = LatencyTest::decode(&bytes).unwrap();
+        assert!(matches!(refusal, LatencyTest::ProtocolError { id: 5, code: 2, .. }));
+        // Nothing else is queued, and the link isn't loaded
+        assert!(rx.recv().await.is_none());
+        assert_eq!(session.load.current(Instant::now()), LoadDirection::Idle);
+    }
+
+    #[test]
+    fn busy_reply_matches_transport_and_skips_filler() {
+        let request = LatencyTest::InitialRequest {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+        };
+        let busy = LatencyTest::Busy {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            retry_after_ms: 250,
+        };
+        let config = Config {
+            busy_retry_ms: 250,
+            ..Config::default()
+        };
+        let reply = |msg| busy_reply(&msg, &config).and_then(|busy| busy.into_message(None));
+        assert_eq!(reply(Message::Binary(request.encode())), Some(Message::Binary(busy.encode())));
+        let text = Message::Text(request.encode_text());
+        assert_eq!(reply(text), Some(Message::Text(busy.encode_text())));
+        assert!(busy_reply(&Message::Binary(load::filler().encode()), &config).is_none());
+    }
+
+    #[test]
+    fn backwards_handshake_has_no_server_leg() {
+        let reply = |server_ack_time| {
             let reply = LatencyTest::SecondReply {
-                magic,
-                server_time,
-                client_time,
-                server_ack_time: shared_data::unix_now_ms(),
+                magic: shared_data::MAGIC_NUMBER,
+                id: 0,
+                server_time: 1000,
+                client_time: 5010,
+                server_ack_time,
             };
-            tx.send(reply.encode()).await.unwrap();
+            Message::Binary(reply.encode())
+        };
+        assert_eq!(server_leg_ms(&reply(1020)), Some(20));
+        assert_eq!(server_leg_ms(&reply(990)), None);
+    }
+
+    #[tokio::test]
+    async fn stale_probes_are_answered_busy() {
+        let config = Arc::new(Config {
+            max_frame_staleness: Some(Duration::from_millis(50)),
+            busy_retry_ms: 250,
+            ..Config::default()
+        });
+        let request = Message::Binary(
+            LatencyTest::InitialRequest {
+                magic: shared_data::MAGIC_NUMBER,
+                id: 0,
+            }
+            .encode(),
+        );
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let session = test_session();
+        handle_frame(
+            request.clone(),
+            Instant::now(),
+            tx.clone(),
+            session.clone(),
+            config.clone(),
+        )
+        .await;
+        let reply = rx.recv().await.unwrap().into_message(None).unwrap();
+        assert_eq!(frame_kind(&reply), Some(MessageKind::FirstReply));
+
+        // Held up behind other work past the threshold
+        let arrived = Instant::now();
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        handle_frame(request, arrived, tx, session, config).await;
+        let reply = rx.recv().await.unwrap().into_message(None).unwrap();
+        let busy = LatencyTest::Busy {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            retry_after_ms: 250,
+        };
+        assert_eq!(reply, Message::Binary(busy.encode()));
+    }
+
+    /// A sink that records what's done to it.
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Vec<&'static str>,
+    }
+
+    impl Sink<Message> for RecordingSink {
+        type Error = std::convert::Infallible;
+
+        fn poll_ready(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::Poll::Ready(Ok(()))
         }
-        _ => {
-            tracing::warn!("Message not expected by server: {decoded:?}");
+
+        fn start_send(mut self: std::pin::Pin<&mut Self>, _: Message) -> Result<(), Self::Error> {
+            self.events.push("write");
+            Ok(())
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            self.events.push("flush");
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn latency_replies_are_flushed_and_others_batched() {
+        let first_reply = LatencyTest::FirstReply {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+        };
+        let mut sink = RecordingSink::default();
+        send_reply(&mut sink, Message::Binary(first_reply.encode()), true).await.unwrap();
+        assert_eq!(sink.events, ["write", "flush"]);
+        // Text frames too
+        let mut sink = RecordingSink::default();
+        send_reply(&mut sink, Message::Text(first_reply.encode_text()), true).await.unwrap();
+        assert_eq!(sink.events, ["write", "flush"]);
+
+        // Filler waits for whatever is queued behind it, but not forever
+        let mut sink = RecordingSink::default();
+        let filler = Message::Binary(load::filler().encode());
+        send_reply(&mut sink, filler.clone(), true).await.unwrap();
+        assert_eq!(sink.events, ["write"]);
+        send_reply(&mut sink, filler, false).await.unwrap();
+        assert_eq!(sink.events, ["write", "write", "flush"]);
+    }
+
+    #[tokio::test]
+    async fn replies_are_stamped_when_sent_not_queued() {
+        const QUEUE_DELAY: Duration = Duration::from_millis(50);
+        let session = test_session();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let initial = LatencyTest::InitialRequest {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+        };
+        handle_socket_message(
+            Message::Binary(initial.encode()),
+            tx.clone(),
+            session.clone(),
+            test_config(),
+        )
+        .await;
+        let server_time = shared_data::unix_now_ms().unwrap();
+        let response = LatencyTest::FirstResponse {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            server_time,
+            client_time: 1030,
+        };
+        handle_socket_message(
+            Message::Binary(response.encode()),
+            tx,
+            session.clone(),
+            test_config(),
+        )
+        .await;
+
+        // Both replies wait in the queue, as if behind a backlog of frames
+        tokio::time::sleep(QUEUE_DELAY).await;
+        let sent_after = shared_data::unix_now_ms().unwrap();
+        let decode = |msg: Message| match msg {
+            Message::Binary(bytes) => LatencyTest::decode(&bytes).unwrap(),
+            other => panic!("Expected a binary reply, got {other:?}"),
+        };
+        match decode(rx.recv().await.unwrap().into_message(None).unwrap()) {
+            LatencyTest::FirstReply { server_time, .. } => assert!(server_time >= sent_after),
+            other => panic!("Expected FirstReply, got {other:?}"),
         }
+        match decode(rx.recv().await.unwrap().into_message(None).unwrap()) {
+            LatencyTest::SecondReply {
+                server_ack_time, ..
+            } => assert!(server_ack_time >= sent_after),
+            other => panic!("Expected SecondReply, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_is_recorded_in_session() {
+        let session = test_session();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let request = LatencyTest::FirstResponse {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            server_time: shared_data::unix_now_ms().unwrap(),
+            client_time: 1030,
+        };
+        handle_socket_message(
+            Message::Binary(request.encode()),
+            tx,
+            session.clone(),
+            test_config(),
+        )
+        .await;
+        rx.recv().await.unwrap().into_message(None).unwrap();
+        assert_eq!(session.store.samples(session.token).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn handshake_is_recorded_once_its_reply_is_sent() {
+        let session = test_session();
+        let (mut connection, mut rx) = Connection::new(session.clone(), test_config());
+        let request = LatencyTest::FirstResponse {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            server_time: shared_data::unix_now_ms().unwrap(),
+            client_time: 1030,
+        };
+        let msg = Message::Binary(request.encode());
+        handle_socket_message(msg, connection.tx.clone(), session.clone(), test_config()).await;
+
+        // Stamped, but not yet sent: measured by the connection alone
+        connection.sending(rx.recv().await.unwrap()).unwrap();
+        assert_eq!(connection.handshake.samples().len(), 1);
+        assert!(session.store.samples(session.token).unwrap().is_empty());
+        connection.delivered();
+        assert!(connection.handshake.samples().is_empty());
+        assert_eq!(session.store.samples(session.token).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_handshake_reply_is_still_recorded() {
+        let session = test_session();
+        let config = Arc::new(Config {
+            drop_rate: 1.0,
+            ..Config::default()
+        });
+        let (mut connection, mut rx) = Connection::new(session.clone(), config.clone());
+        let request = LatencyTest::FirstResponse {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            server_time: shared_data::unix_now_ms().unwrap(),
+            client_time: 1030,
+        };
+        let msg = Message::Binary(request.encode());
+        handle_socket_message(msg, connection.tx.clone(), session.clone(), config).await;
+
+        assert!(connection.sending(rx.recv().await.unwrap()).is_none());
+        assert!(connection.handshake.samples().is_empty());
+        assert_eq!(session.store.samples(session.token).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn handshake_span_carries_session_id() {
+        use std::fmt::Write;
+        use std::sync::Mutex;
+        use tracing::field::Field;
+        use tracing::span::{Attributes, Id};
+        use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+        /// Records the fields of every new span, formatted as `name=value`.
+        struct SpanFields(Arc<Mutex<Vec<(&'static str, String)>>>);
+
+        impl<S: tracing::Subscriber> Layer<S> for SpanFields {
+            fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+                let mut fields = String::new();
+                attrs.record(&mut |field: &Field, value: &dyn std::fmt::Debug| {
+                    write!(fields, "{}={:?} ", field.name(), value).unwrap();
+                });
+                let name = attrs.metadata().name();
+                self.0.lock().unwrap().push((name, fields));
+            }
+        }
+
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(SpanFields(spans.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let session = test_session();
+        let request = LatencyTest::InitialRequest {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+        };
+        let reply = |request: LatencyTest| {
+            let (tx, mut rx) = tokio::sync::mpsc::channel::<Outgoing>(1);
+            let session = session.clone();
+            async move {
+                let msg = Message::Binary(request.encode());
+                handle_socket_message(msg, tx, session, test_config()).await;
+                let Some(Message::Binary(bytes)) = rx.recv().await.unwrap().into_message(None)
+                else {
+                    panic!("Expected a binary reply");
+                };
+                LatencyTest::decode(&bytes).unwrap()
+            }
+        };
+        let LatencyTest::FirstReply { server_time, .. } = reply(request).await else {
+            panic!("Expected a FirstReply");
+        };
+        let response = LatencyTest::FirstResponse {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            server_time,
+            client_time: 1030,
+        };
+        reply(response).await;
+
+        // One span, from the FirstReply to the SecondReply
+        let spans = spans.lock().unwrap();
+        let handshakes: Vec<_> = spans.iter().filter(|(name, _)| *name == "handshake").collect();
+        assert_eq!(handshakes.len(), 1);
+        assert!(handshakes[0].1.contains(&format!("session={} ", session.token)));
+    }
+
+    #[cfg(feature = "otel")]
+    #[tokio::test]
+    async fn handshake_span_records_server_latency() {
+        use opentelemetry::trace::TracerProvider as _;
+        use opentelemetry::Value;
+        use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+        use opentelemetry_sdk::trace::TracerProvider;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let request = LatencyTest::FirstResponse {
+            magic: shared_data::MAGIC_NUMBER,
+            id: 0,
+            server_time: shared_data::unix_now_ms().unwrap(),
+            client_time: 1030,
+        };
+        reply_to(Message::Binary(request.encode())).await;
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let handshake = spans.iter().find(|span| span.name == "handshake").unwrap();
+        let latency = handshake
+            .attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == "server_latency_ms")
+            .unwrap();
+        assert!(matches!(latency.value, Value::F64(ms) if ms >= 0.0));
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn traceparent_header_joins_the_client_trace() {
+        use opentelemetry::trace::{TraceId, TracerProvider as _};
+        use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+        use opentelemetry_sdk::trace::TracerProvider;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let trace_id = "0af7651916cd43dd8448eb211c80319c";
+        let mut headers = HeaderMap::new();
+        let traceparent = format!("00-{trace_id}-b7ad6b7169203331-01");
+        headers.insert("traceparent", traceparent.parse().unwrap());
+        drop(connection_span(&headers));
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let trace_id = TraceId::from_hex(trace_id).unwrap();
+        assert_eq!(spans[0].span_context.trace_id(), trace_id);
     }
 }
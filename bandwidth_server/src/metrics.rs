@@ -0,0 +1,64 @@
+//! What happened on one connection, totalled up as it runs and logged when
+//! it closes, for after-the-fact analysis of individual clients.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionMetrics {
+    /// Handshakes the server completed (sent a `SecondReply` for).
+    pub handshakes: u32,
+    /// Sum of the completed handshakes' server legs.
+    server_latency_total_ms: f64,
+    /// Payload bytes sent and received, excluding WebSocket framing.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    opened: Instant,
+    /// How long the connection was open, once closed.
+    pub duration: Duration,
+}
+
+impl ConnectionMetrics {
+    pub fn new(opened: Instant) -> Self {
+        Self {
+            handshakes: 0,
+            server_latency_total_ms: 0.0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            opened,
+            duration: Duration::ZERO,
+        }
+    }
+
+    pub fn received(&mut self, len: usize) {
+        self.bytes_received += len as u64;
+    }
+
+    /// Counts a frame sent, and the handshake it completed, given the
+    /// handshake's server leg.
+    pub fn sent(&mut self, len: usize, server_leg_ms: Option<u128>) {
+        self.bytes_sent += len as u64;
+        if let Some(server_leg_ms) = server_leg_ms {
+            self.handshakes += 1;
+            self.server_latency_total_ms += server_leg_ms as f64;
+        }
+    }
+
+    /// Mean server leg of the completed handshakes.
+    pub fn mean_server_latency_ms(&self) -> Option<f64> {
+        (self.handshakes > 0).then(|| self.server_latency_total_ms / self.handshakes as f64)
+    }
+
+    /// Stops the clock, and logs the totals.
+    pub fn close(mut self, closed: Instant) -> Self {
+        self.duration = closed.saturating_duration_since(self.opened);
+        tracing::info!(
+            handshakes = self.handshakes,
+            mean_server_latency_ms = self.mean_server_latency_ms(),
+            bytes_sent = self.bytes_sent,
+            bytes_received = self.bytes_received,
+            duration_ms = self.duration.as_millis() as u64,
+            "Connection closed"
+        );
+        self
+    }
+}
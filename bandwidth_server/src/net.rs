@@ -0,0 +1,75 @@
+//! Listener setup. Nagle's algorithm holds back small writes waiting to
+//! coalesce them, which adds directly to the latency being measured, so every
+//! accepted connection has `TCP_NODELAY` set.
+
+use hyper::server::conn::AddrIncoming;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Optional socket tuning, read from the environment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketConfig {
+    /// `SO_SNDBUF` size in bytes, from `SOCKET_SEND_BUFFER`.
+    pub send_buffer: Option<usize>,
+    /// `SO_RCVBUF` size in bytes, from `SOCKET_RECV_BUFFER`.
+    pub recv_buffer: Option<usize>,
+}
+
+impl SocketConfig {
+    pub fn from_env() -> Self {
+        let size = |name| std::env::var(name).ok().and_then(|size| size.parse().ok());
+        Self {
+            send_buffer: size("SOCKET_SEND_BUFFER"),
+            recv_buffer: size("SOCKET_RECV_BUFFER"),
+        }
+    }
+}
+
+/// Binds a listener at `addr`. Buffer sizes set on the listener are
+/// inherited by accepted connections, and each connection gets
+/// `TCP_NODELAY`.
+pub fn bind(addr: SocketAddr, config: &SocketConfig) -> io::Result<AddrIncoming> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if let Some(size) = config.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+
+    let listener = tokio::net::TcpListener::from_std(socket.into())?;
+    let mut incoming = AddrIncoming::from_listener(listener).map_err(io::Error::other)?;
+    incoming.set_nodelay(true);
+    Ok(incoming)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::server::accept::Accept;
+    use std::pin::Pin;
+
+    #[tokio::test]
+    async fn accepted_streams_have_nodelay() {
+        let config = SocketConfig {
+            send_buffer: Some(64 * 1024),
+            recv_buffer: Some(64 * 1024),
+        };
+        let mut incoming = bind(SocketAddr::from(([127, 0, 0, 1], 0)), &config).unwrap();
+        let _client = tokio::net::TcpStream::connect(incoming.local_addr())
+            .await
+            .unwrap();
+        let accepted = std::future::poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(accepted.into_inner().nodelay().unwrap());
+    }
+}
@@ -0,0 +1,93 @@
+//! HTTP request accounting: how often each route is requested, and a warning
+//! for any request over a latency budget. A slow page load (the wasm bundle
+//! especially) delays the first connection, so slow assets are worth
+//! knowing about when reading connection-setup times.
+
+use crate::state::AppState;
+use axum::extract::{MatchedPath, State};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Requests per route, since the counts were last taken.
+#[derive(Default)]
+pub struct RouteCounts {
+    counts: Mutex<BTreeMap<String, u64>>,
+}
+
+impl RouteCounts {
+    pub fn record(&self, route: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts.entry(route.to_string()).or_default() += 1;
+    }
+
+    /// The counts so far, by route, resetting them; so each summary logs the
+    /// requests of its own interval.
+    pub fn take(&self) -> BTreeMap<String, u64> {
+        std::mem::take(&mut *self.counts.lock().unwrap())
+    }
+}
+
+/// Middleware counting each request against its route, and warning of any
+/// that takes longer than `slow_request_budget`. Only matched routes are
+/// counted, so unknown paths can't grow the counts without bound.
+pub async fn track<B>(
+    State(state): State<AppState>,
+    route: MatchedPath,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = start.elapsed();
+    state.routes.record(route.as_str());
+    if state.config.slow_request_budget.is_some_and(|budget| elapsed > budget) {
+        tracing::warn!(
+            route = route.as_str(),
+            elapsed_ms = elapsed.as_millis() as u64,
+            "Slow request"
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sessions::SessionStore;
+    use crate::state::Config;
+    use crate::test_util::capture_events;
+    use axum::body::Body;
+    use axum::{middleware, routing::get, Router};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn slow_request_is_logged() {
+        let (events, _guard) = capture_events();
+
+        let config = Config {
+            slow_request_budget: Some(Duration::from_millis(10)),
+            ..Config::default()
+        };
+        let state = AppState::new(config, SessionStore::new(Duration::from_secs(60)));
+        let app = Router::new()
+            .route("/fast", get(|| async {}))
+            .route("/slow", get(|| tokio::time::sleep(Duration::from_millis(50))))
+            .route_layer(middleware::from_fn_with_state(state.clone(), track))
+            .with_state(state.clone());
+        for uri in ["/fast", "/slow", "/fast", "/missing"] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.iter().filter(|(_, m)| m == "Slow request").count(), 1);
+        let counts = BTreeMap::from([("/fast".to_string(), 2), ("/slow".to_string(), 1)]);
+        assert_eq!(state.routes.take(), counts);
+        assert!(state.routes.take().is_empty());
+    }
+}
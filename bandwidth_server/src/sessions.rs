@@ -0,0 +1,488 @@
+//! Per-client sessions, so a client that reconnects after a drop keeps its
+//! accumulated history. The server issues each new connection a random
+//! token; presenting it when reconnecting resumes the session, unless it has
+//! been idle (disconnected) for longer than the TTL.
+
+use crate::load::LoadPhase;
+use crate::sla::{Sla, SlaEvent, SlaState};
+use crate::spans::HandshakeSpans;
+use shared_data::{LatencyResult, LatencySamples, LoadDirection, Metadata};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many of its latest handshakes a session keeps the server leg of, for
+/// checking the client's reports against.
+const RECENT_SERVER_LEGS: usize = 16;
+
+struct Session {
+    /// Results measured by the server (the server leg only) on an idle link.
+    samples: LatencySamples,
+    /// Results measured by the server while the link was loaded.
+    loaded: HashMap<LoadDirection, LatencySamples>,
+    /// The server leg of the latest results measured, idle or loaded, by
+    /// handshake id, oldest first.
+    server_legs: VecDeque<(u64, f64)>,
+    /// Full results reported by the client.
+    reports: LatencySamples,
+    /// Labels the client attached to its reports.
+    metadata: Metadata,
+    /// The measurement campaign the client tagged its reports with.
+    campaign_id: Option<u64>,
+    /// Progress against the store's SLA, if it has one.
+    sla: SlaState,
+    connected: bool,
+    last_seen: Instant,
+}
+
+pub struct SessionStore {
+    ttl: Duration,
+    sla: Option<Sla>,
+    sessions: Mutex<HashMap<u64, Session>>,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            sla: None,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Alerts when a session's latency breaches `sla`.
+    pub fn with_sla(mut self, sla: Option<Sla>) -> Self {
+        self.sla = sla;
+        self
+    }
+
+    /// Reads the idle TTL from `SESSION_TTL_SECS`, defaulting to five minutes,
+    /// and the SLA as `Sla::from_env` does.
+    pub fn from_env() -> Self {
+        let ttl = std::env::var("SESSION_TTL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(300);
+        Self::new(Duration::from_secs(ttl)).with_sla(Sla::from_env())
+    }
+
+    /// Attaches a connection to the session for `token`, if it exists and
+    /// hasn't expired, or otherwise to a brand new session. Returns the
+    /// session's token.
+    pub fn attach(&self, token: Option<u64>, now: Instant) -> u64 {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| {
+            session.connected || now.duration_since(session.last_seen) < self.ttl
+        });
+
+        if let Some(token) = token {
+            if let Some(session) = sessions.get_mut(&token).filter(|session| !session.connected) {
+                session.connected = true;
+                session.last_seen = now;
+                return token;
+            }
+        }
+
+        let mut token = rand::random();
+        while sessions.contains_key(&token) {
+            token = rand::random();
+        }
+        sessions.insert(
+            token,
+            Session {
+                samples: LatencySamples::new(),
+                loaded: HashMap::new(),
+                server_legs: VecDeque::new(),
+                reports: LatencySamples::new(),
+                metadata: Metadata::new(),
+                campaign_id: None,
+                sla: SlaState::default(),
+                connected: true,
+                last_seen: now,
+            },
+        );
+        token
+    }
+
+    /// Marks the session's connection as closed, starting its idle TTL. Any
+    /// SLA breach ends with the connection: a client that resumes the
+    /// session starts afresh, rather than alerting on latency from before.
+    pub fn detach(&self, token: u64, now: Instant) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&token) {
+            session.connected = false;
+            session.last_seen = now;
+            session.sla = SlaState::default();
+        }
+    }
+
+    /// Records the result of handshake `id`.
+    pub fn record(&self, token: u64, id: u64, timestamp_ms: u128, result: LatencyResult) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&token) {
+            session.samples.push(timestamp_ms, result);
+            self.measured(token, session, id, timestamp_ms, &result);
+        }
+    }
+
+    pub fn samples(&self, token: u64) -> Option<LatencySamples> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(&token).map(|session| session.samples.clone())
+    }
+
+    /// Records a result measured while the link was loaded in `direction`.
+    /// `Idle` results are recorded as usual.
+    pub fn record_under_load(
+        &self,
+        token: u64,
+        id: u64,
+        direction: LoadDirection,
+        timestamp_ms: u128,
+        result: LatencyResult,
+    ) {
+        if direction == LoadDirection::Idle {
+            return self.record(token, id, timestamp_ms, result);
+        }
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&token) {
+            session
+                .loaded
+                .entry(direction)
+                .or_default()
+                .push(timestamp_ms, result);
+            self.measured(token, session, id, timestamp_ms, &result);
+        }
+    }
+
+    /// Notes the latest result the server measured, alerting if it breaches
+    /// the SLA or recovers from a breach.
+    fn measured(
+        &self,
+        token: u64,
+        session: &mut Session,
+        id: u64,
+        timestamp_ms: u128,
+        result: &LatencyResult,
+    ) {
+        let latency_ms = result.server_latency_ms;
+        if session.server_legs.len() == RECENT_SERVER_LEGS {
+            session.server_legs.pop_front();
+        }
+        session.server_legs.push_back((id, latency_ms));
+        let Some(sla) = &self.sla else {
+            return;
+        };
+        let Some(event) = session.sla.observe(sla, timestamp_ms, latency_ms) else {
+            return;
+        };
+        match event {
+            SlaEvent::Breached { since_ms, .. } => tracing::error!(
+                session = token,
+                threshold_ms = sla.threshold_ms,
+                latency_ms,
+                above_for_ms = timestamp_ms.saturating_sub(since_ms) as u64,
+                "Latency SLA breached"
+            ),
+            SlaEvent::Recovered { breached_for_ms } => tracing::info!(
+                session = token,
+                threshold_ms = sla.threshold_ms,
+                latency_ms,
+                breached_for_ms = breached_for_ms as u64,
+                "Latency SLA recovered"
+            ),
+        }
+        #[cfg(feature = "webhook")]
+        if let Some(webhook) = crate::webhook::webhook() {
+            let alert = crate::webhook::Alert::new(sla, timestamp_ms, latency_ms, event);
+            webhook.send(alert);
+        }
+    }
+
+    /// True once the session has completed a handshake, so its client is
+    /// known to be measuring.
+    pub fn is_established(&self, token: u64) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(&token).is_some_and(|session| !session.server_legs.is_empty())
+    }
+
+    /// The server leg of handshake `id`, as the server measured it, whether
+    /// the link was loaded or not. `None` unless it's one of the session's
+    /// latest `RECENT_SERVER_LEGS`.
+    pub fn server_latency_ms(&self, token: u64, id: u64) -> Option<f64> {
+        let sessions = self.sessions.lock().unwrap();
+        let legs = &sessions.get(&token)?.server_legs;
+        legs.iter().rev().find(|(leg, _)| *leg == id).map(|(_, latency_ms)| *latency_ms)
+    }
+
+    /// Results measured while the link was loaded in `direction`.
+    pub fn loaded_samples(&self, token: u64, direction: LoadDirection) -> Option<LatencySamples> {
+        if direction == LoadDirection::Idle {
+            return self.samples(token);
+        }
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(&token)
+            .map(|session| session.loaded.get(&direction).cloned().unwrap_or_default())
+    }
+
+    /// Stores a result reported by the client. Its metadata replaces any
+    /// previously reported. The session's campaign is the one its first
+    /// report named, so its results never count towards two campaigns: a
+    /// later report naming another is dropped, returning false.
+    pub fn report(
+        &self,
+        token: u64,
+        timestamp_ms: u128,
+        result: LatencyResult,
+        campaign_id: Option<u64>,
+        metadata: Metadata,
+    ) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(&token) else {
+            return false;
+        };
+        if session.reports.is_empty() {
+            session.campaign_id = campaign_id;
+        } else if session.campaign_id != campaign_id {
+            return false;
+        }
+        session.reports.push(timestamp_ms, result);
+        session.metadata = metadata;
+        true
+    }
+
+    /// The client-reported results, and their metadata.
+    pub fn reports(&self, token: u64) -> Option<(LatencySamples, Metadata)> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(&token)
+            .map(|session| (session.reports.clone(), session.metadata.clone()))
+    }
+
+    /// The client-reported results of every connected session.
+    pub fn connected_reports(&self) -> Vec<LatencySamples> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .values()
+            .filter(|session| session.connected)
+            .map(|session| session.reports.clone())
+            .collect()
+    }
+
+    /// The client-reported results of every session, connected or not yet
+    /// expired, that reported under `campaign_id` (or of all of them, given
+    /// `None`).
+    pub fn campaign_reports(&self, campaign_id: Option<u64>) -> Vec<LatencySamples> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .values()
+            .filter(|session| campaign_id.is_none() || session.campaign_id == campaign_id)
+            .map(|session| session.reports.clone())
+            .collect()
+    }
+}
+
+/// A connection's handle on its session.
+#[derive(Clone)]
+pub struct SessionHandle {
+    pub store: Arc<SessionStore>,
+    pub token: u64,
+    /// The load this connection is currently under.
+    pub load: Arc<LoadPhase>,
+    /// The spans of this connection's handshakes in progress.
+    pub handshakes: Arc<HandshakeSpans>,
+}
+
+impl SessionHandle {
+    pub fn new(store: Arc<SessionStore>, token: u64) -> Self {
+        Self {
+            store,
+            token,
+            load: Arc::default(),
+            handshakes: Arc::default(),
+        }
+    }
+
+    /// Records the result of handshake `id`, separately from idle results if
+    /// the connection is currently under load.
+    pub fn record(&self, id: u64, timestamp_ms: u128, result: LatencyResult) {
+        let direction = self.load.current(Instant::now());
+        #[cfg(feature = "statsd")]
+        if let Some(metrics) = crate::statsd::metrics() {
+            metrics.send(crate::statsd::Source::Server, direction, &result);
+        }
+        #[cfg(feature = "webhook")]
+        if let Some(webhook) = crate::webhook::webhook() {
+            let payload =
+                crate::webhook::Payload::new("server", direction, timestamp_ms, &result);
+            webhook.send(payload);
+        }
+        self.store
+            .record_under_load(self.token, id, direction, timestamp_ms, result);
+    }
+
+    /// Stores a result reported by the client, and passes it on to any
+    /// metrics or webhook. A report under another campaign than the
+    /// session's first is dropped.
+    pub fn report(
+        &self,
+        timestamp_ms: u128,
+        result: LatencyResult,
+        campaign_id: Option<u64>,
+        metadata: Metadata,
+    ) {
+        let stored = self.store.report(
+            self.token,
+            timestamp_ms,
+            result,
+            campaign_id,
+            metadata.clone(),
+        );
+        if !stored {
+            tracing::warn!(campaign_id, "Dropping a report from another campaign");
+        }
+        #[cfg(feature = "statsd")]
+        if let Some(metrics) = crate::statsd::metrics().filter(|_| stored) {
+            let direction = self.load.current(Instant::now());
+            metrics.send(crate::statsd::Source::Client, direction, &result);
+        }
+        #[cfg(feature = "webhook")]
+        if let Some(webhook) = crate::webhook::webhook().filter(|_| stored) {
+            let direction = self.load.current(Instant::now());
+            let mut payload =
+                crate::webhook::Payload::new("client", direction, timestamp_ms, &result);
+            payload.campaign_id = campaign_id;
+            payload.metadata = metadata;
+            webhook.send(payload);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::capture_events;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn result(latency_ms: f64) -> LatencyResult {
+        LatencyResult {
+            latency_ms,
+            server_latency_ms: latency_ms,
+            client_latency_ms: latency_ms,
+            approximate: true,
+            resolution_limited: false,
+        }
+    }
+
+    #[test]
+    fn reconnect_restores_history() {
+        let store = SessionStore::new(TTL);
+        let start = Instant::now();
+        let token = store.attach(None, start);
+        store.record(token, 0, 1000, result(12.0));
+        store.record(token, 0, 2000, result(14.0));
+        store.detach(token, start + Duration::from_secs(1));
+
+        let resumed = store.attach(Some(token), start + Duration::from_secs(30));
+        assert_eq!(resumed, token);
+        let samples = store.samples(token).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples.mean_ms(), Some(13.0));
+    }
+
+    #[test]
+    fn expired_token_starts_afresh() {
+        let store = SessionStore::new(TTL);
+        let start = Instant::now();
+        let token = store.attach(None, start);
+        store.record(token, 0, 1000, result(12.0));
+        store.detach(token, start);
+
+        let later = start + TTL + Duration::from_secs(1);
+        let fresh = store.attach(Some(token), later);
+        assert_ne!(fresh, token);
+        assert!(store.samples(token).is_none());
+        assert!(store.samples(fresh).unwrap().is_empty());
+    }
+
+    #[test]
+    fn connected_sessions_never_expire() {
+        let store = SessionStore::new(TTL);
+        let start = Instant::now();
+        let token = store.attach(None, start);
+        store.attach(None, start + TTL * 10);
+        assert!(store.samples(token).is_some());
+    }
+
+    #[test]
+    fn token_in_use_cannot_be_shared() {
+        let store = SessionStore::new(TTL);
+        let start = Instant::now();
+        let token = store.attach(None, start);
+        assert_ne!(store.attach(Some(token), start), token);
+    }
+
+    #[test]
+    fn sla_breaches_are_alerted_and_cleared() {
+        let (events, _guard) = capture_events();
+
+        let sla = Sla {
+            threshold_ms: 100.0,
+            duration: Duration::from_secs(2),
+        };
+        let store = SessionStore::new(TTL).with_sla(Some(sla));
+        let token = store.attach(None, Instant::now());
+        let series = [80.0, 150.0, 160.0, 170.0, 180.0, 190.0, 90.0, 85.0];
+        for (i, latency_ms) in series.into_iter().enumerate() {
+            let timestamp_ms = i as u128 * 1000;
+            if i % 2 == 0 {
+                store.record(token, 0, timestamp_ms, result(latency_ms));
+            } else {
+                let direction = LoadDirection::Download;
+                store.record_under_load(token, 0, direction, timestamp_ms, result(latency_ms));
+            }
+        }
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                (tracing::Level::ERROR, "Latency SLA breached".to_string()),
+                (tracing::Level::INFO, "Latency SLA recovered".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_breach_ends_when_the_session_detaches() {
+        let (events, _guard) = capture_events();
+        let sla = Sla {
+            threshold_ms: 100.0,
+            duration: Duration::from_secs(1),
+        };
+        let store = SessionStore::new(TTL).with_sla(Some(sla));
+        let start = Instant::now();
+        let token = store.attach(None, start);
+        for timestamp_ms in [0, 1000, 2000] {
+            store.record(token, 0, timestamp_ms, result(150.0));
+        }
+        store.detach(token, start);
+
+        // Resumed, the session has nothing to recover from
+        assert_eq!(store.attach(Some(token), start), token);
+        store.record(token, 0, 3000, result(90.0));
+        assert_eq!(
+            *events.lock().unwrap(),
+            [(tracing::Level::ERROR, "Latency SLA breached".to_string())]
+        );
+    }
+
+    #[test]
+    fn a_session_keeps_its_first_campaign() {
+        let store = SessionStore::new(TTL);
+        let token = store.attach(None, Instant::now());
+        let metadata = Metadata::new;
+        assert!(store.report(token, 1000, result(10.0), Some(7), metadata()));
+        assert!(!store.report(token, 2000, result(20.0), Some(8), metadata()));
+        assert!(!store.report(token, 3000, result(30.0), None, metadata()));
+        assert!(store.report(token, 4000, result(40.0), Some(7), metadata()));
+        assert_eq!(store.campaign_reports(Some(7))[0].len(), 2);
+        assert!(store.campaign_reports(Some(8)).is_empty());
+    }
+}
@@ -0,0 +1,123 @@
+//! Alerting when a client's latency breaches a service level: stays above
+//! `SLA_THRESHOLD_MS` for longer than `SLA_DURATION_MS`. Each session tracks
+//! its own breaches, from the server leg of the results the server measures.
+
+use std::time::Duration;
+
+/// The default for how long latency must stay above the threshold.
+const DEFAULT_SLA_DURATION: Duration = Duration::from_secs(30);
+
+/// A latency threshold, and how long it may be exceeded before alerting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sla {
+    pub threshold_ms: f64,
+    pub duration: Duration,
+}
+
+impl Sla {
+    /// Reads the SLA from `SLA_THRESHOLD_MS` (unset or zero disables
+    /// alerting) and `SLA_DURATION_MS` (default 30 seconds).
+    pub fn from_env() -> Option<Self> {
+        let var = |name| std::env::var(name).ok().and_then(|value| value.parse::<u64>().ok());
+        let threshold_ms = var("SLA_THRESHOLD_MS").filter(|&ms| ms > 0)?;
+        let duration = var("SLA_DURATION_MS").map_or(DEFAULT_SLA_DURATION, Duration::from_millis);
+        Some(Self {
+            threshold_ms: threshold_ms as f64,
+            duration,
+        })
+    }
+}
+
+/// A change in a session's SLA status.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlaEvent {
+    /// Latency has been above the threshold since `since_ms`, for longer
+    /// than the SLA allows. The latest result was `latency_ms`.
+    Breached { since_ms: u128, latency_ms: f64 },
+    /// Latency is back within the threshold, after `breached_for_ms` above
+    /// it.
+    Recovered { breached_for_ms: u128 },
+}
+
+/// One session's progress against the SLA.
+#[derive(Debug, Default)]
+pub struct SlaState {
+    /// When latency last went above the threshold, if it's still above.
+    above_since: Option<u128>,
+    breached: bool,
+}
+
+impl SlaState {
+    /// Checks a result measured at `timestamp_ms`, returning an event if it
+    /// breaches the SLA, or recovers from a breach.
+    pub fn observe(&mut self, sla: &Sla, timestamp_ms: u128, latency_ms: f64) -> Option<SlaEvent> {
+        if latency_ms <= sla.threshold_ms {
+            let since_ms = self.above_since.take()?;
+            if !std::mem::take(&mut self.breached) {
+                return None;
+            }
+            return Some(SlaEvent::Recovered {
+                breached_for_ms: timestamp_ms.saturating_sub(since_ms),
+            });
+        }
+        let since_ms = *self.above_since.get_or_insert(timestamp_ms);
+        let above_for = timestamp_ms.saturating_sub(since_ms);
+        if self.breached || above_for <= sla.duration.as_millis() {
+            return None;
+        }
+        self.breached = true;
+        Some(SlaEvent::Breached {
+            since_ms,
+            latency_ms,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sustained_breaches_alert_and_clear() {
+        let sla = Sla {
+            threshold_ms: 100.0,
+            duration: Duration::from_millis(3000),
+        };
+        let mut state = SlaState::default();
+        // A brief spike doesn't alert
+        let series = [
+            (0, 50.0),
+            (1000, 150.0),
+            (2000, 50.0),
+            (3000, 150.0),
+            (4000, 180.0),
+            (6000, 160.0),
+            (7000, 170.0),
+            (8000, 200.0),
+            (9000, 90.0),
+            (10000, 95.0),
+        ];
+        let events: Vec<_> = series
+            .into_iter()
+            .filter_map(|(ms, latency)| Some((ms, state.observe(&sla, ms, latency)?)))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (
+                    7000,
+                    SlaEvent::Breached {
+                        since_ms: 3000,
+                        latency_ms: 170.0
+                    }
+                ),
+                (
+                    9000,
+                    SlaEvent::Recovered {
+                        breached_for_ms: 6000
+                    }
+                ),
+            ]
+        );
+    }
+}
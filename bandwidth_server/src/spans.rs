@@ -0,0 +1,67 @@
+//! Spans for handshakes in progress. A handshake's frames are handled by
+//! separate tasks, so its span is kept here between them: opened as the
+//! `FirstReply` is sent, and closed once the `SecondReply` is, so an
+//! exported trace shows the whole exchange.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Most handshakes a connection may have open at once. A handshake the
+/// client abandons is closed once it's pushed out by newer ones.
+const MAX_OPEN: usize = 16;
+
+/// A connection's open handshake spans, by the id of the probe and the
+/// server time issued in its `FirstReply`. Probes in flight at once have
+/// different ids, but may be stamped in the same millisecond.
+#[derive(Default)]
+pub struct HandshakeSpans {
+    open: Mutex<VecDeque<((u64, u128), tracing::Span)>>,
+}
+
+impl HandshakeSpans {
+    /// Keeps `span` open until probe `id`'s handshake from `server_time`
+    /// continues.
+    pub fn open(&self, id: u64, server_time: u128, span: tracing::Span) {
+        let mut open = self.open.lock().unwrap();
+        if open.len() == MAX_OPEN {
+            open.pop_front();
+        }
+        open.push_back(((id, server_time), span));
+    }
+
+    /// The span of probe `id`'s handshake from `server_time`, if it's still
+    /// open.
+    pub fn take(&self, id: u64, server_time: u128) -> Option<tracing::Span> {
+        let mut open = self.open.lock().unwrap();
+        let i = open.iter().position(|(key, _)| *key == (id, server_time))?;
+        open.remove(i).map(|(_, span)| span)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_the_latest_handshakes_are_kept_open() {
+        let spans = HandshakeSpans::default();
+        for server_time in 0..=MAX_OPEN as u128 {
+            spans.open(0, server_time, tracing::Span::none());
+        }
+        assert!(spans.take(0, 0).is_none());
+        assert!(spans.take(0, 1).is_some());
+        assert!(spans.take(0, 1).is_none());
+    }
+
+    #[test]
+    fn probes_stamped_in_one_millisecond_keep_their_own_spans() {
+        let (_events, _guard) = crate::test_util::capture_events();
+        let spans = HandshakeSpans::default();
+        let first = tracing::info_span!("first");
+        let second = tracing::info_span!("second");
+        spans.open(1, 1000, first.clone());
+        spans.open(2, 1000, second.clone());
+        assert_eq!(spans.take(2, 1000).unwrap().id(), second.id());
+        assert_eq!(spans.take(1, 1000).unwrap().id(), first.id());
+    }
+}
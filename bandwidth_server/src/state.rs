@@ -0,0 +1,228 @@
+//! Per-instance server state. Configuration is read from the environment
+//! once, at startup, into a `Config`, which is shared with every connection
+//! along with the session store. Nothing here is global, so two differently
+//! configured servers can run in one process (as they do in the tests).
+
+use crate::auth::Auth;
+use crate::requests::RouteCounts;
+use crate::sessions::SessionStore;
+use serde::Serialize;
+use shared_data::{BinaryCodec, Codec};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Frames handled concurrently per connection, unless overridden by
+/// `MAX_CONCURRENT_FRAMES`.
+const DEFAULT_FRAME_CONCURRENCY: usize = 4;
+
+/// How long a client is asked to back off when all permits are in use,
+/// unless overridden by `BUSY_RETRY_MS`.
+const DEFAULT_BUSY_RETRY_MS: u32 = 100;
+
+/// How long a probe may wait for its handler before it's answered `Busy`
+/// rather than measured, unless overridden by `MAX_FRAME_STALENESS_MS`.
+const DEFAULT_MAX_FRAME_STALENESS: Duration = Duration::from_secs(1);
+
+/// How many times larger than its request a reply may be, unless
+/// overridden by `MAX_AMPLIFICATION`. The largest legitimate ratio is an
+/// `InitialRequest` (14 bytes) answered by a signed `FirstReply` (62 bytes),
+/// numbered (84 bytes).
+const DEFAULT_MAX_AMPLIFICATION: u32 = 16;
+
+/// How often the server-wide latency summary is logged, unless overridden
+/// by `SUMMARY_INTERVAL_SECS`.
+const DEFAULT_SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long an HTTP request may take before it's logged as slow, unless
+/// overridden by `SLOW_REQUEST_MS`.
+const DEFAULT_SLOW_REQUEST_BUDGET: Duration = Duration::from_millis(500);
+
+/// How frames are encoded on the wire.
+pub type FrameCodec = Arc<dyn Codec + Send + Sync>;
+
+pub struct Config {
+    /// Who may open a WebSocket, from `AUTH_TOKEN`.
+    pub auth: Auth,
+    /// Where to record each connection's frames, from `CAPTURE_DIR`.
+    pub capture_dir: Option<PathBuf>,
+    /// Frames handled at once per connection, from `MAX_CONCURRENT_FRAMES`.
+    pub frame_concurrency: usize,
+    /// Back-off asked of clients when every permit is taken, from
+    /// `BUSY_RETRY_MS`.
+    pub busy_retry_ms: u32,
+    /// How long a probe may wait between arriving and its handler starting
+    /// before it's answered `Busy` instead, from `MAX_FRAME_STALENESS_MS`;
+    /// `None` (zero) measures every probe, however late.
+    pub max_frame_staleness: Option<Duration>,
+    /// TESTING ONLY: the fraction of replies (0.0-1.0) to drop without
+    /// sending, from `DROP_RATE`, to simulate packet loss.
+    pub drop_rate: f64,
+    /// The wire format: binary, with server timestamps signed if
+    /// `HMAC_SECRET` is set.
+    pub codec: FrameCodec,
+    /// How many times larger than its request a reply may be, from
+    /// `MAX_AMPLIFICATION`; `None` (zero) disables the cap.
+    pub max_amplification: Option<u32>,
+    /// How often to log the server-wide latency summary, from
+    /// `SUMMARY_INTERVAL_SECS`; `None` (zero) disables it.
+    pub summary_interval: Option<Duration>,
+    /// Where clients can reach the WebTransport endpoint, if it's running.
+    pub webtransport: Option<WebTransportInfo>,
+    /// Handshakes completed on one connection before the server closes it,
+    /// asking the client to reconnect, from `MAX_HANDSHAKES_PER_CONNECTION`;
+    /// `None` (zero, the default) leaves connections open indefinitely.
+    pub max_handshakes: Option<u32>,
+    /// What to do with frames a client should never send, from
+    /// `UNEXPECTED_FRAMES`.
+    pub unexpected_frames: UnexpectedFrames,
+    /// Answer each `Report` with the server's own measurement of its server
+    /// leg, so the client can check its calculation, from `ECHO_REPORTS`.
+    pub echo_reports: bool,
+    /// Number each reply, so the client can count lost replies directly
+    /// rather than by timeouts, from `SEQUENCE_REPLIES`.
+    pub sequence_replies: bool,
+    /// How long an HTTP request may take before it's logged as slow, from
+    /// `SLOW_REQUEST_MS`; `None` (zero) disables the log.
+    pub slow_request_budget: Option<Duration>,
+}
+
+/// What to do when a client sends a frame it never should, such as one of
+/// the server's own (`FirstReply`, `SecondReply`) or a `Final`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnexpectedFrames {
+    /// Log a warning, and otherwise ignore the frame.
+    Warn,
+    /// Close the connection as a policy violation (1008), so the client
+    /// knows it went wrong rather than waiting on a reply. The default.
+    Close,
+}
+
+impl UnexpectedFrames {
+    /// Looks up a policy by its (case-insensitive) name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "warn" => Some(Self::Warn),
+            "close" => Some(Self::Close),
+            _ => None,
+        }
+    }
+}
+
+/// The WebTransport endpoint, as advertised to clients at `/webtransport`.
+/// The site's host is used, so only the port is given.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WebTransportInfo {
+    pub port: u16,
+    /// The SHA-256 of the endpoint's certificate, in hex, if it's
+    /// self-signed. Browsers only accept such a certificate when given its
+    /// hash.
+    pub certificate_hash: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            auth: Auth::default(),
+            capture_dir: None,
+            frame_concurrency: DEFAULT_FRAME_CONCURRENCY,
+            busy_retry_ms: DEFAULT_BUSY_RETRY_MS,
+            max_frame_staleness: Some(DEFAULT_MAX_FRAME_STALENESS),
+            drop_rate: 0.0,
+            codec: Arc::new(BinaryCodec),
+            max_amplification: Some(DEFAULT_MAX_AMPLIFICATION),
+            summary_interval: Some(DEFAULT_SUMMARY_INTERVAL),
+            webtransport: None,
+            max_handshakes: None,
+            unexpected_frames: UnexpectedFrames::Close,
+            echo_reports: false,
+            sequence_replies: false,
+            slow_request_budget: Some(DEFAULT_SLOW_REQUEST_BUDGET),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok();
+        let defaults = Self::default();
+        Self {
+            auth: Auth::from_env(),
+            capture_dir: var("CAPTURE_DIR").map(PathBuf::from),
+            frame_concurrency: var("MAX_CONCURRENT_FRAMES")
+                .and_then(|permits| permits.parse().ok())
+                .filter(|&permits| permits > 0)
+                .unwrap_or(defaults.frame_concurrency),
+            busy_retry_ms: var("BUSY_RETRY_MS")
+                .and_then(|ms| ms.parse().ok())
+                .unwrap_or(defaults.busy_retry_ms),
+            max_frame_staleness: var("MAX_FRAME_STALENESS_MS")
+                .and_then(|ms| ms.parse().ok())
+                .map_or(defaults.max_frame_staleness, |ms| {
+                    (ms > 0).then(|| Duration::from_millis(ms))
+                }),
+            drop_rate: var("DROP_RATE")
+                .and_then(|rate| rate.parse::<f64>().ok())
+                .filter(|rate| rate.is_finite())
+                .map_or(defaults.drop_rate, |rate| rate.clamp(0.0, 1.0)),
+            codec: codec_from_env().unwrap_or(defaults.codec),
+            max_amplification: var("MAX_AMPLIFICATION")
+                .and_then(|factor| factor.parse().ok())
+                .map_or(defaults.max_amplification, |factor| (factor > 0).then_some(factor)),
+            summary_interval: var("SUMMARY_INTERVAL_SECS")
+                .and_then(|secs| secs.parse().ok())
+                .map_or(defaults.summary_interval, |secs| {
+                    (secs > 0).then(|| Duration::from_secs(secs))
+                }),
+            // Only known once the endpoint is bound
+            webtransport: None,
+            max_handshakes: var("MAX_HANDSHAKES_PER_CONNECTION")
+                .and_then(|count| count.parse().ok())
+                .filter(|&count| count > 0),
+            unexpected_frames: var("UNEXPECTED_FRAMES")
+                .and_then(|policy| UnexpectedFrames::from_name(&policy))
+                .unwrap_or(defaults.unexpected_frames),
+            echo_reports: var("ECHO_REPORTS").map_or(defaults.echo_reports, |echo| {
+                echo == "1" || echo.eq_ignore_ascii_case("true")
+            }),
+            sequence_replies: var("SEQUENCE_REPLIES").map_or(defaults.sequence_replies, |seq| {
+                seq == "1" || seq.eq_ignore_ascii_case("true")
+            }),
+            slow_request_budget: var("SLOW_REQUEST_MS")
+                .and_then(|ms| ms.parse().ok())
+                .map_or(defaults.slow_request_budget, |ms| {
+                    (ms > 0).then(|| Duration::from_millis(ms))
+                }),
+        }
+    }
+}
+
+#[cfg(feature = "hmac")]
+fn codec_from_env() -> Option<FrameCodec> {
+    let secret = std::env::var("HMAC_SECRET").ok()?;
+    Some(Arc::new(shared_data::TimestampSigner::new(secret.as_bytes())))
+}
+
+#[cfg(not(feature = "hmac"))]
+fn codec_from_env() -> Option<FrameCodec> {
+    None
+}
+
+/// Shared by every handler of one server instance.
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub sessions: Arc<SessionStore>,
+    /// HTTP requests per route, for the periodic summary.
+    pub routes: Arc<RouteCounts>,
+}
+
+impl AppState {
+    pub fn new(config: Config, sessions: SessionStore) -> Self {
+        Self {
+            config: Arc::new(config),
+            sessions: Arc::new(sessions),
+            routes: Arc::default(),
+        }
+    }
+}
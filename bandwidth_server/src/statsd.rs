@@ -0,0 +1,110 @@
+//! Optional StatsD export, enabled with the `statsd` feature. Every result
+//! recorded in a session is also pushed, over UDP, to the StatsD server at
+//! `STATSD_ADDR` (`host:port`), with metric names prefixed by `STATSD_PREFIX`
+//! (default `wasm_latency`). Each result is sent as a `latency_ms`
+//! histogram, and a `latency_ms.last` gauge, tagged DogStatsD-style with
+//! who measured it (`source:server` for the server leg, `source:client` for
+//! reported results) and the load the link was under.
+
+use cadence::prelude::*;
+use cadence::{StatsdClient, UdpMetricSink};
+use shared_data::{LatencyResult, LoadDirection};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::OnceLock;
+
+/// Who measured a result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Server,
+    Client,
+}
+
+impl Source {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Server => "server",
+            Self::Client => "client",
+        }
+    }
+}
+
+pub struct LatencyMetrics {
+    client: StatsdClient,
+}
+
+impl LatencyMetrics {
+    pub fn new(addr: impl ToSocketAddrs, prefix: &str) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        let sink = UdpMetricSink::from(addr, socket)?;
+        Ok(Self {
+            client: StatsdClient::from_sink(prefix, sink),
+        })
+    }
+
+    /// Sends one result. Metrics are best-effort: send errors are ignored.
+    pub fn send(&self, source: Source, direction: LoadDirection, result: &LatencyResult) {
+        let tags = [("source", source.name()), ("load", direction.name())];
+        let histogram = tags.iter().fold(
+            self.client.histogram_with_tags("latency_ms", result.latency_ms),
+            |metric, (key, value)| metric.with_tag(key, value),
+        );
+        let _ = histogram.try_send();
+        let gauge = tags.iter().fold(
+            self.client.gauge_with_tags("latency_ms.last", result.latency_ms),
+            |metric, (key, value)| metric.with_tag(key, value),
+        );
+        let _ = gauge.try_send();
+    }
+}
+
+/// The StatsD exporter configured by `STATSD_ADDR`, if any.
+pub fn metrics() -> Option<&'static LatencyMetrics> {
+    static METRICS: OnceLock<Option<LatencyMetrics>> = OnceLock::new();
+    METRICS
+        .get_or_init(|| {
+            let addr = std::env::var("STATSD_ADDR").ok()?;
+            let prefix =
+                std::env::var("STATSD_PREFIX").unwrap_or_else(|_| "wasm_latency".to_string());
+            LatencyMetrics::new(&addr, &prefix)
+                .map_err(|e| tracing::warn!("Unable to send metrics to {addr}: {e}"))
+                .ok()
+        })
+        .as_ref()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn results_are_sent_as_statsd_lines() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let metrics = LatencyMetrics::new(receiver.local_addr().unwrap(), "test").unwrap();
+        let result = LatencyResult {
+            latency_ms: 12.5,
+            server_latency_ms: 12.5,
+            client_latency_ms: 12.5,
+            approximate: true,
+            resolution_limited: false,
+        };
+        metrics.send(Source::Server, LoadDirection::Download, &result);
+
+        let mut lines = Vec::new();
+        let mut buf = [0; 512];
+        for _ in 0..2 {
+            let len = receiver.recv(&mut buf).unwrap();
+            lines.push(String::from_utf8(buf[..len].to_vec()).unwrap());
+        }
+        assert_eq!(
+            lines,
+            [
+                "test.latency_ms:12.5|h|#source:server,load:download",
+                "test.latency_ms.last:12.5|g|#source:server,load:download",
+            ]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! A periodic, server-wide latency summary for the logs: percentiles over
+//! the results reported by every connected client, merged together, and the
+//! HTTP requests served since the last summary. The latency summary is also
+//! served at `/summary`, optionally for one campaign.
+
+use crate::requests::RouteCounts;
+use crate::sessions::SessionStore;
+use serde::Serialize;
+use shared_data::LatencySamples;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Summary {
+    /// Clients that contributed at least one result.
+    pub clients: usize,
+    pub samples: usize,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+/// Merges each client's results and computes percentiles over the lot.
+/// `None` if there are no results at all.
+pub fn summarize(clients: &[LatencySamples]) -> Option<Summary> {
+    let mut merged = LatencySamples::new();
+    for sample in clients.iter().flat_map(LatencySamples::iter) {
+        merged.push(sample.timestamp_ms, sample.result);
+    }
+    Some(Summary {
+        clients: clients.iter().filter(|samples| !samples.is_empty()).count(),
+        samples: merged.len(),
+        p50_ms: merged.percentile_ms(50.0)?,
+        p95_ms: merged.percentile_ms(95.0)?,
+        p99_ms: merged.percentile_ms(99.0)?,
+    })
+}
+
+/// Logs a summary every `interval` in a background task. The store's lock is
+/// only held to copy the results out; the percentiles are computed after,
+/// so the WebSocket loops are never kept waiting on them.
+pub fn spawn(
+    sessions: Arc<SessionStore>,
+    routes: Arc<RouteCounts>,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        // The first tick completes immediately, with nothing to report yet
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            match summarize(&sessions.connected_reports()) {
+                Some(summary) => tracing::info!(
+                    clients = summary.clients,
+                    samples = summary.samples,
+                    "Latency p50 {:.1}ms, p95 {:.1}ms, p99 {:.1}ms",
+                    summary.p50_ms,
+                    summary.p95_ms,
+                    summary.p99_ms,
+                ),
+                None => tracing::info!("Latency summary: no results reported"),
+            }
+            let requests = routes.take();
+            if !requests.is_empty() {
+                let counts: Vec<String> = requests
+                    .iter()
+                    .map(|(route, count)| format!("{route} {count}"))
+                    .collect();
+                tracing::info!("Requests: {}", counts.join(", "));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shared_data::LatencyResult;
+
+    fn client(latencies: impl IntoIterator<Item = u32>) -> LatencySamples {
+        let mut samples = LatencySamples::new();
+        for (i, latency_ms) in latencies.into_iter().enumerate() {
+            let latency_ms = latency_ms as f64;
+            let result = LatencyResult {
+                latency_ms,
+                server_latency_ms: latency_ms,
+                client_latency_ms: latency_ms,
+                approximate: false,
+                resolution_limited: false,
+            };
+            samples.push(i as u128, result);
+        }
+        samples
+    }
+
+    #[test]
+    fn no_results_no_summary() {
+        assert_eq!(summarize(&[]), None);
+        assert_eq!(summarize(&[LatencySamples::new()]), None);
+    }
+
+    #[test]
+    fn percentiles_over_merged_clients() {
+        // 1..=100ms, split unevenly across three clients (and one silent)
+        let clients = [
+            client((1..=100).filter(|ms| ms % 2 == 0)),
+            client((1..=100).filter(|ms| ms % 4 == 1)),
+            client((1..=100).filter(|ms| ms % 4 == 3)),
+            LatencySamples::new(),
+        ];
+        let summary = summarize(&clients).unwrap();
+        assert_eq!(summary.clients, 3);
+        assert_eq!(summary.samples, 100);
+        assert_eq!(summary.p50_ms, 50.0);
+        assert_eq!(summary.p95_ms, 95.0);
+        assert_eq!(summary.p99_ms, 99.0);
+    }
+}
@@ -0,0 +1,39 @@
+//! Optional OpenTelemetry export, enabled with the `otel` feature. Spans are
+//! bridged from the existing `tracing` setup by `tracing-opentelemetry`, and
+//! exported over OTLP/HTTP (configured with the standard `OTEL_EXPORTER_OTLP_*`
+//! environment variables).
+
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::runtime;
+use opentelemetry_sdk::trace::TracerProvider;
+use std::collections::HashMap;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Builds a `tracing` layer that exports spans over OTLP.
+pub fn otlp_layer<S>() -> anyhow::Result<impl Layer<S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .build();
+    let tracer = provider.tracer("bandwidth_server");
+    opentelemetry::global::set_tracer_provider(provider);
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Makes `span` a child of the client-supplied W3C `traceparent`, so the
+/// server's spans join the client's distributed trace.
+pub fn set_parent(span: &tracing::Span, traceparent: &str) {
+    let carrier = HashMap::from([("traceparent".to_string(), traceparent.to_string())]);
+    let context = TraceContextPropagator::new().extract(&carrier);
+    span.set_parent(context);
+}
@@ -0,0 +1,34 @@
+//! Helpers shared by the server's tests.
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use tracing::field::Field;
+use tracing::subscriber::DefaultGuard;
+use tracing::Level;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+/// The level and message of each event logged.
+pub type Events = Arc<Mutex<Vec<(Level, String)>>>;
+
+/// Captures every event logged on this thread, until the guard is dropped.
+pub fn capture_events() -> (Events, DefaultGuard) {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let subscriber = tracing_subscriber::registry().with(Capture(events.clone()));
+    (events, tracing::subscriber::set_default(subscriber))
+}
+
+/// Collects the level and message of every event logged.
+struct Capture(Events);
+
+impl<S: tracing::Subscriber> Layer<S> for Capture {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        let mut message = String::new();
+        event.record(&mut |field: &Field, value: &dyn std::fmt::Debug| {
+            if field.name() == "message" {
+                write!(message, "{value:?}").unwrap();
+            }
+        });
+        let level = *event.metadata().level();
+        self.0.lock().unwrap().push((level, message));
+    }
+}
@@ -0,0 +1,286 @@
+//! Optional webhook, enabled with the `webhook` feature. Every result
+//! recorded in a session is also POSTed, as JSON, to the collector at
+//! `WEBHOOK_URL`. Deliveries are queued and sent from tasks of their own,
+//! several at once, so neither a slow collector nor one stuck delivery holds
+//! up a connection or the results behind it; failed deliveries are retried
+//! with exponential backoff, and results that arrive while the queue is full
+//! are dropped. Latency SLA alerts are POSTed to the same collector. Neither
+//! carries the session token, which would let anyone reading them resume the
+//! session.
+
+use crate::sla::{Sla, SlaEvent};
+use serde::Serialize;
+use shared_data::{LatencyResult, LoadDirection, Metadata};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+
+/// Results waiting to be delivered, at most.
+const QUEUE_LEN: usize = 1024;
+/// Deliveries in flight at once, at most, retries included.
+const WORKERS: usize = 8;
+/// Attempts to deliver each result, the first included.
+const ATTEMPTS: u32 = 5;
+/// Delay before the first retry, doubling after each.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// How long the collector has to answer each attempt.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One result, as POSTed.
+#[derive(Debug, Clone, Serialize)]
+pub struct Payload {
+    /// Who measured the result: `server` for the server leg, `client` for
+    /// reported results.
+    pub source: &'static str,
+    /// The load the link was under.
+    pub load: &'static str,
+    pub timestamp_ms: u128,
+    pub latency_ms: f64,
+    pub server_latency_ms: f64,
+    pub client_latency_ms: f64,
+    pub approximate: bool,
+    pub resolution_limited: bool,
+    pub campaign_id: Option<u64>,
+    pub metadata: Metadata,
+}
+
+impl Payload {
+    pub fn new(
+        source: &'static str,
+        direction: LoadDirection,
+        timestamp_ms: u128,
+        result: &LatencyResult,
+    ) -> Self {
+        Self {
+            source,
+            load: direction.name(),
+            timestamp_ms,
+            latency_ms: result.latency_ms,
+            server_latency_ms: result.server_latency_ms,
+            client_latency_ms: result.client_latency_ms,
+            approximate: result.approximate,
+            resolution_limited: result.resolution_limited,
+            campaign_id: None,
+            metadata: Metadata::new(),
+        }
+    }
+}
+
+/// A session breaching its latency SLA, or recovering, as POSTed.
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    /// `sla_breached` or `sla_recovered`.
+    pub alert: &'static str,
+    pub timestamp_ms: u128,
+    pub threshold_ms: f64,
+    /// The latest result's server leg.
+    pub latency_ms: f64,
+    /// How long latency has been (for a breach) or was (on recovery) above
+    /// the threshold.
+    pub above_for_ms: u128,
+}
+
+impl Alert {
+    pub fn new(sla: &Sla, timestamp_ms: u128, latency_ms: f64, event: SlaEvent) -> Self {
+        let (alert, above_for_ms) = match event {
+            SlaEvent::Breached { since_ms, .. } => {
+                ("sla_breached", timestamp_ms.saturating_sub(since_ms))
+            }
+            SlaEvent::Recovered { breached_for_ms } => ("sla_recovered", breached_for_ms),
+        };
+        Self {
+            alert,
+            timestamp_ms,
+            threshold_ms: sla.threshold_ms,
+            latency_ms,
+            above_for_ms,
+        }
+    }
+}
+
+pub struct Webhook {
+    queue: mpsc::Sender<serde_json::Value>,
+}
+
+impl Webhook {
+    /// Starts delivering to `url`, waiting `backoff` before the first retry
+    /// of a failed delivery. Must be called from within the Tokio runtime.
+    pub fn spawn(url: String, backoff: Duration) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let (queue, rx) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(deliver(client, url, backoff, rx));
+        Ok(Self { queue })
+    }
+
+    /// Queues a result (or an alert) for delivery, without waiting for it.
+    pub fn send(&self, payload: impl Serialize) {
+        let payload = match serde_json::to_value(payload) {
+            Ok(payload) => payload,
+            Err(e) => return tracing::warn!("Unable to serialize a webhook payload: {e}"),
+        };
+        if self.queue.try_send(payload).is_err() {
+            tracing::warn!("Webhook queue full, dropping a result");
+        }
+    }
+}
+
+/// Delivers queued results, up to `WORKERS` at once, in the order they were
+/// queued (though a retried one may land after those behind it).
+async fn deliver(
+    client: reqwest::Client,
+    url: String,
+    backoff: Duration,
+    mut rx: mpsc::Receiver<serde_json::Value>,
+) {
+    let url: Arc<str> = url.into();
+    let workers = Arc::new(Semaphore::new(WORKERS));
+    while let Some(payload) = rx.recv().await {
+        let Ok(worker) = workers.clone().acquire_owned().await else {
+            break;
+        };
+        let (client, url) = (client.clone(), url.clone());
+        tokio::spawn(async move {
+            deliver_one(&client, &url, backoff, &payload).await;
+            drop(worker);
+        });
+    }
+}
+
+/// Delivers one result, retrying until it succeeds or runs out of attempts.
+async fn deliver_one(
+    client: &reqwest::Client,
+    url: &str,
+    backoff: Duration,
+    payload: &serde_json::Value,
+) {
+    let mut delay = backoff;
+    for attempt in 1..=ATTEMPTS {
+        let response = client.post(url).json(payload).send().await;
+        match response.and_then(|response| response.error_for_status()) {
+            Ok(_) => break,
+            Err(e) if attempt == ATTEMPTS => {
+                tracing::warn!("Webhook delivery failed, giving up: {e}");
+            }
+            Err(e) => {
+                tracing::debug!("Webhook delivery failed, retrying in {delay:?}: {e}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+}
+
+/// The webhook configured by `WEBHOOK_URL`, if any.
+pub fn webhook() -> Option<&'static Webhook> {
+    static WEBHOOK: OnceLock<Option<Webhook>> = OnceLock::new();
+    WEBHOOK
+        .get_or_init(|| {
+            let url = std::env::var("WEBHOOK_URL").ok()?;
+            Webhook::spawn(url.clone(), INITIAL_BACKOFF)
+                .map_err(|e| tracing::warn!("Unable to send results to {url}: {e}"))
+                .ok()
+        })
+        .as_ref()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn results_are_posted_and_failures_retried() {
+        // A collector that fails its first request
+        let (tx, mut received) = mpsc::channel(1);
+        let hits = Arc::new(AtomicU32::new(0));
+        let collect = |State((hits, tx)): State<(Arc<AtomicU32>, mpsc::Sender<_>)>,
+                       Json(body): Json<serde_json::Value>| async move {
+            if hits.fetch_add(1, Ordering::SeqCst) == 0 {
+                return StatusCode::SERVICE_UNAVAILABLE;
+            }
+            tx.send(body).await.unwrap();
+            StatusCode::OK
+        };
+        let app = Router::new()
+            .route("/results", post(collect))
+            .with_state((hits.clone(), tx));
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let url = format!("http://{}/results", server.local_addr());
+        tokio::spawn(server);
+
+        let webhook = Webhook::spawn(url, Duration::from_millis(10)).unwrap();
+        let result = LatencyResult {
+            latency_ms: 12.5,
+            server_latency_ms: 12.0,
+            client_latency_ms: 13.0,
+            approximate: false,
+            resolution_limited: false,
+        };
+        let mut payload = Payload::new("client", LoadDirection::Upload, 1000, &result);
+        payload.campaign_id = Some(42);
+        payload
+            .metadata
+            .insert("isp".to_string(), "Example Fiber".to_string());
+        webhook.send(payload);
+
+        let body = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "source": "client",
+                "load": "upload",
+                "timestamp_ms": 1000,
+                "latency_ms": 12.5,
+                "server_latency_ms": 12.0,
+                "client_latency_ms": 13.0,
+                "approximate": false,
+                "resolution_limited": false,
+                "campaign_id": 42,
+                "metadata": { "isp": "Example Fiber" },
+            })
+        );
+    }
+    #[tokio::test]
+    async fn a_stuck_delivery_does_not_hold_up_the_rest() {
+        // A collector that never answers its first request
+        let (tx, mut received) = mpsc::channel(1);
+        let hits = Arc::new(AtomicU32::new(0));
+        let collect = |State((hits, tx)): State<(Arc<AtomicU32>, mpsc::Sender<_>)>,
+                       Json(body): Json<serde_json::Value>| async move {
+            if hits.fetch_add(1, Ordering::SeqCst) == 0 {
+                std::future::pending::<()>().await;
+            }
+            tx.send(body).await.unwrap();
+            StatusCode::OK
+        };
+        let app = Router::new()
+            .route("/results", post(collect))
+            .with_state((hits.clone(), tx));
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let url = format!("http://{}/results", server.local_addr());
+        tokio::spawn(server);
+
+        let webhook = Webhook::spawn(url, Duration::from_millis(10)).unwrap();
+        webhook.send(serde_json::json!({ "n": 1 }));
+        webhook.send(serde_json::json!({ "n": 2 }));
+
+        // Well within the first delivery's REQUEST_TIMEOUT
+        let body = tokio::time::timeout(Duration::from_secs(2), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body, serde_json::json!({ "n": 2 }));
+    }
+}
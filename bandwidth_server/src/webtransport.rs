@@ -0,0 +1,309 @@
+//! WebTransport (HTTP/3) as an alternate transport, enabled with the
+//! `webtransport` feature and `WEBTRANSPORT_PORT`. A WebSocket runs over TCP,
+//! so one lost packet holds up every frame behind it, and latency measured
+//! under load suffers for it. WebTransport runs over QUIC, which doesn't:
+//! probes travel as datagrams, each on its own, and everything else is
+//! length-prefixed on a single stream opened by the server. Either way, the
+//! frames are the same `LatencyTest`s, handled exactly as they are over a
+//! WebSocket.
+
+use crate::sessions::SessionHandle;
+use crate::state::{AppState, Config, WebTransportInfo};
+use crate::{connection_span, reconnect_close, until, Connection, WsParams};
+use axum::extract::ws::Message;
+use axum::extract::Query;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Uri};
+use shared_data::{FrameReader, LatencyTestRef, Transport, MAX_FRAME_SIZE};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::Instrument;
+use wtransport::endpoint::endpoint_side::Server;
+use wtransport::endpoint::IncomingSession;
+use wtransport::{Endpoint, Identity, SendStream, ServerConfig, VarInt};
+
+/// Where to listen, and with what certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebTransportConfig {
+    /// The UDP port, from `WEBTRANSPORT_PORT`.
+    pub port: u16,
+    /// PEM certificate chain and private key files, from `WEBTRANSPORT_CERT`
+    /// and `WEBTRANSPORT_KEY`. Without them, a self-signed certificate is
+    /// generated.
+    pub cert: Option<(PathBuf, PathBuf)>,
+}
+
+impl WebTransportConfig {
+    /// `None` unless `WEBTRANSPORT_PORT` is set.
+    pub fn from_env() -> Option<Self> {
+        let var = |name| std::env::var(name).ok();
+        let port = var("WEBTRANSPORT_PORT")?.parse().ok()?;
+        let cert = var("WEBTRANSPORT_CERT").zip(var("WEBTRANSPORT_KEY"));
+        Some(Self {
+            port,
+            cert: cert.map(|(cert, key)| (cert.into(), key.into())),
+        })
+    }
+}
+
+/// A bound WebTransport endpoint, not yet accepting sessions.
+pub struct Listener {
+    endpoint: Endpoint<Server>,
+    info: WebTransportInfo,
+}
+
+impl Listener {
+    pub async fn bind(addr: SocketAddr, config: &WebTransportConfig) -> anyhow::Result<Self> {
+        let (identity, certificate_hash) = match &config.cert {
+            Some((cert, key)) => (Identity::load_pemfiles(cert, key).await?, None),
+            None => {
+                // Browsers accept a self-signed certificate only by its hash,
+                // and only if it's valid for at most two weeks (as these are)
+                let identity = Identity::self_signed(["localhost", "127.0.0.1", "::1"])?;
+                let hash = identity.certificate_chain().as_slice()[0].hash();
+                let hex = hash.as_ref().iter().map(|byte| format!("{byte:02x}")).collect();
+                (identity, Some(hex))
+            }
+        };
+        let server_config = ServerConfig::builder()
+            .with_bind_address(addr)
+            .with_identity(identity)
+            .build();
+        let endpoint = Endpoint::server(server_config)?;
+        let info = WebTransportInfo {
+            port: endpoint.local_addr()?.port(),
+            certificate_hash,
+        };
+        Ok(Self { endpoint, info })
+    }
+
+    pub fn info(&self) -> WebTransportInfo {
+        self.info.clone()
+    }
+
+    /// Accepts sessions until the endpoint is dropped.
+    pub async fn serve(self, state: AppState) {
+        tracing::info!("WebTransport listening on UDP port {}", self.info.port);
+        loop {
+            let incoming = self.endpoint.accept().await;
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = accept(incoming, state).await {
+                    tracing::warn!("WebTransport session failed: {e}");
+                }
+            });
+        }
+    }
+}
+
+/// The counterpart of `ws_handler`: the same query parameters and headers
+/// are accepted, and the same token required.
+async fn accept(incoming: IncomingSession, state: AppState) -> anyhow::Result<()> {
+    let request = incoming.await?;
+    tracing::info!("WebTransport Session Requested");
+    let uri: Uri = request.path().parse()?;
+    let Ok(Query(params)) = Query::<WsParams>::try_from_uri(&uri) else {
+        request.not_found().await;
+        return Ok(());
+    };
+    let headers = header_map(request.headers());
+    if !state.config.auth.check(&headers, params.token.as_deref()) {
+        tracing::warn!("Rejecting WebTransport session without a valid token");
+        request.forbidden().await;
+        return Ok(());
+    }
+    let span = connection_span(&headers);
+    let wt = request.accept().await?;
+    let token = state.sessions.attach(params.session, Instant::now());
+    let session = SessionHandle::new(state.sessions, token);
+    span.record("session", session.token);
+    handle_session(wt, session, state.config).instrument(span).await
+}
+
+fn header_map(headers: &HashMap<String, String>) -> HeaderMap {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            Some((name, HeaderValue::from_str(value).ok()?))
+        })
+        .collect()
+}
+
+async fn handle_session(
+    wt: wtransport::Connection,
+    session: SessionHandle,
+    config: Arc<Config>,
+) -> anyhow::Result<()> {
+    tracing::info!("WebTransport Connected");
+
+    let (mut connection, mut rx) = Connection::new(session, config);
+    let result = async {
+        // Announcing the session straight away means the stream reaches the
+        // client (which sees it only once it carries data) before it sends
+        // anything. WebTransport is always binary.
+        let (mut send, mut recv) = wt.open_bi().await?.await?;
+        connection.announce(Transport::Binary);
+
+        let mut reader = FrameReader::new();
+        let mut buf = vec![0; MAX_FRAME_SIZE];
+        loop {
+            let received = tokio::select! {
+                datagram = wt.receive_datagram() => match datagram {
+                    Ok(datagram) => vec![datagram.payload().to_vec()],
+                    Err(_) => break,
+                },
+                read = recv.read(&mut buf) => match read {
+                    Ok(Some(len)) => reader.feed_raw(&buf[..len]),
+                    _ => break,
+                },
+                msg = rx.recv() => {
+                    let Some(reply) = msg else {
+                        break;
+                    };
+                    if let Some(reply) = connection.sending(reply) {
+                        if let Message::Close(Some(close)) = reply {
+                            wt.close(close.code.into(), close.reason.as_bytes());
+                            break;
+                        }
+                        connection.sent(&reply);
+                        send_frame(&wt, &mut send, reply).await?;
+                        connection.delivered();
+                    }
+                    continue;
+                },
+                _ = until(connection.reconnect_at) => {
+                    let close = reconnect_close();
+                    wt.close(VarInt::from_u32(close.code.into()), close.reason.as_bytes());
+                    break;
+                },
+            };
+            for bytes in received {
+                if let Some(busy) = connection.received(Message::Binary(bytes)).await {
+                    send_frame(&wt, &mut send, busy).await?;
+                }
+            }
+        }
+        anyhow::Ok(())
+    }
+    .await;
+
+    tracing::info!("WebTransport Disconnected");
+    connection.close();
+    result
+}
+
+/// Sends a probe as a datagram if it fits in one, and anything else (or a
+/// probe that doesn't fit) on the stream.
+async fn send_frame(
+    wt: &wtransport::Connection,
+    stream: &mut SendStream,
+    msg: Message,
+) -> anyhow::Result<()> {
+    let Message::Binary(bytes) = msg else {
+        return Ok(());
+    };
+    let probe = LatencyTestRef::decode(&bytes).is_ok_and(|frame| frame.kind().is_probe());
+    let fits = wt.max_datagram_size().is_some_and(|max| bytes.len() <= max);
+    if probe && fits && wt.send_datagram(&bytes).is_ok() {
+        return Ok(());
+    }
+    stream.write_all(&shared_data::encode_frame_bytes(&bytes)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sessions::SessionStore;
+    use shared_data::{LatencyTest, MAGIC_NUMBER};
+    use std::time::Duration;
+    use wtransport::tls::Sha256Digest;
+    use wtransport::ClientConfig;
+
+    async fn serve(config: Config) -> (WebTransportInfo, AppState) {
+        let wt_config = WebTransportConfig { port: 0, cert: None };
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), &wt_config).await.unwrap();
+        let info = listener.info();
+        let state = AppState::new(config, SessionStore::new(Duration::from_secs(60)));
+        tokio::spawn(listener.serve(state.clone()));
+        (info, state)
+    }
+
+    async fn connect(
+        info: &WebTransportInfo,
+        path: &str,
+    ) -> anyhow::Result<wtransport::Connection> {
+        let hex = info.certificate_hash.as_deref().unwrap();
+        let mut hash = [0; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        let config = ClientConfig::builder()
+            .with_bind_address("127.0.0.1:0".parse().unwrap())
+            .with_server_certificate_hashes([Sha256Digest::new(hash)])
+            .build();
+        let url = format!("https://127.0.0.1:{}{path}", info.port);
+        Ok(Endpoint::client(config)?.connect(url).await?)
+    }
+
+    async fn datagram(wt: &wtransport::Connection) -> LatencyTest {
+        let datagram = tokio::time::timeout(Duration::from_secs(5), wt.receive_datagram());
+        LatencyTest::decode(&datagram.await.unwrap().unwrap().payload()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn handshake_over_webtransport() {
+        let (info, state) = serve(Config::default()).await;
+        let wt = connect(&info, "/").await.unwrap();
+
+        // The server opens the stream, and announces the session on it
+        let (_send, mut recv) = wt.accept_bi().await.unwrap();
+        let mut reader = FrameReader::new();
+        let mut buf = [0; 64];
+        let token = loop {
+            let len = recv.read(&mut buf).await.unwrap().unwrap();
+            if let Some(LatencyTest::Session { token, .. }) = reader.feed(&buf[..len]).pop() {
+                break token;
+            }
+        };
+
+        // Probes go both ways as datagrams
+        let request = LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: 0,
+        };
+        wt.send_datagram(request.encode()).unwrap();
+        let LatencyTest::FirstReply { server_time, .. } = datagram(&wt).await else {
+            panic!("Expected a FirstReply");
+        };
+        let response = LatencyTest::FirstResponse {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time,
+            client_time: shared_data::unix_now_ms().unwrap(),
+        };
+        wt.send_datagram(response.encode()).unwrap();
+        let reply = datagram(&wt).await;
+        assert!(matches!(
+            reply,
+            LatencyTest::SecondReply { server_time: time, .. } if time == server_time
+        ));
+
+        // Recorded against the session, as over a WebSocket
+        assert_eq!(state.sessions.samples(token).map(|samples| samples.len()), Some(1));
+    }
+
+    #[tokio::test]
+    async fn session_without_token_is_refused() {
+        let config = Config {
+            auth: crate::auth::Auth::new("s3cret"),
+            ..Config::default()
+        };
+        let (info, _state) = serve(config).await;
+        assert!(connect(&info, "/").await.is_err());
+        assert!(connect(&info, "/?token=s3cret").await.is_ok());
+    }
+}
@@ -1,4 +1,4 @@
-import init, { LatencyClient } from '../wasm/wasm_client.js';
+import init, { LatencyClient, set_log_level, timer_resolution_ms } from '../wasm/wasm_client.js';
 
 const N_BANDS = 20;
 const BAND_DIVISOR = 10.0;
@@ -43,6 +43,38 @@ function reportLatency(avg: Number, server: Number, client: Number) {
     }
 }
 
+function reportLoadedLatency(direction: string, avg: Number, server: Number, client: Number) {
+    setSpanText(direction + "Latency", avg.toString() + " ms (server " + server.toString() + " ms, client " + client.toString() + " ms)");
+}
+
+function reportRunSummary(mean: number, jitter: number, p95: number, floor: number, loss: number) {
+    setSpanText("runSummary", "mean " + mean.toFixed(1) + " ms, jitter " + jitter.toFixed(1) + " ms, p95 " + p95.toFixed(1) + " ms, floor " + floor.toFixed(1) + " ms, loss " + (loss * 100).toFixed(1) + "%");
+}
+
+function reportClientError(message: string) {
+    console.error(message);
+    setSpanText("clientError", message);
+}
+
+async function fetchServerVersion() {
+    const response = await fetch("/version");
+    if (!response.ok) {
+        console.error("Unable to fetch server version: " + response.status);
+        return;
+    }
+    window.serverVersion = await response.json();
+    setSpanText("serverVersion", window.serverVersion.version + " (" + window.serverVersion.git_hash + "), protocol " + window.serverVersion.protocol_version);
+}
+
+// Where the server accepts WebTransport, if it does
+async function fetchWebTransport(): Promise<WebTransportInfo | null> {
+    const response = await fetch("/webtransport");
+    if (!response.ok) {
+        return null;
+    }
+    return await response.json();
+}
+
 function latencyUrl() : string {
     let url = "";
     const currentUrlWithoutAnchors = window.location.href.split('#')[0].replace("https://", "").replace("http://", "");
@@ -54,9 +86,24 @@ function latencyUrl() : string {
     return url;
 }
 
+interface WebTransportInfo {
+    port: number,
+    certificate_hash: string | null,
+}
+
+interface ServerVersion {
+    version: string,
+    git_hash: string,
+    protocol_version: number,
+}
+
 declare global {
     interface Window {
+        serverVersion: ServerVersion,
         reportLatency: typeof reportLatency,
+        reportLoadedLatency: typeof reportLoadedLatency,
+        reportClientError: typeof reportClientError,
+        reportRunSummary: typeof reportRunSummary,
         latencyClient: LatencyClient,
         worst: Number,
         best: Number,
@@ -64,6 +111,9 @@ declare global {
     }
 }
 window.reportLatency = reportLatency;
+window.reportLoadedLatency = reportLoadedLatency;
+window.reportClientError = reportClientError;
+window.reportRunSummary = reportRunSummary;
 window.worst = 0;
 window.best = 10000;
 window.frequency = [];
@@ -71,18 +121,48 @@ for (let i=0; i<N_BANDS; i++) {
     window.frequency.push(0);
 }
 
+// Tag results with the server build we're measuring against. Not awaited:
+// measuring doesn't depend on it, so a slow or failing fetch can't hold it up
+fetchServerVersion().catch((e) => console.error("Unable to fetch server version: " + e));
+
 // Load the WASM Module
 await init();
 console.log("WASM Loaded");
 
+// Client diagnostics default to "info"; add ?log=debug (or trace, warn...) for more or less
+const logLevel = new URLSearchParams(window.location.search).get("log");
+if (logLevel && !set_log_level(logLevel)) {
+    console.error("Unknown log level: " + logLevel);
+}
+
 // Connect
 let latencyClient = new LatencyClient(latencyUrl());
 window.latencyClient = latencyClient;
-window.latencyClient.connect_socket();
+// Some browsers coarsen their clocks; latencies finer than that can't be measured
+const resolution = timer_resolution_ms();
+if (resolution > 1) {
+    setSpanText("clientError", "This browser's clock only measures to " + resolution + " ms");
+}
+// Servers started with AUTH_TOKEN need it: pass it on as ?token=
+window.latencyClient.set_auth_token(new URLSearchParams(window.location.search).get("token") ?? undefined);
+function startMeasuring() {
+    window.latencyClient.connect_socket();
 
-// Loop
-window.setInterval(() => {
-    if (window.latencyClient.is_connected()) {
-        window.latencyClient.start_latency_run();
-    }
-}, 1000);
\ No newline at end of file
+    // Only measure from one tab at a time
+    window.latencyClient.set_tab_coordination(true);
+
+    // Probe once a second until the page closes
+    window.latencyClient.run_profile("continuous");
+}
+
+// Prefer WebTransport where the server offers it; the client falls back to the WebSocket.
+// Measuring starts once the lookup settles, whether or not it succeeds
+fetchWebTransport()
+    .then((webTransport) => {
+        if (webTransport) {
+            const url = "https://" + window.location.hostname + ":" + webTransport.port + "/";
+            window.latencyClient.set_webtransport(url, webTransport.certificate_hash ?? undefined);
+        }
+    })
+    .catch((e) => console.error("Unable to fetch WebTransport info: " + e))
+    .finally(startMeasuring);
\ No newline at end of file
@@ -0,0 +1,19 @@
+//! Feeds arbitrary bytes to the decoders, which must never panic. Anything
+//! that decodes must re-encode to a prefix of the input.
+//!
+//! Run with `cargo +nightly fuzz run decode fuzz/corpus/decode` from
+//! `shared_data`. The corpus is seeded with a valid encoding of each message.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared_data::LatencyTest;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = LatencyTest::decode(data) {
+        assert!(data.starts_with(&message.encode()));
+    }
+    if let Ok(message) = LatencyTest::decode_lenient(data) {
+        assert!(data.starts_with(&message.encode()));
+    }
+});
@@ -0,0 +1,524 @@
+//! Analysis of latency measurements taken under load.
+
+use crate::{LatencySamples, LatencyTest};
+
+/// How far (as a fraction of the normalized range) the curve must bow below
+/// a straight line before the bend is treated as a knee rather than noise.
+const MIN_KNEE_DISTANCE: f64 = 0.1;
+
+/// Estimates the load at which the link saturates, given `(load, latency)`
+/// samples. Below saturation latency stays roughly flat; once buffers start
+/// filling (bufferbloat), latency climbs with load.
+///
+/// This finds the knee of the latency-vs-load curve "Kneedle" style: both
+/// axes are normalized to `0..=1`, and the knee is the sample furthest below
+/// the straight line from the first sample to the last. Returns `None` if
+/// there are fewer than three samples, or the curve has no clear knee (for
+/// example, latency never rises, or rises steadily from the start).
+pub fn estimate_saturation(samples: &[(f64, f64)]) -> Option<f64> {
+    if samples.len() < 3 {
+        return None;
+    }
+    let mut samples = samples.to_vec();
+    samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let (min_load, max_load) = (samples[0].0, samples[samples.len() - 1].0);
+    let min_latency = samples.iter().map(|s| s.1).fold(f64::INFINITY, f64::min);
+    let max_latency = samples.iter().map(|s| s.1).fold(f64::NEG_INFINITY, f64::max);
+    let (load_range, latency_range) = (max_load - min_load, max_latency - min_latency);
+    if load_range <= 0.0 || latency_range <= 0.0 {
+        return None;
+    }
+
+    let normalized = |&(load, latency): &(f64, f64)| {
+        ((load - min_load) / load_range, (latency - min_latency) / latency_range)
+    };
+    let (first, last) = (normalized(&samples[0]), normalized(&samples[samples.len() - 1]));
+    let slope = (last.1 - first.1) / (last.0 - first.0);
+
+    let (knee, distance) = samples
+        .iter()
+        .map(|sample| {
+            let (x, y) = normalized(sample);
+            let chord = first.1 + slope * (x - first.0);
+            (sample.0, chord - y)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+
+    (distance >= MIN_KNEE_DISTANCE).then_some(knee)
+}
+
+/// One statistic from a baseline and a candidate run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MetricDelta {
+    pub baseline_ms: f64,
+    pub candidate_ms: f64,
+    /// Change from baseline to candidate, as a percentage of the baseline.
+    /// Positive means the candidate is slower.
+    pub delta_pct: f64,
+}
+
+impl MetricDelta {
+    fn new(baseline_ms: f64, candidate_ms: f64) -> Self {
+        let delta_pct = if baseline_ms > 0.0 {
+            (candidate_ms - baseline_ms) / baseline_ms * 100.0
+        } else if candidate_ms > baseline_ms {
+            f64::INFINITY
+        } else {
+            0.0
+        };
+        Self {
+            baseline_ms,
+            candidate_ms,
+            delta_pct,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The candidate is no slower than the baseline, within tolerance.
+    Pass,
+    /// The candidate's mean or p95 latency regressed beyond tolerance.
+    Fail,
+    /// One of the runs has no samples.
+    Inconclusive,
+}
+
+/// The result of [`compare`]. Deltas are `None` if either run is empty.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegressionReport {
+    pub mean: Option<MetricDelta>,
+    pub p95: Option<MetricDelta>,
+    pub verdict: Verdict,
+}
+
+/// Compares a candidate run against a baseline, failing if the candidate's
+/// mean or 95th percentile latency exceeds the baseline's by more than
+/// `tolerance_pct` percent. Intended for gating network changes in CI.
+pub fn compare(
+    baseline: &LatencySamples,
+    candidate: &LatencySamples,
+    tolerance_pct: f64,
+) -> RegressionReport {
+    let delta = |stat: fn(&LatencySamples) -> Option<f64>| {
+        Some(MetricDelta::new(stat(baseline)?, stat(candidate)?))
+    };
+    let mean = delta(LatencySamples::mean_ms);
+    let p95 = delta(|samples| samples.percentile_ms(95.0));
+    let verdict = match (mean, p95) {
+        (Some(mean), Some(p95)) => {
+            if mean.delta_pct > tolerance_pct || p95.delta_pct > tolerance_pct {
+                Verdict::Fail
+            } else {
+                Verdict::Pass
+            }
+        }
+        _ => Verdict::Inconclusive,
+    };
+    RegressionReport { mean, p95, verdict }
+}
+
+/// Least-squares slope of `y` against `x`, or `None` if `x` doesn't vary.
+fn linear_slope(points: &[(f64, f64)]) -> Option<f64> {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let covariance: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    let variance: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    (variance > 0.0).then(|| covariance / variance)
+}
+
+/// Latency of each `Final` in a session, corrected for drift between the
+/// client and server clocks. Other messages are skipped, as are handshakes
+/// whose timestamps run backwards (see `LatencyResult::from_timestamps`).
+///
+/// Each handshake gives an estimate of the clock offset, NTP style: the
+/// client stamps `client_time` roughly midway between `server_time` and
+/// `server_ack_time`. A linear fit of offset against server time gives the
+/// drift rate, and the drift accumulated over each sample's client leg is
+/// subtracted, putting both legs on the server's clock. With fewer than two
+/// samples there is no trend, and the latencies are returned as measured.
+pub fn drift_corrected_latency(results: &[LatencyTest]) -> Vec<f64> {
+    // (server time, clock offset, server leg, client leg) for each handshake
+    let samples: Vec<(f64, f64, f64, f64)> = results
+        .iter()
+        .filter_map(|result| match *result {
+            LatencyTest::Final {
+                server_time,
+                client_time,
+                server_ack_time,
+                client_ack_time,
+                ..
+            } if server_ack_time >= server_time && client_ack_time >= client_time => {
+                let (server_time, server_ack_time) = (server_time as f64, server_ack_time as f64);
+                let (client_time, client_ack_time) = (client_time as f64, client_ack_time as f64);
+                let offset = client_time - (server_time + server_ack_time) * 0.5;
+                Some((
+                    server_time,
+                    offset,
+                    server_ack_time - server_time,
+                    client_ack_time - client_time,
+                ))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let trend: Vec<(f64, f64)> = samples.iter().map(|s| (s.0, s.1)).collect();
+    let drift = if trend.len() >= 2 {
+        linear_slope(&trend).unwrap_or(0.0)
+    } else {
+        0.0
+    };
+    samples
+        .iter()
+        .map(|&(_, _, server_leg, client_leg)| {
+            let client_leg = client_leg / (1.0 + drift);
+            (server_leg + client_leg) * 0.5
+        })
+        .collect()
+}
+
+/// A handshake's delay in each direction, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OneWayDelay {
+    /// Server to client.
+    pub downstream_ms: f64,
+    /// Client to server.
+    pub upstream_ms: f64,
+}
+
+/// The one-way delays of a handshake, given the offset of the client's clock
+/// from the server's (client minus server, in ms) as known from an external
+/// sync such as PTP or NTP. Latency is otherwise half the round trip, which
+/// assumes the path is symmetric; with the offset known, each direction can
+/// be timed on its own.
+///
+/// Downstream is `FirstReply`'s trip, averaged with `SecondReply`'s for a
+/// `Final`; upstream is `FirstResponse`'s. Returns `None` for any other
+/// variant. The delays are only as good as the offset: an error in it moves
+/// time from one direction to the other.
+pub fn one_way_delay(frame: &LatencyTest, clock_offset_ms: f64) -> Option<OneWayDelay> {
+    let (server_time, client_time, server_ack_time, client_ack_time) = match *frame {
+        LatencyTest::SecondReply {
+            server_time,
+            client_time,
+            server_ack_time,
+            ..
+        } => (server_time, client_time, server_ack_time, None),
+        LatencyTest::Final {
+            server_time,
+            client_time,
+            server_ack_time,
+            client_ack_time,
+            ..
+        } => (server_time, client_time, server_ack_time, Some(client_ack_time)),
+        _ => return None,
+    };
+    // Client timestamps, on the server's clock
+    let on_server_clock = |client: u128| client as f64 - clock_offset_ms;
+    let first_downstream = on_server_clock(client_time) - server_time as f64;
+    let downstream_ms = match client_ack_time {
+        Some(client_ack_time) => {
+            let second_downstream = on_server_clock(client_ack_time) - server_ack_time as f64;
+            (first_downstream + second_downstream) * 0.5
+        }
+        None => first_downstream,
+    };
+    Some(OneWayDelay {
+        downstream_ms,
+        upstream_ms: server_ack_time as f64 - on_server_clock(client_time),
+    })
+}
+
+/// The number of samples needed for the mean to be within `margin_ms` of
+/// the true mean with probability `confidence` (such as 0.95), given the
+/// standard deviation observed so far: `n = (z * stddev / margin)²`, rounded
+/// up, where `z` is the two-sided normal critical value for `confidence`.
+/// At least 1. A client can compare it with the samples it has taken to
+/// decide when it has measured enough. `None` if `observed_stddev` isn't
+/// finite, `margin_ms` isn't positive, or `confidence` isn't strictly between
+/// 0 and 1.
+pub fn required_samples(observed_stddev: f64, margin_ms: f64, confidence: f64) -> Option<usize> {
+    if !(observed_stddev.is_finite() && margin_ms > 0.0 && confidence > 0.0 && confidence < 1.0) {
+        return None;
+    }
+    let z = normal_quantile(0.5 + confidence / 2.0);
+    let n = (z * observed_stddev.abs() / margin_ms).powi(2).ceil();
+    Some((n as usize).max(1))
+}
+
+/// The standard normal distribution's quantile function (inverse CDF), for
+/// `p` in (0, 1), by Acklam's rational approximation (relative error under
+/// 1.2e-9).
+fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e1,
+        2.209460984245205e2,
+        -2.759285104469687e2,
+        1.38357751867269e2,
+        -3.066479806614716e1,
+        2.506628277459239,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e1,
+        1.615858368580409e2,
+        -1.556989798598866e2,
+        6.680131188771972e1,
+        -1.328068155288572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-3,
+        -3.223964580411365e-1,
+        -2.400758277161838,
+        -2.549732539343734,
+        4.374664141464968,
+        2.938163982698783,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-3,
+        3.224671290700398e-1,
+        2.445134137142996,
+        3.754408661907416,
+    ];
+    const P_LOW: f64 = 0.02425;
+
+    // Polynomials, evaluated by Horner's method; the denominators have an
+    // implicit trailing 1
+    let poly = |coeffs: &[f64], x: f64| coeffs.iter().fold(0.0, |acc, c| acc * x + c);
+    let tail = |q: f64| poly(&C, q) / (poly(&D, q) * q + 1.0);
+    if p < P_LOW {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p > 1.0 - P_LOW {
+        -tail((-2.0 * (1.0 - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        poly(&A, r) * q / (poly(&B, r) * r + 1.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn critical_values_match_tables() {
+        for (confidence, z) in [(0.90, 1.644854), (0.95, 1.959964), (0.99, 2.575829)] {
+            let quantile = normal_quantile(0.5 + confidence / 2.0);
+            assert!((quantile - z).abs() < 1e-6, "{confidence}: {quantile}");
+        }
+        assert!((normal_quantile(0.01) + 2.326348).abs() < 1e-6);
+        assert_eq!(normal_quantile(0.5), 0.0);
+    }
+
+    #[test]
+    fn required_samples_matches_textbook_examples() {
+        // σ = 15, ±5 at 95%: (1.96 × 15 / 5)² = 34.6, so 35
+        assert_eq!(required_samples(15.0, 5.0, 0.95), Some(35));
+        // σ = 20, ±3 at 99%: (2.576 × 20 / 3)² = 294.9, so 295
+        assert_eq!(required_samples(20.0, 3.0, 0.99), Some(295));
+        // σ = 10, ±2 at 90%: (1.645 × 10 / 2)² = 67.6, so 68
+        assert_eq!(required_samples(10.0, 2.0, 0.90), Some(68));
+        // A tighter margin needs quadratically more samples
+        assert_eq!(required_samples(15.0, 2.5, 0.95), Some(139));
+        // No variation at all still needs one sample
+        assert_eq!(required_samples(0.0, 1.0, 0.95), Some(1));
+        // Nonsense targets, or a stddev that isn't a number
+        assert_eq!(required_samples(15.0, 0.0, 0.95), None);
+        assert_eq!(required_samples(15.0, 5.0, 1.0), None);
+        assert_eq!(required_samples(15.0, f64::NAN, 0.95), None);
+        assert_eq!(required_samples(f64::NAN, 5.0, 0.95), None);
+        assert_eq!(required_samples(f64::INFINITY, 5.0, 0.95), None);
+    }
+
+    #[test]
+    fn finds_known_knee() {
+        // Flat at 20ms until a load of 50, then climbing 2ms per unit of load
+        let samples: Vec<(f64, f64)> = (0..=10)
+            .map(|i| {
+                let load = i as f64 * 10.0;
+                let latency = 20.0 + (load - 50.0).max(0.0) * 2.0;
+                (load, latency)
+            })
+            .collect();
+        assert_eq!(estimate_saturation(&samples), Some(50.0));
+    }
+
+    #[test]
+    fn sample_order_does_not_matter() {
+        let samples = [(40.0, 10.0), (0.0, 10.0), (80.0, 50.0), (20.0, 10.0), (60.0, 30.0)];
+        assert_eq!(estimate_saturation(&samples), Some(40.0));
+    }
+
+    #[test]
+    fn no_knee_without_a_bend() {
+        let flat: Vec<(f64, f64)> = (0..10).map(|i| (i as f64, 15.0)).collect();
+        assert_eq!(estimate_saturation(&flat), None);
+        let linear: Vec<(f64, f64)> = (0..10).map(|i| (i as f64, 15.0 + i as f64)).collect();
+        assert_eq!(estimate_saturation(&linear), None);
+        assert_eq!(estimate_saturation(&[(0.0, 1.0), (1.0, 5.0)]), None);
+    }
+
+    fn run(latencies: &[f64]) -> LatencySamples {
+        let mut samples = LatencySamples::new();
+        for (i, &latency_ms) in latencies.iter().enumerate() {
+            samples.push(
+                i as u128,
+                crate::LatencyResult {
+                    latency_ms,
+                    server_latency_ms: latency_ms,
+                    client_latency_ms: latency_ms,
+                    approximate: false,
+                    resolution_limited: false,
+                },
+            );
+        }
+        samples
+    }
+
+    #[test]
+    fn faster_candidate_passes() {
+        let report = compare(&run(&[20.0, 20.0, 40.0]), &run(&[10.0, 10.0, 20.0]), 5.0);
+        assert_eq!(report.verdict, Verdict::Pass);
+        assert_eq!(report.mean.unwrap().delta_pct, -50.0);
+        assert_eq!(report.p95.unwrap().delta_pct, -50.0);
+    }
+
+    #[test]
+    fn slower_tail_fails() {
+        // Same mean, but a much worse p95
+        let baseline = run(&[20.0, 20.0, 20.0, 20.0]);
+        let report = compare(&baseline, &run(&[10.0, 10.0, 10.0, 50.0]), 10.0);
+        assert_eq!(report.mean.unwrap().delta_pct, 0.0);
+        assert_eq!(report.p95.unwrap().delta_pct, 150.0);
+        assert_eq!(report.verdict, Verdict::Fail);
+    }
+
+    #[test]
+    fn small_regression_within_tolerance() {
+        let report = compare(&run(&[20.0, 20.0]), &run(&[21.0, 21.0]), 10.0);
+        assert_eq!(report.mean.unwrap().delta_pct, 5.0);
+        assert_eq!(report.verdict, Verdict::Pass);
+        let strict = compare(&run(&[20.0, 20.0]), &run(&[21.0, 21.0]), 1.0);
+        assert_eq!(strict.verdict, Verdict::Fail);
+    }
+
+    #[test]
+    fn empty_run_is_inconclusive() {
+        let report = compare(&run(&[20.0]), &LatencySamples::new(), 10.0);
+        assert_eq!(report.verdict, Verdict::Inconclusive);
+        assert_eq!(report.mean, None);
+    }
+
+    /// A handshake at server time `at`, with a 10ms one-way trip, seen by a
+    /// client whose clock runs `rate` faster than the server's, from `offset`.
+    fn drifting_final(at: u128, rate: f64, offset: f64) -> LatencyTest {
+        let client_clock = |server: u128| (server as f64 * (1.0 + rate) + offset).round() as u128;
+        LatencyTest::Final {
+            magic: crate::MAGIC_NUMBER,
+            id: 0,
+            server_time: at,
+            client_time: client_clock(at + 10),
+            server_ack_time: at + 20,
+            client_ack_time: client_clock(at + 30),
+        }
+    }
+
+    #[test]
+    fn linear_drift_is_removed() {
+        // An absurdly fast client clock (5%), so the bias survives whole-ms timestamps
+        let session: Vec<LatencyTest> = (0..20)
+            .map(|i| drifting_final(1_000_000 + i * 60_000, 0.05, 12_345.0))
+            .collect();
+        let raw: Vec<f64> =
+            session.iter().map(|f| f.calculate_latency().unwrap().latency_ms).collect();
+        assert!(raw.iter().all(|&latency| latency == 20.5));
+
+        let corrected = drift_corrected_latency(&session);
+        assert_eq!(corrected.len(), session.len());
+        for latency in corrected {
+            assert!((latency - 20.0).abs() < 0.01, "{latency}");
+        }
+    }
+
+    #[test]
+    fn no_drift_without_a_trend() {
+        let session = [
+            drifting_final(1000, 0.0, 50.0),
+            LatencyTest::InitialRequest {
+                magic: crate::MAGIC_NUMBER,
+                id: 0,
+            },
+        ];
+        assert_eq!(drift_corrected_latency(&session), vec![20.0]);
+        assert!(drift_corrected_latency(&[]).is_empty());
+    }
+
+    #[test]
+    fn backwards_handshakes_are_skipped() {
+        let backwards = LatencyTest::Final {
+            magic: crate::MAGIC_NUMBER,
+            id: 0,
+            server_time: 1020,
+            client_time: 5010,
+            server_ack_time: 1000,
+            client_ack_time: 5030,
+        };
+        let session = [drifting_final(1000, 0.0, 50.0), backwards];
+        assert_eq!(drift_corrected_latency(&session), vec![20.0]);
+    }
+
+    #[test]
+    fn one_way_delay_under_known_offset() {
+        // The client's clock runs 4000ms ahead. 5ms down, 15ms up, then 5ms
+        // down again: a 20ms round trip, but far from symmetric.
+        let last = LatencyTest::Final {
+            magic: crate::MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 5005,
+            server_ack_time: 1020,
+            client_ack_time: 5025,
+        };
+        let expected = OneWayDelay {
+            downstream_ms: 5.0,
+            upstream_ms: 15.0,
+        };
+        assert_eq!(one_way_delay(&last, 4000.0), Some(expected));
+        assert_eq!(last.calculate_latency().unwrap().latency_ms, 20.0);
+
+        // Without the second downstream leg, as the server sees it
+        let second_reply = LatencyTest::SecondReply {
+            magic: crate::MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 5005,
+            server_ack_time: 1020,
+        };
+        assert_eq!(one_way_delay(&second_reply, 4000.0), Some(expected));
+    }
+
+    #[test]
+    fn one_way_delay_shifts_with_offset_error() {
+        let last = LatencyTest::Final {
+            magic: crate::MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 1010,
+            server_ack_time: 1020,
+            client_ack_time: 1030,
+        };
+        let synced = one_way_delay(&last, 0.0).unwrap();
+        assert_eq!((synced.downstream_ms, synced.upstream_ms), (10.0, 10.0));
+        // Believing the client 2ms behind moves 2ms from upstream to downstream
+        let skewed = one_way_delay(&last, -2.0).unwrap();
+        assert_eq!((skewed.downstream_ms, skewed.upstream_ms), (12.0, 8.0));
+        let request = LatencyTest::InitialRequest {
+            magic: crate::MAGIC_NUMBER,
+            id: 0,
+        };
+        assert_eq!(one_way_delay(&request, 0.0), None);
+    }
+}
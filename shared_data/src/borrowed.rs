@@ -0,0 +1,258 @@
+//! Decoding without copying. `LatencyTest::decode` copies the payload of
+//! `DataChunk` and `Filler` frames into owned `Vec`s, which is wasted work
+//! for a receiver that only wants their size. `LatencyTestRef` borrows those
+//! payloads from the input instead.
+
+use crate::{
+    decode_header, read_u32, LatencyTest, LatencyTestError, MessageKind, CHUNK_OVERHEAD,
+    HEADER_SIZE, MAGIC_NUMBER, MAX_FRAME_SIZE, SIZE_U32,
+};
+
+/// A decoded message whose variable-length payload, if any, borrows from the
+/// buffer it was decoded from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LatencyTestRef<'a> {
+    DataChunk {
+        magic: u16,
+        id: u64,
+        seq: u32,
+        total: u32,
+        bytes: &'a [u8],
+    },
+    Filler {
+        magic: u16,
+        id: u64,
+        bytes: &'a [u8],
+    },
+    /// Any other message. These are small, fixed-size control frames (or a
+    /// `Report`, whose metadata is capped), so they decode as usual.
+    Control(LatencyTest),
+}
+
+impl<'a> LatencyTestRef<'a> {
+    /// Decodes a message like `LatencyTest::decode`, but without copying
+    /// payloads.
+    pub fn decode(bytes: &'a [u8]) -> Result<Self, LatencyTestError> {
+        Self::decode_with(bytes, LatencyTest::decode)
+    }
+
+    /// Decodes a message, also returning any bytes that follow it in the
+    /// frame, as `LatencyTest::decode_with_trailer`.
+    pub fn decode_with_trailer(bytes: &'a [u8]) -> Result<(Self, &'a [u8]), LatencyTestError> {
+        let message = Self::decode(bytes)?;
+        let trailer = bytes.get(message.wire_len()..).ok_or(LatencyTestError::Read)?;
+        Ok((message, trailer))
+    }
+
+    /// Decodes a message without copying payloads, decoding control frames
+    /// with `control` (such as a `Codec`'s `decode`).
+    pub fn decode_with(
+        bytes: &'a [u8],
+        control: impl FnOnce(&[u8]) -> Result<LatencyTest, LatencyTestError>,
+    ) -> Result<Self, LatencyTestError> {
+        let magic = MAGIC_NUMBER;
+        match decode_header(bytes)? {
+            (6, id) => {
+                let (seq, total, bytes) = chunk_fields(bytes)?;
+                Ok(Self::DataChunk {
+                    magic,
+                    id,
+                    seq,
+                    total,
+                    bytes,
+                })
+            }
+            (10, id) => Ok(Self::Filler {
+                magic,
+                id,
+                bytes: filler_payload(bytes)?,
+            }),
+            _ => control(bytes).map(Self::Control),
+        }
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Self::DataChunk { .. } => MessageKind::DataChunk,
+            Self::Filler { .. } => MessageKind::Filler,
+            Self::Control(message) => message.kind(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        match self {
+            Self::DataChunk { id, .. } | Self::Filler { id, .. } => *id,
+            Self::Control(message) => message.id(),
+        }
+    }
+
+    /// The length of the message's encoding, as `LatencyTest::wire_len`.
+    pub fn wire_len(&self) -> usize {
+        match self {
+            Self::DataChunk { bytes, .. } => CHUNK_OVERHEAD + bytes.len(),
+            Self::Filler { bytes, .. } => HEADER_SIZE + SIZE_U32 + bytes.len(),
+            Self::Control(message) => message.wire_len(),
+        }
+    }
+
+    /// Copies any borrowed payload, giving the equivalent `LatencyTest`.
+    pub fn to_owned(&self) -> LatencyTest {
+        match self {
+            Self::DataChunk {
+                magic,
+                id,
+                seq,
+                total,
+                bytes,
+            } => LatencyTest::DataChunk {
+                magic: *magic,
+                id: *id,
+                seq: *seq,
+                total: *total,
+                bytes: bytes.to_vec(),
+            },
+            Self::Filler { magic, id, bytes } => LatencyTest::Filler {
+                magic: *magic,
+                id: *id,
+                bytes: bytes.to_vec(),
+            },
+            Self::Control(message) => message.clone(),
+        }
+    }
+
+    /// As `to_owned`, but moving a control frame rather than cloning it.
+    pub fn into_owned(self) -> LatencyTest {
+        match self {
+            Self::Control(message) => message,
+            payload => payload.to_owned(),
+        }
+    }
+}
+
+/// The `seq`, `total` and data of an encoded `DataChunk`.
+pub(crate) fn chunk_fields(bytes: &[u8]) -> Result<(u32, u32, &[u8]), LatencyTestError> {
+    let seq = read_u32(bytes, HEADER_SIZE)?;
+    let total = read_u32(bytes, HEADER_SIZE + SIZE_U32)?;
+    let data = payload(bytes, HEADER_SIZE + (SIZE_U32 * 2))?;
+    Ok((seq, total, data))
+}
+
+/// The sequence number and wrapped frame of an encoded `Sequenced`.
+pub(crate) fn sequenced_fields(bytes: &[u8]) -> Result<(u32, &[u8]), LatencyTestError> {
+    let seq = read_u32(bytes, HEADER_SIZE)?;
+    let frame = payload(bytes, HEADER_SIZE + SIZE_U32)?;
+    Ok((seq, frame))
+}
+
+/// The data of an encoded `Filler`.
+pub(crate) fn filler_payload(bytes: &[u8]) -> Result<&[u8], LatencyTestError> {
+    payload(bytes, HEADER_SIZE)
+}
+
+/// A length-prefixed payload, with its length at `offset`.
+fn payload(bytes: &[u8], offset: usize) -> Result<&[u8], LatencyTestError> {
+    let len = read_u32(bytes, offset)? as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(LatencyTestError::Read);
+    }
+    let start = offset + SIZE_U32;
+    bytes.get(start..start + len).ok_or(LatencyTestError::Read)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn payload_borrows_from_the_input() {
+        let chunk = LatencyTest::DataChunk {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            seq: 1,
+            total: 4,
+            bytes: vec![9; 1000],
+        };
+        let encoded = chunk.encode();
+        let decoded = LatencyTestRef::decode(&encoded).unwrap();
+        let LatencyTestRef::DataChunk { seq, total, bytes, .. } = decoded else {
+            panic!("Expected a DataChunk, got {decoded:?}");
+        };
+        assert_eq!((seq, total), (1, 4));
+        // The very bytes of the input, not a copy of them
+        assert_eq!(bytes.as_ptr(), encoded[CHUNK_OVERHEAD..].as_ptr());
+        assert_eq!(bytes.len(), 1000);
+        assert_eq!(decoded.to_owned(), chunk);
+
+        let filler = LatencyTest::Filler {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            bytes: vec![0xAA; 64],
+        };
+        let encoded = filler.encode();
+        match LatencyTestRef::decode(&encoded).unwrap() {
+            LatencyTestRef::Filler { bytes, .. } => {
+                assert_eq!(bytes.as_ptr(), encoded[HEADER_SIZE + SIZE_U32..].as_ptr());
+            }
+            other => panic!("Expected Filler, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn control_frames_decode_as_usual() {
+        let request = LatencyTest::FirstReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+        };
+        let encoded = request.encode();
+        let decoded = LatencyTestRef::decode(&encoded).unwrap();
+        assert_eq!(decoded, LatencyTestRef::Control(request.clone()));
+        assert_eq!(decoded.kind(), MessageKind::FirstReply);
+        assert_eq!(decoded.to_owned(), request);
+    }
+
+    #[test]
+    fn trailer_follows_the_borrowed_payload() {
+        let filler = LatencyTest::Filler {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            bytes: vec![0xAA; 64],
+        };
+        let request = LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: 0,
+        };
+        for message in [filler, request] {
+            let mut bytes = message.encode();
+            bytes.extend([9, 8, 7]);
+            let (decoded, trailer) = LatencyTestRef::decode_with_trailer(&bytes).unwrap();
+            assert_eq!(decoded.wire_len(), message.wire_len());
+            assert_eq!(trailer, &[9, 8, 7]);
+            assert_eq!(decoded.into_owned(), message);
+        }
+    }
+
+    #[test]
+    fn errors_match_decode() {
+        let mut bad_magic = LatencyTest::Filler {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            bytes: vec![1, 2, 3],
+        }
+        .encode();
+        bad_magic[0] = 0;
+        assert!(matches!(
+            LatencyTestRef::decode(&bad_magic),
+            Err(LatencyTestError::InvalidMagic)
+        ));
+        let mut truncated = LatencyTest::Filler {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            bytes: vec![1, 2, 3],
+        }
+        .encode();
+        truncated.pop();
+        assert!(matches!(LatencyTestRef::decode(&truncated), Err(LatencyTestError::Read)));
+        assert!(matches!(LatencyTestRef::decode(&[0xBE]), Err(LatencyTestError::Read)));
+    }
+}
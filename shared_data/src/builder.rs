@@ -0,0 +1,191 @@
+//! Typed construction of the handshake messages. Their timestamps are all
+//! `u128`s, so a struct literal can't stop one being passed as another.
+//! Here each timestamp is set by its own method, in handshake order, and
+//! each step is a distinct type that can only build the message it has every
+//! timestamp for. The message's id, if not 0, is set first:
+//!
+//! ```
+//! use shared_data::LatencyTest;
+//!
+//! let second_reply = LatencyTest::builder()
+//!     .id(7)
+//!     .server_time(1000)
+//!     .client_time(5010)
+//!     .server_ack_time(1020)
+//!     .build();
+//! assert!(matches!(second_reply, LatencyTest::SecondReply { id: 7, client_time: 5010, .. }));
+//! ```
+
+use crate::{LatencyTest, MAGIC_NUMBER};
+
+/// Builds an `InitialRequest`, or (given the server's time) the rest of the
+/// handshake.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessageBuilder {
+    id: u64,
+}
+
+/// Has the server's time: builds a `FirstReply`.
+#[derive(Debug, Clone, Copy)]
+pub struct WithServerTime {
+    id: u64,
+    server_time: u128,
+}
+
+/// Has the server's and client's times: builds a `FirstResponse`.
+#[derive(Debug, Clone, Copy)]
+pub struct WithClientTime {
+    id: u64,
+    server_time: u128,
+    client_time: u128,
+}
+
+/// Has the server's acknowledgement too: builds a `SecondReply`.
+#[derive(Debug, Clone, Copy)]
+pub struct WithServerAckTime {
+    id: u64,
+    server_time: u128,
+    client_time: u128,
+    server_ack_time: u128,
+}
+
+/// Has every timestamp: builds a `Final`.
+#[derive(Debug, Clone, Copy)]
+pub struct WithClientAckTime {
+    id: u64,
+    server_time: u128,
+    client_time: u128,
+    server_ack_time: u128,
+    client_ack_time: u128,
+}
+
+impl LatencyTest {
+    /// Starts building a handshake message, one named timestamp at a time.
+    pub fn builder() -> MessageBuilder {
+        MessageBuilder::default()
+    }
+}
+
+impl MessageBuilder {
+    /// Sets the id of the message, and of any built from it.
+    pub fn id(self, id: u64) -> Self {
+        Self { id }
+    }
+
+    pub fn server_time(self, server_time: u128) -> WithServerTime {
+        WithServerTime {
+            id: self.id,
+            server_time,
+        }
+    }
+
+    pub fn build(self) -> LatencyTest {
+        LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: self.id,
+        }
+    }
+}
+
+impl WithServerTime {
+    pub fn client_time(self, client_time: u128) -> WithClientTime {
+        WithClientTime {
+            id: self.id,
+            server_time: self.server_time,
+            client_time,
+        }
+    }
+
+    pub fn build(self) -> LatencyTest {
+        LatencyTest::FirstReply {
+            magic: MAGIC_NUMBER,
+            id: self.id,
+            server_time: self.server_time,
+        }
+    }
+}
+
+impl WithClientTime {
+    pub fn server_ack_time(self, server_ack_time: u128) -> WithServerAckTime {
+        WithServerAckTime {
+            id: self.id,
+            server_time: self.server_time,
+            client_time: self.client_time,
+            server_ack_time,
+        }
+    }
+
+    pub fn build(self) -> LatencyTest {
+        LatencyTest::FirstResponse {
+            magic: MAGIC_NUMBER,
+            id: self.id,
+            server_time: self.server_time,
+            client_time: self.client_time,
+        }
+    }
+}
+
+impl WithServerAckTime {
+    pub fn client_ack_time(self, client_ack_time: u128) -> WithClientAckTime {
+        WithClientAckTime {
+            id: self.id,
+            server_time: self.server_time,
+            client_time: self.client_time,
+            server_ack_time: self.server_ack_time,
+            client_ack_time,
+        }
+    }
+
+    pub fn build(self) -> LatencyTest {
+        LatencyTest::SecondReply {
+            magic: MAGIC_NUMBER,
+            id: self.id,
+            server_time: self.server_time,
+            client_time: self.client_time,
+            server_ack_time: self.server_ack_time,
+        }
+    }
+}
+
+impl WithClientAckTime {
+    pub fn build(self) -> LatencyTest {
+        LatencyTest::Final {
+            magic: MAGIC_NUMBER,
+            id: self.id,
+            server_time: self.server_time,
+            client_time: self.client_time,
+            server_ack_time: self.server_ack_time,
+            client_ack_time: self.client_ack_time,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builder_encodes_like_struct_literals() {
+        let handshake = crate::scenario! {
+            server_time: 1_000,
+            client_time: 5_010,
+            server_ack_time: 1_020,
+            client_ack_time: 5_030,
+        };
+        let builder = LatencyTest::builder();
+        let first_reply = builder.server_time(1_000);
+        let first_response = first_reply.client_time(5_010);
+        let second_reply = first_response.server_ack_time(1_020);
+        let last = second_reply.client_ack_time(5_030);
+        let built = [
+            builder.build(),
+            first_reply.build(),
+            first_response.build(),
+            second_reply.build(),
+            last.build(),
+        ];
+        for (built, literal) in built.iter().zip(handshake.messages()) {
+            assert_eq!(built.encode(), literal.encode());
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! A simple capture file format for recording raw frames, so tricky latency
+//! issues can be replayed offline.
+//!
+//! A capture starts with `CAPTURE_MAGIC` and a `u16` format version, then
+//! holds any number of records, each laid out (big-endian) as:
+//!
+//! `timestamp_ms (u128) | direction (u8) | length (u32) | bytes`
+
+use crate::MAX_FRAME_SIZE;
+use std::io::{self, Read, Write};
+
+/// Identifies a capture file.
+pub const CAPTURE_MAGIC: &[u8; 4] = b"LTCP";
+const CAPTURE_VERSION: u16 = 1;
+
+/// Which way a captured frame was travelling, from the capturer's view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(Direction::Inbound),
+            1 => Ok(Direction::Outbound),
+            _ => Err(invalid_data("Unknown capture direction")),
+        }
+    }
+}
+
+/// A single frame read back from a capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedFrame {
+    pub timestamp_ms: u128,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+/// Writes captured frames.
+pub struct FrameCapture<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> FrameCapture<W> {
+    /// Starts a capture, writing the file header.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(CAPTURE_MAGIC)?;
+        writer.write_all(&CAPTURE_VERSION.to_be_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn record(&mut self, timestamp_ms: u128, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        if bytes.len() > MAX_FRAME_SIZE {
+            return Err(invalid_data("Frame too large to capture"));
+        }
+        self.writer.write_all(&timestamp_ms.to_be_bytes())?;
+        self.writer.write_all(&[direction.to_byte()])?;
+        self.writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        self.writer.write_all(bytes)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads frames back from a capture, in the order they were recorded.
+pub struct CaptureReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Opens a capture, validating the file header.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != CAPTURE_MAGIC {
+            return Err(invalid_data("Not a capture file"));
+        }
+        let mut version = [0u8; 2];
+        reader.read_exact(&mut version)?;
+        if u16::from_be_bytes(version) != CAPTURE_VERSION {
+            return Err(invalid_data("Unsupported capture version"));
+        }
+        Ok(Self { reader })
+    }
+
+    fn read_record(&mut self, first: u8) -> io::Result<CapturedFrame> {
+        let mut timestamp = [0u8; 16];
+        timestamp[0] = first;
+        self.reader.read_exact(&mut timestamp[1..])?;
+        let mut direction = [0u8; 1];
+        self.reader.read_exact(&mut direction)?;
+        let mut len = [0u8; 4];
+        self.reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(invalid_data("Captured frame too large"));
+        }
+        let mut bytes = vec![0u8; len];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(CapturedFrame {
+            timestamp_ms: u128::from_be_bytes(timestamp),
+            direction: Direction::from_byte(direction[0])?,
+            bytes,
+        })
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<CapturedFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // A clean end of file can only fall between records.
+        let mut first = [0u8; 1];
+        match self.reader.read(&mut first) {
+            Ok(0) => None,
+            Ok(_) => Some(self.read_record(first[0])),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{LatencyTest, MAGIC_NUMBER};
+
+    #[test]
+    fn capture_round_trip() {
+        let request = LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: 0,
+        }
+        .encode();
+        let reply = LatencyTest::FirstReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+        }
+        .encode();
+        let frames = vec![
+            CapturedFrame {
+                timestamp_ms: 999,
+                direction: Direction::Inbound,
+                bytes: request,
+            },
+            CapturedFrame {
+                timestamp_ms: 1000,
+                direction: Direction::Outbound,
+                bytes: reply,
+            },
+            CapturedFrame {
+                timestamp_ms: 1001,
+                direction: Direction::Inbound,
+                bytes: Vec::new(),
+            },
+        ];
+
+        let mut capture = FrameCapture::new(Vec::new()).unwrap();
+        for frame in &frames {
+            capture
+                .record(frame.timestamp_ms, frame.direction, &frame.bytes)
+                .unwrap();
+        }
+        let file = capture.into_inner();
+
+        let read_back: Vec<CapturedFrame> = CaptureReader::new(file.as_slice())
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(read_back, frames);
+    }
+
+    #[test]
+    fn truncated_record_is_an_error() {
+        let mut capture = FrameCapture::new(Vec::new()).unwrap();
+        capture.record(1000, Direction::Outbound, &[1, 2, 3]).unwrap();
+        let mut file = capture.into_inner();
+        file.pop();
+
+        let mut reader = CaptureReader::new(file.as_slice()).unwrap();
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn rejects_non_capture_file() {
+        assert!(CaptureReader::new(&b"nope, not a capture"[..]).is_err());
+    }
+}
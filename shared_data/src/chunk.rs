@@ -0,0 +1,225 @@
+//! Splitting payloads too large for a single frame into a sequence of
+//! `DataChunk` frames, and reassembling them on the other side.
+
+use crate::{LatencyTest, HEADER_SIZE, MAGIC_NUMBER, SIZE_U32};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// Encoded size of a `DataChunk` frame, excluding its data.
+pub const CHUNK_OVERHEAD: usize = HEADER_SIZE + (SIZE_U32 * 3);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ChunkError {
+    #[error("Not a data chunk")]
+    NotAChunk,
+    #[error("Chunk {seq} is outside a payload of {total} chunks")]
+    OutOfRange { seq: u32, total: u32 },
+    #[error("Chunk total changed mid-payload")]
+    TotalMismatch,
+    #[error("Chunk {0} received twice")]
+    Duplicate(u32),
+    #[error("Chunk {0} is missing")]
+    Missing(u32),
+    #[error("No chunks received")]
+    Empty,
+    #[error("A {0} byte frame has no room for data after the chunk's {CHUNK_OVERHEAD}")]
+    FrameTooSmall(usize),
+    #[error("Payload needs more than {} chunks", u32::MAX)]
+    TooManyChunks,
+}
+
+/// Splits `payload` into `DataChunk` frames that each encode to at most
+/// `max_frame_size` bytes. An empty payload still produces one (empty) chunk,
+/// so the receiver sees it arrive. Fails if `max_frame_size` leaves no room
+/// for data after `CHUNK_OVERHEAD`, or the payload would need more chunks
+/// than a `u32` can number.
+pub fn chunk_payload(
+    payload: &[u8],
+    max_frame_size: usize,
+) -> Result<Vec<LatencyTest>, ChunkError> {
+    let chunk_size = max_frame_size.saturating_sub(CHUNK_OVERHEAD);
+    if chunk_size == 0 {
+        return Err(ChunkError::FrameTooSmall(max_frame_size));
+    }
+    let total = payload.len().div_ceil(chunk_size).max(1);
+    let total = u32::try_from(total).map_err(|_| ChunkError::TooManyChunks)?;
+    if payload.is_empty() {
+        return Ok(vec![LatencyTest::DataChunk {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            seq: 0,
+            total,
+            bytes: Vec::new(),
+        }]);
+    }
+    let chunks = payload
+        .chunks(chunk_size)
+        .enumerate()
+        .map(|(seq, bytes)| LatencyTest::DataChunk {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            seq: seq as u32,
+            total,
+            bytes: bytes.to_vec(),
+        })
+        .collect();
+    Ok(chunks)
+}
+
+/// Collects the `DataChunk` frames of one payload, in any order.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    total: Option<u32>,
+    chunks: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, chunk: LatencyTest) -> Result<(), ChunkError> {
+        let LatencyTest::DataChunk {
+            seq, total, bytes, ..
+        } = chunk
+        else {
+            return Err(ChunkError::NotAChunk);
+        };
+        if seq >= total {
+            return Err(ChunkError::OutOfRange { seq, total });
+        }
+        if *self.total.get_or_insert(total) != total {
+            return Err(ChunkError::TotalMismatch);
+        }
+        if self.chunks.insert(seq, bytes).is_some() {
+            return Err(ChunkError::Duplicate(seq));
+        }
+        Ok(())
+    }
+
+    /// True once every chunk of the payload has arrived.
+    pub fn is_complete(&self) -> bool {
+        self.total == Some(self.chunks.len() as u32)
+    }
+
+    /// Joins the chunks back into the original payload, reporting the first
+    /// missing chunk if any didn't arrive.
+    pub fn finish(self) -> Result<Vec<u8>, ChunkError> {
+        let total = self.total.ok_or(ChunkError::Empty)?;
+        if let Some(missing) = (0..total).find(|seq| !self.chunks.contains_key(seq)) {
+            return Err(ChunkError::Missing(missing));
+        }
+        Ok(self.chunks.into_values().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn payload() -> Vec<u8> {
+        (0..1000).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn chunks_fit_frame_size() {
+        let chunks = chunk_payload(&payload(), 126).unwrap();
+        assert_eq!(chunks.len(), 10);
+        for chunk in &chunks {
+            assert!(chunk.encode().len() <= 126);
+        }
+    }
+
+    #[test]
+    fn frame_with_no_room_for_data_is_refused() {
+        for max_frame_size in [0, CHUNK_OVERHEAD] {
+            assert_eq!(
+                chunk_payload(&payload(), max_frame_size),
+                Err(ChunkError::FrameTooSmall(max_frame_size))
+            );
+        }
+        // One byte of data apiece
+        let chunks = chunk_payload(&payload()[..3], CHUNK_OVERHEAD + 1).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.encode().len() == CHUNK_OVERHEAD + 1));
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let mut chunks = chunk_payload(&payload(), 100).unwrap();
+        chunks.reverse();
+        let mut reassembler = Reassembler::new();
+        for chunk in chunks {
+            assert!(!reassembler.is_complete());
+            reassembler.push(chunk).unwrap();
+        }
+        assert!(reassembler.is_complete());
+        assert_eq!(reassembler.finish().unwrap(), payload());
+    }
+
+    #[test]
+    fn reassembles_via_wire_encoding() {
+        let mut reassembler = Reassembler::new();
+        for chunk in chunk_payload(&payload(), 64).unwrap() {
+            reassembler
+                .push(LatencyTest::decode(&chunk.encode()).unwrap())
+                .unwrap();
+        }
+        assert_eq!(reassembler.finish().unwrap(), payload());
+    }
+
+    #[test]
+    fn empty_payload() {
+        let mut reassembler = Reassembler::new();
+        for chunk in chunk_payload(&[], 64).unwrap() {
+            reassembler.push(chunk).unwrap();
+        }
+        assert_eq!(reassembler.finish().unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn missing_chunk_is_reported() {
+        let mut reassembler = Reassembler::new();
+        for chunk in chunk_payload(&payload(), 100).unwrap()
+            .into_iter()
+            .filter(|chunk| !matches!(chunk, LatencyTest::DataChunk { seq: 3, .. }))
+        {
+            reassembler.push(chunk).unwrap();
+        }
+        assert!(!reassembler.is_complete());
+        assert_eq!(reassembler.finish(), Err(ChunkError::Missing(3)));
+    }
+
+    #[test]
+    fn duplicate_chunk_is_reported() {
+        let chunks = chunk_payload(&payload(), 100).unwrap();
+        let mut reassembler = Reassembler::new();
+        reassembler.push(chunks[1].clone()).unwrap();
+        assert_eq!(reassembler.push(chunks[1].clone()), Err(ChunkError::Duplicate(1)));
+    }
+
+    #[test]
+    fn rejects_inconsistent_chunks() {
+        let mut reassembler = Reassembler::new();
+        let chunk = |seq, total| LatencyTest::DataChunk {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            seq,
+            total,
+            bytes: Vec::new(),
+        };
+        assert_eq!(
+            reassembler.push(chunk(5, 5)),
+            Err(ChunkError::OutOfRange { seq: 5, total: 5 })
+        );
+        reassembler.push(chunk(0, 5)).unwrap();
+        assert_eq!(reassembler.push(chunk(1, 6)), Err(ChunkError::TotalMismatch));
+        assert_eq!(
+            reassembler.push(LatencyTest::InitialRequest {
+                magic: MAGIC_NUMBER,
+                id: 0,
+            }),
+            Err(ChunkError::NotAChunk)
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! Pluggable wire formats. The handshake logic works on `LatencyTest`
+//! values, so nothing but the `Codec` needs to know how they're laid out as
+//! bytes: a JSON-speaking peer, or a future protobuf format, only needs a
+//! new `Codec`.
+
+use crate::{LatencyTest, LatencyTestError, LatencyTestRef};
+
+/// Converts messages to and from a wire format.
+pub trait Codec {
+    fn encode(&self, message: &LatencyTest) -> Vec<u8>;
+    fn decode(&self, bytes: &[u8]) -> Result<LatencyTest, LatencyTestError>;
+
+    /// Decodes like `decode`, borrowing load frames' payloads if the format
+    /// allows it. By default nothing is borrowed: every message is given as
+    /// a `Control`.
+    fn decode_ref<'a>(&self, bytes: &'a [u8]) -> Result<LatencyTestRef<'a>, LatencyTestError> {
+        self.decode(bytes).map(LatencyTestRef::Control)
+    }
+
+    /// Decodes like `decode_ref`, also returning any bytes that follow the
+    /// message in the frame (such as a server signature), which peers should
+    /// echo back.
+    fn decode_with_trailer<'a>(
+        &self,
+        bytes: &'a [u8],
+    ) -> Result<(LatencyTestRef<'a>, &'a [u8]), LatencyTestError> {
+        let message = self.decode_ref(bytes)?;
+        let len = match &message {
+            LatencyTestRef::Control(control) => self.encode(control).len(),
+            payload => payload.wire_len(),
+        };
+        let trailer = bytes.get(len..).ok_or(LatencyTestError::Read)?;
+        Ok((message, trailer))
+    }
+}
+
+/// The native binary format: see `LatencyTest::encode`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BinaryCodec;
+
+impl Codec for BinaryCodec {
+    fn encode(&self, message: &LatencyTest) -> Vec<u8> {
+        message.encode()
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<LatencyTest, LatencyTestError> {
+        LatencyTest::decode(bytes)
+    }
+
+    fn decode_ref<'a>(&self, bytes: &'a [u8]) -> Result<LatencyTestRef<'a>, LatencyTestError> {
+        LatencyTestRef::decode(bytes)
+    }
+
+    fn decode_with_trailer<'a>(
+        &self,
+        bytes: &'a [u8],
+    ) -> Result<(LatencyTestRef<'a>, &'a [u8]), LatencyTestError> {
+        LatencyTestRef::decode_with_trailer(bytes)
+    }
+}
+
+/// Signs server timestamps on encoding, and verifies them on decoding.
+#[cfg(feature = "hmac")]
+impl Codec for crate::TimestampSigner {
+    fn encode(&self, message: &LatencyTest) -> Vec<u8> {
+        self.encode_signed(message)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<LatencyTest, LatencyTestError> {
+        self.decode_verified(bytes)
+    }
+
+    fn decode_ref<'a>(&self, bytes: &'a [u8]) -> Result<LatencyTestRef<'a>, LatencyTestError> {
+        LatencyTestRef::decode_with(bytes, |bytes| self.decode_verified(bytes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handshake::{client_step, ClientStep};
+    use crate::MAGIC_NUMBER;
+
+    /// A trivial alternate format: the binary encoding, as hex text.
+    struct HexCodec;
+
+    impl Codec for HexCodec {
+        fn encode(&self, message: &LatencyTest) -> Vec<u8> {
+            message
+                .encode()
+                .iter()
+                .flat_map(|byte| format!("{byte:02x}").into_bytes())
+                .collect()
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<LatencyTest, LatencyTestError> {
+            let binary = bytes
+                .chunks(2)
+                .map(|pair| {
+                    let pair = std::str::from_utf8(pair).map_err(|_| LatencyTestError::Read)?;
+                    u8::from_str_radix(pair, 16).map_err(|_| LatencyTestError::Read)
+                })
+                .collect::<Result<Vec<u8>, _>>()?;
+            LatencyTest::decode(&binary)
+        }
+    }
+
+    /// Runs a handshake where every frame crosses the wire through `codec`,
+    /// playing the server's part by hand. Returns the client's result.
+    fn handshake(codec: &dyn Codec) -> crate::LatencyResult {
+        let wire = |message: LatencyTest| codec.decode(&codec.encode(&message)).unwrap();
+
+        let request = wire(LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: 0,
+        });
+        assert!(matches!(request, LatencyTest::InitialRequest { .. }));
+        let first_reply = wire(LatencyTest::FirstReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+        });
+        let ClientStep::Reply(response) = client_step(first_reply, 5010) else {
+            panic!("Expected a reply to FirstReply");
+        };
+        let LatencyTest::FirstResponse {
+            server_time,
+            client_time,
+            ..
+        } = wire(response)
+        else {
+            panic!("Expected FirstResponse");
+        };
+        let second_reply = wire(LatencyTest::SecondReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time,
+            client_time,
+            server_ack_time: 1020,
+        });
+        match client_step(second_reply, 5030) {
+            ClientStep::Complete { last, result } => {
+                assert!(matches!(wire(last), LatencyTest::Final { .. }));
+                result
+            }
+            other => panic!("Expected a completed handshake, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn handshake_through_alternate_codec() {
+        let hex = HexCodec.encode(&LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: 0,
+        });
+        assert_eq!(hex, b"be47000300010000000000000000");
+
+        let result = handshake(&HexCodec);
+        assert_eq!(result.latency_ms, 20.0);
+        assert_eq!(result, handshake(&BinaryCodec));
+    }
+
+    #[test]
+    fn only_formats_that_can_borrow_do() {
+        let filler = LatencyTest::Filler {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            bytes: vec![0xAA; 64],
+        };
+        let binary = BinaryCodec.encode(&filler);
+        let borrowed = BinaryCodec.decode_ref(&binary).unwrap();
+        assert!(matches!(borrowed, LatencyTestRef::Filler { .. }));
+        let hex = HexCodec.encode(&filler);
+        assert_eq!(HexCodec.decode_ref(&hex).unwrap(), LatencyTestRef::Control(filler));
+    }
+
+    #[test]
+    fn trailers_follow_the_encoding_in_any_codec() {
+        let reply = LatencyTest::FirstReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+        };
+        let codecs: [&dyn Codec; 2] = [&HexCodec, &BinaryCodec];
+        for codec in codecs {
+            let mut bytes = codec.encode(&reply);
+            bytes.extend(b"abcd");
+            let (decoded, trailer) = codec.decode_with_trailer(&bytes).unwrap();
+            assert_eq!(decoded, LatencyTestRef::Control(reply.clone()));
+            assert_eq!(trailer, b"abcd");
+        }
+    }
+
+    #[test]
+    fn codecs_reject_garbage() {
+        assert!(HexCodec.decode(b"zz").is_err());
+        assert!(BinaryCodec.decode(&[0xFF, 0xFF, 0x00, 0x01]).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+//! Timestamps as dates, enabled with the `chrono` feature. The handshake's
+//! timestamps are milliseconds since the Unix epoch, as `u128`s.
+
+use crate::LatencyTest;
+use chrono::{DateTime, Utc};
+
+/// The UTC date and time `ms` milliseconds after the Unix epoch, or `None`
+/// if that's beyond what `DateTime` can represent.
+pub fn to_datetime(ms: u128) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp_millis(i64::try_from(ms).ok()?)
+}
+
+impl LatencyTest {
+    /// The server's first timestamp as a date, if the message carries one
+    /// and it's in range.
+    pub fn server_datetime(&self) -> Option<DateTime<Utc>> {
+        match self {
+            LatencyTest::FirstReply { server_time, .. }
+            | LatencyTest::FirstResponse { server_time, .. }
+            | LatencyTest::SecondReply { server_time, .. }
+            | LatencyTest::Final { server_time, .. }
+            | LatencyTest::KeepAliveAck { server_time, .. } => to_datetime(*server_time),
+            _ => None,
+        }
+    }
+
+    /// The client's first timestamp as a date, likewise.
+    pub fn client_datetime(&self) -> Option<DateTime<Utc>> {
+        match self {
+            LatencyTest::FirstResponse { client_time, .. }
+            | LatencyTest::SecondReply { client_time, .. }
+            | LatencyTest::Final { client_time, .. }
+            | LatencyTest::KeepAlive { client_time, .. }
+            | LatencyTest::KeepAliveAck { client_time, .. } => to_datetime(*client_time),
+            _ => None,
+        }
+    }
+
+    /// The server's acknowledgement timestamp as a date, likewise.
+    pub fn server_ack_datetime(&self) -> Option<DateTime<Utc>> {
+        match self {
+            LatencyTest::SecondReply {
+                server_ack_time, ..
+            }
+            | LatencyTest::Final {
+                server_ack_time, ..
+            } => to_datetime(*server_ack_time),
+            _ => None,
+        }
+    }
+
+    /// The client's acknowledgement timestamp as a date, likewise.
+    pub fn client_ack_datetime(&self) -> Option<DateTime<Utc>> {
+        match self {
+            LatencyTest::Final {
+                client_ack_time, ..
+            } => to_datetime(*client_ack_time),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MAGIC_NUMBER;
+    use chrono::TimeZone;
+
+    #[test]
+    fn epoch_ms_converts_to_utc() {
+        let expected = Utc.with_ymd_and_hms(2023, 11, 14, 22, 13, 20).unwrap()
+            + chrono::Duration::milliseconds(123);
+        assert_eq!(to_datetime(1_700_000_000_123), Some(expected));
+        assert_eq!(to_datetime(0), Some(DateTime::UNIX_EPOCH));
+        assert_eq!(to_datetime(u128::MAX), None);
+        assert_eq!(to_datetime(i64::MAX as u128), None);
+
+        let last = LatencyTest::Final {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1_700_000_000_123,
+            client_time: 1_700_000_000_130,
+            server_ack_time: 1_700_000_000_150,
+            client_ack_time: u128::MAX,
+        };
+        assert_eq!(last.server_datetime(), Some(expected));
+        assert_eq!(
+            last.server_ack_datetime(),
+            Some(expected + chrono::Duration::milliseconds(27))
+        );
+        assert_eq!(last.client_ack_datetime(), None);
+        let first = LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: 0,
+        };
+        assert_eq!(first.client_datetime(), None);
+    }
+}
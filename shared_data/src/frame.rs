@@ -0,0 +1,137 @@
+//! Framing for stream transports (such as raw TCP), which don't preserve
+//! message boundaries. Each frame is the encoded `LatencyTest`, prefixed with
+//! its length as a big-endian `u32`.
+
+use crate::{LatencyTest, MAX_FRAME_SIZE};
+
+const LENGTH_PREFIX: usize = std::mem::size_of::<u32>();
+
+/// Encodes a message with its length prefix, ready to write to a stream.
+pub fn encode_frame(message: &LatencyTest) -> Vec<u8> {
+    encode_frame_bytes(&message.encode())
+}
+
+/// Prefixes an already encoded message (perhaps with a trailer, such as a
+/// timestamp signature) with its length, ready to write to a stream.
+pub fn encode_frame_bytes(body: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(LENGTH_PREFIX + body.len());
+    buf.extend((body.len() as u32).to_be_bytes());
+    buf.extend(body);
+    buf
+}
+
+/// Reassembles length-prefixed frames from a byte stream, which may deliver
+/// a frame split across any number of reads.
+#[derive(Default)]
+pub struct FrameReader {
+    buffer: Vec<u8>,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds the next bytes read from the stream, returning every frame they
+    /// complete. Any incomplete remainder is kept for the next call. Frames
+    /// that fail to decode are dropped; a length prefix over `MAX_FRAME_SIZE`
+    /// means the stream is corrupt, so everything buffered is discarded.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<LatencyTest> {
+        self.feed_raw(bytes)
+            .iter()
+            .filter_map(|body| LatencyTest::decode(body).ok())
+            .collect()
+    }
+
+    /// Like `feed`, but returns each frame's bytes without decoding them,
+    /// for a receiver that decodes with its own `Codec`.
+    pub fn feed_raw(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut frames = Vec::new();
+        let mut start = 0;
+        while let Some(prefix) = self.buffer.get(start..start + LENGTH_PREFIX) {
+            let len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+            if len > MAX_FRAME_SIZE {
+                self.buffer.clear();
+                return frames;
+            }
+            let body_start = start + LENGTH_PREFIX;
+            let Some(body) = self.buffer.get(body_start..body_start + len) else {
+                break;
+            };
+            frames.push(body.to_vec());
+            start = body_start + len;
+        }
+        self.buffer.drain(..start);
+        frames
+    }
+
+    /// Number of bytes buffered while waiting for the rest of a frame.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MAGIC_NUMBER;
+
+    fn second_reply() -> LatencyTest {
+        LatencyTest::SecondReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 1030,
+            server_ack_time: 1060,
+        }
+    }
+
+    #[test]
+    fn frame_fed_one_byte_at_a_time() {
+        let frame = encode_frame(&second_reply());
+        let mut reader = FrameReader::new();
+        for byte in &frame[..frame.len() - 1] {
+            assert!(reader.feed(&[*byte]).is_empty());
+        }
+        assert_eq!(reader.feed(&frame[frame.len() - 1..]), vec![second_reply()]);
+        assert_eq!(reader.pending(), 0);
+    }
+
+    #[test]
+    fn two_and_a_half_frames() {
+        let initial = LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: 0,
+        };
+        let mut stream = encode_frame(&initial);
+        stream.extend(encode_frame(&second_reply()));
+        let last = encode_frame(&second_reply());
+        let half = last.len() / 2;
+        stream.extend(&last[..half]);
+
+        let mut reader = FrameReader::new();
+        assert_eq!(reader.feed(&stream), vec![initial, second_reply()]);
+        assert_eq!(reader.pending(), half);
+        assert_eq!(reader.feed(&last[half..]), vec![second_reply()]);
+        assert_eq!(reader.pending(), 0);
+    }
+
+    #[test]
+    fn raw_frames_keep_their_trailer() {
+        let mut body = second_reply().encode();
+        body.extend([0xAA; 4]);
+        let mut reader = FrameReader::new();
+        assert_eq!(reader.feed_raw(&encode_frame_bytes(&body)), vec![body]);
+        assert_eq!(reader.pending(), 0);
+    }
+
+    #[test]
+    fn oversized_length_discards_buffer() {
+        let mut reader = FrameReader::new();
+        let bogus = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+        assert!(reader.feed(&bogus).is_empty());
+        assert_eq!(reader.pending(), 0);
+    }
+}
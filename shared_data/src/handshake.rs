@@ -0,0 +1,699 @@
+//! The handshake logic, independent of any transport.
+
+use crate::{LatencyResult, LatencySamples, LatencyTest, MessageKind, MAGIC_NUMBER};
+use thiserror::Error;
+
+/// The handshake's state machine: each `(from, to)` pair means a `to`
+/// message legitimately follows a `from` message. Messages outside the
+/// handshake (chunks, session announcements) can arrive at any time, so
+/// don't appear here.
+const TRANSITIONS: [(MessageKind, MessageKind); 4] = [
+    (MessageKind::InitialRequest, MessageKind::FirstReply),
+    (MessageKind::FirstReply, MessageKind::FirstResponse),
+    (MessageKind::FirstResponse, MessageKind::SecondReply),
+    (MessageKind::SecondReply, MessageKind::Final),
+];
+
+/// The valid handshake transitions, as data, for validators and UIs.
+pub fn valid_transitions() -> &'static [(MessageKind, MessageKind)] {
+    &TRANSITIONS
+}
+
+/// True if a `to` message may follow a `from` message in a handshake.
+pub fn is_valid_transition(from: MessageKind, to: MessageKind) -> bool {
+    TRANSITIONS.contains(&(from, to))
+}
+
+/// Why a captured handshake sequence is invalid. `index` is the position of
+/// the offending frame.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SequenceError {
+    #[error("Frame {index}: {found:?} can't follow {after:?}")]
+    OutOfOrder {
+        index: usize,
+        after: Option<MessageKind>,
+        found: MessageKind,
+    },
+    #[error("Frame {index}: {field} doesn't match the previous frame")]
+    Mismatch { index: usize, field: &'static str },
+    #[error("Frame {index}: {field} is earlier than the time it acknowledges")]
+    Backwards { index: usize, field: &'static str },
+    #[error("Sequence doesn't end with a Final")]
+    MissingFinal,
+}
+
+const TIMESTAMP_FIELDS: [&str; 4] = [
+    "server_time",
+    "client_time",
+    "server_ack_time",
+    "client_ack_time",
+];
+
+/// The timestamps a handshake frame carries, in `TIMESTAMP_FIELDS` order.
+fn timestamps(frame: &LatencyTest) -> [Option<u128>; 4] {
+    match *frame {
+        LatencyTest::FirstReply { server_time, .. } => [Some(server_time), None, None, None],
+        LatencyTest::FirstResponse {
+            server_time,
+            client_time,
+            ..
+        } => [Some(server_time), Some(client_time), None, None],
+        LatencyTest::SecondReply {
+            server_time,
+            client_time,
+            server_ack_time,
+            ..
+        } => [
+            Some(server_time),
+            Some(client_time),
+            Some(server_ack_time),
+            None,
+        ],
+        LatencyTest::Final {
+            server_time,
+            client_time,
+            server_ack_time,
+            client_ack_time,
+            ..
+        } => [
+            Some(server_time),
+            Some(client_time),
+            Some(server_ack_time),
+            Some(client_ack_time),
+        ],
+        _ => [None; 4],
+    }
+}
+
+/// Checks a captured run (or a test fixture): one or more handshakes, each
+/// following `valid_transitions()` from `InitialRequest` to `Final`, with
+/// every frame carrying the previous frame's id and timestamps forward
+/// unchanged, and each acknowledgement no earlier than the time it
+/// acknowledges (on the same clock). Frames outside the handshake, such as
+/// sessions or load filler, are skipped.
+pub fn validate_sequence(frames: &[LatencyTest]) -> Result<(), SequenceError> {
+    let mut previous: Option<&LatencyTest> = None;
+    let handshake_frames = frames.iter().enumerate().filter(|(_, frame)| {
+        TRANSITIONS
+            .iter()
+            .any(|&(from, to)| frame.kind() == from || frame.kind() == to)
+    });
+    for (index, frame) in handshake_frames {
+        let kind = frame.kind();
+        let after = previous.map(LatencyTest::kind);
+        let in_order = match after {
+            None | Some(MessageKind::Final) => kind == MessageKind::InitialRequest,
+            Some(from) => is_valid_transition(from, kind),
+        };
+        if !in_order {
+            return Err(SequenceError::OutOfOrder {
+                index,
+                after,
+                found: kind,
+            });
+        }
+
+        let carried = timestamps(frame);
+        if after != Some(MessageKind::Final) {
+            if previous.is_some_and(|previous| previous.id() != frame.id()) {
+                return Err(SequenceError::Mismatch { index, field: "id" });
+            }
+            let earlier = previous.map_or([None; 4], timestamps);
+            for (field, (earlier, carried)) in
+                TIMESTAMP_FIELDS.iter().zip(earlier.iter().zip(carried))
+            {
+                if earlier.is_some() && *earlier != carried {
+                    return Err(SequenceError::Mismatch { index, field });
+                }
+            }
+        }
+        // Each ack is on the same clock as the time it answers
+        for (ack, time) in [(2, 0), (3, 1)] {
+            if let (Some(ack_time), Some(time)) = (carried[ack], carried[time]) {
+                if ack_time < time {
+                    let field = TIMESTAMP_FIELDS[ack];
+                    return Err(SequenceError::Backwards { index, field });
+                }
+            }
+        }
+        previous = Some(frame);
+    }
+    match previous.map(LatencyTest::kind) {
+        Some(MessageKind::Final) => Ok(()),
+        _ => Err(SequenceError::MissingFinal),
+    }
+}
+
+/// What the client should do with a frame received from the server.
+#[derive(Debug, PartialEq)]
+pub enum ClientStep {
+    /// Send this frame back to the server.
+    Reply(LatencyTest),
+    /// The handshake is complete, yielding the `Final` frame and its result.
+    Complete {
+        last: LatencyTest,
+        result: LatencyResult,
+    },
+    /// The frame isn't part of the client's side of the handshake, or is a
+    /// `SecondReply` acknowledging a time later than its own.
+    Unexpected(LatencyTest),
+}
+
+/// Advances the client's side of the handshake, given a frame from the
+/// server received at `now`. Every server frame carries all the timestamps
+/// gathered so far, so no state is needed between frames: replies can be
+/// processed in any order. Each reply carries the id of the frame it
+/// answers.
+pub fn client_step(frame: LatencyTest, now: u128) -> ClientStep {
+    match frame {
+        LatencyTest::FirstReply {
+            id, server_time, ..
+        } => ClientStep::Reply(LatencyTest::FirstResponse {
+            magic: MAGIC_NUMBER,
+            id,
+            server_time,
+            client_time: now,
+        }),
+        LatencyTest::SecondReply {
+            id,
+            server_time,
+            client_time,
+            server_ack_time,
+            ..
+        } => {
+            let last = LatencyTest::Final {
+                magic: MAGIC_NUMBER,
+                id,
+                server_time,
+                client_time,
+                server_ack_time,
+                client_ack_time: now,
+            };
+            let Ok(result) =
+                LatencyResult::from_timestamps(server_time, client_time, server_ack_time, now)
+            else {
+                return ClientStep::Unexpected(frame);
+            };
+            ClientStep::Complete { last, result }
+        }
+        _ => ClientStep::Unexpected(frame),
+    }
+}
+
+/// The server's side of one connection's handshakes: what it has measured
+/// so far. Driven by `process_frame`, from any transport.
+#[derive(Debug, Default)]
+pub struct HandshakeState {
+    samples: LatencySamples,
+}
+
+impl HandshakeState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The results measured so far, as timed by the server's clock alone
+    /// (see `LatencyTest::calculate_latency_partial`), each when its
+    /// `SecondReply` was sent.
+    pub fn samples(&self) -> &LatencySamples {
+        &self.samples
+    }
+
+    /// Takes the results measured so far, leaving none.
+    pub fn take_samples(&mut self) -> LatencySamples {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+/// Advances the server's side of the handshake, given a frame from the
+/// client, returning the reply to send. `now` is the server's time as the
+/// reply is sent: stamping it any earlier would count time spent queued as
+/// network latency. The reply carries the id of the frame it answers.
+/// Frames that need no reply, or aren't part of the handshake, give `None`.
+pub fn process_frame(
+    state: &mut HandshakeState,
+    frame: LatencyTest,
+    now: u128,
+) -> Option<LatencyTest> {
+    match frame {
+        LatencyTest::InitialRequest { id, .. } => Some(LatencyTest::FirstReply {
+            magic: MAGIC_NUMBER,
+            id,
+            server_time: now,
+        }),
+        LatencyTest::FirstResponse {
+            id,
+            server_time,
+            client_time,
+            ..
+        } => {
+            let reply = LatencyTest::SecondReply {
+                magic: MAGIC_NUMBER,
+                id,
+                server_time,
+                client_time,
+                server_ack_time: now,
+            };
+            if let Some(result) = reply.calculate_latency_partial() {
+                state.samples.push(now, result);
+            }
+            Some(reply)
+        }
+        // Not a measurement, so not recorded
+        LatencyTest::KeepAlive {
+            id, client_time, ..
+        } => Some(LatencyTest::KeepAliveAck {
+            magic: MAGIC_NUMBER,
+            id,
+            client_time,
+            server_time: now,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{reorder_within_window, TestRng};
+
+    #[test]
+    fn client_replies_to_first_reply() {
+        let step = client_step(
+            LatencyTest::FirstReply {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time: 1000,
+            },
+            1030,
+        );
+        assert_eq!(
+            step,
+            ClientStep::Reply(LatencyTest::FirstResponse {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time: 1000,
+                client_time: 1030,
+            })
+        );
+    }
+
+    #[test]
+    fn full_handshake_without_a_socket() {
+        let mut server = HandshakeState::new();
+        let request = LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: 0,
+        };
+        let first_reply = process_frame(&mut server, request, 1000).unwrap();
+        let ClientStep::Reply(response) = client_step(first_reply, 5010) else {
+            panic!("Expected a FirstResponse");
+        };
+        let second_reply = process_frame(&mut server, response, 1020).unwrap();
+        assert_eq!(
+            second_reply,
+            LatencyTest::SecondReply {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time: 1000,
+                client_time: 5010,
+                server_ack_time: 1020,
+            }
+        );
+        let ClientStep::Complete { last, result } = client_step(second_reply, 5030) else {
+            panic!("Expected the handshake to complete");
+        };
+        assert!(matches!(last, LatencyTest::Final { client_ack_time: 5030, .. }));
+        assert_eq!(result.latency_ms, 20.0);
+
+        // The server measured it too, from its own clock
+        let samples = server.take_samples();
+        assert_eq!(samples.len(), 1);
+        let sample = samples.iter().next().unwrap();
+        assert_eq!((sample.timestamp_ms, sample.result.latency_ms), (1020, 20.0));
+        assert!(server.samples().is_empty());
+    }
+
+    #[test]
+    fn server_answers_keepalives_and_ignores_the_rest() {
+        let mut server = HandshakeState::new();
+        let keepalive = LatencyTest::KeepAlive {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            client_time: 7,
+        };
+        assert_eq!(
+            process_frame(&mut server, keepalive, 9),
+            Some(LatencyTest::KeepAliveAck {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                client_time: 7,
+                server_time: 9,
+            })
+        );
+        let busy = LatencyTest::Busy {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            retry_after_ms: 100,
+        };
+        assert_eq!(process_frame(&mut server, busy, 9), None);
+        assert!(server.samples().is_empty());
+    }
+
+    #[test]
+    fn sub_millisecond_round_trip_is_flagged() {
+        let reply = LatencyTest::SecondReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 5000,
+            server_ack_time: 1001,
+        };
+        let ClientStep::Complete { result, .. } = client_step(reply, 5000) else {
+            panic!("Expected the handshake to complete");
+        };
+        assert_eq!(result.client_latency_ms, 0.0);
+        assert!(result.resolution_limited);
+    }
+
+    #[test]
+    fn client_ignores_its_own_frames() {
+        let frame = LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: 0,
+        };
+        assert!(matches!(client_step(frame, 0), ClientStep::Unexpected(_)));
+        // Nor completes a handshake whose clocks ran backwards
+        let backwards = LatencyTest::SecondReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1020,
+            client_time: 5010,
+            server_ack_time: 1000,
+        };
+        assert!(matches!(client_step(backwards, 5030), ClientStep::Unexpected(_)));
+    }
+
+    #[test]
+    fn transitions_follow_the_five_message_handshake() {
+        let handshake = [
+            MessageKind::InitialRequest,
+            MessageKind::FirstReply,
+            MessageKind::FirstResponse,
+            MessageKind::SecondReply,
+            MessageKind::Final,
+        ];
+        let expected: Vec<_> = handshake.windows(2).map(|pair| (pair[0], pair[1])).collect();
+        assert_eq!(valid_transitions(), expected.as_slice());
+
+        assert!(is_valid_transition(MessageKind::FirstReply, MessageKind::FirstResponse));
+        assert!(!is_valid_transition(MessageKind::FirstReply, MessageKind::SecondReply));
+        assert!(!is_valid_transition(MessageKind::Final, MessageKind::InitialRequest));
+    }
+
+    /// A run of `SecondReply` frames with varying latency, each paired with
+    /// the time the client receives it.
+    fn replies() -> Vec<(LatencyTest, u128)> {
+        (0..50u128)
+            .map(|i| {
+                let server_time = 10_000 + i * 1000;
+                let leg = 10 + (i * 7) % 23;
+                let reply = LatencyTest::SecondReply {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    server_time,
+                    client_time: server_time + 5 + leg,
+                    server_ack_time: server_time + 2 * leg,
+                };
+                (reply, server_time + 5 + 2 * leg)
+            })
+            .collect()
+    }
+
+    fn measure(replies: Vec<(LatencyTest, u128)>) -> LatencySamples {
+        let mut samples = LatencySamples::new();
+        for (reply, now) in replies {
+            let LatencyTest::SecondReply {
+                server_time,
+                client_time,
+                server_ack_time,
+                ..
+            } = reply
+            else {
+                unreachable!()
+            };
+            match client_step(reply, now) {
+                ClientStep::Complete { last, result } => {
+                    // Each Final carries the timestamps of the reply that produced it
+                    assert_eq!(
+                        last,
+                        LatencyTest::Final {
+                            magic: MAGIC_NUMBER,
+                            id: 0,
+                            server_time,
+                            client_time,
+                            server_ack_time,
+                            client_ack_time: now,
+                        }
+                    );
+                    samples.push(now, result);
+                }
+                other => panic!("Expected a completed handshake, got {other:?}"),
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn reordered_replies_give_same_stats() {
+        let in_order = measure(replies());
+        let reordered = reorder_within_window(replies(), 5, 42);
+        assert_ne!(reordered, replies());
+        let reordered = measure(reordered);
+
+        assert_eq!(in_order.len(), reordered.len());
+        assert_eq!(in_order.mean_ms(), reordered.mean_ms());
+        assert_eq!(in_order.min_ms(), reordered.min_ms());
+        assert_eq!(in_order.max_ms(), reordered.max_ms());
+    }
+
+    fn handshake(server_time: u128) -> Vec<LatencyTest> {
+        vec![
+            LatencyTest::InitialRequest {
+                magic: MAGIC_NUMBER,
+                id: 0,
+            },
+            LatencyTest::FirstReply {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time,
+            },
+            LatencyTest::FirstResponse {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time,
+                client_time: 5000,
+            },
+            LatencyTest::SecondReply {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time,
+                client_time: 5000,
+                server_ack_time: server_time + 20,
+            },
+            LatencyTest::Final {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time,
+                client_time: 5000,
+                server_ack_time: server_time + 20,
+                client_ack_time: 5020,
+            },
+        ]
+    }
+
+    #[test]
+    fn valid_sequences() {
+        assert_eq!(validate_sequence(&handshake(1000)), Ok(()));
+
+        let mut run = vec![LatencyTest::Session {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            token: 7,
+        }];
+        run.extend(handshake(1000));
+        run.extend(handshake(2000));
+        assert_eq!(validate_sequence(&run), Ok(()));
+    }
+
+    #[test]
+    fn wrong_order_is_rejected() {
+        let mut frames = handshake(1000);
+        frames.swap(2, 3);
+        assert_eq!(
+            validate_sequence(&frames),
+            Err(SequenceError::OutOfOrder {
+                index: 2,
+                after: Some(MessageKind::FirstReply),
+                found: MessageKind::SecondReply,
+            })
+        );
+        assert!(matches!(
+            validate_sequence(&frames[1..]),
+            Err(SequenceError::OutOfOrder {
+                index: 0,
+                after: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn mismatched_timestamps_are_rejected() {
+        let mut frames = handshake(1000);
+        frames[3] = LatencyTest::SecondReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1001,
+            client_time: 5000,
+            server_ack_time: 1020,
+        };
+        assert_eq!(
+            validate_sequence(&frames),
+            Err(SequenceError::Mismatch {
+                index: 3,
+                field: "server_time"
+            })
+        );
+
+        let mut frames = handshake(1000);
+        frames[4] = LatencyTest::Final {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 5000,
+            server_ack_time: 1020,
+            client_ack_time: 4990,
+        };
+        assert_eq!(
+            validate_sequence(&frames),
+            Err(SequenceError::Backwards {
+                index: 4,
+                field: "client_ack_time"
+            })
+        );
+    }
+
+    #[test]
+    fn mismatched_id_is_rejected() {
+        let mut frames = handshake(1000);
+        frames[2] = frames[2].clone().with_id(1);
+        assert_eq!(
+            validate_sequence(&frames),
+            Err(SequenceError::Mismatch {
+                index: 2,
+                field: "id"
+            })
+        );
+        // Each handshake has an id of its own
+        let mut run: Vec<_> = handshake(1000).into_iter().map(|frame| frame.with_id(1)).collect();
+        run.extend(handshake(2000).into_iter().map(|frame| frame.with_id(2)));
+        assert_eq!(validate_sequence(&run), Ok(()));
+    }
+
+    #[test]
+    fn concurrent_handshakes_are_told_apart_by_id() {
+        let mut server = HandshakeState::new();
+        let requests = [1, 2, 3].map(|id| LatencyTest::builder().id(id).build());
+        let first_replies: Vec<_> = requests
+            .into_iter()
+            .enumerate()
+            .map(|(i, request)| process_frame(&mut server, request, 1000 + i as u128).unwrap())
+            .collect();
+        // Answered out of order, each reply still carries its request's id
+        let mut results = Vec::new();
+        for first_reply in first_replies.into_iter().rev() {
+            let ClientStep::Reply(response) = client_step(first_reply, 5000) else {
+                panic!("Expected a FirstResponse");
+            };
+            let second_reply = process_frame(&mut server, response, 1030).unwrap();
+            let ClientStep::Complete { last, result } = client_step(second_reply, 5010) else {
+                panic!("Expected the handshake to complete");
+            };
+            results.push((last.id(), result.server_latency_ms));
+        }
+        assert_eq!(results, [(3, 28.0), (2, 29.0), (1, 30.0)]);
+    }
+
+    #[test]
+    fn missing_final_is_rejected() {
+        let frames = handshake(1000);
+        assert_eq!(
+            validate_sequence(&frames[..4]),
+            Err(SequenceError::MissingFinal)
+        );
+        assert_eq!(validate_sequence(&[]), Err(SequenceError::MissingFinal));
+    }
+
+    /// Sends `frame` across the wire: encoded, then decoded at the far end.
+    fn transmit(frame: &LatencyTest) -> LatencyTest {
+        LatencyTest::decode(&frame.encode()).unwrap()
+    }
+
+    /// The end-to-end check of the latency formula: thousands of random
+    /// handshakes, with known network and processing delays between clocks
+    /// set arbitrarily far apart, run through both sides' logic and the
+    /// codec. Every result must match the delays that were injected. Miri
+    /// is far too slow for thousands, so checks a sample.
+    #[test]
+    fn random_handshakes_measure_the_injected_delays() {
+        let mut rng = TestRng::new(0x5EED);
+        let handshakes = if cfg!(miri) { 50 } else { 5_000 };
+        for _ in 0..handshakes {
+            // Each side's clock, as an offset from true time
+            let server_clock = rng.below(1 << 48) as u128;
+            let client_clock = rng.below(1 << 48) as u128;
+            let start = rng.below(1 << 40) as u128;
+            // Downstream, client turnaround, upstream and server turnaround
+            let [down1, client_turn, up, server_turn, down2] =
+                [500, 20, 500, 20, 500].map(|max| rng.below(max) as u128);
+
+            let mut state = HandshakeState::new();
+            let initial = LatencyTest::InitialRequest {
+                magic: MAGIC_NUMBER,
+                id: 0,
+            };
+            let sent = start;
+            let first_reply = process_frame(&mut state, transmit(&initial), server_clock + sent);
+            let received = sent + down1;
+            let ClientStep::Reply(response) =
+                client_step(transmit(&first_reply.unwrap()), client_clock + received)
+            else {
+                panic!("Expected a FirstResponse");
+            };
+            let sent = received + client_turn + up + server_turn;
+            let second_reply = process_frame(&mut state, transmit(&response), server_clock + sent);
+            let received = sent + down2;
+            let ClientStep::Complete { last, result } =
+                client_step(transmit(&second_reply.unwrap()), client_clock + received)
+            else {
+                panic!("Expected the handshake to complete");
+            };
+
+            let server_leg = (down1 + client_turn + up + server_turn) as f64;
+            let client_leg = (client_turn + up + server_turn + down2) as f64;
+            let latency = (server_leg + client_leg) / 2.0;
+            assert!((result.server_latency_ms - server_leg).abs() < 1e-9, "{result:?}");
+            assert!((result.client_latency_ms - client_leg).abs() < 1e-9, "{result:?}");
+            assert!((result.latency_ms - latency).abs() < 1e-9, "{result:?}");
+            // The client's Final reaches the server intact, and agrees
+            let final_latency = transmit(&last).calculate_latency().unwrap().latency_ms;
+            assert!((final_latency - latency).abs() < 1e-9);
+            let samples: Vec<_> = state.samples().iter().collect();
+            let [sample] = samples[..] else {
+                panic!("Expected one server sample, got {}", samples.len());
+            };
+            assert!(result.agrees_with_server(sample.result.server_latency_ms));
+        }
+    }
+}
@@ -12,233 +12,1146 @@
 //! You now have everything you need to calculate round-trip latency, without trusting
 //! either clock.
 //!
-//! `Latency of M = (server_ack_ts - server_ts) - ((client_ack_ts - client_ts) * 0.5)`
+//! `Latency of M = ((server_ack_ts - server_ts) + (client_ack_ts - client_ts)) * 0.5`
 //!
 //! See [this document](https://ankitbko.github.io/blog/2022/06/websocket-latency/)
+//!
+//! Everything here is meant to be used directly on untrusted bytes from the
+//! network, so public functions aim not to panic on arbitrary input:
+//! truncated or malformed frames, timestamps that run backwards and out of
+//! range parameters give an `Err` (or `None`) rather than a panic or a
+//! wrapped value. The test `no_public_function_panics_on_arbitrary_input`
+//! holds the public surface to that, with random and edge-case inputs; it's
+//! a check, not a proof.
 
-use std::time::{SystemTime, UNIX_EPOCH};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use thiserror::Error;
 
-/// Helper function to get the current time in ms since the UNIX epoch.
-/// This corresponds to JavaScript's `now()` function.
+pub mod analysis;
+mod borrowed;
+mod builder;
+mod capture;
+mod chunk;
+mod codec;
+#[cfg(feature = "chrono")]
+mod datetime;
+mod frame;
+pub mod handshake;
+mod load;
+mod metadata;
+mod protocol_error;
+mod quality;
+#[cfg(feature = "hmac")]
+mod signing;
+mod snapshot;
+mod stats;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub use borrowed::LatencyTestRef;
+pub use builder::{
+    MessageBuilder, WithClientAckTime, WithClientTime, WithServerAckTime, WithServerTime,
+};
+pub use capture::{CaptureReader, CapturedFrame, Direction, FrameCapture, CAPTURE_MAGIC};
+pub use chunk::{chunk_payload, ChunkError, Reassembler, CHUNK_OVERHEAD};
+pub use codec::{BinaryCodec, Codec};
+#[cfg(feature = "chrono")]
+pub use datetime::to_datetime;
+pub use frame::{encode_frame, encode_frame_bytes, FrameReader};
+pub use load::{LoadDirection, FILLER_SIZE, MAX_LOAD_DURATION_MS};
+pub use metadata::{check_metadata, Metadata, MAX_METADATA_BYTES};
+pub use protocol_error::{ErrorCode, MAX_ERROR_DETAIL_BYTES};
+pub use quality::{classify, Quality, QualityThresholds};
+#[cfg(feature = "hmac")]
+pub use signing::{TimestampSigner, SIGNATURE_SIZE};
+pub use snapshot::SNAPSHOT_MAGIC;
+pub use stats::{LatencySample, LatencySamples, LatencySeries, WindowedSamples};
+
+use std::sync::OnceLock;
 #[cfg(not(target_arch = "wasm32"))]
-pub fn unix_now_ms() -> u128 {
-    match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(t) => t.as_millis(),
-        Err(_e) => 0,
-    }
-}
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+#[cfg(target_arch = "wasm32")]
+use web_time::{Instant, SystemTime, UNIX_EPOCH};
 
 /// Helper function to get the current time in ms since the UNIX epoch.
-/// This corresponds to JavaScript's `now()` function. (WASM version)
-#[cfg(target_arch = "wasm32")]
-pub fn unix_now_ms() -> u128 {
-    use web_time::SystemTime;
-    match SystemTime::now().duration_since(web_time::UNIX_EPOCH) {
-        Ok(t) => t.as_millis(),
-        Err(_e) => 0,
-    }
+/// This corresponds to JavaScript's `now()` function. Returns `None` if the
+/// clock is set before the epoch: a zero timestamp would silently corrupt
+/// every latency calculated from it, so callers must deal with a broken
+/// clock themselves.
+pub fn unix_now_ms() -> Option<u128> {
+    ms_since_epoch(SystemTime::now())
+}
+
+/// Milliseconds from a monotonic clock, counted from this function's first
+/// call in the process (so the first call gives 0). On wasm32 this is the
+/// browser's `performance.now()`.
+///
+/// Unlike `unix_now_ms`, it never jumps when the system clock is stepped
+/// (by NTP, say), so it's the better source for timestamps that are only
+/// ever subtracted from others taken by the same process. That includes
+/// each side's timestamps in a handshake: `calculate_latency` only takes
+/// differences between a peer's own times. It means nothing outside the
+/// process, though, so use `unix_now_ms` for times that are stored, shown
+/// as dates, or compared between machines.
+pub fn monotonic_now_ms() -> u128 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis()
+}
+
+fn ms_since_epoch(time: SystemTime) -> Option<u128> {
+    time.duration_since(UNIX_EPOCH).ok().map(|t| t.as_millis())
 }
 
 pub const MAGIC_NUMBER: u16 = 0xBE47;
+
+/// Version of the wire protocol implemented by this crate, sent in every
+/// message's header after the magic number. Bump this whenever the encoded
+/// layout of `LatencyTest` changes: `decode` rejects any other version with
+/// `UnsupportedVersion`, rather than misreading a layout it doesn't know.
+///
+/// - 1: the header was the magic number and tag alone. The report flags,
+///   the campaign id, `ProtocolError` and `Sequenced` were all added while
+///   it was, so version 1 peers may disagree on them; no version 1 peer can
+///   send a version, so none is accepted.
+/// - 2: the version follows the magic number.
+/// - 3: a correlation id follows the tag.
+pub const PROTOCOL_VERSION: u16 = 3;
+
+/// Largest encoded message a peer is expected to send, in bytes.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// The close code a server uses to end a connection the client should
+/// reopen straight away, resuming its session: a WebSocket close code, or
+/// a WebTransport session's error code. (4000-4999 are the WebSocket codes
+/// left for applications.)
+pub const RECONNECT_CLOSE_CODE: u16 = 4000;
+
+/// How far a client's server leg may differ from the server's own (in a
+/// `ReportAck`) before it's flagged. Both are whole milliseconds apart on
+/// the same clock, so a clean run agrees exactly; this allows for rounding.
+pub const REPORT_TOLERANCE_MS: f64 = 1.0;
+
+/// The most decimal places `latency_ms_rounded` rounds to: an `f64` carries
+/// no more than about 15 significant digits.
+pub const MAX_ROUNDED_DECIMALS: u32 = 15;
 const SIZE_U16: usize = std::mem::size_of::<u16>();
-const HEADER_SIZE: usize = SIZE_U16 * 2;
+/// Magic number, protocol version, the message's tag, then its id.
+const HEADER_SIZE: usize = SIZE_U16 * 3 + SIZE_U64;
+const VERSION_OFFSET: usize = SIZE_U16;
+const TAG_OFFSET: usize = SIZE_U16 * 2;
+const ID_OFFSET: usize = SIZE_U16 * 3;
+const SIZE_U32: usize = std::mem::size_of::<u32>();
+const SIZE_U64: usize = std::mem::size_of::<u64>();
 const SIZE_U128: usize = std::mem::size_of::<u128>();
 
-#[derive(Debug, PartialEq)]
+/// Bits of a `Report`'s flags byte.
+const FLAG_APPROXIMATE: u8 = 1;
+const FLAG_RESOLUTION_LIMITED: u8 = 2;
+/// Set if a campaign id follows the flags.
+const FLAG_CAMPAIGN: u8 = 4;
+
+/// How encoded frames are carried over the WebSocket. Some corporate proxies
+/// mangle binary frames but pass text, so frames can optionally be sent as
+/// base64 text instead. The server replies in whichever mode it received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Raw bytes in a binary frame (the default, and the fastest).
+    Binary,
+    /// Base64-encoded bytes in a text frame.
+    Text,
+}
+
+/// A message of the protocol. Every message carries an `id`, chosen by
+/// whoever starts an exchange and echoed unchanged in every reply to it, so
+/// a client can run several handshakes at once and tell their replies
+/// apart. Messages that start no exchange and answer none (sessions, load
+/// filler) use 0.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum LatencyTest {
     InitialRequest {
         magic: u16,
+        id: u64,
     },
     FirstReply {
         magic: u16,
+        id: u64,
         server_time: u128,
     },
     FirstResponse {
         magic: u16,
+        id: u64,
         server_time: u128,
         client_time: u128,
     },
     SecondReply {
         magic: u16,
+        id: u64,
         server_time: u128,
         client_time: u128,
         server_ack_time: u128,
     },
     Final {
         magic: u16,
+        id: u64,
         server_time: u128,
         client_time: u128,
         server_ack_time: u128,
         client_ack_time: u128,
     },
+    /// One piece of a payload too large for a single frame. See
+    /// `chunk_payload` and `Reassembler`.
+    DataChunk {
+        magic: u16,
+        id: u64,
+        seq: u32,
+        total: u32,
+        bytes: Vec<u8>,
+    },
+    /// Sent by the server to identify the client's session. Presenting the
+    /// token when reconnecting resumes the session's history.
+    Session {
+        magic: u16,
+        id: u64,
+        token: u64,
+    },
+    /// Sent by the client after a completed handshake, reporting its result
+    /// and any metadata labelling the run. Metadata should be checked with
+    /// `check_metadata` first: oversized metadata isn't encoded.
+    /// `campaign_id` groups the results of a coordinated measurement across
+    /// many clients; it only costs bytes on the wire when set.
+    Report {
+        magic: u16,
+        id: u64,
+        result: LatencyResult,
+        campaign_id: Option<u64>,
+        metadata: Metadata,
+    },
+    /// Sent by the client to load the link in `direction` for `duration_ms`
+    /// (capped at `MAX_LOAD_DURATION_MS`), while it keeps measuring latency.
+    Load {
+        magic: u16,
+        id: u64,
+        direction: LoadDirection,
+        duration_ms: u32,
+    },
+    /// Bulk data used to load the link. Its contents are meaningless.
+    Filler {
+        magic: u16,
+        id: u64,
+        bytes: Vec<u8>,
+    },
+    /// Sent by the server instead of a reply when it is too busy to handle
+    /// a frame. The client should wait `retry_after_ms` before probing again,
+    /// and not count the probe as lost.
+    Busy {
+        magic: u16,
+        id: u64,
+        retry_after_ms: u32,
+    },
+    /// Sent by the client while the connection is otherwise idle, to keep it
+    /// warm and to notice a dead peer sooner than TCP would. Not part of the
+    /// latency measurement.
+    KeepAlive {
+        magic: u16,
+        id: u64,
+        client_time: u128,
+    },
+    /// The server's answer to a `KeepAlive`, echoing the client's time.
+    KeepAliveAck {
+        magic: u16,
+        id: u64,
+        client_time: u128,
+        server_time: u128,
+    },
+    /// The server's answer to a `Report`, if it echoes them: the server leg
+    /// of the reported handshake as the server measured it, for the client
+    /// to check its own `server_latency_ms` against.
+    ReportAck {
+        magic: u16,
+        id: u64,
+        server_latency_ms: f64,
+    },
+    /// Sent instead of a reply to a frame the peer refused. `code` says why,
+    /// as an `ErrorCode`; it's kept raw, as a newer peer may send codes this
+    /// version doesn't know. `detail` explains it for humans, and is cut to
+    /// `MAX_ERROR_DETAIL_BYTES` when encoded.
+    ProtocolError {
+        magic: u16,
+        id: u64,
+        code: u16,
+        detail: String,
+    },
+    /// A reply from the server, numbered. If the server numbers its replies,
+    /// each is sent wrapped in one of these, with `seq` counting up from 0 on
+    /// each connection, so the client can tell a lost reply (a gap) from a
+    /// late one (a number it skipped earlier). `frame` is the reply's own
+    /// encoding, exactly as it would otherwise have been sent (signature and
+    /// all), and is decoded separately.
+    Sequenced {
+        magic: u16,
+        id: u64,
+        seq: u32,
+        frame: Vec<u8>,
+    },
+    /// A message with a tag this version doesn't recognize, produced only by
+    /// `decode_lenient`. `raw` holds everything after the header, so the
+    /// message can be forwarded unchanged by a proxy.
+    Unknown {
+        magic: u16,
+        id: u64,
+        kind: u16,
+        raw: Vec<u8>,
+    },
+}
+
+/// The kind of a `LatencyTest` message, without its fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    InitialRequest,
+    FirstReply,
+    FirstResponse,
+    SecondReply,
+    Final,
+    DataChunk,
+    Session,
+    Report,
+    Load,
+    Filler,
+    Busy,
+    KeepAlive,
+    KeepAliveAck,
+    ReportAck,
+    ProtocolError,
+    Sequenced,
+    Unknown,
+}
+
+impl MessageKind {
+    /// The kind of message with request number `tag` (the tag after the
+    /// protocol version), or `None` if the tag is unknown. The one table of
+    /// tags decoding goes by.
+    pub fn from_tag(tag: u16) -> Option<Self> {
+        Some(match tag {
+            1 => MessageKind::InitialRequest,
+            2 => MessageKind::FirstReply,
+            3 => MessageKind::FirstResponse,
+            4 => MessageKind::SecondReply,
+            5 => MessageKind::Final,
+            6 => MessageKind::DataChunk,
+            7 => MessageKind::Session,
+            8 => MessageKind::Report,
+            9 => MessageKind::Load,
+            10 => MessageKind::Filler,
+            11 => MessageKind::Busy,
+            12 => MessageKind::KeepAlive,
+            13 => MessageKind::KeepAliveAck,
+            14 => MessageKind::ReportAck,
+            15 => MessageKind::ProtocolError,
+            16 => MessageKind::Sequenced,
+            _ => return None,
+        })
+    }
+
+    /// Frames that time a round trip: the handshake and keepalives. These
+    /// are better lost than late, so a transport that can send them
+    /// unreliably (such as WebTransport datagrams) should.
+    pub fn is_probe(self) -> bool {
+        matches!(
+            self,
+            MessageKind::InitialRequest
+                | MessageKind::FirstReply
+                | MessageKind::FirstResponse
+                | MessageKind::SecondReply
+                | MessageKind::Final
+                | MessageKind::KeepAlive
+                | MessageKind::KeepAliveAck
+        )
+    }
+
+    /// The encoded length of every message of this kind, header included, or
+    /// `None` if it varies with the message's contents.
+    pub fn expected_len(self) -> Option<usize> {
+        let timestamps = match self {
+            MessageKind::InitialRequest => 0,
+            MessageKind::FirstReply => 1,
+            MessageKind::FirstResponse => 2,
+            MessageKind::SecondReply => 3,
+            MessageKind::Final => 4,
+            MessageKind::Session => return Some(HEADER_SIZE + SIZE_U64),
+            MessageKind::Load => return Some(HEADER_SIZE + 1 + SIZE_U32),
+            MessageKind::Busy => return Some(HEADER_SIZE + SIZE_U32),
+            MessageKind::ReportAck => return Some(HEADER_SIZE + SIZE_U64),
+            MessageKind::KeepAlive => 1,
+            MessageKind::KeepAliveAck => 2,
+            MessageKind::DataChunk
+            | MessageKind::Report
+            | MessageKind::Filler
+            | MessageKind::ProtocolError
+            | MessageKind::Sequenced
+            | MessageKind::Unknown => return None,
+        };
+        Some(HEADER_SIZE + SIZE_U128 * timestamps)
+    }
+}
+
+/// The probe bytes one measurement puts on the wire: the encoded sizes of the
+/// five handshake messages, summed (230 bytes).
+///
+/// This counts the protocol alone. Over a WebSocket, each message also has a
+/// frame header: as every handshake message is under 126 bytes, that's 2
+/// bytes from the server and 6 from the client (whose frames are masked), so
+/// 22 more per run. Base64 text frames are a third larger again, and TCP/IP
+/// headers come on top of all of it.
+pub fn run_wire_bytes() -> usize {
+    [
+        MessageKind::InitialRequest,
+        MessageKind::FirstReply,
+        MessageKind::FirstResponse,
+        MessageKind::SecondReply,
+        MessageKind::Final,
+    ]
+    .into_iter()
+    .filter_map(MessageKind::expected_len)
+    .sum()
+}
+
+/// The encoded length of every message with request number `req` (the tag
+/// after the protocol version), header included, as `MessageKind::expected_len`:
+/// `None` if it varies with the message's contents, or `req` is unknown. A
+/// receiver can use it to reject a malformed frame before decoding it.
+pub fn expected_len_for_request(req: u16) -> Option<usize> {
+    MessageKind::from_tag(req)?.expected_len()
 }
 
 impl LatencyTest {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            LatencyTest::InitialRequest { .. } => MessageKind::InitialRequest,
+            LatencyTest::FirstReply { .. } => MessageKind::FirstReply,
+            LatencyTest::FirstResponse { .. } => MessageKind::FirstResponse,
+            LatencyTest::SecondReply { .. } => MessageKind::SecondReply,
+            LatencyTest::Final { .. } => MessageKind::Final,
+            LatencyTest::DataChunk { .. } => MessageKind::DataChunk,
+            LatencyTest::Session { .. } => MessageKind::Session,
+            LatencyTest::Report { .. } => MessageKind::Report,
+            LatencyTest::Load { .. } => MessageKind::Load,
+            LatencyTest::Filler { .. } => MessageKind::Filler,
+            LatencyTest::Busy { .. } => MessageKind::Busy,
+            LatencyTest::KeepAlive { .. } => MessageKind::KeepAlive,
+            LatencyTest::KeepAliveAck { .. } => MessageKind::KeepAliveAck,
+            LatencyTest::ReportAck { .. } => MessageKind::ReportAck,
+            LatencyTest::ProtocolError { .. } => MessageKind::ProtocolError,
+            LatencyTest::Sequenced { .. } => MessageKind::Sequenced,
+            LatencyTest::Unknown { .. } => MessageKind::Unknown,
+        }
+    }
+
+    /// The message's id: see `LatencyTest`.
+    pub fn id(&self) -> u64 {
+        match self {
+            LatencyTest::InitialRequest { id, .. }
+            | LatencyTest::FirstReply { id, .. }
+            | LatencyTest::FirstResponse { id, .. }
+            | LatencyTest::SecondReply { id, .. }
+            | LatencyTest::Final { id, .. }
+            | LatencyTest::DataChunk { id, .. }
+            | LatencyTest::Session { id, .. }
+            | LatencyTest::Report { id, .. }
+            | LatencyTest::Load { id, .. }
+            | LatencyTest::Filler { id, .. }
+            | LatencyTest::Busy { id, .. }
+            | LatencyTest::KeepAlive { id, .. }
+            | LatencyTest::KeepAliveAck { id, .. }
+            | LatencyTest::ReportAck { id, .. }
+            | LatencyTest::ProtocolError { id, .. }
+            | LatencyTest::Sequenced { id, .. }
+            | LatencyTest::Unknown { id, .. } => *id,
+        }
+    }
+
+    /// The message with its id replaced, such as to answer a frame with a
+    /// message built without one.
+    pub fn with_id(mut self, new_id: u64) -> Self {
+        match &mut self {
+            LatencyTest::InitialRequest { id, .. }
+            | LatencyTest::FirstReply { id, .. }
+            | LatencyTest::FirstResponse { id, .. }
+            | LatencyTest::SecondReply { id, .. }
+            | LatencyTest::Final { id, .. }
+            | LatencyTest::DataChunk { id, .. }
+            | LatencyTest::Session { id, .. }
+            | LatencyTest::Report { id, .. }
+            | LatencyTest::Load { id, .. }
+            | LatencyTest::Filler { id, .. }
+            | LatencyTest::Busy { id, .. }
+            | LatencyTest::KeepAlive { id, .. }
+            | LatencyTest::KeepAliveAck { id, .. }
+            | LatencyTest::ReportAck { id, .. }
+            | LatencyTest::ProtocolError { id, .. }
+            | LatencyTest::Sequenced { id, .. }
+            | LatencyTest::Unknown { id, .. } => *id = new_id,
+        }
+        self
+    }
+
+    /// Encodes the message. A `Report`'s metadata is left out if it's over
+    /// `MAX_METADATA_BYTES`; use `try_encode` to refuse it instead.
     pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
+        let mut buf = Vec::with_capacity(self.wire_len());
+        self.encode_into(&mut buf);
+        buf
+    }
 
+    /// As `encode`, but a `Report` whose metadata is over
+    /// `MAX_METADATA_BYTES` is refused with `MetadataTooLarge`.
+    pub fn try_encode(&self) -> Result<Vec<u8>, LatencyTestError> {
+        if let LatencyTest::Report { metadata, .. } = self {
+            check_metadata(metadata)?;
+        }
+        Ok(self.encode())
+    }
+
+    /// The length of the encoded message, header included, without encoding
+    /// it.
+    pub fn wire_len(&self) -> usize {
+        if let Some(len) = self.kind().expected_len() {
+            return len;
+        }
+        let payload = match self {
+            LatencyTest::DataChunk { bytes, .. } => SIZE_U32 * 3 + bytes.len(),
+            LatencyTest::Report {
+                campaign_id,
+                metadata,
+                ..
+            } => {
+                let campaign = campaign_id.map_or(0, |_| SIZE_U64);
+                let metadata = match check_metadata(metadata) {
+                    Ok(()) => metadata::encoded_len(metadata),
+                    Err(_) => SIZE_U16,
+                };
+                SIZE_U64 * 3 + 1 + campaign + metadata
+            }
+            LatencyTest::Filler { bytes, .. } => SIZE_U32 + bytes.len(),
+            LatencyTest::ProtocolError { detail, .. } => {
+                SIZE_U16 * 2 + protocol_error::truncate_detail(detail).len()
+            }
+            LatencyTest::Sequenced { frame, .. } => SIZE_U32 * 2 + frame.len(),
+            LatencyTest::Unknown { raw, .. } => raw.len(),
+            _ => 0,
+        };
+        HEADER_SIZE + payload
+    }
+
+    /// Encodes the message onto the end of `buf`, as `encode`, so a sender
+    /// can reuse one buffer rather than allocate for every frame. Anything
+    /// already in `buf` is kept: clear it first to encode just this message.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
         match self {
-            LatencyTest::InitialRequest { magic } => {
-                buf.extend(magic.to_be_bytes());
-                buf.extend((1u16).to_be_bytes());
+            LatencyTest::InitialRequest { magic, id } => {
+                encode_header(buf, *magic, 1, *id);
             }
-            LatencyTest::FirstReply { magic, server_time } => {
-                buf.extend(magic.to_be_bytes());
-                buf.extend((2u16).to_be_bytes());
+            LatencyTest::FirstReply { magic, id, server_time } => {
+                encode_header(buf, *magic, 2, *id);
                 buf.extend(server_time.to_be_bytes());
             }
             LatencyTest::FirstResponse {
                 magic,
+                id,
                 server_time,
                 client_time,
             } => {
-                buf.extend(magic.to_be_bytes());
-                buf.extend((3u16).to_be_bytes());
+                encode_header(buf, *magic, 3, *id);
                 buf.extend(server_time.to_be_bytes());
                 buf.extend(client_time.to_be_bytes());
             }
             LatencyTest::SecondReply {
                 magic,
+                id,
                 server_time,
                 client_time,
                 server_ack_time,
             } => {
-                buf.extend(magic.to_be_bytes());
-                buf.extend((4u16).to_be_bytes());
+                encode_header(buf, *magic, 4, *id);
                 buf.extend(server_time.to_be_bytes());
                 buf.extend(client_time.to_be_bytes());
                 buf.extend(server_ack_time.to_be_bytes());
             }
             LatencyTest::Final {
                 magic,
+                id,
                 server_time,
                 client_time,
                 server_ack_time,
                 client_ack_time,
             } => {
-                buf.extend(magic.to_be_bytes());
-                buf.extend((5u16).to_be_bytes());
+                encode_header(buf, *magic, 5, *id);
                 buf.extend(server_time.to_be_bytes());
                 buf.extend(client_time.to_be_bytes());
                 buf.extend(server_ack_time.to_be_bytes());
                 buf.extend(client_ack_time.to_be_bytes());
             }
+            LatencyTest::DataChunk {
+                magic,
+                id,
+                seq,
+                total,
+                bytes,
+            } => {
+                encode_header(buf, *magic, 6, *id);
+                buf.extend(seq.to_be_bytes());
+                buf.extend(total.to_be_bytes());
+                buf.extend((bytes.len() as u32).to_be_bytes());
+                buf.extend(bytes);
+            }
+            LatencyTest::Session { magic, id, token } => {
+                encode_header(buf, *magic, 7, *id);
+                buf.extend(token.to_be_bytes());
+            }
+            LatencyTest::Report {
+                magic,
+                id,
+                result,
+                campaign_id,
+                metadata,
+            } => {
+                encode_header(buf, *magic, 8, *id);
+                buf.extend(result.latency_ms.to_be_bytes());
+                buf.extend(result.server_latency_ms.to_be_bytes());
+                buf.extend(result.client_latency_ms.to_be_bytes());
+                let mut flags = 0;
+                if result.approximate {
+                    flags |= FLAG_APPROXIMATE;
+                }
+                if result.resolution_limited {
+                    flags |= FLAG_RESOLUTION_LIMITED;
+                }
+                if campaign_id.is_some() {
+                    flags |= FLAG_CAMPAIGN;
+                }
+                buf.push(flags);
+                if let Some(campaign_id) = campaign_id {
+                    buf.extend(campaign_id.to_be_bytes());
+                }
+                if metadata::encode_metadata(buf, metadata).is_err() {
+                    // Left out rather than sent truncated; `try_encode`
+                    // refuses it instead
+                    buf.extend(0u16.to_be_bytes());
+                }
+            }
+            LatencyTest::Load {
+                magic,
+                id,
+                direction,
+                duration_ms,
+            } => {
+                encode_header(buf, *magic, 9, *id);
+                buf.push(direction.as_u8());
+                buf.extend(duration_ms.to_be_bytes());
+            }
+            LatencyTest::Filler { magic, id, bytes } => {
+                encode_header(buf, *magic, 10, *id);
+                buf.extend((bytes.len() as u32).to_be_bytes());
+                buf.extend(bytes);
+            }
+            LatencyTest::Busy {
+                magic,
+                id,
+                retry_after_ms,
+            } => {
+                encode_header(buf, *magic, 11, *id);
+                buf.extend(retry_after_ms.to_be_bytes());
+            }
+            LatencyTest::KeepAlive { magic, id, client_time } => {
+                encode_header(buf, *magic, 12, *id);
+                buf.extend(client_time.to_be_bytes());
+            }
+            LatencyTest::KeepAliveAck {
+                magic,
+                id,
+                client_time,
+                server_time,
+            } => {
+                encode_header(buf, *magic, 13, *id);
+                buf.extend(client_time.to_be_bytes());
+                buf.extend(server_time.to_be_bytes());
+            }
+            LatencyTest::ReportAck {
+                magic,
+                id,
+                server_latency_ms,
+            } => {
+                encode_header(buf, *magic, 14, *id);
+                buf.extend(server_latency_ms.to_be_bytes());
+            }
+            LatencyTest::ProtocolError {
+                magic,
+                id,
+                code,
+                detail,
+            } => {
+                let detail = protocol_error::truncate_detail(detail);
+                encode_header(buf, *magic, 15, *id);
+                buf.extend(code.to_be_bytes());
+                buf.extend((detail.len() as u16).to_be_bytes());
+                buf.extend(detail.as_bytes());
+            }
+            LatencyTest::Sequenced { magic, id, seq, frame } => {
+                encode_header(buf, *magic, 16, *id);
+                buf.extend(seq.to_be_bytes());
+                buf.extend((frame.len() as u32).to_be_bytes());
+                buf.extend(frame);
+            }
+            LatencyTest::Unknown { magic, id, kind, raw } => {
+                encode_header(buf, *magic, *kind, *id);
+                buf.extend(raw);
+            }
         }
+    }
 
-        buf
+    /// Encodes the message as base64 text, for use with `Transport::Text`.
+    pub fn encode_text(&self) -> String {
+        encode_base64(&self.encode())
+    }
+
+    /// Decodes a message sent with `Transport::Text`.
+    pub fn decode_text(text: &str) -> Result<Self, LatencyTestError> {
+        Self::decode(&decode_base64(text)?)
     }
 
+    /// Decodes a message, also returning any bytes that follow it in the
+    /// frame (such as a server signature), which peers should echo back.
+    pub fn decode_with_trailer(bytes: &[u8]) -> Result<(Self, &[u8]), LatencyTestError> {
+        let message = Self::decode(bytes)?;
+        let trailer = bytes.get(message.wire_len()..).ok_or(LatencyTestError::Read)?;
+        Ok((message, trailer))
+    }
+
+    /// Decodes a message, rejecting unrecognized tags with `BadRequest`.
     pub fn decode(bytes: &[u8]) -> Result<Self, LatencyTestError> {
-        let magic = u16::from_be_bytes(bytes[0..2].try_into().map_err(|_| LatencyTestError::Read)?);
-        if magic != MAGIC_NUMBER {
-            return Err(LatencyTestError::InvalidMagic);
-        }
-
-        let req = u16::from_be_bytes(bytes[2..4].try_into().map_err(|_| LatencyTestError::Read)?);
-        match req {
-            1 => Ok(Self::InitialRequest { magic }),
-            2 => {
-                let server_time = u128::from_be_bytes(
-                    bytes[HEADER_SIZE..HEADER_SIZE + SIZE_U128]
-                        .try_into()
-                        .map_err(|_| LatencyTestError::Read)?,
-                );
-                Ok(Self::FirstReply { magic, server_time })
+        Self::decode_impl(bytes, false)
+    }
+
+    /// Like `decode`, but messages with unrecognized tags (for example, from
+    /// a newer peer) decode as `Unknown` rather than failing. Encoding an
+    /// `Unknown` reproduces the original bytes.
+    pub fn decode_lenient(bytes: &[u8]) -> Result<Self, LatencyTestError> {
+        Self::decode_impl(bytes, true)
+    }
+
+    fn decode_impl(bytes: &[u8], lenient: bool) -> Result<Self, LatencyTestError> {
+        let magic = MAGIC_NUMBER;
+        let (req, id) = decode_header(bytes)?;
+        // The handshake's timestamps, in order, from just after the header
+        let time = |field| read_u128(bytes, HEADER_SIZE + SIZE_U128 * field);
+        match MessageKind::from_tag(req) {
+            Some(MessageKind::InitialRequest) => Ok(Self::InitialRequest { magic, id }),
+            Some(MessageKind::FirstReply) => Ok(Self::FirstReply {
+                magic,
+                id,
+                server_time: time(0)?,
+            }),
+            Some(MessageKind::FirstResponse) => Ok(Self::FirstResponse {
+                magic,
+                id,
+                server_time: time(0)?,
+                client_time: time(1)?,
+            }),
+            Some(MessageKind::SecondReply) => Ok(Self::SecondReply {
+                magic,
+                id,
+                server_time: time(0)?,
+                client_time: time(1)?,
+                server_ack_time: time(2)?,
+            }),
+            Some(MessageKind::Final) => Ok(Self::Final {
+                magic,
+                id,
+                server_time: time(0)?,
+                client_time: time(1)?,
+                server_ack_time: time(2)?,
+                client_ack_time: time(3)?,
+            }),
+            Some(MessageKind::DataChunk) => {
+                let (seq, total, data) = borrowed::chunk_fields(bytes)?;
+                Ok(Self::DataChunk {
+                    magic,
+                    id,
+                    seq,
+                    total,
+                    bytes: data.to_vec(),
+                })
             }
-            3 => {
-                let server_time = u128::from_be_bytes(
-                    bytes[HEADER_SIZE..HEADER_SIZE + SIZE_U128]
-                        .try_into()
-                        .map_err(|_| LatencyTestError::Read)?,
-                );
-                let client_time = u128::from_be_bytes(
-                    bytes[HEADER_SIZE + SIZE_U128..HEADER_SIZE + (SIZE_U128 * 2)]
-                        .try_into()
-                        .map_err(|_| LatencyTestError::Read)?,
-                );
-                Ok(Self::FirstResponse {
+            Some(MessageKind::Session) => Ok(Self::Session {
+                magic,
+                id,
+                token: read_u64(bytes, HEADER_SIZE)?,
+            }),
+            Some(MessageKind::Report) => {
+                let read_f64 = |offset| read_u64(bytes, offset).map(f64::from_bits);
+                let flags = *bytes.get(HEADER_SIZE + SIZE_U64 * 3).ok_or(LatencyTestError::Read)?;
+                let result = LatencyResult {
+                    latency_ms: read_f64(HEADER_SIZE)?,
+                    server_latency_ms: read_f64(HEADER_SIZE + SIZE_U64)?,
+                    client_latency_ms: read_f64(HEADER_SIZE + SIZE_U64 * 2)?,
+                    approximate: flags & FLAG_APPROXIMATE != 0,
+                    resolution_limited: flags & FLAG_RESOLUTION_LIMITED != 0,
+                };
+                let offset = HEADER_SIZE + SIZE_U64 * 3 + 1;
+                let campaign_id = (flags & FLAG_CAMPAIGN != 0)
+                    .then(|| read_u64(bytes, offset))
+                    .transpose()?;
+                let offset = offset + campaign_id.map_or(0, |_| SIZE_U64);
+                let metadata = metadata::decode_metadata(bytes, offset)?;
+                Ok(Self::Report {
                     magic,
-                    server_time,
-                    client_time,
+                    id,
+                    result,
+                    campaign_id,
+                    metadata,
                 })
             }
-            4 => {
-                let server_time = u128::from_be_bytes(
-                    bytes[HEADER_SIZE..HEADER_SIZE + SIZE_U128]
-                        .try_into()
-                        .map_err(|_| LatencyTestError::Read)?,
-                );
-                let client_time = u128::from_be_bytes(
-                    bytes[HEADER_SIZE + SIZE_U128..HEADER_SIZE + (SIZE_U128 * 2)]
-                        .try_into()
-                        .map_err(|_| LatencyTestError::Read)?,
-                );
-                let server_ack_time = u128::from_be_bytes(
-                    bytes[HEADER_SIZE + (SIZE_U128 * 2)..HEADER_SIZE + (SIZE_U128 * 3)]
-                        .try_into()
-                        .map_err(|_| LatencyTestError::Read)?,
-                );
-                Ok(Self::SecondReply {
+            Some(MessageKind::Load) => {
+                let direction = bytes
+                    .get(HEADER_SIZE)
+                    .and_then(|&direction| LoadDirection::from_u8(direction))
+                    .ok_or(LatencyTestError::Read)?;
+                Ok(Self::Load {
                     magic,
-                    server_time,
-                    client_time,
-                    server_ack_time,
+                    id,
+                    direction,
+                    duration_ms: read_u32(bytes, HEADER_SIZE + 1)?,
                 })
             }
-            5 => {
-                let server_time = u128::from_be_bytes(
-                    bytes[HEADER_SIZE..HEADER_SIZE + SIZE_U128]
-                        .try_into()
-                        .map_err(|_| LatencyTestError::Read)?,
-                );
-                let client_time = u128::from_be_bytes(
-                    bytes[HEADER_SIZE + SIZE_U128..HEADER_SIZE + (SIZE_U128 * 2)]
-                        .try_into()
-                        .map_err(|_| LatencyTestError::Read)?,
-                );
-                let server_ack_time = u128::from_be_bytes(
-                    bytes[HEADER_SIZE + (SIZE_U128 * 2)..HEADER_SIZE + (SIZE_U128 * 3)]
-                        .try_into()
-                        .map_err(|_| LatencyTestError::Read)?,
-                );
-                let client_ack_time = u128::from_be_bytes(
-                    bytes[HEADER_SIZE + (SIZE_U128 * 3)..HEADER_SIZE + (SIZE_U128 * 4)]
-                        .try_into()
-                        .map_err(|_| LatencyTestError::Read)?,
-                );
-                Ok(Self::Final {
+            Some(MessageKind::Filler) => Ok(Self::Filler {
+                magic,
+                id,
+                bytes: borrowed::filler_payload(bytes)?.to_vec(),
+            }),
+            Some(MessageKind::Busy) => Ok(Self::Busy {
+                magic,
+                id,
+                retry_after_ms: read_u32(bytes, HEADER_SIZE)?,
+            }),
+            Some(MessageKind::KeepAlive) => Ok(Self::KeepAlive {
+                magic,
+                id,
+                client_time: read_u128(bytes, HEADER_SIZE)?,
+            }),
+            Some(MessageKind::KeepAliveAck) => Ok(Self::KeepAliveAck {
+                magic,
+                id,
+                client_time: read_u128(bytes, HEADER_SIZE)?,
+                server_time: read_u128(bytes, HEADER_SIZE + SIZE_U128)?,
+            }),
+            Some(MessageKind::ReportAck) => Ok(Self::ReportAck {
+                magic,
+                id,
+                server_latency_ms: read_u64(bytes, HEADER_SIZE).map(f64::from_bits)?,
+            }),
+            Some(MessageKind::ProtocolError) => {
+                let code = read_u16(bytes, HEADER_SIZE)?;
+                let len = read_u16(bytes, HEADER_SIZE + SIZE_U16)? as usize;
+                if len > MAX_ERROR_DETAIL_BYTES {
+                    return Err(LatencyTestError::Read);
+                }
+                let offset = HEADER_SIZE + SIZE_U16 * 2;
+                let detail = bytes.get(offset..offset + len).ok_or(LatencyTestError::Read)?;
+                Ok(Self::ProtocolError {
                     magic,
-                    server_time,
-                    client_time,
-                    server_ack_time,
-                    client_ack_time,
+                    id,
+                    code,
+                    detail: String::from_utf8(detail.to_vec()).map_err(|_| LatencyTestError::Read)?,
                 })
             }
+            Some(MessageKind::Sequenced) => {
+                let (seq, frame) = borrowed::sequenced_fields(bytes)?;
+                Ok(Self::Sequenced {
+                    magic,
+                    id,
+                    seq,
+                    frame: frame.to_vec(),
+                })
+            }
+            _ if lenient => Ok(Self::Unknown {
+                magic,
+                id,
+                kind: req,
+                raw: bytes[HEADER_SIZE..].to_vec(),
+            }),
             _ => Err(LatencyTestError::BadRequest),
         }
     }
 
-    pub fn calculate_latency(&self) -> (f64, f64, f64) {
-        match self {
+    /// The result of a completed handshake: see `LatencyResult::from_timestamps`.
+    /// `None` for any variant but `Final`, or if either ack time is before
+    /// the time it acknowledges, as a peer's frame can claim.
+    pub fn calculate_latency(&self) -> Option<LatencyResult> {
+        match *self {
             LatencyTest::Final {
                 server_time,
                 client_time,
                 server_ack_time,
                 client_ack_time,
                 ..
+            } => LatencyResult::from_timestamps(
+                server_time,
+                client_time,
+                server_ack_time,
+                client_ack_time,
+            )
+            .ok(),
+            _ => None,
+        }
+    }
+
+    /// A compact, single-line summary of the message, for logging. Much
+    /// shorter than the `Debug` output, e.g. `Final(lat=12.5ms)`.
+    pub fn short(&self) -> String {
+        match self {
+            LatencyTest::InitialRequest { .. } => "InitialRequest".to_string(),
+            LatencyTest::FirstReply { server_time, .. } => format!("FirstReply(server={server_time})"),
+            LatencyTest::FirstResponse {
+                server_time,
+                client_time,
+                ..
+            } => format!("FirstResponse(server={server_time}, client={client_time})"),
+            LatencyTest::SecondReply {
+                server_time,
+                server_ack_time,
+                ..
+            } => match server_ack_time.checked_sub(*server_time) {
+                Some(leg) => format!("SecondReply(server_leg={leg}ms)"),
+                None => "SecondReply(server_leg=?)".to_string(),
+            },
+            LatencyTest::Final { .. } => match self.latency_ms_rounded(1) {
+                Some(latency) => format!("Final(lat={latency}ms)"),
+                None => "Final(lat=?)".to_string(),
+            },
+            LatencyTest::DataChunk {
+                seq, total, bytes, ..
+            } => format!("DataChunk({}/{total}, {}B)", seq + 1, bytes.len()),
+            LatencyTest::Session { token, .. } => format!("Session(token={token:x})"),
+            LatencyTest::Report {
+                result, metadata, ..
+            } => format!("Report(lat={}ms, {} labels)", result.latency_ms, metadata.len()),
+            LatencyTest::Load {
+                direction,
+                duration_ms,
+                ..
+            } => format!("Load({}, {duration_ms}ms)", direction.name()),
+            LatencyTest::Filler { bytes, .. } => format!("Filler({}B)", bytes.len()),
+            LatencyTest::Busy { retry_after_ms, .. } => format!("Busy(retry={retry_after_ms}ms)"),
+            LatencyTest::KeepAlive { client_time, .. } => format!("KeepAlive(client={client_time})"),
+            LatencyTest::KeepAliveAck {
+                client_time,
+                server_time,
+                ..
+            } => format!("KeepAliveAck(client={client_time}, server={server_time})"),
+            LatencyTest::ReportAck {
+                server_latency_ms, ..
+            } => format!("ReportAck(server_leg={server_latency_ms}ms)"),
+            LatencyTest::ProtocolError { code, detail, .. } => match ErrorCode::from_u16(*code) {
+                Some(code) => format!("ProtocolError({}: {detail})", code.name()),
+                None => format!("ProtocolError(code={code}: {detail})"),
+            },
+            LatencyTest::Sequenced { seq, frame, .. } => {
+                format!("Sequenced(#{seq}, {}B)", frame.len())
+            }
+            LatencyTest::Unknown { kind, raw, .. } => format!("Unknown(kind={kind}, {}B)", raw.len()),
+        }
+    }
+
+    /// The latency from a `Final` message, as computed by `calculate_latency`,
+    /// rounded to `decimals` decimal places for presentation. Values stored
+    /// or aggregated elsewhere stay at full precision; round only for display.
+    /// More than `MAX_ROUNDED_DECIMALS` places, more than an `f64` carries,
+    /// round to that many. Returns `None` for any other variant, or if either
+    /// leg's timestamps run backwards.
+    pub fn latency_ms_rounded(&self, decimals: u32) -> Option<f64> {
+        let result = self.calculate_latency()?;
+        // Exact (up to 10^22) where `powi` need not be, so a value already at
+        // `decimals` places rounds to itself everywhere
+        let scale = (0..decimals.min(MAX_ROUNDED_DECIMALS)).fold(1.0, |scale, _| scale * 10.0);
+        Some((result.latency_ms * scale).round() / scale)
+    }
+
+    /// A `ProtocolError` refusing a frame for `code`'s reason.
+    pub fn protocol_error(code: ErrorCode, detail: impl Into<String>) -> Self {
+        LatencyTest::ProtocolError {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            code: code.as_u16(),
+            detail: detail.into(),
+        }
+    }
+
+    /// Salvages an approximate result from a `SecondReply` when the final
+    /// leg was lost. Only the server leg is measured; the client leg is
+    /// assumed to be symmetric with it, so the result is flagged as
+    /// `approximate`. Returns `None` for any other variant, or if the server
+    /// timestamps run backwards.
+    pub fn calculate_latency_partial(&self) -> Option<LatencyResult> {
+        match self {
+            LatencyTest::SecondReply {
+                server_time,
+                server_ack_time,
+                ..
             } => {
-                let server_latency = (server_ack_time - server_time) as f64;
-                let client_latency = (client_ack_time - client_time) as f64;
-                let latency = (server_latency + client_latency) * 0.5;
-                (latency, server_latency , client_latency)
+                let server_latency = server_ack_time.checked_sub(*server_time)? as f64;
+                Some(LatencyResult {
+                    latency_ms: server_latency,
+                    server_latency_ms: server_latency,
+                    client_latency_ms: server_latency,
+                    approximate: true,
+                    resolution_limited: server_latency == 0.0,
+                })
             }
-            _ => (0., 0., 0.),
+            _ => None,
         }
     }
 }
 
+/// Writes a message header: the magic number, `PROTOCOL_VERSION`, the
+/// message's tag and its id.
+fn encode_header(buf: &mut Vec<u8>, magic: u16, tag: u16, id: u64) {
+    buf.extend(magic.to_be_bytes());
+    buf.extend(PROTOCOL_VERSION.to_be_bytes());
+    buf.extend(tag.to_be_bytes());
+    buf.extend(id.to_be_bytes());
+}
+
+/// Checks a message header's magic number and protocol version, returning
+/// the message's tag and id.
+fn decode_header(bytes: &[u8]) -> Result<(u16, u64), LatencyTestError> {
+    if read_u16(bytes, 0)? != MAGIC_NUMBER {
+        return Err(LatencyTestError::InvalidMagic);
+    }
+    let version = read_u16(bytes, VERSION_OFFSET)?;
+    if version != PROTOCOL_VERSION {
+        return Err(LatencyTestError::UnsupportedVersion(version));
+    }
+    Ok((read_u16(bytes, TAG_OFFSET)?, read_u64(bytes, ID_OFFSET)?))
+}
+
+/// Base64-encodes raw frame bytes, for use with `Transport::Text`.
+pub fn encode_base64(bytes: &[u8]) -> String {
+    BASE64.encode(bytes)
+}
+
+/// Reverses `encode_base64`.
+pub fn decode_base64(text: &str) -> Result<Vec<u8>, LatencyTestError> {
+    BASE64.decode(text).map_err(|_| LatencyTestError::Text)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, LatencyTestError> {
+    bytes
+        .get(offset..offset + SIZE_U16)
+        .and_then(|field| field.try_into().ok())
+        .map(u16::from_be_bytes)
+        .ok_or(LatencyTestError::Read)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, LatencyTestError> {
+    bytes
+        .get(offset..offset + SIZE_U32)
+        .and_then(|field| field.try_into().ok())
+        .map(u32::from_be_bytes)
+        .ok_or(LatencyTestError::Read)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, LatencyTestError> {
+    bytes
+        .get(offset..offset + SIZE_U64)
+        .and_then(|field| field.try_into().ok())
+        .map(u64::from_be_bytes)
+        .ok_or(LatencyTestError::Read)
+}
+
+fn read_u128(bytes: &[u8], offset: usize) -> Result<u128, LatencyTestError> {
+    bytes
+        .get(offset..offset + SIZE_U128)
+        .and_then(|field| field.try_into().ok())
+        .map(u128::from_be_bytes)
+        .ok_or(LatencyTestError::Read)
+}
+
+/// The outcome of a latency calculation, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LatencyResult {
+    /// Estimated round-trip latency.
+    pub latency_ms: f64,
+    /// Round trip as timed by the server's clock.
+    pub server_latency_ms: f64,
+    /// Round trip as timed by the client's clock.
+    pub client_latency_ms: f64,
+    /// True if part of the result was estimated rather than measured.
+    pub approximate: bool,
+    /// True if a round trip took under a millisecond, the resolution of the
+    /// timestamps: it then reads as 0ms, which would otherwise look like a
+    /// failed measurement. The latency is really somewhere below 1ms.
+    pub resolution_limited: bool,
+}
+
+impl LatencyResult {
+    /// The latency from the four timestamps of a completed handshake, as
+    /// `calculate_latency` computes it from a `Final`: each side times its
+    /// own round trip, and the latency is the mean of the two. Fails with
+    /// `Backwards` if either side's ack time is before its send time.
+    pub fn from_timestamps(
+        server_time: u128,
+        client_time: u128,
+        server_ack_time: u128,
+        client_ack_time: u128,
+    ) -> Result<Self, LatencyTestError> {
+        let server_latency = server_ack_time
+            .checked_sub(server_time)
+            .ok_or(LatencyTestError::Backwards)? as f64;
+        let client_latency = client_ack_time
+            .checked_sub(client_time)
+            .ok_or(LatencyTestError::Backwards)? as f64;
+        Ok(Self {
+            latency_ms: (server_latency + client_latency) * 0.5,
+            server_latency_ms: server_latency,
+            client_latency_ms: client_latency,
+            approximate: false,
+            resolution_limited: server_latency == 0.0 || client_latency == 0.0,
+        })
+    }
+
+    /// Column names matching [`LatencyResult::to_csv_row`].
+    pub const CSV_HEADER: &'static str = "latency_ms,server_latency_ms,client_latency_ms,approximate";
+
+    /// Formats the result as one CSV row, without a trailing newline. Every
+    /// field is a number or boolean, so nothing needs quoting.
+    pub fn to_csv_row(&self) -> String {
+        format!(
+            "{},{},{},{}",
+            self.latency_ms, self.server_latency_ms, self.client_latency_ms, self.approximate
+        )
+    }
+
+    /// True if this result's server leg agrees, within `REPORT_TOLERANCE_MS`,
+    /// with the server's own measurement from a `ReportAck`. A mismatch means
+    /// the timestamps were altered in transit, or one side miscalculated.
+    pub fn agrees_with_server(&self, server_latency_ms: f64) -> bool {
+        (self.server_latency_ms - server_latency_ms).abs() <= REPORT_TOLERANCE_MS
+    }
+
+    /// The pure network round trip, without the time the server spent
+    /// handling the client's frame, given that time (if known). The client's
+    /// round trip is the one the server's processing sits inside, so it's
+    /// taken from that; a result finer than the server's own timing can come
+    /// out slightly negative, and is clamped to zero. `None` if the server
+    /// didn't say how long it took.
+    pub fn network_rtt_ms(&self, server_processing_ms: Option<f64>) -> Option<f64> {
+        let processing_ms = server_processing_ms?;
+        Some((self.client_latency_ms - processing_ms).max(0.0))
+    }
+}
+
 #[derive(Error, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum LatencyTestError {
     #[error("Error reading byte data")]
     Read,
@@ -246,16 +1159,37 @@ pub enum LatencyTestError {
     InvalidMagic,
     #[error("Bad request number")]
     BadRequest,
+    #[error("Invalid base64 text frame")]
+    Text,
+    #[error("Timestamp signature missing or invalid")]
+    BadSignature,
+    #[error("Metadata exceeds {MAX_METADATA_BYTES} bytes")]
+    MetadataTooLarge,
+    #[error("Timestamps run backwards")]
+    Backwards,
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(u16),
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    /// The time now, or a fixed time under Miri, which isolates tests from
+    /// the real clock.
+    fn now_ms() -> u128 {
+        if cfg!(miri) {
+            1_700_000_000_000
+        } else {
+            unix_now_ms().unwrap()
+        }
+    }
+
     #[test]
     fn encode_decode_initial() {
         let original = LatencyTest::InitialRequest {
             magic: MAGIC_NUMBER,
+            id: 0,
         };
         let bytes = original.encode();
         let decoded = LatencyTest::decode(&bytes).unwrap();
@@ -266,7 +1200,8 @@ mod test {
     fn encode_decode_first_reply() {
         let original = LatencyTest::FirstReply {
             magic: MAGIC_NUMBER,
-            server_time: unix_now_ms(),
+            id: 0,
+            server_time: now_ms(),
         };
         let bytes = original.encode();
         let decoded = LatencyTest::decode(&bytes).unwrap();
@@ -277,8 +1212,9 @@ mod test {
     fn encode_decode_first_response() {
         let original = LatencyTest::FirstResponse {
             magic: MAGIC_NUMBER,
-            server_time: unix_now_ms(),
-            client_time: unix_now_ms() + 30,
+            id: 0,
+            server_time: now_ms(),
+            client_time: now_ms() + 30,
         };
         let bytes = original.encode();
         let decoded = LatencyTest::decode(&bytes).unwrap();
@@ -289,9 +1225,10 @@ mod test {
     fn encode_decode_second_reply() {
         let original = LatencyTest::SecondReply {
             magic: MAGIC_NUMBER,
-            server_time: unix_now_ms(),
-            client_time: unix_now_ms() + 30,
-            server_ack_time: unix_now_ms() + 60,
+            id: 0,
+            server_time: now_ms(),
+            client_time: now_ms() + 30,
+            server_ack_time: now_ms() + 60,
         };
         let bytes = original.encode();
         let decoded = LatencyTest::decode(&bytes).unwrap();
@@ -302,13 +1239,1071 @@ mod test {
     fn encode_decode_final() {
         let original = LatencyTest::Final {
             magic: MAGIC_NUMBER,
-            server_time: unix_now_ms(),
-            client_time: unix_now_ms() + 30,
-            server_ack_time: unix_now_ms() + 60,
-            client_ack_time: unix_now_ms() + 90,
+            id: 0,
+            server_time: now_ms(),
+            client_time: now_ms() + 30,
+            server_ack_time: now_ms() + 60,
+            client_ack_time: now_ms() + 90,
         };
         let bytes = original.encode();
         let decoded = LatencyTest::decode(&bytes).unwrap();
         assert_eq!(original, decoded);
     }
+
+    fn all_variants() -> Vec<LatencyTest> {
+        vec![
+            LatencyTest::InitialRequest {
+                magic: MAGIC_NUMBER,
+                id: 0,
+            },
+            LatencyTest::FirstReply {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time: 1000,
+            },
+            LatencyTest::FirstResponse {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time: 1000,
+                client_time: 1030,
+            },
+            LatencyTest::SecondReply {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time: 1000,
+                client_time: 1030,
+                server_ack_time: 1060,
+            },
+            LatencyTest::Final {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time: 1000,
+                client_time: 1030,
+                server_ack_time: 1060,
+                client_ack_time: 1090,
+            },
+            LatencyTest::DataChunk {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                seq: 2,
+                total: 3,
+                bytes: vec![1, 2, 3, 4, 5],
+            },
+            LatencyTest::Session {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                token: 0x0123_4567_89AB_CDEF,
+            },
+            LatencyTest::Report {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                result: LatencyResult {
+                    latency_ms: 30.5,
+                    server_latency_ms: 30.0,
+                    client_latency_ms: 31.0,
+                    approximate: false,
+                    resolution_limited: true,
+                },
+                campaign_id: Some(0x0123_4567_89AB_CDEF),
+                metadata: Metadata::from([
+                    ("isp".to_string(), "Example Fiber".to_string()),
+                    ("region".to_string(), "eu-west".to_string()),
+                ]),
+            },
+            LatencyTest::Load {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                direction: LoadDirection::Upload,
+                duration_ms: 5000,
+            },
+            LatencyTest::Filler {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                bytes: vec![0xAA; 64],
+            },
+            LatencyTest::Busy {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                retry_after_ms: 250,
+            },
+            LatencyTest::KeepAlive {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                client_time: 2000,
+            },
+            LatencyTest::KeepAliveAck {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                client_time: 2000,
+                server_time: 5000,
+            },
+            LatencyTest::ReportAck {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_latency_ms: 30.0,
+            },
+            LatencyTest::protocol_error(ErrorCode::Unexpected, "Unexpected Final"),
+            LatencyTest::Sequenced {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                seq: 7,
+                frame: LatencyTest::FirstReply {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    server_time: 1000,
+                }
+                .encode(),
+            },
+        ]
+    }
+
+    #[test]
+    fn layout_is_pinned_to_the_version() {
+        // FNV-1a over every variant's encoding. If this fails, the layout
+        // changed: bump PROTOCOL_VERSION, note the change in its docs, and
+        // pin the new digest against the new version here.
+        let mut digest = 0xcbf2_9ce4_8422_2325_u64;
+        for byte in all_variants().iter().flat_map(LatencyTest::encode) {
+            digest = (digest ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        assert_eq!((PROTOCOL_VERSION, digest), (3, 0x7438_4c6c_ec47_04cd));
+    }
+
+    #[test]
+    fn encode_decode_all_variants() {
+        for original in all_variants() {
+            let bytes = original.encode();
+            let decoded = LatencyTest::decode(&bytes).unwrap();
+            assert_eq!(original, decoded);
+        }
+    }
+
+    #[test]
+    fn id_round_trips_in_every_variant() {
+        for (i, original) in all_variants().into_iter().enumerate() {
+            let id = u64::MAX - i as u64;
+            let message = original.with_id(id);
+            let decoded = LatencyTest::decode(&message.encode()).unwrap();
+            assert_eq!(decoded.id(), id, "{message:?}");
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[test]
+    fn decode_every_request_tag() {
+        // Long enough for any message, with every field zeroed
+        let mut bytes = vec![0; HEADER_SIZE + SIZE_U128 * 4];
+        bytes[..SIZE_U16].copy_from_slice(&MAGIC_NUMBER.to_be_bytes());
+        bytes[VERSION_OFFSET..TAG_OFFSET].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        for tag in 0..=u16::MAX {
+            bytes[TAG_OFFSET..ID_OFFSET].copy_from_slice(&tag.to_be_bytes());
+            match LatencyTest::decode(&bytes) {
+                Ok(message) => {
+                    assert!((1..=16).contains(&tag), "tag {tag} decoded");
+                    assert!(bytes.starts_with(&message.encode()));
+                }
+                Err(e) => {
+                    assert!(!(1..=16).contains(&tag), "tag {tag} failed: {e}");
+                    assert!(matches!(e, LatencyTestError::BadRequest));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_decode_keepalive() {
+        let probe = LatencyTest::KeepAlive {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            client_time: now_ms(),
+        };
+        let bytes = probe.encode();
+        assert_eq!(bytes.len(), HEADER_SIZE + SIZE_U128);
+        assert_eq!(LatencyTest::decode(&bytes).unwrap(), probe);
+
+        let ack = LatencyTest::KeepAliveAck {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            client_time: 2000,
+            server_time: 5000,
+        };
+        let bytes = ack.encode();
+        assert_eq!(LatencyTest::decode(&bytes).unwrap(), ack);
+        assert!(matches!(
+            LatencyTest::decode(&bytes[..bytes.len() - 1]),
+            Err(LatencyTestError::Read)
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn final_round_trips_through_json() {
+        let last = LatencyTest::Final {
+            magic: MAGIC_NUMBER,
+            id: 3,
+            server_time: 1_700_000_000_000,
+            client_time: 1_700_000_005_010,
+            server_ack_time: 1_700_000_000_020,
+            client_ack_time: 1_700_000_005_030,
+        };
+        let json = serde_json::to_string(&last).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Final":{"magic":48711,"id":3,"server_time":1700000000000,"#
+                .to_owned()
+                + r#""client_time":1700000005010,"server_ack_time":1700000000020,"#
+                + r#""client_ack_time":1700000005030}}"#
+        );
+        assert_eq!(serde_json::from_str::<LatencyTest>(&json).unwrap(), last);
+
+        let json = serde_json::to_string(&LatencyTestError::Backwards).unwrap();
+        assert_eq!(json, r#""Backwards""#);
+        let error: LatencyTestError = serde_json::from_str(&json).unwrap();
+        assert!(matches!(error, LatencyTestError::Backwards));
+    }
+
+    #[test]
+    fn wire_len_matches_the_encoding() {
+        for message in all_variants() {
+            let bytes = message.encode();
+            assert_eq!(message.wire_len(), bytes.len(), "{message:?}");
+            let req = u16::from_be_bytes([bytes[TAG_OFFSET], bytes[TAG_OFFSET + 1]]);
+            assert_eq!(expected_len_for_request(req), message.kind().expected_len(), "{req}");
+            if message.kind() != MessageKind::Unknown {
+                assert_eq!(MessageKind::from_tag(req), Some(message.kind()));
+            }
+        }
+        // Encoded truncated, so counted truncated
+        let long = LatencyTest::protocol_error(ErrorCode::BadFrame, "x".repeat(5000));
+        assert_eq!(long.wire_len(), long.encode().len());
+        assert_eq!(expected_len_for_request(5), Some(HEADER_SIZE + SIZE_U128 * 4));
+        assert_eq!(expected_len_for_request(0), None);
+        assert_eq!(expected_len_for_request(17), None);
+    }
+
+    #[test]
+    fn oversized_metadata_is_refused_or_left_out() {
+        let report = |metadata| LatencyTest::Report {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            result: LatencyResult {
+                latency_ms: 30.0,
+                server_latency_ms: 30.0,
+                client_latency_ms: 30.0,
+                approximate: false,
+                resolution_limited: false,
+            },
+            campaign_id: None,
+            metadata,
+        };
+        let oversized = report(Metadata::from([("notes".to_string(), "x".repeat(70_000))]));
+        assert!(matches!(
+            oversized.try_encode(),
+            Err(LatencyTestError::MetadataTooLarge)
+        ));
+        let bytes = oversized.encode();
+        assert_eq!(oversized.wire_len(), bytes.len());
+        assert_eq!(LatencyTest::decode(&bytes).unwrap(), report(Metadata::new()));
+        let fits = report(Metadata::from([("isp".to_string(), "Example".to_string())]));
+        assert_eq!(fits.try_encode().unwrap(), fits.encode());
+    }
+
+    #[test]
+    fn encode_into_appends_to_a_reused_buffer() {
+        let request = LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: 0,
+        };
+        let last = LatencyTest::Final {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 5010,
+            server_ack_time: 1020,
+            client_ack_time: 5030,
+        };
+        let mut buf = Vec::new();
+        request.encode_into(&mut buf);
+        let split = buf.len();
+        last.encode_into(&mut buf);
+        assert_eq!(LatencyTest::decode(&buf[..split]).unwrap(), request);
+        assert_eq!(LatencyTest::decode(&buf[split..]).unwrap(), last);
+        assert_eq!(buf, [request.encode(), last.encode()].concat());
+
+        buf.clear();
+        last.encode_into(&mut buf);
+        assert_eq!(buf, last.encode());
+    }
+
+    #[test]
+    fn every_variant_one_byte_short_is_a_read_error() {
+        for message in all_variants() {
+            let bytes = message.encode();
+            let short = &bytes[..bytes.len() - 1];
+            let read_error = |result: Result<(), _>| matches!(result, Err(LatencyTestError::Read));
+            assert!(read_error(LatencyTest::decode(short).map(drop)), "{message:?}");
+            assert!(read_error(LatencyTest::decode_lenient(short).map(drop)), "{message:?}");
+            assert!(read_error(LatencyTestRef::decode(short).map(drop)), "{message:?}");
+        }
+        // A header cut short, or with nothing after it
+        let final_header = [&[0xBE, 0x47, 0x00, 0x03, 0x00, 0x05][..], &[0; 8]].concat();
+        for bytes in [&final_header[..HEADER_SIZE - 1], &final_header] {
+            assert!(matches!(LatencyTest::decode(bytes), Err(LatencyTestError::Read)));
+        }
+    }
+
+    #[test]
+    fn truncated_data_chunk_is_an_error() {
+        let chunk = LatencyTest::DataChunk {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            seq: 0,
+            total: 1,
+            bytes: vec![7; 16],
+        };
+        let bytes = chunk.encode();
+        assert!(matches!(
+            LatencyTest::decode(&bytes[..bytes.len() - 1]),
+            Err(LatencyTestError::Read)
+        ));
+    }
+
+    #[test]
+    fn partial_latency_matches_symmetric_full() {
+        let second_reply = LatencyTest::SecondReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 1030,
+            server_ack_time: 1060,
+        };
+        let final_result = LatencyTest::Final {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 1030,
+            server_ack_time: 1060,
+            client_ack_time: 1090,
+        };
+        let partial = second_reply.calculate_latency_partial().unwrap();
+        let result = final_result.calculate_latency().unwrap();
+        assert!(partial.approximate);
+        assert_eq!(partial.latency_ms, result.latency_ms);
+        assert_eq!(partial.server_latency_ms, result.server_latency_ms);
+        assert_eq!(partial.client_latency_ms, result.client_latency_ms);
+    }
+
+    #[test]
+    fn partial_latency_only_for_second_reply() {
+        for variant in all_variants() {
+            let partial = variant.calculate_latency_partial();
+            assert_eq!(
+                partial.is_some(),
+                matches!(variant, LatencyTest::SecondReply { .. })
+            );
+        }
+    }
+
+    #[test]
+    fn latency_only_for_final() {
+        let last = LatencyTest::Final {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 5010,
+            server_ack_time: 1020,
+            client_ack_time: 5040,
+        };
+        let result = last.calculate_latency().unwrap();
+        assert_eq!(result.latency_ms, 25.0);
+        assert_eq!(result.server_latency_ms, 20.0);
+        assert_eq!(result.client_latency_ms, 30.0);
+        // Not an indistinguishable zero latency for anything else
+        for variant in all_variants() {
+            let result = variant.calculate_latency();
+            assert_eq!(result.is_some(), matches!(variant, LatencyTest::Final { .. }));
+        }
+    }
+
+    #[test]
+    fn unknown_tag_round_trips_when_lenient() {
+        let mut bytes = Vec::new();
+        bytes.extend(MAGIC_NUMBER.to_be_bytes());
+        bytes.extend(PROTOCOL_VERSION.to_be_bytes());
+        bytes.extend(99u16.to_be_bytes());
+        bytes.extend(7u64.to_be_bytes());
+        bytes.extend([1, 2, 3, 4, 5]);
+
+        assert!(matches!(
+            LatencyTest::decode(&bytes),
+            Err(LatencyTestError::BadRequest)
+        ));
+        let decoded = LatencyTest::decode_lenient(&bytes).unwrap();
+        assert_eq!(
+            decoded,
+            LatencyTest::Unknown {
+                magic: MAGIC_NUMBER,
+                id: 7,
+                kind: 99,
+                raw: vec![1, 2, 3, 4, 5],
+            }
+        );
+        assert_eq!(decoded.encode(), bytes);
+    }
+
+    #[test]
+    fn future_version_is_rejected() {
+        let future = PROTOCOL_VERSION + 1;
+        let unsupported = |result: Result<(), _>| {
+            matches!(result, Err(LatencyTestError::UnsupportedVersion(v)) if v == future)
+        };
+        for original in all_variants() {
+            let mut bytes = original.encode();
+            bytes[VERSION_OFFSET..TAG_OFFSET].copy_from_slice(&future.to_be_bytes());
+            assert!(unsupported(LatencyTest::decode(&bytes).map(drop)), "{original:?}");
+            // Even a lenient decode can't know how a newer layout is framed
+            assert!(unsupported(LatencyTest::decode_lenient(&bytes).map(drop)), "{original:?}");
+            assert!(unsupported(LatencyTestRef::decode(&bytes).map(drop)), "{original:?}");
+        }
+        // A message from before the version was sent has its tag where the
+        // version now is
+        let unversioned = [0xBE, 0x47, 0x00, 0x05, 0x00, 0x00];
+        assert!(matches!(
+            LatencyTest::decode(&unversioned),
+            Err(LatencyTestError::UnsupportedVersion(5))
+        ));
+    }
+
+    #[test]
+    fn lenient_decode_matches_strict_for_known_tags() {
+        for original in all_variants() {
+            let bytes = original.encode();
+            assert_eq!(LatencyTest::decode_lenient(&bytes).unwrap(), original);
+        }
+    }
+
+    #[test]
+    fn short_form() {
+        let second_reply = LatencyTest::SecondReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 1030,
+            server_ack_time: 1060,
+        };
+        assert_eq!(second_reply.short(), "SecondReply(server_leg=60ms)");
+        let final_result = LatencyTest::Final {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 2000,
+            server_ack_time: 1012,
+            client_ack_time: 2013,
+        };
+        assert_eq!(final_result.short(), "Final(lat=12.5ms)");
+        let chunk = LatencyTest::DataChunk {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            seq: 2,
+            total: 10,
+            bytes: vec![0; 512],
+        };
+        assert_eq!(chunk.short(), "DataChunk(3/10, 512B)");
+        let ack = LatencyTest::KeepAliveAck {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            client_time: 2000,
+            server_time: 5000,
+        };
+        assert_eq!(ack.short(), "KeepAliveAck(client=2000, server=5000)");
+    }
+
+    /// The encoding every implementation must match, byte for byte: all
+    /// fields big-endian, in declaration order, after the magic number,
+    /// protocol version, message tag and id. The timestamps are chosen so that every byte differs,
+    /// so a swapped or reordered field can't go unnoticed.
+    #[test]
+    fn encoding_matches_golden_bytes() {
+        const T1: u128 = 0x0001_0203_0405_0607_0809_0a0b_0c0d_0e0f;
+        const T2: u128 = 0x1011_1213_1415_1617_1819_1a1b_1c1d_1e1f;
+        const T3: u128 = 0x2021_2223_2425_2627_2829_2a2b_2c2d_2e2f;
+        const T4: u128 = 0x3031_3233_3435_3637_3839_3a3b_3c3d_3e3f;
+        const HEX_T1: &str = "000102030405060708090a0b0c0d0e0f";
+        const HEX_T2: &str = "101112131415161718191a1b1c1d1e1f";
+        const HEX_T3: &str = "202122232425262728292a2b2c2d2e2f";
+        const HEX_T4: &str = "303132333435363738393a3b3c3d3e3f";
+        const ID: u64 = 0x4041_4243_4445_4647;
+        const HEX_ID: &str = "4041424344454647";
+
+        let golden = [
+            (
+                LatencyTest::InitialRequest {
+                    magic: MAGIC_NUMBER,
+                    id: ID,
+                },
+                format!("be47 0003 0001 {HEX_ID}"),
+            ),
+            (
+                LatencyTest::FirstReply {
+                    magic: MAGIC_NUMBER,
+                    id: ID,
+                    server_time: T1,
+                },
+                format!("be47 0003 0002 {HEX_ID} {HEX_T1}"),
+            ),
+            (
+                LatencyTest::FirstResponse {
+                    magic: MAGIC_NUMBER,
+                    id: ID,
+                    server_time: T1,
+                    client_time: T2,
+                },
+                format!("be47 0003 0003 {HEX_ID} {HEX_T1} {HEX_T2}"),
+            ),
+            (
+                LatencyTest::SecondReply {
+                    magic: MAGIC_NUMBER,
+                    id: ID,
+                    server_time: T1,
+                    client_time: T2,
+                    server_ack_time: T3,
+                },
+                format!("be47 0003 0004 {HEX_ID} {HEX_T1} {HEX_T2} {HEX_T3}"),
+            ),
+            (
+                LatencyTest::Final {
+                    magic: MAGIC_NUMBER,
+                    id: ID,
+                    server_time: T1,
+                    client_time: T2,
+                    server_ack_time: T3,
+                    client_ack_time: T4,
+                },
+                format!("be47 0003 0005 {HEX_ID} {HEX_T1} {HEX_T2} {HEX_T3} {HEX_T4}"),
+            ),
+            (
+                LatencyTest::DataChunk {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    seq: 0x0102_0304,
+                    total: 0x0506_0708,
+                    bytes: vec![0xaa, 0xbb],
+                },
+                // seq, total, then the length of the bytes that follow
+                "be47 0003 0006 0000000000000000 01020304 05060708 00000002 aabb".to_string(),
+            ),
+            (
+                LatencyTest::Session {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    token: 0x0102_0304_0506_0708,
+                },
+                "be47 0003 0007 0000000000000000 0102030405060708".to_string(),
+            ),
+            (
+                LatencyTest::Report {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    result: LatencyResult {
+                        latency_ms: 1.5,
+                        server_latency_ms: 0.5,
+                        client_latency_ms: 1.0,
+                        approximate: true,
+                        resolution_limited: false,
+                    },
+                    campaign_id: None,
+                    metadata: [("k".to_string(), "v".to_string())].into(),
+                },
+                // IEEE 754 doubles, the flags (1 for approximate, 2 for
+                // resolution limited), then the metadata:
+                // its entry count, and each key and value length-prefixed
+                "be47 0003 0008 0000000000000000 \
+                 3ff8000000000000 3fe0000000000000 3ff0000000000000 01 \
+                 0001 0001 6b 0001 76"
+                    .to_string(),
+            ),
+            (
+                LatencyTest::Report {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    result: LatencyResult {
+                        latency_ms: 1.5,
+                        server_latency_ms: 0.5,
+                        client_latency_ms: 1.0,
+                        approximate: false,
+                        resolution_limited: false,
+                    },
+                    campaign_id: Some(0x0102_0304_0506_0708),
+                    metadata: Metadata::new(),
+                },
+                // Flag 4 for a campaign, its id, then no metadata
+                "be47 0003 0008 0000000000000000 \
+                 3ff8000000000000 3fe0000000000000 3ff0000000000000 04 \
+                 0102030405060708 0000"
+                    .to_string(),
+            ),
+            (
+                LatencyTest::Load {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    direction: LoadDirection::Upload,
+                    duration_ms: 0x0102_0304,
+                },
+                "be47 0003 0009 0000000000000000 02 01020304".to_string(),
+            ),
+            (
+                LatencyTest::Filler {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    bytes: vec![0xcc, 0xdd],
+                },
+                "be47 0003 000a 0000000000000000 00000002 ccdd".to_string(),
+            ),
+            (
+                LatencyTest::Busy {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    retry_after_ms: 0x0102_0304,
+                },
+                "be47 0003 000b 0000000000000000 01020304".to_string(),
+            ),
+            (
+                LatencyTest::KeepAlive {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    client_time: T2,
+                },
+                format!("be47 0003 000c 0000000000000000 {HEX_T2}"),
+            ),
+            (
+                LatencyTest::KeepAliveAck {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    client_time: T2,
+                    server_time: T1,
+                },
+                format!("be47 0003 000d 0000000000000000 {HEX_T2} {HEX_T1}"),
+            ),
+            (
+                LatencyTest::ReportAck {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    server_latency_ms: 1.5,
+                },
+                "be47 0003 000e 0000000000000000 3ff8000000000000".to_string(),
+            ),
+            (
+                LatencyTest::protocol_error(ErrorCode::TooLarge, "big"),
+                "be47 0003 000f 0000000000000000 0006 0003 626967".to_string(),
+            ),
+            (
+                LatencyTest::Sequenced {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    seq: 3,
+                    frame: [&[0xbe, 0x47, 0x00, 0x03, 0x00, 0x01][..], &[0; 8]].concat(),
+                },
+                "be47 0003 0010 0000000000000000 00000003 0000000e \
+                 be4700030001 0000000000000000"
+                    .to_string(),
+            ),
+            (
+                LatencyTest::Unknown {
+                    magic: MAGIC_NUMBER,
+                    id: 0,
+                    kind: 0x0102,
+                    raw: vec![0xee, 0xff],
+                },
+                "be47 0003 0102 0000000000000000 eeff".to_string(),
+            ),
+        ];
+
+        for (message, expected) in golden {
+            let expected: String = expected.split_whitespace().collect();
+            let hex: String = message.encode().iter().map(|byte| format!("{byte:02x}")).collect();
+            assert_eq!(hex, expected, "{}", message.short());
+            let bytes: Vec<u8> = (0..expected.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&expected[i..i + 2], 16).unwrap())
+                .collect();
+            assert_eq!(LatencyTest::decode_lenient(&bytes).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn run_wire_bytes_sums_the_handshake() {
+        let scenario = crate::test_util::Scenario {
+            server_time: 1,
+            client_time: 2,
+            server_ack_time: 3,
+            client_ack_time: 4,
+        };
+        let mut total = 0;
+        for message in scenario.messages() {
+            let expected = message.kind().expected_len().unwrap();
+            assert_eq!(message.encode().len(), expected, "{}", message.short());
+            total += expected;
+        }
+        assert_eq!(run_wire_bytes(), total);
+        assert_eq!(run_wire_bytes(), 230);
+    }
+
+    #[test]
+    fn encode_decode_protocol_errors() {
+        for code in [ErrorCode::BadFrame, ErrorCode::Unexpected, ErrorCode::TooLarge] {
+            let error = LatencyTest::protocol_error(code, format!("{} detail", code.name()));
+            let decoded = LatencyTest::decode(&error.encode()).unwrap();
+            assert_eq!(decoded, error);
+            let LatencyTest::ProtocolError { code: raw, .. } = decoded else {
+                panic!("Expected ProtocolError, got {decoded:?}");
+            };
+            assert_eq!(ErrorCode::from_u16(raw), Some(code));
+        }
+
+        // A code from a newer peer still decodes
+        let unknown = LatencyTest::ProtocolError {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            code: 999,
+            detail: String::new(),
+        };
+        assert_eq!(LatencyTest::decode(&unknown.encode()).unwrap(), unknown);
+    }
+
+    #[test]
+    fn protocol_error_detail_is_bounded() {
+        let long = LatencyTest::protocol_error(ErrorCode::BadFrame, "x".repeat(MAX_FRAME_SIZE));
+        let bytes = long.encode();
+        assert_eq!(bytes.len(), HEADER_SIZE + SIZE_U16 * 2 + MAX_ERROR_DETAIL_BYTES);
+        let LatencyTest::ProtocolError { detail, .. } = LatencyTest::decode(&bytes).unwrap() else {
+            panic!("Expected ProtocolError");
+        };
+        assert_eq!(detail, "x".repeat(MAX_ERROR_DETAIL_BYTES));
+
+        // A length over the limit, past the end, or over invalid UTF-8 fails
+        let header = [&[0xbe, 0x47, 0x00, 0x03, 0x00, 0x0f][..], &[0; 8], &[0x00, 0x01]].concat();
+        let oversized = [&header[..], &((MAX_ERROR_DETAIL_BYTES + 1) as u16).to_be_bytes()];
+        let mut bytes = oversized.concat();
+        bytes.resize(bytes.len() + MAX_ERROR_DETAIL_BYTES + 1, b'x');
+        assert!(matches!(LatencyTest::decode(&bytes), Err(LatencyTestError::Read)));
+        let truncated = [&header[..], &[0x00, 0x05], b"abc"].concat();
+        assert!(matches!(LatencyTest::decode(&truncated), Err(LatencyTestError::Read)));
+        let invalid = [&header[..], &[0x00, 0x02], &[0xff, 0xfe]].concat();
+        assert!(matches!(LatencyTest::decode(&invalid), Err(LatencyTestError::Read)));
+    }
+
+    #[test]
+    fn expected_len_matches_fixed_size_messages() {
+        let messages = [
+            LatencyTest::Session {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                token: 7,
+            },
+            LatencyTest::Load {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                direction: LoadDirection::Upload,
+                duration_ms: 100,
+            },
+            LatencyTest::Busy {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                retry_after_ms: 5,
+            },
+            LatencyTest::KeepAlive {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                client_time: 1,
+            },
+            LatencyTest::KeepAliveAck {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                client_time: 1,
+                server_time: 2,
+            },
+            LatencyTest::ReportAck {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_latency_ms: 3.0,
+            },
+        ];
+        for message in messages {
+            assert_eq!(Some(message.encode().len()), message.kind().expected_len());
+        }
+        assert_eq!(MessageKind::Filler.expected_len(), None);
+    }
+
+    #[test]
+    fn clock_before_epoch_is_not_zero() {
+        let before = UNIX_EPOCH - std::time::Duration::from_millis(1);
+        assert_eq!(ms_since_epoch(before), None);
+        assert_eq!(ms_since_epoch(UNIX_EPOCH + std::time::Duration::from_millis(1500)), Some(1500));
+        if !cfg!(miri) {
+            assert!(unix_now_ms().is_some_and(|now| now > 0));
+        }
+    }
+
+    #[test]
+    fn monotonic_clock_never_goes_backwards() {
+        let mut last = monotonic_now_ms();
+        for _ in 0..1000 {
+            let now = monotonic_now_ms();
+            assert!(now >= last, "{now} after {last}");
+            last = now;
+        }
+    }
+
+    #[test]
+    fn latency_from_timestamps_matches_final() {
+        for (server_time, client_time, server_ack_time, client_ack_time) in [
+            (1000, 2000, 1003, 2004),
+            (1000, 2000, 1012, 2013),
+            (5, 5, 5, 5),
+            (0, u128::MAX - 10, 7, u128::MAX),
+        ] {
+            let final_result = LatencyTest::Final {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time,
+                client_time,
+                server_ack_time,
+                client_ack_time,
+            };
+            let result = LatencyResult::from_timestamps(
+                server_time,
+                client_time,
+                server_ack_time,
+                client_ack_time,
+            )
+            .unwrap();
+            assert_eq!(final_result.calculate_latency(), Some(result));
+            assert!(!result.approximate);
+        }
+    }
+
+    #[test]
+    fn same_millisecond_timestamps_are_resolution_limited() {
+        // The server's reply left in the millisecond it first replied in
+        let result = LatencyResult::from_timestamps(1000, 5000, 1000, 5002).unwrap();
+        assert_eq!(result.server_latency_ms, 0.0);
+        assert!(result.resolution_limited);
+        let result = LatencyResult::from_timestamps(1000, 5000, 1001, 5001).unwrap();
+        assert!(!result.resolution_limited);
+
+        let reply = LatencyTest::SecondReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 5000,
+            server_ack_time: 1000,
+        };
+        assert!(reply.calculate_latency_partial().unwrap().resolution_limited);
+    }
+
+    #[test]
+    fn latency_from_backwards_timestamps_fails() {
+        assert!(matches!(
+            LatencyResult::from_timestamps(1003, 2000, 1000, 2004),
+            Err(LatencyTestError::Backwards)
+        ));
+        assert!(matches!(
+            LatencyResult::from_timestamps(1000, 2004, 1003, 2000),
+            Err(LatencyTestError::Backwards)
+        ));
+        // Rejected, not a wrapped or saturated latency
+        for (server_time, client_time, server_ack_time, client_ack_time) in
+            [(1003, 2000, 1000, 2004), (1000, 2004, 1003, 2000), (u128::MAX, 0, 0, 1)]
+        {
+            let last = LatencyTest::Final {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time,
+                client_time,
+                server_ack_time,
+                client_ack_time,
+            };
+            assert_eq!(last.calculate_latency(), None);
+            assert_eq!(last.latency_ms_rounded(1), None);
+        }
+        let reply = LatencyTest::SecondReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1003,
+            client_time: 2000,
+            server_ack_time: 1000,
+        };
+        assert_eq!(reply.calculate_latency_partial(), None);
+    }
+
+    #[test]
+    fn rounded_latency() {
+        let final_result = LatencyTest::Final {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 2000,
+            server_ack_time: 1003,
+            client_ack_time: 2004,
+        };
+        assert_eq!(final_result.calculate_latency().unwrap().latency_ms, 3.5);
+        assert_eq!(final_result.latency_ms_rounded(0), Some(4.0));
+        assert_eq!(final_result.latency_ms_rounded(1), Some(3.5));
+        assert_eq!(final_result.latency_ms_rounded(3), Some(3.5));
+
+        let final_result = LatencyTest::Final {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 2000,
+            server_ack_time: 1012,
+            client_ack_time: 2013,
+        };
+        assert_eq!(final_result.latency_ms_rounded(0), Some(13.0));
+        assert_eq!(final_result.latency_ms_rounded(1), Some(12.5));
+        // Clamped, rather than scaling by infinity (or counting to u32::MAX)
+        assert_eq!(final_result.latency_ms_rounded(u32::MAX), Some(12.5));
+        assert_eq!(
+            LatencyTest::InitialRequest { magic: MAGIC_NUMBER, id: 0 }.latency_ms_rounded(1),
+            None
+        );
+    }
+
+    #[test]
+    fn encode_decode_text() {
+        for original in all_variants() {
+            let text = original.encode_text();
+            let decoded = LatencyTest::decode_text(&text).unwrap();
+            assert_eq!(original, decoded);
+        }
+    }
+
+    #[test]
+    fn network_rtt_excludes_server_processing() {
+        // The server held the client's frame for 3ms of its 20ms round trip
+        let result = LatencyResult::from_timestamps(1000, 5000, 1018, 5020).unwrap();
+        assert_eq!(result.latency_ms, 19.0);
+        assert_eq!(result.network_rtt_ms(Some(3.0)), Some(17.0));
+        assert_eq!(result.network_rtt_ms(Some(0.0)), Some(20.0));
+        // Processing longer than a coarse round trip doesn't go negative
+        assert_eq!(result.network_rtt_ms(Some(25.0)), Some(0.0));
+        assert_eq!(result.network_rtt_ms(None), None);
+    }
+
+    #[test]
+    fn text_carries_binary_encoding() {
+        for original in all_variants() {
+            let text = original.encode_text();
+            assert_eq!(BASE64.decode(text).unwrap(), original.encode());
+        }
+    }
+
+    #[test]
+    fn decode_with_trailer_splits_trailing_bytes() {
+        for original in all_variants() {
+            let mut bytes = original.encode();
+            bytes.extend([9, 8, 7]);
+            let (decoded, trailer) = LatencyTest::decode_with_trailer(&bytes).unwrap();
+            assert_eq!(decoded, original);
+            assert_eq!(trailer, &[9, 8, 7]);
+        }
+    }
+
+    #[test]
+    fn decode_text_rejects_invalid_base64() {
+        assert!(matches!(
+            LatencyTest::decode_text("not base64!"),
+            Err(LatencyTestError::Text)
+        ));
+    }
+
+    #[test]
+    fn no_public_function_panics_on_arbitrary_input() {
+        use crate::test_util::TestRng;
+        let mut rng = TestRng::new(0xBAD_F00D);
+        let mut inputs: Vec<Vec<u8>> = Vec::new();
+        // Every variant, cut short at every length, and with bytes to spare
+        for message in all_variants() {
+            let bytes = message.encode();
+            inputs.extend((0..bytes.len()).map(|len| bytes[..len].to_vec()));
+            inputs.push([&bytes[..], &[0xFF; 40]].concat());
+        }
+        // Noise, mostly behind a valid header so it reaches every tag
+        let rounds = if cfg!(miri) { 100 } else { 10_000 };
+        for _ in 0..rounds {
+            let len = rng.below(160) as usize;
+            let mut bytes: Vec<u8> = (0..len).map(|_| rng.next_u64() as u8).collect();
+            if len >= HEADER_SIZE && rng.below(4) != 0 {
+                bytes[..SIZE_U16].copy_from_slice(&MAGIC_NUMBER.to_be_bytes());
+                bytes[VERSION_OFFSET..TAG_OFFSET].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+                let tag = rng.below(20) as u16;
+                bytes[TAG_OFFSET..ID_OFFSET].copy_from_slice(&tag.to_be_bytes());
+            }
+            inputs.push(bytes);
+        }
+
+        let mut frames = FrameReader::new();
+        let mut state = handshake::HandshakeState::new();
+        let mut decoded = Vec::new();
+        for bytes in &inputs {
+            let _ = LatencyTest::decode_lenient(bytes);
+            let _ = LatencyTest::decode_with_trailer(bytes);
+            let _ = LatencyTestRef::decode(bytes).map(|frame| (frame.kind(), frame.to_owned()));
+            let _ = LatencyTest::decode_text(&String::from_utf8_lossy(bytes));
+            let _ = LatencyTest::decode_text(&encode_base64(bytes));
+            let _ = frames.feed(bytes);
+            let _ = CaptureReader::new(bytes.as_slice()).map(|reader| reader.count());
+            let _ = LatencySamples::read_snapshot(bytes.as_slice());
+            let Ok(message) = LatencyTest::decode(bytes) else {
+                continue;
+            };
+            let _ = message.calculate_latency();
+            let _ = message.calculate_latency_partial();
+            let _ = message.latency_ms_rounded(rng.next_u64() as u32);
+            let _ = message.latency_ms_rounded(u32::MAX);
+            let _ = message.short();
+            let _ = analysis::one_way_delay(&message, f64::from_bits(rng.next_u64()));
+            let _ = handshake::client_step(message.clone(), rng.next_u64() as u128);
+            let _ = handshake::process_frame(&mut state, message.clone(), rng.next_u64() as u128);
+            decoded.push(message);
+        }
+        let _ = handshake::validate_sequence(&decoded);
+        let _ = analysis::drift_corrected_latency(&decoded);
+
+        // Arbitrary numbers, NaN and infinities included
+        let mut number = || f64::from_bits(rng.next_u64());
+        let mut samples = LatencySamples::new();
+        for timestamp_ms in 0..100 {
+            let (latency_ms, server_latency_ms, client_latency_ms) = (number(), number(), number());
+            samples.push(
+                timestamp_ms,
+                LatencyResult {
+                    latency_ms,
+                    server_latency_ms,
+                    client_latency_ms,
+                    approximate: false,
+                    resolution_limited: false,
+                },
+            );
+        }
+        for target in 0..10 {
+            let _ = samples.downsample(target);
+        }
+        let _ = samples.percentile_ms(number());
+        let _ = samples.recommended_buffer_ms(number());
+        let _ = samples.bufferbloat();
+        let _ = samples.rfc3550_jitter();
+        let _ = samples.jitter_ms();
+        let _ = samples.stddev_ms();
+        let _ = analysis::compare(&samples, &LatencySamples::new(), number());
+        let points: Vec<(f64, f64)> = (0..10).map(|_| (number(), number())).collect();
+        let _ = analysis::estimate_saturation(&points);
+        let _ = analysis::required_samples(number(), number(), number());
+        for edge in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY, f64::MAX, f64::MIN_POSITIVE] {
+            let _ = samples.percentile_ms(edge);
+            let _ = samples.recommended_buffer_ms(edge);
+            let _ = analysis::required_samples(edge, edge, 0.95);
+            let _ = analysis::required_samples(1.0, edge, 0.95);
+        }
+    }
 }
@@ -0,0 +1,73 @@
+//! Loading the link while measuring latency. Latency on an idle link says
+//! little about how it behaves in use: once a transfer fills the buffers
+//! along the path (bufferbloat), latency can climb by orders of magnitude,
+//! and often differently for each direction.
+
+/// Which way the link is being loaded while latency is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LoadDirection {
+    /// No deliberate load.
+    Idle,
+    /// The server streams `Filler` frames to the client.
+    Download,
+    /// The client streams `Filler` frames to the server.
+    Upload,
+}
+
+/// Longest load a client may request, in milliseconds.
+pub const MAX_LOAD_DURATION_MS: u32 = 30_000;
+
+/// Size of the payload in each `Filler` frame.
+pub const FILLER_SIZE: usize = 32 * 1024;
+
+impl LoadDirection {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Idle),
+            1 => Some(Self::Download),
+            2 => Some(Self::Upload),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Self::Idle => 0,
+            Self::Download => 1,
+            Self::Upload => 2,
+        }
+    }
+
+    /// Looks up a direction by its (case-insensitive) name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "idle" => Some(Self::Idle),
+            "download" => Some(Self::Download),
+            "upload" => Some(Self::Upload),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Idle => "idle",
+            Self::Download => "download",
+            Self::Upload => "upload",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn direction_round_trips() {
+        for direction in [LoadDirection::Idle, LoadDirection::Download, LoadDirection::Upload] {
+            assert_eq!(LoadDirection::from_u8(direction.as_u8()), Some(direction));
+            assert_eq!(LoadDirection::from_name(direction.name()), Some(direction));
+        }
+        assert_eq!(LoadDirection::from_u8(3), None);
+    }
+}
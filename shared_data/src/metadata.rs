@@ -0,0 +1,140 @@
+//! Free-form client metadata (ISP, region, device...) attached to reported
+//! results. Encoded as a `u16` entry count, then for each entry a `u16`
+//! length-prefixed UTF-8 key and value.
+
+use crate::{LatencyTestError, SIZE_U16};
+use std::collections::BTreeMap;
+
+/// Client-supplied labels for a run.
+pub type Metadata = BTreeMap<String, String>;
+
+/// Upper bound on the total length of all keys and values, in bytes.
+pub const MAX_METADATA_BYTES: usize = 1024;
+
+/// Checks that metadata is within `MAX_METADATA_BYTES`.
+pub fn check_metadata(metadata: &Metadata) -> Result<(), LatencyTestError> {
+    let size: usize = metadata.iter().map(|(key, value)| key.len() + value.len()).sum();
+    if size > MAX_METADATA_BYTES {
+        return Err(LatencyTestError::MetadataTooLarge);
+    }
+    Ok(())
+}
+
+/// Encodes `metadata`, or refuses it with `MetadataTooLarge`, writing
+/// nothing, if it's over `MAX_METADATA_BYTES`. Within the limit, every count
+/// and length fits the `u16` it's encoded as.
+pub(crate) fn encode_metadata(
+    buf: &mut Vec<u8>,
+    metadata: &Metadata,
+) -> Result<(), LatencyTestError> {
+    check_metadata(metadata)?;
+    let as_u16 = |len: usize| u16::try_from(len).map_err(|_| LatencyTestError::MetadataTooLarge);
+    buf.extend(as_u16(metadata.len())?.to_be_bytes());
+    for (key, value) in metadata {
+        for field in [key, value] {
+            buf.extend(as_u16(field.len())?.to_be_bytes());
+            buf.extend(field.as_bytes());
+        }
+    }
+    Ok(())
+}
+
+/// The bytes `encode_metadata` writes for `metadata`, if it's within the
+/// limit.
+pub(crate) fn encoded_len(metadata: &Metadata) -> usize {
+    let fields: usize = metadata.iter().map(|(key, value)| key.len() + value.len()).sum();
+    SIZE_U16 * (1 + metadata.len() * 2) + fields
+}
+
+fn read_string(bytes: &[u8], offset: &mut usize) -> Result<String, LatencyTestError> {
+    let len = read_u16(bytes, *offset)? as usize;
+    *offset += SIZE_U16;
+    let field = bytes.get(*offset..*offset + len).ok_or(LatencyTestError::Read)?;
+    *offset += len;
+    String::from_utf8(field.to_vec()).map_err(|_| LatencyTestError::Read)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, LatencyTestError> {
+    bytes
+        .get(offset..offset + SIZE_U16)
+        .and_then(|field| field.try_into().ok())
+        .map(u16::from_be_bytes)
+        .ok_or(LatencyTestError::Read)
+}
+
+/// Decodes metadata starting at `offset`, rejecting it if oversized.
+pub(crate) fn decode_metadata(bytes: &[u8], offset: usize) -> Result<Metadata, LatencyTestError> {
+    let count = read_u16(bytes, offset)?;
+    let mut offset = offset + SIZE_U16;
+    let mut metadata = Metadata::new();
+    let mut size = 0;
+    for _ in 0..count {
+        let key = read_string(bytes, &mut offset)?;
+        let value = read_string(bytes, &mut offset)?;
+        size += key.len() + value.len();
+        if size > MAX_METADATA_BYTES {
+            return Err(LatencyTestError::MetadataTooLarge);
+        }
+        metadata.insert(key, value);
+    }
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode_metadata() {
+        let metadata = Metadata::from([
+            ("isp".to_string(), "Example Fiber".to_string()),
+            ("region".to_string(), "eu-west".to_string()),
+            ("device".to_string(), String::new()),
+        ]);
+        let mut buf = vec![0xFF];
+        encode_metadata(&mut buf, &metadata).unwrap();
+        assert_eq!(decode_metadata(&buf, 1).unwrap(), metadata);
+        assert!(matches!(
+            decode_metadata(&buf[..buf.len() - 1], 1),
+            Err(LatencyTestError::Read)
+        ));
+    }
+
+    #[test]
+    fn metadata_too_long_for_its_lengths_is_refused() {
+        // Over u16::MAX, a length encoded as a u16 would wrap
+        let metadata = Metadata::from([("notes".to_string(), "x".repeat(70_000))]);
+        let mut buf = Vec::new();
+        assert!(matches!(
+            encode_metadata(&mut buf, &metadata),
+            Err(LatencyTestError::MetadataTooLarge)
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn oversized_metadata_is_rejected() {
+        let metadata = Metadata::from([("notes".to_string(), "x".repeat(MAX_METADATA_BYTES))]);
+        assert!(matches!(
+            check_metadata(&metadata),
+            Err(LatencyTestError::MetadataTooLarge)
+        ));
+        let mut buf = Vec::new();
+        assert!(matches!(
+            encode_metadata(&mut buf, &metadata),
+            Err(LatencyTestError::MetadataTooLarge)
+        ));
+        assert!(buf.is_empty());
+
+        // As a peer that doesn't check might send it
+        let value = "x".repeat(MAX_METADATA_BYTES);
+        let mut buf = vec![0, 1, 0, 5];
+        buf.extend(b"notes");
+        buf.extend((value.len() as u16).to_be_bytes());
+        buf.extend(value.as_bytes());
+        assert!(matches!(
+            decode_metadata(&buf, 0),
+            Err(LatencyTestError::MetadataTooLarge)
+        ));
+    }
+}
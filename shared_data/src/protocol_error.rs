@@ -0,0 +1,86 @@
+//! Reasons a peer refused a frame, as carried by `LatencyTest::ProtocolError`.
+
+/// Longest `ProtocolError` detail, in bytes. Longer details are truncated
+/// when encoded, keeping every error frame far below `MAX_FRAME_SIZE`.
+pub const MAX_ERROR_DETAIL_BYTES: usize = 1024;
+
+/// Why a frame was refused, so a client can react to each reason. Codes are
+/// stable on the wire; a peer may receive codes it doesn't know (from a
+/// newer peer), so the message keeps the raw `u16`. Codes 3 to 5 are
+/// retired, and never reused: they named reasons (ordering, rate limits,
+/// overload) no server sent. An overloaded server answers with `Busy`,
+/// which also says when to retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// The frame couldn't be decoded.
+    BadFrame,
+    /// The frame decoded, but isn't one the peer accepts.
+    Unexpected,
+    /// The frame is over `MAX_FRAME_SIZE`.
+    TooLarge,
+}
+
+impl ErrorCode {
+    pub fn from_u16(value: u16) -> Option<Self> {
+        match value {
+            1 => Some(Self::BadFrame),
+            2 => Some(Self::Unexpected),
+            6 => Some(Self::TooLarge),
+            _ => None,
+        }
+    }
+
+    pub fn as_u16(self) -> u16 {
+        match self {
+            Self::BadFrame => 1,
+            Self::Unexpected => 2,
+            Self::TooLarge => 6,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::BadFrame => "bad_frame",
+            Self::Unexpected => "unexpected",
+            Self::TooLarge => "too_large",
+        }
+    }
+}
+
+/// `detail`, cut to at most `MAX_ERROR_DETAIL_BYTES` on a character boundary.
+pub(crate) fn truncate_detail(detail: &str) -> &str {
+    if detail.len() <= MAX_ERROR_DETAIL_BYTES {
+        return detail;
+    }
+    let mut end = MAX_ERROR_DETAIL_BYTES;
+    while !detail.is_char_boundary(end) {
+        end -= 1;
+    }
+    &detail[..end]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn codes_round_trip() {
+        for value in 0..=u16::MAX {
+            if let Some(code) = ErrorCode::from_u16(value) {
+                assert_eq!(code.as_u16(), value);
+            } else {
+                assert!(![1, 2, 6].contains(&value), "{value} has no code");
+            }
+        }
+    }
+
+    #[test]
+    fn long_details_are_truncated_on_a_char_boundary() {
+        assert_eq!(truncate_detail("short"), "short");
+        // Three bytes per character, so the limit falls mid-character
+        let detail = "€".repeat(MAX_ERROR_DETAIL_BYTES);
+        let truncated = truncate_detail(&detail);
+        assert_eq!(truncated.len(), MAX_ERROR_DETAIL_BYTES / 3 * 3);
+        assert!(detail.starts_with(truncated));
+    }
+}
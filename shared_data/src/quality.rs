@@ -0,0 +1,104 @@
+//! Classifying a latency result into a quality bucket, for a UI's simple
+//! "good/fair/poor" label. Kept here so every consumer draws the lines in
+//! the same place.
+
+use crate::LatencyResult;
+
+/// How good a latency is, best first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Quality {
+    Excellent,
+    Good,
+    Fair,
+    Poor,
+    Bad,
+}
+
+/// The highest latency, in milliseconds, each bucket allows. A latency
+/// exactly on a threshold falls in the better bucket; anything above `poor_ms`
+/// is `Bad`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityThresholds {
+    pub excellent_ms: f64,
+    pub good_ms: f64,
+    pub fair_ms: f64,
+    pub poor_ms: f64,
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        Self {
+            excellent_ms: 20.0,
+            good_ms: 50.0,
+            fair_ms: 100.0,
+            poor_ms: 200.0,
+        }
+    }
+}
+
+/// The bucket `result`'s latency falls in.
+pub fn classify(result: &LatencyResult, thresholds: &QualityThresholds) -> Quality {
+    let latency_ms = result.latency_ms;
+    if latency_ms <= thresholds.excellent_ms {
+        Quality::Excellent
+    } else if latency_ms <= thresholds.good_ms {
+        Quality::Good
+    } else if latency_ms <= thresholds.fair_ms {
+        Quality::Fair
+    } else if latency_ms <= thresholds.poor_ms {
+        Quality::Poor
+    } else {
+        Quality::Bad
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn result(latency_ms: f64) -> LatencyResult {
+        LatencyResult {
+            latency_ms,
+            server_latency_ms: latency_ms,
+            client_latency_ms: latency_ms,
+            approximate: false,
+            resolution_limited: false,
+        }
+    }
+
+    #[test]
+    fn boundaries_fall_in_the_better_bucket() {
+        let thresholds = QualityThresholds::default();
+        let cases = [
+            (0.0, Quality::Excellent),
+            (20.0, Quality::Excellent),
+            (20.5, Quality::Good),
+            (50.0, Quality::Good),
+            (50.5, Quality::Fair),
+            (100.0, Quality::Fair),
+            (100.5, Quality::Poor),
+            (200.0, Quality::Poor),
+            (200.5, Quality::Bad),
+            (f64::INFINITY, Quality::Bad),
+        ];
+        for (latency_ms, quality) in cases {
+            assert_eq!(
+                classify(&result(latency_ms), &thresholds),
+                quality,
+                "{latency_ms}ms"
+            );
+        }
+    }
+
+    #[test]
+    fn custom_thresholds_move_the_lines() {
+        let thresholds = QualityThresholds {
+            excellent_ms: 5.0,
+            good_ms: 10.0,
+            fair_ms: 15.0,
+            poor_ms: 20.0,
+        };
+        assert_eq!(classify(&result(10.0), &thresholds), Quality::Good);
+        assert_eq!(classify(&result(21.0), &thresholds), Quality::Bad);
+    }
+}
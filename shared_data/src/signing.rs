@@ -0,0 +1,181 @@
+//! Optional signing of server-originated timestamps (the `hmac` feature), so
+//! the server can detect a client fabricating favorable timestamps.
+//!
+//! The server appends an HMAC-SHA256 signature of its timestamps to the
+//! frames it sends. Decoding ignores trailing bytes, so clients don't need to
+//! understand the signature: they echo it back, untouched, after their reply.
+//! The server then verifies its timestamps against the echoed signature.
+
+use crate::{LatencyTest, LatencyTestError};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Size of the signature appended to a signed frame.
+pub const SIGNATURE_SIZE: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs and verifies server timestamps with a server-side secret.
+pub struct TimestampSigner {
+    secret: Vec<u8>,
+}
+
+impl TimestampSigner {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            secret: secret.to_vec(),
+        }
+    }
+
+    fn mac(&self, message: &LatencyTest) -> Option<HmacSha256> {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).ok()?;
+        match message {
+            LatencyTest::FirstReply { server_time, .. }
+            | LatencyTest::FirstResponse { server_time, .. } => {
+                mac.update(&server_time.to_be_bytes());
+            }
+            LatencyTest::SecondReply {
+                server_time,
+                server_ack_time,
+                ..
+            }
+            | LatencyTest::Final {
+                server_time,
+                server_ack_time,
+                ..
+            } => {
+                mac.update(&server_time.to_be_bytes());
+                mac.update(&server_ack_time.to_be_bytes());
+            }
+            _ => return None,
+        }
+        Some(mac)
+    }
+
+    /// Signs the server timestamps carried by `message`, or returns `None` if
+    /// it carries none.
+    pub fn sign(&self, message: &LatencyTest) -> Option<[u8; SIGNATURE_SIZE]> {
+        self.mac(message)
+            .map(|mac| mac.finalize().into_bytes().into())
+    }
+
+    /// Encodes `message`, appending a signature if it carries server
+    /// timestamps.
+    pub fn encode_signed(&self, message: &LatencyTest) -> Vec<u8> {
+        let mut buf = message.encode();
+        if let Some(signature) = self.sign(message) {
+            buf.extend(signature);
+        }
+        buf
+    }
+
+    /// Decodes a frame, verifying the trailing signature if it carries server
+    /// timestamps. Frames without server timestamps need no signature.
+    pub fn decode_verified(&self, bytes: &[u8]) -> Result<LatencyTest, LatencyTestError> {
+        let (message, trailer) = LatencyTest::decode_with_trailer(bytes)?;
+        if let Some(mac) = self.mac(&message) {
+            let signature = trailer
+                .get(..SIGNATURE_SIZE)
+                .ok_or(LatencyTestError::BadSignature)?;
+            mac.verify_slice(signature)
+                .map_err(|_| LatencyTestError::BadSignature)?;
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MAGIC_NUMBER;
+
+    fn signer() -> TimestampSigner {
+        TimestampSigner::new(b"server secret")
+    }
+
+    /// The client's reply to a signed `FirstReply`, echoing the signature.
+    fn echo(first_reply: &[u8], client_time: u128, tamper: u128) -> Vec<u8> {
+        let (message, trailer) = LatencyTest::decode_with_trailer(first_reply).unwrap();
+        let LatencyTest::FirstReply { server_time, .. } = message else {
+            panic!("Expected a FirstReply");
+        };
+        let mut reply = LatencyTest::FirstResponse {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: server_time - tamper,
+            client_time,
+        }
+        .encode();
+        reply.extend(trailer);
+        reply
+    }
+
+    fn first_reply() -> Vec<u8> {
+        signer().encode_signed(&LatencyTest::FirstReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+        })
+    }
+
+    #[test]
+    fn untouched_timestamp_verifies() {
+        let reply = echo(&first_reply(), 1030, 0);
+        assert!(signer().decode_verified(&reply).is_ok());
+    }
+
+    #[test]
+    fn tampered_timestamp_fails() {
+        let reply = echo(&first_reply(), 1030, 20);
+        assert!(matches!(
+            signer().decode_verified(&reply),
+            Err(LatencyTestError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn tampered_ack_time_fails() {
+        let second_reply = LatencyTest::SecondReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 1030,
+            server_ack_time: 1060,
+        };
+        let mut bytes = signer().encode_signed(&second_reply);
+        assert!(signer().decode_verified(&bytes).is_ok());
+        // Knock a millisecond off server_ack_time's low byte
+        bytes[14 + 16 * 3 - 1] -= 1;
+        assert!(signer().decode_verified(&bytes).is_err());
+    }
+
+    #[test]
+    fn missing_signature_fails() {
+        let unsigned = LatencyTest::FirstResponse {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+            client_time: 1030,
+        }
+        .encode();
+        assert!(signer().decode_verified(&unsigned).is_err());
+    }
+
+    #[test]
+    fn other_secret_fails() {
+        let reply = echo(&first_reply(), 1030, 0);
+        assert!(TimestampSigner::new(b"another secret")
+            .decode_verified(&reply)
+            .is_err());
+    }
+
+    #[test]
+    fn unsigned_frames_need_no_signature() {
+        let request = LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: 0,
+        };
+        assert_eq!(signer().encode_signed(&request), request.encode());
+        assert!(signer().decode_verified(&request.encode()).is_ok());
+    }
+}
@@ -0,0 +1,181 @@
+//! Saving `LatencySamples` to disk and loading them back, so a long-running
+//! monitor keeps its history across restarts (and upgrades of this crate).
+//!
+//! A snapshot starts with `SNAPSHOT_MAGIC`, a `u16` format version and a
+//! `u64` sample count, followed by that many samples, each laid out
+//! (big-endian) as:
+//!
+//! `timestamp_ms (u128) | latency_ms (f64) | server_latency_ms (f64) |
+//! client_latency_ms (f64) | flags (u8)`
+//!
+//! Flags are as in a `Report`. Nothing may follow the last sample.
+
+use crate::{LatencyResult, LatencySamples, FLAG_APPROXIMATE, FLAG_RESOLUTION_LIMITED};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Identifies a snapshot file.
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"LTSS";
+const SNAPSHOT_VERSION: u16 = 1;
+
+impl LatencySamples {
+    /// Writes a snapshot of every sample to `path`, replacing any file
+    /// there. The snapshot is written alongside and renamed into place, so
+    /// a crash mid-save leaves the previous snapshot intact.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        self.write_snapshot(&mut writer)?;
+        writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+        fs::rename(&partial, path)
+    }
+
+    /// Reads back a snapshot written by `save`. Fails with `InvalidData` if
+    /// the file isn't a snapshot, is from an unknown version, or is
+    /// truncated or corrupt.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read_snapshot(BufReader::new(File::open(path)?))
+    }
+
+    /// Writes a snapshot to `writer`, as `save` does to a file.
+    pub fn write_snapshot<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_be_bytes())?;
+        writer.write_all(&(self.len() as u64).to_be_bytes())?;
+        for sample in self.iter() {
+            let result = &sample.result;
+            let mut flags = 0;
+            if result.approximate {
+                flags |= FLAG_APPROXIMATE;
+            }
+            if result.resolution_limited {
+                flags |= FLAG_RESOLUTION_LIMITED;
+            }
+            writer.write_all(&sample.timestamp_ms.to_be_bytes())?;
+            writer.write_all(&result.latency_ms.to_be_bytes())?;
+            writer.write_all(&result.server_latency_ms.to_be_bytes())?;
+            writer.write_all(&result.client_latency_ms.to_be_bytes())?;
+            writer.write_all(&[flags])?;
+        }
+        Ok(())
+    }
+
+    /// Reads a snapshot from `reader`, as `load` does from a file.
+    pub fn read_snapshot<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).map_err(truncated)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(invalid_data("Not a snapshot file"));
+        }
+        let version = u16::from_be_bytes(read_array(&mut reader)?);
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid_data("Unsupported snapshot version"));
+        }
+        let count = u64::from_be_bytes(read_array(&mut reader)?);
+        let mut samples = LatencySamples::new();
+        for _ in 0..count {
+            let timestamp_ms = u128::from_be_bytes(read_array(&mut reader)?);
+            let mut read_f64 = || read_array(&mut reader).map(f64::from_be_bytes);
+            let (latency_ms, server_latency_ms, client_latency_ms) =
+                (read_f64()?, read_f64()?, read_f64()?);
+            let [flags] = read_array(&mut reader)?;
+            if flags & !(FLAG_APPROXIMATE | FLAG_RESOLUTION_LIMITED) != 0 {
+                return Err(invalid_data("Unknown snapshot flags"));
+            }
+            let result = LatencyResult {
+                latency_ms,
+                server_latency_ms,
+                client_latency_ms,
+                approximate: flags & FLAG_APPROXIMATE != 0,
+                resolution_limited: flags & FLAG_RESOLUTION_LIMITED != 0,
+            };
+            samples.push(timestamp_ms, result);
+        }
+        if reader.read(&mut [0u8; 1])? != 0 {
+            return Err(invalid_data("Data after the last sample"));
+        }
+        Ok(samples)
+    }
+}
+
+fn read_array<const N: usize, R: Read>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes).map_err(truncated)?;
+    Ok(bytes)
+}
+
+/// A snapshot that ends early is corrupt, not merely short.
+fn truncated(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => invalid_data("Snapshot truncated"),
+        _ => e,
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn samples() -> LatencySamples {
+        let mut samples = LatencySamples::new();
+        for (i, latency_ms) in [12.5, 40.0, 0.0].into_iter().enumerate() {
+            let result = LatencyResult {
+                latency_ms,
+                server_latency_ms: latency_ms - 0.5,
+                client_latency_ms: latency_ms + 0.5,
+                approximate: i == 1,
+                resolution_limited: latency_ms == 0.0,
+            };
+            samples.push(1_700_000_000_000 + i as u128 * 1000, result);
+        }
+        samples
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "Miri isolates tests from the filesystem")]
+    fn snapshot_round_trip() {
+        let dir = std::env::temp_dir().join(format!("snapshot-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("samples.snapshot");
+        let samples = samples();
+        samples.save(&path).unwrap();
+        assert_eq!(LatencySamples::load(&path).unwrap(), samples);
+
+        // Saving again replaces it
+        LatencySamples::new().save(&path).unwrap();
+        assert!(LatencySamples::load(&path).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_snapshots_are_rejected() {
+        let mut snapshot = Vec::new();
+        samples().write_snapshot(&mut snapshot).unwrap();
+        let rejects = |bytes: &[u8], reason: &str| {
+            let e = LatencySamples::read_snapshot(bytes).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(e.to_string(), reason);
+        };
+
+        let mut wrong_version = snapshot.clone();
+        wrong_version[4..6].copy_from_slice(&2u16.to_be_bytes());
+        rejects(&wrong_version, "Unsupported snapshot version");
+        rejects(b"LTCP\0\x01", "Not a snapshot file");
+        rejects(&snapshot[..snapshot.len() - 1], "Snapshot truncated");
+        rejects(&[&snapshot[..], &[0]].concat(), "Data after the last sample");
+        let mut bad_flags = snapshot.clone();
+        *bad_flags.last_mut().unwrap() = 0x80;
+        rejects(&bad_flags, "Unknown snapshot flags");
+        // A count far beyond the data doesn't allocate for it
+        let mut huge_count = snapshot.clone();
+        huge_count[6..14].copy_from_slice(&u64::MAX.to_be_bytes());
+        rejects(&huge_count, "Snapshot truncated");
+    }
+}
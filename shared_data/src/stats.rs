@@ -0,0 +1,488 @@
+//! Accumulation of latency results, and statistics over them.
+
+use crate::LatencyResult;
+use std::time::Duration;
+
+/// A latency result, and when it was measured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencySample {
+    pub timestamp_ms: u128,
+    pub result: LatencyResult,
+}
+
+/// A series of latency results, in the order they were recorded. Statistics
+/// are computed over each result's `latency_ms`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LatencySamples {
+    samples: Vec<LatencySample>,
+}
+
+impl LatencySamples {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, timestamp_ms: u128, result: LatencyResult) {
+        self.samples.push(LatencySample {
+            timestamp_ms,
+            result,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LatencySample> {
+        self.samples.iter()
+    }
+
+    /// The latency of each sample, in order.
+    pub fn latencies(&self) -> impl Iterator<Item = f64> + Clone + '_ {
+        self.samples.iter().map(|sample| sample.result.latency_ms)
+    }
+
+    pub fn mean_ms(&self) -> Option<f64> {
+        mean(self.latencies())
+    }
+
+    pub fn min_ms(&self) -> Option<f64> {
+        self.latencies().reduce(f64::min)
+    }
+
+    pub fn max_ms(&self) -> Option<f64> {
+        self.latencies().reduce(f64::max)
+    }
+
+    /// Jitter as the mean absolute difference between consecutive samples.
+    /// Unlike `rfc3550_jitter` this is unsmoothed, so every pair counts
+    /// equally. Returns `None` with fewer than two samples.
+    pub fn jitter_ms(&self) -> Option<f64> {
+        jitter(self.latencies())
+    }
+
+    /// The population standard deviation of the latencies.
+    pub fn stddev_ms(&self) -> Option<f64> {
+        stddev(self.latencies())
+    }
+
+    /// The `pct`th percentile latency (0-100), by the nearest-rank method.
+    pub fn percentile_ms(&self, pct: f64) -> Option<f64> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<f64> = self.latencies().collect();
+        sorted.sort_by(f64::total_cmp);
+        let rank = (pct.clamp(0.0, 100.0) / 100.0 * sorted.len() as f64).ceil() as usize;
+        Some(sorted[rank.saturating_sub(1)])
+    }
+
+    /// The lowest latency observed: the link's floor. This is as close as
+    /// the samples get to the path's propagation delay, with no queueing, so
+    /// it's the baseline other latencies are judged against.
+    pub fn floor(&self) -> Option<f64> {
+        self.min_ms()
+    }
+
+    /// How far the 95th percentile sits above the floor: the latency added
+    /// by queueing. On an idle link this stays small; a large value under
+    /// load is the signature of bufferbloat.
+    pub fn bufferbloat(&self) -> Option<f64> {
+        Some(self.percentile_ms(95.0)? - self.floor()?)
+    }
+
+    /// The playout buffer, in ms, a VoIP or streaming receiver would need to
+    /// absorb the measured jitter while losing at most `target_loss` (say
+    /// 0.01 for 1%) of packets to late arrival. A buffer of `B` plays out
+    /// anything delayed by at most `floor + B`, so it's the distance from the
+    /// floor to the `1 - target_loss` quantile of latency. These are round
+    /// trips, whose variation is at least that of either direction, so the
+    /// figure errs on the generous side. `None` with no samples, or a
+    /// `target_loss` outside `0.0..1.0`.
+    pub fn recommended_buffer_ms(&self, target_loss: f64) -> Option<f64> {
+        if !(0.0..1.0).contains(&target_loss) {
+            return None;
+        }
+        Some(self.percentile_ms((1.0 - target_loss) * 100.0)? - self.floor()?)
+    }
+
+    /// Interarrival jitter, as RTP estimates it (RFC 3550, section 6.4.1).
+    /// For each consecutive pair of samples, the difference in transit time
+    /// is `D = latency[i] - latency[i - 1]`, and the estimate is smoothed as
+    ///
+    /// `J = J + (|D| - J) / 16`
+    ///
+    /// starting from `J = 0`. Samples must be in the order they were
+    /// measured. Returns `None` with fewer than two samples.
+    pub fn rfc3550_jitter(&self) -> Option<f64> {
+        if self.len() < 2 {
+            return None;
+        }
+        let latencies: Vec<f64> = self.latencies().collect();
+        let jitter = latencies.windows(2).fold(0.0, |jitter, pair| {
+            let difference = (pair[1] - pair[0]).abs();
+            jitter + (difference - jitter) / 16.0
+        });
+        Some(jitter)
+    }
+
+    /// Reduces the latencies to at most `target` points for plotting, keeping
+    /// the shape of the series. Uses min-max decimation: the series is split
+    /// into `target / 2` equal buckets, and each contributes its lowest and
+    /// highest latency, in their original order. Unlike simple striding,
+    /// this never drops a spike, so every peak (and trough) survives.
+    pub fn downsample(&self, target: usize) -> Vec<f64> {
+        let latencies: Vec<f64> = self.latencies().collect();
+        if latencies.len() <= target {
+            return latencies;
+        }
+        if target < 2 {
+            // No room for a bucket pair; keep the worst case
+            return self.max_ms().into_iter().take(target).collect();
+        }
+        let buckets = target / 2;
+        let mut points = Vec::with_capacity(buckets * 2);
+        for bucket in 0..buckets {
+            let start = bucket * latencies.len() / buckets;
+            let end = (bucket + 1) * latencies.len() / buckets;
+            let indexed = latencies[start..end].iter().enumerate();
+            let (min_at, _) = indexed.clone().min_by(|a, b| a.1.total_cmp(b.1)).unwrap();
+            let (max_at, _) = indexed.max_by(|a, b| a.1.total_cmp(b.1)).unwrap();
+            let (first, second) = (min_at.min(max_at), min_at.max(max_at));
+            points.push(latencies[start + first]);
+            if second != first {
+                points.push(latencies[start + second]);
+            }
+        }
+        points
+    }
+
+    /// Formats every sample as CSV, with a header row and a leading
+    /// `timestamp_ms` column. Each row ends with a newline.
+    pub fn to_csv(&self) -> String {
+        let mut csv = format!("timestamp_ms,{}\n", LatencyResult::CSV_HEADER);
+        for sample in self.iter() {
+            csv.push_str(&format!("{},{}\n", sample.timestamp_ms, sample.result.to_csv_row()));
+        }
+        csv
+    }
+}
+
+/// A series of latency results, in the order they were measured, for when
+/// only their spread matters and not when each was taken. The statistics are
+/// those of `LatencySamples`, and are `None` until there are enough samples
+/// to compute them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LatencySeries {
+    samples: Vec<LatencyResult>,
+}
+
+impl LatencySeries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, sample: LatencyResult) {
+        self.samples.push(sample);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// The latency of each sample, in order.
+    pub fn latencies(&self) -> impl Iterator<Item = f64> + Clone + '_ {
+        self.samples.iter().map(|sample| sample.latency_ms)
+    }
+
+    pub fn mean_ms(&self) -> Option<f64> {
+        mean(self.latencies())
+    }
+
+    pub fn min_ms(&self) -> Option<f64> {
+        self.latencies().reduce(f64::min)
+    }
+
+    pub fn max_ms(&self) -> Option<f64> {
+        self.latencies().reduce(f64::max)
+    }
+
+    /// See `LatencySamples::jitter_ms`.
+    pub fn jitter_ms(&self) -> Option<f64> {
+        jitter(self.latencies())
+    }
+
+    /// See `LatencySamples::stddev_ms`.
+    pub fn stddev_ms(&self) -> Option<f64> {
+        stddev(self.latencies())
+    }
+}
+
+/// The mean of `latencies`, or `None` if there are none.
+fn mean(latencies: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = latencies.fold((0.0, 0), |(sum, count), latency| (sum + latency, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+/// The mean absolute difference between consecutive `latencies`, or `None`
+/// with fewer than two.
+fn jitter(latencies: impl Iterator<Item = f64>) -> Option<f64> {
+    let mut previous = None;
+    let differences = latencies.filter_map(|latency| {
+        let difference = previous.map(|previous: f64| (latency - previous).abs());
+        previous = Some(latency);
+        difference
+    });
+    mean(differences)
+}
+
+/// The population standard deviation of `latencies`, or `None` if there are
+/// none.
+fn stddev(latencies: impl Iterator<Item = f64> + Clone) -> Option<f64> {
+    let mean_ms = mean(latencies.clone())?;
+    let variance = mean(latencies.map(|latency| (latency - mean_ms).powi(2)))?;
+    Some(variance.sqrt())
+}
+
+/// Latency results from the last `window` (say, five minutes) rather than
+/// the last N. Samples older than the window, measured back from the newest
+/// sample, are evicted as each new one is pushed, so statistics (through
+/// `samples()`) always cover just the live window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowedSamples {
+    window_ms: u128,
+    samples: LatencySamples,
+}
+
+impl WindowedSamples {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window_ms: window.as_millis(),
+            samples: LatencySamples::new(),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window_ms as u64)
+    }
+
+    /// Changes the window, evicting anything now outside it.
+    pub fn set_window(&mut self, window: Duration) {
+        self.window_ms = window.as_millis();
+        self.evict();
+    }
+
+    pub fn push(&mut self, timestamp_ms: u128, result: LatencyResult) {
+        self.samples.push(timestamp_ms, result);
+        self.evict();
+    }
+
+    /// The samples in the live window, in the order they were recorded.
+    pub fn samples(&self) -> &LatencySamples {
+        &self.samples
+    }
+
+    fn evict(&mut self) {
+        let Some(newest) = self.samples.iter().map(|sample| sample.timestamp_ms).max() else {
+            return;
+        };
+        let oldest = newest.saturating_sub(self.window_ms);
+        self.samples.samples.retain(|sample| sample.timestamp_ms >= oldest);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn result(latency_ms: f64) -> LatencyResult {
+        LatencyResult {
+            latency_ms,
+            server_latency_ms: latency_ms,
+            client_latency_ms: latency_ms,
+            approximate: false,
+            resolution_limited: false,
+        }
+    }
+
+    #[test]
+    fn empty_samples_have_no_stats() {
+        let samples = LatencySamples::new();
+        assert_eq!(samples.mean_ms(), None);
+        assert_eq!(samples.min_ms(), None);
+        assert_eq!(samples.max_ms(), None);
+        assert_eq!(samples.percentile_ms(95.0), None);
+    }
+
+    #[test]
+    fn basic_stats() {
+        let mut samples = LatencySamples::new();
+        for (i, latency) in [10.0, 20.0, 15.0, 35.0].into_iter().enumerate() {
+            samples.push(i as u128 * 1000, result(latency));
+        }
+        assert_eq!(samples.len(), 4);
+        assert_eq!(samples.mean_ms(), Some(20.0));
+        assert_eq!(samples.min_ms(), Some(10.0));
+        assert_eq!(samples.max_ms(), Some(35.0));
+        assert_eq!(samples.percentile_ms(50.0), Some(15.0));
+        assert_eq!(samples.percentile_ms(95.0), Some(35.0));
+        assert_eq!(samples.percentile_ms(0.0), Some(10.0));
+    }
+
+    #[test]
+    fn floor_and_bufferbloat() {
+        let mut samples = LatencySamples::new();
+        assert_eq!(samples.floor(), None);
+        assert_eq!(samples.bufferbloat(), None);
+        // A 12ms floor, with queueing pushing the tail up to 200ms
+        for i in 0..100u32 {
+            let latency = match i {
+                0..=49 => 12.0 + (i % 5) as f64,
+                50..=89 => 40.0,
+                _ => 150.0 + (i - 90) as f64 * 5.0,
+            };
+            samples.push(i as u128, result(latency));
+        }
+        assert_eq!(samples.floor(), Some(12.0));
+        assert_eq!(samples.percentile_ms(95.0), Some(170.0));
+        assert_eq!(samples.bufferbloat(), Some(158.0));
+    }
+
+    #[test]
+    fn buffer_covers_all_but_the_target_loss() {
+        let mut samples = LatencySamples::new();
+        assert_eq!(samples.recommended_buffer_ms(0.01), None);
+        // 20ms to 119ms, evenly spread
+        for i in 0..100 {
+            samples.push(i as u128, result(20.0 + i as f64));
+        }
+        assert_eq!(samples.recommended_buffer_ms(0.05), Some(94.0));
+        assert_eq!(samples.recommended_buffer_ms(0.01), Some(98.0));
+        // No late packets at all: the whole spread
+        assert_eq!(samples.recommended_buffer_ms(0.0), Some(99.0));
+        assert_eq!(samples.recommended_buffer_ms(1.0), None);
+        assert_eq!(samples.recommended_buffer_ms(-0.1), None);
+    }
+
+    #[test]
+    fn rfc3550_jitter_follows_the_recursion() {
+        let mut samples = LatencySamples::new();
+        samples.push(0, result(10.0));
+        assert_eq!(samples.rfc3550_jitter(), None);
+        for (i, latency) in [14.0, 12.0, 20.0].into_iter().enumerate() {
+            samples.push((i as u128 + 1) * 1000, result(latency));
+        }
+        // D = 4, -2, 8:
+        // J1 = 0 + (4 - 0) / 16 = 0.25
+        // J2 = 0.25 + (2 - 0.25) / 16 = 0.359375
+        // J3 = 0.359375 + (8 - 0.359375) / 16 = 0.8369140625
+        assert_eq!(samples.rfc3550_jitter(), Some(0.8369140625));
+    }
+
+    #[test]
+    fn downsample_keeps_extremes() {
+        let mut samples = LatencySamples::new();
+        for i in 0..10_000u32 {
+            // A gentle wave, with one spike and one dip
+            let latency = match i {
+                1234 => 500.0,
+                8765 => 1.0,
+                _ => 20.0 + (i as f64 / 100.0).sin() * 5.0,
+            };
+            samples.push(i as u128, result(latency));
+        }
+        for target in [1, 2, 3, 100, 301] {
+            let points = samples.downsample(target);
+            assert!(points.len() <= target);
+            assert!(points.contains(&500.0));
+            if target >= 2 {
+                assert!(points.contains(&1.0));
+            }
+        }
+        // Already small enough: unchanged
+        assert_eq!(samples.downsample(20_000).len(), 10_000);
+        assert!(samples.downsample(0).is_empty());
+    }
+
+    #[test]
+    fn csv_output() {
+        let mut samples = LatencySamples::new();
+        samples.push(1000, result(12.5));
+        samples.push(
+            2000,
+            LatencyResult {
+                latency_ms: 20.0,
+                server_latency_ms: 18.0,
+                client_latency_ms: 22.0,
+                approximate: true,
+                resolution_limited: false,
+            },
+        );
+        assert_eq!(
+            samples.to_csv(),
+            "timestamp_ms,latency_ms,server_latency_ms,client_latency_ms,approximate\n\
+             1000,12.5,12.5,12.5,false\n\
+             2000,20,18,22,true\n"
+        );
+    }
+
+    #[test]
+    fn series_stats() {
+        let mut series = LatencySeries::new();
+        assert_eq!(series.mean_ms(), None);
+        assert_eq!(series.stddev_ms(), None);
+        series.push(result(10.0));
+        assert_eq!(series.jitter_ms(), None);
+        assert_eq!(series.stddev_ms(), Some(0.0));
+        for latency in [14.0, 12.0, 20.0] {
+            series.push(result(latency));
+        }
+        assert_eq!(series.len(), 4);
+        assert_eq!(series.mean_ms(), Some(14.0));
+        assert_eq!(series.min_ms(), Some(10.0));
+        assert_eq!(series.max_ms(), Some(20.0));
+        // |14 - 10| + |12 - 14| + |20 - 12| = 4 + 2 + 8 = 14, over 3 pairs
+        assert_eq!(series.jitter_ms(), Some(14.0 / 3.0));
+        // Deviations from 14 are -4, 0, -2, 6: (16 + 0 + 4 + 36) / 4 = 14
+        assert_eq!(series.stddev_ms(), Some(14.0f64.sqrt()));
+
+        // The same statistics as timestamped samples
+        let mut samples = LatencySamples::new();
+        for (timestamp_ms, latency) in series.latencies().enumerate() {
+            samples.push(timestamp_ms as u128, result(latency));
+        }
+        assert_eq!(samples.jitter_ms(), series.jitter_ms());
+        assert_eq!(samples.stddev_ms(), series.stddev_ms());
+        assert_eq!(samples.mean_ms(), series.mean_ms());
+    }
+
+    #[test]
+    fn window_evicts_old_samples() {
+        let mut window = WindowedSamples::new(Duration::from_secs(60));
+        assert!(window.samples().is_empty());
+        assert_eq!(window.samples().mean_ms(), None);
+
+        window.push(0, result(100.0));
+        window.push(30_000, result(10.0));
+        window.push(60_000, result(20.0));
+        // The first sample is exactly a window old, so still live
+        assert_eq!(window.samples().len(), 3);
+
+        window.push(90_000, result(30.0));
+        assert_eq!(window.samples().len(), 3);
+        assert_eq!(window.samples().mean_ms(), Some(20.0));
+        assert_eq!(window.samples().max_ms(), Some(30.0));
+
+        window.set_window(Duration::from_secs(10));
+        assert_eq!(window.samples().latencies().collect::<Vec<_>>(), [30.0]);
+        assert_eq!(window.window(), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,207 @@
+//! Helpers for testing code built on this crate. Enable the `test-util`
+//! feature to use them from other crates.
+
+use crate::{LatencyTest, MAGIC_NUMBER};
+
+/// A small, deterministic pseudo-random generator (xorshift64*), so test
+/// scenarios are reproducible without pulling in an RNG dependency.
+pub struct TestRng(u64);
+
+impl TestRng {
+    pub fn new(seed: u64) -> Self {
+        // Xorshift gets stuck on zero
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A value in `0..bound`.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+/// Simulates a network delivering items out of order, as a jitter buffer
+/// might see them: items are shuffled within consecutive windows of `window`
+/// items, so no item moves further than `window - 1` places.
+pub fn reorder_within_window<T>(mut items: Vec<T>, window: usize, seed: u64) -> Vec<T> {
+    let mut rng = TestRng::new(seed);
+    for chunk in items.chunks_mut(window.max(1)) {
+        // Fisher-Yates
+        for i in (1..chunk.len()).rev() {
+            let j = rng.below(i as u64 + 1) as usize;
+            chunk.swap(i, j);
+        }
+    }
+    items
+}
+
+/// The timestamps of one handshake, from which every message in it can be
+/// built. Usually written with `scenario!`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Scenario {
+    pub server_time: u128,
+    pub client_time: u128,
+    pub server_ack_time: u128,
+    pub client_ack_time: u128,
+}
+
+impl Scenario {
+    /// The five handshake messages, in order.
+    pub fn messages(&self) -> [LatencyTest; 5] {
+        let Self {
+            server_time,
+            client_time,
+            server_ack_time,
+            client_ack_time,
+        } = *self;
+        [
+            LatencyTest::InitialRequest {
+                magic: MAGIC_NUMBER,
+                id: 0,
+            },
+            LatencyTest::FirstReply {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time,
+            },
+            LatencyTest::FirstResponse {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time,
+                client_time,
+            },
+            LatencyTest::SecondReply {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time,
+                client_time,
+                server_ack_time,
+            },
+            LatencyTest::Final {
+                magic: MAGIC_NUMBER,
+                id: 0,
+                server_time,
+                client_time,
+                server_ack_time,
+                client_ack_time,
+            },
+        ]
+    }
+
+    /// Each handshake message, encoded.
+    pub fn encoded(&self) -> Vec<Vec<u8>> {
+        self.messages().iter().map(LatencyTest::encode).collect()
+    }
+}
+
+/// Builds a `Scenario` from named timestamps, for readable fixtures:
+///
+/// ```ignore
+/// let handshake = scenario! {
+///     server_time: 1_000,
+///     client_time: 5_010,
+///     server_ack_time: 1_020,
+///     client_ack_time: 5_030,
+/// };
+/// assert_eq!(handshake.encoded(), GOLDEN);
+/// ```
+///
+/// Timestamps left out are zero; misspelt ones don't compile.
+#[macro_export]
+macro_rules! scenario {
+    ($($name:ident : $time:expr),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut scenario = $crate::test_util::Scenario::default();
+        $(scenario.$name = $time;)*
+        scenario
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reordering_stays_within_window() {
+        let items: Vec<usize> = (0..100).collect();
+        let reordered = reorder_within_window(items.clone(), 4, 7);
+        assert_ne!(reordered, items);
+        for (position, item) in reordered.iter().enumerate() {
+            assert_eq!(position / 4, item / 4);
+        }
+    }
+
+    #[test]
+    fn reordering_is_deterministic() {
+        let items: Vec<usize> = (0..100).collect();
+        assert_eq!(
+            reorder_within_window(items.clone(), 8, 3),
+            reorder_within_window(items, 8, 3)
+        );
+    }
+
+    /// The encoding of the handshake below. If this test fails, the wire
+    /// format has changed: update these only for a deliberate protocol change.
+    const GOLDEN: [&[u8]; 5] = [
+        &[
+            0xBE, 0x47, 0x00, 0x03, 0x00, 0x01,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ],
+        &[
+            0xBE, 0x47, 0x00, 0x03, 0x00, 0x02,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xE8,
+        ],
+        &[
+            0xBE, 0x47, 0x00, 0x03, 0x00, 0x03,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xE8,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x13, 0x92,
+        ],
+        &[
+            0xBE, 0x47, 0x00, 0x03, 0x00, 0x04,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xE8,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x13, 0x92,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xFC,
+        ],
+        &[
+            0xBE, 0x47, 0x00, 0x03, 0x00, 0x05,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xE8,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x13, 0x92,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xFC,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x13, 0xA6,
+        ],
+    ];
+
+    #[test]
+    fn handshake_encoding_matches_golden_bytes() {
+        let handshake = crate::scenario! {
+            server_time: 1_000,
+            client_time: 5_010,
+            server_ack_time: 1_020,
+            client_ack_time: 5_030,
+        };
+        assert_eq!(handshake.encoded(), GOLDEN);
+    }
+
+    #[test]
+    fn scenario_defaults_unnamed_times_to_zero() {
+        let handshake = crate::scenario! { server_time: 7 };
+        assert_eq!(
+            handshake,
+            Scenario {
+                server_time: 7,
+                ..Scenario::default()
+            }
+        );
+        assert_eq!(crate::scenario! {}, Scenario::default());
+    }
+}
@@ -0,0 +1,86 @@
+//! Adaptive probe scheduling: probe less often while latency is steady, and
+//! more often while it fluctuates. The run scheduler asks it for the next
+//! interval after each result.
+
+/// Number of recent latencies the jitter is measured over.
+pub const JITTER_WINDOW: usize = 10;
+
+/// Jitter at which the base interval is used. Steadier links are probed
+/// less often, and jitterier ones more often, in proportion.
+const REFERENCE_JITTER_MS: f64 = 2.0;
+
+/// Bounds for the adaptive probe interval, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveParams {
+    pub min_ms: u32,
+    pub base_ms: u32,
+    pub max_ms: u32,
+}
+
+impl Default for AdaptiveParams {
+    fn default() -> Self {
+        Self {
+            min_ms: 200,
+            base_ms: 1000,
+            max_ms: 5000,
+        }
+    }
+}
+
+/// Mean absolute difference between consecutive latencies, or `None` with
+/// fewer than two.
+pub fn jitter_ms(recent: &[f64]) -> Option<f64> {
+    if recent.len() < 2 {
+        return None;
+    }
+    let total: f64 = recent.windows(2).map(|pair| (pair[1] - pair[0]).abs()).sum();
+    Some(total / (recent.len() - 1) as f64)
+}
+
+/// The delay before the next probe, given recent latencies: the base
+/// interval scaled by `REFERENCE_JITTER_MS / jitter`, clamped to the bounds.
+/// Uses the base interval until there are enough samples to judge.
+pub fn next_interval(params: &AdaptiveParams, recent: &[f64]) -> u32 {
+    let (min, max) = (params.min_ms.min(params.max_ms), params.max_ms);
+    let Some(jitter) = jitter_ms(recent) else {
+        return params.base_ms.clamp(min, max);
+    };
+    if jitter <= 0.0 {
+        return max;
+    }
+    let interval = params.base_ms as f64 * REFERENCE_JITTER_MS / jitter;
+    interval.clamp(min as f64, max as f64) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PARAMS: AdaptiveParams = AdaptiveParams {
+        min_ms: 100,
+        base_ms: 1000,
+        max_ms: 4000,
+    };
+
+    #[test]
+    fn base_interval_until_enough_samples() {
+        assert_eq!(next_interval(&PARAMS, &[]), 1000);
+        assert_eq!(next_interval(&PARAMS, &[20.0]), 1000);
+    }
+
+    #[test]
+    fn steady_latency_backs_off() {
+        let steady = [20.0, 20.5, 20.0, 20.5, 20.0];
+        assert_eq!(next_interval(&PARAMS, &steady), 4000);
+        assert_eq!(next_interval(&PARAMS, &[20.0; 5]), 4000);
+    }
+
+    #[test]
+    fn volatile_latency_probes_faster() {
+        // Jitter of 4ms: twice the reference, so half the base interval
+        let moderate = [20.0, 24.0, 20.0, 24.0];
+        assert_eq!(next_interval(&PARAMS, &moderate), 500);
+        let volatile = [20.0, 80.0, 15.0, 120.0, 30.0];
+        assert_eq!(next_interval(&PARAMS, &volatile), 100);
+    }
+}
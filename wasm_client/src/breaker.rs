@@ -0,0 +1,135 @@
+//! A circuit breaker that stops probing an unreachable server, after enough
+//! consecutive failures, and lets a probe through again once it cools down.
+
+/// Consecutive lost probes before the breaker opens.
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+/// How long an open breaker waits before allowing a trial probe.
+pub const DEFAULT_COOLDOWN_MS: u32 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Probing normally.
+    Closed,
+    /// Probing paused until the given time (in ms since the epoch).
+    Open { until: u128 },
+    /// Cooldown over; a single trial probe decides whether to resume.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown_ms: u32,
+    consecutive_failures: u32,
+    state: BreakerState,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(DEFAULT_FAILURE_THRESHOLD, DEFAULT_COOLDOWN_MS)
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown_ms: u32) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            cooldown_ms,
+            consecutive_failures: 0,
+            state: BreakerState::Closed,
+        }
+    }
+
+    pub fn set_failure_threshold(&mut self, threshold: u32) {
+        self.failure_threshold = threshold.max(1);
+    }
+
+    pub fn set_cooldown_ms(&mut self, cooldown_ms: u32) {
+        self.cooldown_ms = cooldown_ms;
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    /// Returns true if a probe may be sent now. An open breaker moves to
+    /// half-open once its cooldown has passed. Since only one probe is in
+    /// flight at a time, the probe sent while half-open is the trial.
+    pub fn allow(&mut self, now: u128) -> bool {
+        match self.state {
+            BreakerState::Closed | BreakerState::HalfOpen => true,
+            BreakerState::Open { until } if now >= until => {
+                self.state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::Open { .. } => false,
+        }
+    }
+
+    /// A probe received a reply: the server is reachable again.
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.state = BreakerState::Closed;
+    }
+
+    /// A probe timed out. Returns true if this opened the breaker.
+    pub fn record_failure(&mut self, now: u128) -> bool {
+        self.consecutive_failures += 1;
+        let trip = match self.state {
+            BreakerState::Closed => self.consecutive_failures >= self.failure_threshold,
+            BreakerState::HalfOpen => true,
+            BreakerState::Open { .. } => false,
+        };
+        if trip {
+            self.state = BreakerState::Open {
+                until: now + self.cooldown_ms as u128,
+            };
+        }
+        trip
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let mut breaker = CircuitBreaker::new(3, 1000);
+        assert!(!breaker.record_failure(0));
+        assert!(!breaker.record_failure(100));
+        // A success in between resets the count.
+        breaker.record_success();
+        assert!(!breaker.record_failure(200));
+        assert!(!breaker.record_failure(300));
+        assert!(breaker.record_failure(400));
+        assert_eq!(breaker.state(), BreakerState::Open { until: 1400 });
+        assert!(!breaker.allow(1399));
+    }
+
+    #[test]
+    fn trial_probe_success_closes() {
+        let mut breaker = CircuitBreaker::new(1, 1000);
+        breaker.record_failure(0);
+        assert!(!breaker.allow(500));
+        assert!(breaker.allow(1000));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow(1001));
+    }
+
+    #[test]
+    fn trial_probe_failure_reopens() {
+        let mut breaker = CircuitBreaker::new(3, 1000);
+        for now in 0..3 {
+            breaker.record_failure(now);
+        }
+        assert!(breaker.allow(1002));
+        // A single failed trial is enough to reopen, whatever the threshold.
+        assert!(breaker.record_failure(1500));
+        assert_eq!(breaker.state(), BreakerState::Open { until: 2500 });
+        assert!(!breaker.allow(2000));
+        assert!(breaker.allow(2500));
+    }
+}
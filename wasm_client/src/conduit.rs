@@ -0,0 +1,59 @@
+//! The connection to the server, over whichever transport was negotiated.
+
+use crate::webtransport::WebTransportConduit;
+use shared_data::Transport;
+use web_sys::WebSocket;
+#[cfg(test)]
+use std::{cell::RefCell, rc::Rc};
+
+#[derive(Clone)]
+pub enum Conduit {
+    WebSocket(WebSocket),
+    WebTransport(WebTransportConduit),
+    /// Keeps what's sent, for tests to inspect, as there's no browser.
+    #[cfg(test)]
+    Recorder(Rc<RefCell<Vec<Vec<u8>>>>),
+}
+
+impl Conduit {
+    /// Sends an encoded frame. Over a WebSocket, `transport` says whether as
+    /// binary or base64 text; WebTransport is always binary.
+    pub fn send(&self, bytes: &[u8], transport: Transport) {
+        match self {
+            Self::WebSocket(socket) => crate::send_frame(socket, bytes, transport),
+            Self::WebTransport(conduit) => conduit.send(bytes),
+            #[cfg(test)]
+            Self::Recorder(sent) => sent.borrow_mut().push(bytes.to_vec()),
+        }
+    }
+
+    pub fn close(&self) {
+        match self {
+            Self::WebSocket(socket) => {
+                let _ = socket.close();
+            }
+            Self::WebTransport(conduit) => conduit.close(),
+            #[cfg(test)]
+            Self::Recorder(_) => {}
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        match self {
+            Self::WebSocket(socket) => socket.ready_state() == WebSocket::OPEN,
+            Self::WebTransport(conduit) => conduit.is_open(),
+            #[cfg(test)]
+            Self::Recorder(_) => true,
+        }
+    }
+
+    /// Bytes queued to send, but not yet sent.
+    pub fn buffered_amount(&self) -> u32 {
+        match self {
+            Self::WebSocket(socket) => socket.buffered_amount(),
+            Self::WebTransport(conduit) => conduit.buffered_amount(),
+            #[cfg(test)]
+            Self::Recorder(_) => 0,
+        }
+    }
+}
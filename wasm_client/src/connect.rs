@@ -0,0 +1,125 @@
+//! Retrying the connection. A page loaded during a network blip (a DNS
+//! hiccup, a server restart) would otherwise never connect, and a run whose
+//! connection drops would never finish. Attempts are bounded and spaced out,
+//! so a server that's down isn't hammered. Once connected, lost probes are
+//! handled by the circuit breaker.
+
+use shared_data::RECONNECT_CLOSE_CODE;
+
+/// Connection attempts, including the first, before giving up.
+pub const DEFAULT_CONNECT_ATTEMPTS: u32 = 5;
+/// Delay between connection attempts.
+pub const DEFAULT_CONNECT_RETRY_MS: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectRetry {
+    attempts: u32,
+    delay_ms: u32,
+}
+
+impl Default for ConnectRetry {
+    fn default() -> Self {
+        Self::new(DEFAULT_CONNECT_ATTEMPTS, DEFAULT_CONNECT_RETRY_MS)
+    }
+}
+
+impl ConnectRetry {
+    pub fn new(attempts: u32, delay_ms: u32) -> Self {
+        Self {
+            attempts: attempts.max(1),
+            delay_ms,
+        }
+    }
+
+    pub fn set_attempts(&mut self, attempts: u32) {
+        self.attempts = attempts.max(1);
+    }
+
+    pub fn set_delay_ms(&mut self, delay_ms: u32) {
+        self.delay_ms = delay_ms;
+    }
+
+    /// How long to wait before the next attempt, after `failures` failed
+    /// attempts, or `None` once every attempt has been used.
+    pub fn delay_after(&self, failures: u32) -> Option<u32> {
+        (failures < self.attempts).then_some(self.delay_ms)
+    }
+
+    /// What to do once a connection closes with `code`: `was_open` if it
+    /// had opened, and `resume` if a run is waiting to carry on over it.
+    pub fn after_close(&self, was_open: bool, code: u16, resume: bool) -> AfterClose {
+        if !was_open {
+            AfterClose::Failed
+        } else if code == RECONNECT_CLOSE_CODE {
+            AfterClose::Reconnect { delay_ms: 0 }
+        } else if resume {
+            AfterClose::Reconnect {
+                delay_ms: self.delay_ms,
+            }
+        } else {
+            AfterClose::Stay
+        }
+    }
+}
+
+/// What to do once a connection closes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AfterClose {
+    /// It never opened: a failed attempt, retried after the delay while
+    /// attempts remain.
+    Failed,
+    /// Reconnect after `delay_ms`, counting attempts afresh.
+    Reconnect { delay_ms: u32 },
+    /// Stay disconnected.
+    Stay,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retries_are_bounded() {
+        let retry = ConnectRetry::new(3, 250);
+        assert_eq!(retry.delay_after(1), Some(250));
+        assert_eq!(retry.delay_after(2), Some(250));
+        assert_eq!(retry.delay_after(3), None);
+        assert_eq!(retry.delay_after(4), None);
+    }
+
+    #[test]
+    fn reconnecting_to_a_server_that_is_down_gives_up() {
+        let retry = ConnectRetry::new(3, 250);
+        // The connection drops mid-run: reconnect, but not straight away
+        assert_eq!(retry.after_close(true, 1006, true), AfterClose::Reconnect { delay_ms: 250 });
+        // The server is down, so every attempt closes without opening. Each
+        // is counted, and retried after the delay until attempts run out.
+        let mut delays = Vec::new();
+        let mut failures = 0;
+        while retry.after_close(false, 1006, true) == AfterClose::Failed {
+            failures += 1;
+            let Some(delay) = retry.delay_after(failures) else {
+                break;
+            };
+            delays.push(delay);
+        }
+        assert_eq!(delays, [250, 250]);
+    }
+
+    #[test]
+    fn server_requested_reconnects_are_immediate() {
+        let retry = ConnectRetry::default();
+        let reconnect = retry.after_close(true, RECONNECT_CLOSE_CODE, false);
+        assert_eq!(reconnect, AfterClose::Reconnect { delay_ms: 0 });
+        // Dropped with no run to resume
+        assert_eq!(retry.after_close(true, 1006, false), AfterClose::Stay);
+    }
+
+    #[test]
+    fn always_at_least_one_attempt() {
+        let mut retry = ConnectRetry::default();
+        assert_eq!(retry.delay_after(1), Some(DEFAULT_CONNECT_RETRY_MS));
+        retry.set_attempts(0);
+        assert_eq!(retry.delay_after(1), None);
+    }
+}
@@ -0,0 +1,112 @@
+//! Deduplicating completed handshakes. A retransmitted (or reordered)
+//! `SecondReply` would otherwise complete the same handshake twice, counting
+//! one probe as two samples. A handshake is identified by its probe's id,
+//! which every reply echoes, with the server timestamp issued in its
+//! `FirstReply`: probes in flight at once have different ids, but may be
+//! stamped in the same millisecond, and ids start over on a new client.
+
+use shared_data::LatencyTest;
+use std::collections::{HashSet, VecDeque};
+
+/// How many recently completed handshakes are remembered. Duplicates arrive
+/// close behind the original, so this needn't be large.
+pub const COMPLETED_HISTORY: usize = 64;
+
+#[derive(Debug, Default)]
+pub struct CompletedHandshakes {
+    order: VecDeque<(u64, u128)>,
+    seen: HashSet<(u64, u128)>,
+}
+
+impl CompletedHandshakes {
+    /// Records the handshake completed by `last` (its `Final`), by its id
+    /// and server time. Returns false if that handshake had already
+    /// completed.
+    pub fn complete(&mut self, last: &LatencyTest) -> bool {
+        let LatencyTest::Final { id, server_time, .. } = *last else {
+            return true;
+        };
+        let key = (id, server_time);
+        if !self.seen.insert(key) {
+            return false;
+        }
+        if self.order.len() == COMPLETED_HISTORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.order.push_back(key);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shared_data::handshake::{client_step, ClientStep};
+    use shared_data::{LatencySamples, MAGIC_NUMBER};
+
+    fn second_reply(server_time: u128) -> LatencyTest {
+        LatencyTest::SecondReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time,
+            client_time: server_time + 10,
+            server_ack_time: server_time + 20,
+        }
+    }
+
+    #[test]
+    fn duplicate_reply_is_counted_once() {
+        let mut completed = CompletedHandshakes::default();
+        let mut samples = LatencySamples::new();
+        for (reply, now) in [
+            (second_reply(1000), 1030),
+            (second_reply(1000), 1045),
+            (second_reply(2000), 2030),
+        ] {
+            if let ClientStep::Complete { last, result } = client_step(reply, now) {
+                if completed.complete(&last) {
+                    samples.push(now, result);
+                }
+            }
+        }
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples.iter().map(|s| s.timestamp_ms).collect::<Vec<_>>(), [1030, 2030]);
+    }
+
+    #[test]
+    fn concurrent_probes_in_one_millisecond_both_count() {
+        let mut completed = CompletedHandshakes::default();
+        let last = |id| LatencyTest::Final {
+            magic: MAGIC_NUMBER,
+            id,
+            server_time: 1000,
+            client_time: 1010,
+            server_ack_time: 1020,
+            client_ack_time: 1030,
+        };
+        assert!(completed.complete(&last(1)));
+        assert!(completed.complete(&last(2)));
+        assert!(!completed.complete(&last(2)));
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut completed = CompletedHandshakes::default();
+        let finals = (0..=COMPLETED_HISTORY as u128).map(|server_time| LatencyTest::Final {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time,
+            client_time: 0,
+            server_ack_time: 0,
+            client_ack_time: 0,
+        });
+        let finals: Vec<_> = finals.collect();
+        assert!(finals.iter().all(|last| completed.complete(last)));
+        assert_eq!(completed.seen.len(), COMPLETED_HISTORY);
+        // The oldest has been forgotten; the newest is still remembered
+        assert!(completed.complete(&finals[0]));
+        assert!(!completed.complete(&finals[COMPLETED_HISTORY]));
+    }
+}
@@ -0,0 +1,150 @@
+//! Application-level keepalive. While the connection is idle, a `KeepAlive`
+//! is sent every so often; the server's `KeepAliveAck` (or any other frame)
+//! shows the connection is still alive. A probe left unanswered for too long
+//! means the server is gone, which is noticed much sooner than a TCP timeout
+//! would. Keepalives aren't latency measurements, and their round trips are
+//! kept apart from the run's results.
+
+/// Idle time before a keepalive is sent.
+pub const DEFAULT_KEEPALIVE_MS: u32 = 15_000;
+/// How long a keepalive may go unanswered before the connection is dead.
+pub const DEFAULT_KEEPALIVE_TIMEOUT_MS: u32 = 5_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepAliveAction {
+    /// Nothing to do yet.
+    Wait,
+    /// Send a keepalive now.
+    Send,
+    /// A keepalive went unanswered; the connection should be closed.
+    Dead,
+}
+
+#[derive(Debug, Clone)]
+pub struct KeepAlive {
+    /// Zero disables keepalives.
+    interval_ms: u32,
+    timeout_ms: u32,
+    last_activity: u128,
+    /// When the unanswered keepalive, if any, was sent.
+    outstanding: Option<u128>,
+    last_rtt_ms: Option<u128>,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self::new(DEFAULT_KEEPALIVE_MS, DEFAULT_KEEPALIVE_TIMEOUT_MS)
+    }
+}
+
+impl KeepAlive {
+    pub fn new(interval_ms: u32, timeout_ms: u32) -> Self {
+        Self {
+            interval_ms,
+            timeout_ms,
+            last_activity: 0,
+            outstanding: None,
+            last_rtt_ms: None,
+        }
+    }
+
+    pub fn set_interval_ms(&mut self, interval_ms: u32) {
+        self.interval_ms = interval_ms;
+    }
+
+    pub fn set_timeout_ms(&mut self, timeout_ms: u32) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    /// Starts afresh on a new connection, opened at `now`.
+    pub fn reset(&mut self, now: u128) {
+        self.last_activity = now;
+        self.outstanding = None;
+        self.last_rtt_ms = None;
+    }
+
+    /// Any frame received at `now` shows the connection is alive.
+    pub fn activity(&mut self, now: u128) {
+        self.last_activity = self.last_activity.max(now);
+        self.outstanding = None;
+    }
+
+    /// Records the ack of a keepalive sent at `client_time`. An ack claiming
+    /// a send time after `now` still shows activity, but gives no round trip.
+    pub fn acked(&mut self, client_time: u128, now: u128) {
+        if let Some(rtt_ms) = now.checked_sub(client_time) {
+            self.last_rtt_ms = Some(rtt_ms);
+        }
+        self.activity(now);
+    }
+
+    /// Round trip of the most recently acked keepalive.
+    pub fn last_rtt_ms(&self) -> Option<u128> {
+        self.last_rtt_ms
+    }
+
+    /// What to do at `now`. Returning `Send` counts the keepalive as sent.
+    pub fn poll(&mut self, now: u128) -> KeepAliveAction {
+        if self.interval_ms == 0 {
+            return KeepAliveAction::Wait;
+        }
+        match self.outstanding {
+            Some(sent) if now.saturating_sub(sent) >= self.timeout_ms as u128 => {
+                KeepAliveAction::Dead
+            }
+            Some(_) => KeepAliveAction::Wait,
+            None if now.saturating_sub(self.last_activity) >= self.interval_ms as u128 => {
+                self.outstanding = Some(now);
+                KeepAliveAction::Send
+            }
+            None => KeepAliveAction::Wait,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sends_only_when_idle() {
+        let mut keepalive = KeepAlive::new(1000, 500);
+        keepalive.reset(0);
+        assert_eq!(keepalive.poll(999), KeepAliveAction::Wait);
+        keepalive.activity(900);
+        assert_eq!(keepalive.poll(1500), KeepAliveAction::Wait);
+        assert_eq!(keepalive.poll(1900), KeepAliveAction::Send);
+        // Only one keepalive outstanding at a time
+        assert_eq!(keepalive.poll(2000), KeepAliveAction::Wait);
+    }
+
+    #[test]
+    fn unanswered_keepalive_is_dead() {
+        let mut keepalive = KeepAlive::new(1000, 500);
+        keepalive.reset(0);
+        assert_eq!(keepalive.poll(1000), KeepAliveAction::Send);
+        assert_eq!(keepalive.poll(1499), KeepAliveAction::Wait);
+        assert_eq!(keepalive.poll(1500), KeepAliveAction::Dead);
+    }
+
+    #[test]
+    fn ack_clears_the_outstanding_keepalive() {
+        let mut keepalive = KeepAlive::new(1000, 500);
+        keepalive.reset(0);
+        assert_eq!(keepalive.poll(1000), KeepAliveAction::Send);
+        keepalive.acked(1000, 1040);
+        assert_eq!(keepalive.last_rtt_ms(), Some(40));
+        assert_eq!(keepalive.poll(1600), KeepAliveAction::Wait);
+        assert_eq!(keepalive.poll(2040), KeepAliveAction::Send);
+        // An ack from the future keeps the last real round trip
+        keepalive.acked(9000, 2050);
+        assert_eq!(keepalive.last_rtt_ms(), Some(40));
+    }
+
+    #[test]
+    fn zero_interval_disables() {
+        let mut keepalive = KeepAlive::new(0, 500);
+        keepalive.reset(0);
+        assert_eq!(keepalive.poll(u32::MAX as u128), KeepAliveAction::Wait);
+    }
+}
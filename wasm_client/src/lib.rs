@@ -1,19 +1,79 @@
 //! WebAssembly Client. Designed to be loaded as part of the embedded
 //! website, rather than used standalone.
+//!
+//! The browser is only touched here, and by the transports in `conduit` and
+//! `webtransport`. The logic in the other modules is kept free of browser
+//! APIs, so it can be tested on the host.
 
+use std::collections::VecDeque;
+use std::sync::OnceLock;
 use std::{cell::RefCell, rc::Rc};
-use shared_data::{LatencyTest, MAGIC_NUMBER, unix_now_ms};
+use shared_data::analysis::{one_way_delay, OneWayDelay};
+use shared_data::handshake::{client_step, ClientStep};
+use shared_data::{
+    check_metadata, decode_base64, encode_base64, BinaryCodec, Codec, FrameReader, LatencyResult,
+    LatencyTest, LatencyTestRef, LoadDirection, Metadata, Transport, FILLER_SIZE, MAGIC_NUMBER,
+    RECONNECT_CLOSE_CODE,
+};
 use thiserror::Error;
 use wasm_bindgen::prelude::*;
-use web_sys::{BinaryType, ErrorEvent, MessageEvent, WebSocket};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{BinaryType, BroadcastChannel, CloseEvent, ErrorEvent, MessageEvent, WebSocket};
+
+mod adaptive;
+mod breaker;
+mod conduit;
+mod connect;
+mod dedup;
+mod keepalive;
+mod logging;
+mod offered;
+mod outliers;
+mod overhead;
+mod panics;
+mod precision;
+mod profile;
+mod ranking;
+mod refusals;
+mod resolution;
+#[cfg(any(test, feature = "debug"))]
+mod replay;
+mod run;
+mod sequence;
+mod tabs;
+mod webtransport;
+use adaptive::AdaptiveParams;
+use breaker::{BreakerState, CircuitBreaker};
+use conduit::Conduit;
+use connect::{AfterClose, ConnectRetry};
+use dedup::CompletedHandshakes;
+use keepalive::{KeepAlive, KeepAliveAction};
+use logging::diag;
+use offered::{LoadTestReport, OfferedLoad};
+use overhead::BoundaryOverhead;
+use precision::Precision;
+use profile::Profile;
+use ranking::{ProbeOutcome, RankedServer};
+use refusals::Refusal;
+use run::{RunParams, RunState};
+use sequence::{Arrival, ReplySequence};
+use tabs::{TabCoordinator, TabMessage};
 
 #[wasm_bindgen]
 extern "C" {
-    #[wasm_bindgen(js_namespace = console)]
-    fn log(s: &str);
-
     #[wasm_bindgen(js_name = "window.reportLatency")]
     fn report_latency(average: f64, server: f64, client: f64);
+
+    #[wasm_bindgen(js_name = "window.reportLoadedLatency")]
+    fn report_loaded_latency(direction: &str, average: f64, server: f64, client: f64);
+
+    #[wasm_bindgen(js_name = "window.reportClientError")]
+    fn report_client_error(message: &str);
+
+    /// Optional: a page without it just doesn't get run summaries.
+    #[wasm_bindgen(catch, js_name = "window.reportRunSummary")]
+    fn report_run_summary(mean: f64, jitter: f64, p95: f64, floor: f64, loss: f64)
+        -> Result<(), JsValue>;
 }
 
 #[derive(Error, Debug)]
@@ -31,9 +91,19 @@ enum WebSocketError {
 #[derive(PartialEq, Eq)]
 enum ConnectionStatus {
     New,
+    /// Trying WebTransport, before falling back to the WebSocket.
+    Negotiating,
     Connected,
 }
 
+/// The server's WebTransport endpoint, from `set_webtransport`.
+#[derive(Clone)]
+struct WebTransportEndpoint {
+    url: String,
+    /// SHA-256 of the server's certificate, if it's self-signed.
+    certificate_hash: Option<Vec<u8>>,
+}
+
 /// Handles WS connection to the server.
 #[wasm_bindgen]
 pub struct LatencyClient {
@@ -42,31 +112,962 @@ pub struct LatencyClient {
 
 struct LatencyClientInner {
     status: ConnectionStatus,
-    socket: Option<WebSocket>,
+    socket: Option<Conduit>,
     url: String,
+    /// Tried before the WebSocket at `url`, if set and supported. Cleared if
+    /// it fails, so reconnects go straight to the WebSocket.
+    webtransport: Option<WebTransportEndpoint>,
+    transport: Transport,
+    /// The wire format frames are encoded in, as the server's.
+    codec: Rc<dyn Codec>,
+    run: Option<RunState>,
+    timer: Option<Timer>,
+    /// Session token issued by the server, presented again on reconnect.
+    session_token: Option<u64>,
+    /// Shared secret for servers that require one (`AUTH_TOKEN`).
+    auth_token: Option<String>,
+    breaker: CircuitBreaker,
+    /// Interval bounds for adaptive runs, used if `adaptive_enabled`.
+    adaptive: AdaptiveParams,
+    adaptive_enabled: bool,
+    /// Outlier threshold for runs' stats, in MADs; `None` keeps everything.
+    outlier_k: Option<f64>,
+    /// The mode `run_precision` measures with.
+    precision: Precision,
+    /// Client clock minus server clock, in ms, if known from an external
+    /// sync; enables one-way delays.
+    clock_offset_ms: Option<f64>,
+    /// One-way delays of the latest handshake, with `clock_offset_ms` set.
+    one_way: Option<OneWayDelay>,
+    /// Labels sent to the server with each reported result.
+    metadata: Metadata,
+    /// The measurement campaign reported results belong to, if any.
+    campaign_id: Option<u64>,
+    /// Results reported, by handshake id, until the server acknowledges
+    /// them; at most `MAX_UNACKED_REPORTS`, oldest first.
+    unacked_reports: VecDeque<(u64, LatencyResult)>,
+    /// Reports whose server leg the server measured differently.
+    report_mismatches: u32,
+    /// This connection's numbered replies, if the server numbers them.
+    replies: ReplySequence,
+    /// The load requested with `start_load`, and when it ends.
+    load: Option<(LoadDirection, u128)>,
+    upload_timer: Option<Timer>,
+    /// The offered load test in progress (or last finished), if any, until
+    /// the run is stopped.
+    offered: Option<OfferedLoad>,
+    offered_timer: Option<Timer>,
+    connect_retry: ConnectRetry,
+    /// Failed attempts so far, while making the initial connection; `None`
+    /// once it has opened.
+    connect_failures: Option<u32>,
+    /// Which tab's turn it is to probe, if coordinating over `tab_channel`.
+    tabs: TabCoordinator,
+    tab_channel: Option<TabChannel>,
+    /// Recently completed handshakes, so duplicates aren't counted twice.
+    completed: CompletedHandshakes,
+    keepalive: KeepAlive,
+    keepalive_timer: Option<Timer>,
+    /// The id of the next probe sent, which its replies will carry.
+    next_probe_id: u64,
+    /// Time spent crossing the JS/wasm boundary, if `instrument_boundary`.
+    boundary: BoundaryOverhead,
+    instrument_boundary: bool,
+}
+
+impl LatencyClientInner {
+    /// A client for the server at `url`, not yet connected. `tab_id`
+    /// identifies this tab to the client in others.
+    fn new(url: String, tab_id: u64) -> Self {
+        Self {
+            status: ConnectionStatus::New,
+            socket: None,
+            url,
+            webtransport: None,
+            transport: Transport::Binary,
+            codec: Rc::new(BinaryCodec),
+            run: None,
+            timer: None,
+            session_token: None,
+            auth_token: None,
+            breaker: CircuitBreaker::default(),
+            adaptive: AdaptiveParams::default(),
+            adaptive_enabled: false,
+            outlier_k: None,
+            precision: Precision::Fast,
+            clock_offset_ms: None,
+            one_way: None,
+            metadata: Metadata::new(),
+            campaign_id: None,
+            unacked_reports: VecDeque::new(),
+            report_mismatches: 0,
+            replies: ReplySequence::default(),
+            load: None,
+            upload_timer: None,
+            offered: None,
+            offered_timer: None,
+            connect_retry: ConnectRetry::default(),
+            connect_failures: None,
+            tabs: TabCoordinator::new(tab_id),
+            tab_channel: None,
+            completed: CompletedHandshakes::default(),
+            keepalive: KeepAlive::default(),
+            keepalive_timer: None,
+            next_probe_id: 1,
+            boundary: BoundaryOverhead::default(),
+            instrument_boundary: false,
+        }
+    }
+
+    /// The direction being loaded at `now`.
+    fn load_at(&self, now: u128) -> LoadDirection {
+        match self.load {
+            Some((direction, until)) if now < until => direction,
+            _ => LoadDirection::Idle,
+        }
+    }
+
+    /// Takes an id for a new probe. Ids count up from 1, so replies to
+    /// probes in flight at once can be told apart.
+    fn take_probe_id(&mut self) -> u64 {
+        let id = self.next_probe_id;
+        self.next_probe_id = id.wrapping_add(1);
+        id
+    }
+
+    /// A new `InitialRequest`, with an id of its own.
+    fn initial_request(&mut self) -> LatencyTest {
+        LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: self.take_probe_id(),
+        }
+    }
+
+    /// Sends the load test's probes due at `now` on `socket`, or skips them
+    /// if it's backed up.
+    fn send_offered_probes(&mut self, socket: &Conduit, now: u128) {
+        let Some(offered) = self.offered.as_mut() else {
+            return;
+        };
+        let backlogged = socket.buffered_amount() > OFFERED_BACKLOG_BYTES;
+        for _ in 0..offered.due(now, backlogged) {
+            let request = self.initial_request();
+            socket.send(&self.codec.encode(&request), self.transport);
+        }
+    }
+
+    /// Gives up this tab's turn to probe, telling the other tabs.
+    fn release_tab_turn(&mut self) {
+        if self.tabs.release() {
+            if let Some(channel) = &self.tab_channel {
+                channel.post(TabMessage::Done(self.tabs.id()));
+            }
+        }
+    }
+}
+
+/// A `BroadcastChannel` to the client in other tabs, closed when dropped.
+struct TabChannel {
+    channel: BroadcastChannel,
+    _onmessage: Closure<dyn FnMut(MessageEvent)>,
+}
+
+impl TabChannel {
+    fn post(&self, message: TabMessage) {
+        let _ = self.channel.post_message(&JsValue::from_str(&message.encode()));
+    }
+}
+
+impl Drop for TabChannel {
+    fn drop(&mut self) {
+        self.channel.close();
+    }
+}
+
+/// A repeating browser timer, cleared when dropped.
+struct Timer {
+    handle: i32,
+    _tick: Closure<dyn FnMut()>,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(window) = web_sys::window() {
+            window.clear_interval_with_handle(self.handle);
+        }
+    }
+}
+
+/// Sends an encoded frame using the configured transport.
+fn send_frame(socket: &WebSocket, bytes: &[u8], transport: Transport) {
+    match transport {
+        Transport::Binary => socket.send_with_u8_array(bytes).unwrap(),
+        Transport::Text => socket.send_with_str(&encode_base64(bytes)).unwrap(),
+    }
+}
+
+/// The time now, in ms since the UNIX epoch. `None` (and logged) if the
+/// clock is before the epoch, as any latency measured with it would be wrong.
+fn now_ms() -> Option<u128> {
+    let now = shared_data::unix_now_ms();
+    if now.is_none() {
+        diag!(ERROR, "System clock is before the UNIX epoch, unable to measure");
+    }
+    now
+}
+
+/// The browser's high-resolution clock, in fractional milliseconds.
+fn performance_now() -> Option<f64> {
+    Some(web_sys::window()?.performance()?.now())
+}
+
+/// Milliseconds elapsed since `start`, a `performance_now` reading; `None`
+/// if there wasn't one (when not instrumenting).
+fn elapsed_ms(start: Option<f64>) -> Option<f64> {
+    let start = start?;
+    Some(performance_now()? - start)
+}
+
+/// The bytes of an incoming binary or text WebSocket frame.
+fn message_bytes(data: JsValue) -> Option<Vec<u8>> {
+    if let Some(abuf) = data.dyn_ref::<js_sys::ArrayBuffer>() {
+        Some(js_sys::Uint8Array::new(abuf).to_vec())
+    } else if let Some(text) = data.as_string() {
+        decode_base64(&text).ok()
+    } else {
+        None
+    }
+}
+
+/// Decodes a frame with `codec`, along with any trailing bytes (such as a
+/// server timestamp signature) that must be echoed back. Numbered replies
+/// come wrapped in a `Sequenced`, which is binary whatever the codec.
+fn decode_frame<'a>(
+    codec: &dyn Codec,
+    bytes: &'a [u8],
+) -> Option<(LatencyTestRef<'a>, &'a [u8])> {
+    codec.decode_with_trailer(bytes).ok().or_else(|| {
+        let frame = LatencyTestRef::decode_with_trailer(bytes).ok()?;
+        let sequenced = matches!(frame.0, LatencyTestRef::Control(LatencyTest::Sequenced { .. }));
+        sequenced.then_some(frame)
+    })
+}
+
+/// Schedules another try at the initial connection, or reports the failure
+/// to the page once the attempts are used up.
+fn initial_connect_failed(inner: &Rc<RefCell<LatencyClientInner>>) {
+    let (failures, delay) = {
+        let mut inner = inner.borrow_mut();
+        let failures = inner.connect_failures.unwrap_or(0) + 1;
+        inner.connect_failures = Some(failures);
+        (failures, inner.connect_retry.delay_after(failures))
+    };
+    let Some(delay) = delay else {
+        inner.borrow_mut().connect_failures = None;
+        let message = format!("Unable to connect after {failures} attempts");
+        diag!(ERROR, "{message}");
+        report_client_error(&message);
+        return;
+    };
+    diag!(WARN, "Connection attempt {failures} failed, retrying in {delay}ms");
+    schedule_connect(inner, delay);
+}
+
+/// Tries to connect after `delay_ms`.
+fn schedule_connect(inner: &Rc<RefCell<LatencyClientInner>>, delay_ms: u32) {
+    let Some(window) = web_sys::window() else {
+        return;
+    };
+    let inner = inner.clone();
+    let retry = Closure::once_into_js(move || LatencyClient { inner }.try_connect());
+    let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(
+        retry.unchecked_ref(),
+        delay_ms as i32,
+    );
+}
+
+/// `base` with the session and auth tokens added as query parameters.
+fn connect_url(inner: &LatencyClientInner, base: &str) -> String {
+    let mut params = Vec::new();
+    if let Some(token) = inner.session_token {
+        params.push(format!("session={token}"));
+    }
+    if let Some(token) = &inner.auth_token {
+        let token: String = js_sys::encode_uri_component(token).into();
+        params.push(format!("token={token}"));
+    }
+    if params.is_empty() {
+        base.to_string()
+    } else {
+        let separator = if base.contains('?') { '&' } else { '?' };
+        format!("{base}{separator}{}", params.join("&"))
+    }
+}
+
+/// Connects over WebTransport, and reads from it until the session ends.
+/// If it can't be opened, falls back to the WebSocket, for good.
+async fn connect_webtransport(
+    inner: Rc<RefCell<LatencyClientInner>>,
+    endpoint: WebTransportEndpoint,
+) {
+    let url = connect_url(&inner.borrow(), &endpoint.url);
+    diag!(INFO, "Connecting to: {}", logging::redact_url(&url));
+    let opened = match webtransport::open(&url, endpoint.certificate_hash.as_deref()).await {
+        Ok(opened) => opened,
+        Err(e) => {
+            diag!(WARN, "WebTransport unavailable ({e:?}), falling back to WebSocket");
+            {
+                let mut inner = inner.borrow_mut();
+                inner.webtransport = None;
+                inner.status = ConnectionStatus::New;
+            }
+            LatencyClient { inner }.try_connect();
+            return;
+        }
+    };
+    inner.borrow_mut().socket = Some(Conduit::WebTransport(opened.conduit.clone()));
+    on_open(&inner);
+
+    let datagrams = {
+        let inner = inner.clone();
+        webtransport::read_chunks(opened.datagrams, move |bytes| {
+            on_frame(&inner, || Some(bytes))
+        })
+    };
+    wasm_bindgen_futures::spawn_local(datagrams);
+    let mut reader = FrameReader::new();
+    webtransport::read_chunks(opened.stream, |chunk| {
+        for bytes in reader.feed_raw(&chunk) {
+            on_frame(&inner, || Some(bytes));
+        }
+    })
+    .await;
+
+    // The stream only ends with the session
+    opened.conduit.closed();
+    let code = opened.conduit.close_code().await;
+    on_server_close(&inner, code.unwrap_or(0));
+}
+
+fn on_open(inner: &Rc<RefCell<LatencyClientInner>>) {
+    diag!(DEBUG, "Open Received");
+    inner.borrow_mut().status = ConnectionStatus::Connected;
+    inner.borrow_mut().connect_failures = None;
+    // The server numbers each connection's replies afresh
+    inner.borrow_mut().replies = ReplySequence::default();
+    if let Some(now) = now_ms() {
+        inner.borrow_mut().keepalive.reset(now);
+    }
+    let timer = start_keepalive(inner);
+    inner.borrow_mut().keepalive_timer = timer;
+}
+
+fn on_close(inner: &Rc<RefCell<LatencyClientInner>>) {
+    let mut inner = inner.borrow_mut();
+    if let Some(socket) = inner.socket.take() {
+        socket.close();
+    }
+    inner.status = ConnectionStatus::New;
+    inner.keepalive_timer = None;
+}
+
+/// The connection closed with `code`. An attempt that never opened counts
+/// against the connection retries. If the server closed an open connection
+/// asking for a reconnect (having served its quota of handshakes), reconnect
+/// straight away: the session token is presented again, so the run carries
+/// on. If it dropped for any other reason mid-run, reconnect after the retry
+/// delay, with the usual retries, and resume the run's remaining probes.
+fn on_server_close(inner: &Rc<RefCell<LatencyClientInner>>, code: u16) {
+    let was_open = inner.borrow().status == ConnectionStatus::Connected;
+    on_close(inner);
+    let resume = match inner.borrow_mut().run.as_mut() {
+        Some(run) if !run.is_finished() => {
+            run.disconnected();
+            true
+        }
+        _ => false,
+    };
+    let after = inner.borrow().connect_retry.after_close(was_open, code, resume);
+    match after {
+        AfterClose::Failed if inner.borrow().connect_failures.is_some() => {
+            initial_connect_failed(inner);
+        }
+        AfterClose::Failed | AfterClose::Stay => {}
+        AfterClose::Reconnect { delay_ms } => {
+            if code == RECONNECT_CLOSE_CODE {
+                diag!(INFO, "Server asked for a reconnect");
+            } else {
+                diag!(WARN, "Connection lost mid-run, reconnecting to resume it");
+            }
+            inner.borrow_mut().connect_failures = Some(0);
+            schedule_connect(inner, delay_ms);
+        }
+    }
+}
+
+/// Handles a frame from the server, over either transport. `receive` gives
+/// its bytes, and is timed with decoding them as part of the boundary
+/// overhead.
+///
+/// Replies are sent before this returns: no `await`, timer or spawned task
+/// may come between decoding a frame and sending its reply, as any delay
+/// there is measured as network latency. `replies_are_sent_before_returning`
+/// checks it.
+fn on_frame(
+    inner: &Rc<RefCell<LatencyClientInner>>,
+    receive: impl FnOnce() -> Option<Vec<u8>>,
+) {
+    diag!(TRACE, "Message Received");
+    let (instrument, codec) = {
+        let inner = inner.borrow();
+        (inner.instrument_boundary, inner.codec.clone())
+    };
+    let decode_start = instrument.then(performance_now).flatten();
+    let bytes = receive();
+    // Borrowed, so download load isn't copied just to be counted
+    let frame = bytes.as_deref().and_then(|bytes| decode_frame(&*codec, bytes));
+    let message = frame.and_then(|(decoded, trailer)| match decoded {
+        LatencyTestRef::Control(LatencyTest::Sequenced { seq, frame, .. }) => {
+            if let Arrival::AfterGap { skipped } = inner.borrow_mut().replies.observe(seq) {
+                diag!(DEBUG, "{skipped} replies lost before #{seq}");
+            }
+            let (decoded, trailer) = decode_frame(&*codec, &frame)?;
+            Some((Some(decoded.into_owned()), trailer.to_vec()))
+        }
+        // Download load; only its arrival matters
+        LatencyTestRef::Filler { .. } => Some((None, Vec::new())),
+        decoded => Some((Some(decoded.into_owned()), trailer.to_vec())),
+    });
+    let decode_ms = elapsed_ms(decode_start);
+    if let Some((decoded, trailer)) = message {
+        let Some(now) = now_ms() else {
+            return;
+        };
+        inner.borrow_mut().keepalive.activity(now);
+        let Some(decoded) = decoded else {
+            return;
+        };
+        match decoded {
+            LatencyTest::Session { token, .. } => {
+                inner.borrow_mut().session_token = Some(token);
+                return;
+            }
+            LatencyTest::Busy { retry_after_ms, .. } => {
+                diag!(INFO, "Server busy, retrying in {retry_after_ms}ms");
+                if let Some(run) = inner.borrow_mut().run.as_mut() {
+                    run.busy(now, retry_after_ms);
+                }
+                return;
+            }
+            LatencyTest::KeepAliveAck { client_time, .. } => {
+                let mut inner = inner.borrow_mut();
+                inner.keepalive.acked(client_time, now);
+                return;
+            }
+            LatencyTest::ReportAck {
+                id,
+                server_latency_ms,
+                ..
+            } => {
+                let mut inner = inner.borrow_mut();
+                let pending = inner.unacked_reports.iter().position(|(report, _)| *report == id);
+                let Some((_, reported)) = pending.and_then(|i| inner.unacked_reports.remove(i))
+                else {
+                    return;
+                };
+                if !reported.agrees_with_server(server_latency_ms) {
+                    diag!(
+                        WARN,
+                        "Server measured its leg as {server_latency_ms}ms, we reported {}ms",
+                        reported.server_latency_ms
+                    );
+                    inner.report_mismatches += 1;
+                }
+                return;
+            }
+            LatencyTest::ProtocolError { code, .. } => {
+                diag!(WARN, "Server refused a frame: {}", decoded.short());
+                match refusals::on_protocol_error(code) {
+                    Refusal::Retry { after_ms } => {
+                        if let Some(run) = inner.borrow_mut().run.as_mut() {
+                            run.busy(now, after_ms);
+                        }
+                    }
+                    Refusal::Report => {}
+                }
+                return;
+            }
+            _ => {}
+        }
+        // Only handshake frames count towards the overhead
+        if let Some(ms) = decode_ms {
+            inner.borrow_mut().boundary.record_decode(ms);
+        }
+        match client_step(decoded, now) {
+            ClientStep::Reply(reply) => {
+                let send_start = instrument.then(performance_now).flatten();
+                let mut bytes = codec.encode(&reply);
+                bytes.extend(trailer);
+                let mut inner = inner.borrow_mut();
+                if let Some(socket) = &inner.socket {
+                    socket.send(&bytes, inner.transport);
+                }
+                if let Some(ms) = elapsed_ms(send_start) {
+                    inner.boundary.record_send(ms);
+                }
+            }
+            ClientStep::Complete { last, result } => on_result(inner, last, result, now),
+            ClientStep::Unexpected(decoded) => {
+                diag!(WARN, "Received: {:?}", decoded);
+            }
+        }
+    }
+
+}
+
+/// Handles a completed handshake: `last` is the `Final` that completes it,
+/// and `result` its latency.
+fn on_result(
+    inner: &Rc<RefCell<LatencyClientInner>>,
+    last: LatencyTest,
+    result: LatencyResult,
+    now: u128,
+) {
+    if !inner.borrow_mut().completed.complete(&last) {
+        diag!(WARN, "Ignoring duplicate {}", last.short());
+        return;
+    }
+    diag!(
+        INFO,
+        "Average: {}ms, Server: {}ms, Client: {}ms",
+        result.latency_ms, result.server_latency_ms, result.client_latency_ms
+    );
+    if result.resolution_limited {
+        diag!(DEBUG, "Round trip under the 1ms timestamp resolution, read as 0ms");
+    }
+    let mut inner = inner.borrow_mut();
+    inner.breaker.record_success();
+    if let Some(offset) = inner.clock_offset_ms {
+        inner.one_way = one_way_delay(&last, offset);
+    }
+    let offered = inner.offered.as_mut().filter(|offered| !offered.is_finished(now));
+    let under_test = offered.is_some();
+    if let Some(offered) = offered {
+        offered.record(now, result.latency_ms);
+    }
+    let report = match inner.run.as_mut() {
+        Some(run) => {
+            run.observe(result.latency_ms);
+            let report = run.complete();
+            if report {
+                run.record(result.latency_ms);
+            }
+            report
+        }
+        // A load test's results are summarized in its report instead
+        None => !under_test,
+    };
+    if report {
+        // Share the result (and our labels) with the server
+        let message = LatencyTest::Report {
+            magic: MAGIC_NUMBER,
+            id: last.id(),
+            result,
+            campaign_id: inner.campaign_id,
+            metadata: inner.metadata.clone(),
+        };
+        if let Some(socket) = &inner.socket {
+            socket.send(&inner.codec.encode(&message), inner.transport);
+        }
+        // Unless the server echoes reports, none are acked
+        if inner.unacked_reports.len() == MAX_UNACKED_REPORTS {
+            inner.unacked_reports.pop_front();
+        }
+        inner.unacked_reports.push_back((last.id(), result));
+    }
+    let direction = inner.load_at(now);
+    drop(inner);
+    if report && direction == LoadDirection::Idle {
+        report_latency(result.latency_ms, result.server_latency_ms, result.client_latency_ms);
+    } else if report {
+        report_loaded_latency(
+            direction.name(),
+            result.latency_ms,
+            result.server_latency_ms,
+            result.client_latency_ms,
+        );
+    }
+}
+
+/// Most reports awaiting the server's acknowledgement at once.
+const MAX_UNACKED_REPORTS: usize = 16;
+
+/// How often the keepalive is checked. Keepalive intervals are rounded up
+/// to a multiple of this.
+const KEEPALIVE_TICK_MS: i32 = 1000;
+
+/// Sends a `KeepAlive` whenever the connection has been idle for the
+/// keepalive interval, and closes it if one goes unanswered.
+fn start_keepalive(inner: &Rc<RefCell<LatencyClientInner>>) -> Option<Timer> {
+    let window = web_sys::window()?;
+    let inner = inner.clone();
+    let tick = Closure::<dyn FnMut()>::new(move || {
+        let Some(now) = now_ms() else {
+            return;
+        };
+        let mut inner = inner.borrow_mut();
+        let action = inner.keepalive.poll(now);
+        let Some(socket) = inner.socket.clone() else {
+            return;
+        };
+        match action {
+            KeepAliveAction::Wait => {}
+            KeepAliveAction::Send => {
+                let probe = LatencyTest::KeepAlive {
+                    magic: MAGIC_NUMBER,
+                    id: inner.take_probe_id(),
+                    client_time: now,
+                };
+                socket.send(&inner.codec.encode(&probe), inner.transport);
+            }
+            KeepAliveAction::Dead => {
+                diag!(WARN, "Keepalive unanswered, closing connection");
+                socket.close();
+            }
+        }
+    });
+    let handle = window
+        .set_interval_with_callback_and_timeout_and_arguments_0(
+            tick.as_ref().unchecked_ref(),
+            KEEPALIVE_TICK_MS,
+        )
+        .ok()?;
+    Some(Timer {
+        handle,
+        _tick: tick,
+    })
+}
+
+/// Keep this much filler queued in the socket during an upload load, so the
+/// uplink stays saturated without buffering unboundedly in the browser.
+const UPLOAD_BUFFER_TARGET: u32 = 1024 * 1024;
+const UPLOAD_TICK_MS: i32 = 10;
+
+/// Streams filler to the server until `until`, topping up the socket's send
+/// buffer on each tick.
+fn start_upload(
+    socket: Conduit,
+    codec: &dyn Codec,
+    transport: Transport,
+    until: u128,
+) -> Option<Timer> {
+    let window = web_sys::window()?;
+    let filler = codec.encode(&LatencyTest::Filler {
+        magic: MAGIC_NUMBER,
+        id: 0,
+        bytes: vec![0; FILLER_SIZE],
+    });
+    let handle = Rc::new(RefCell::new(None));
+    let tick = {
+        let handle = handle.clone();
+        Closure::<dyn FnMut()>::new(move || {
+            if now_ms().is_none_or(|now| now >= until) || !socket.is_open() {
+                if let (Some(window), Some(handle)) = (web_sys::window(), *handle.borrow()) {
+                    window.clear_interval_with_handle(handle);
+                }
+                return;
+            }
+            while socket.buffered_amount() < UPLOAD_BUFFER_TARGET {
+                socket.send(&filler, transport);
+            }
+        })
+    };
+    let id = window
+        .set_interval_with_callback_and_timeout_and_arguments_0(
+            tick.as_ref().unchecked_ref(),
+            UPLOAD_TICK_MS,
+        )
+        .ok()?;
+    *handle.borrow_mut() = Some(id);
+    Some(Timer {
+        handle: id,
+        _tick: tick,
+    })
+}
+
+/// Longest interval between a load test's ticks, in ms. Slower rates tick
+/// once per probe.
+const OFFERED_TICK_MS: u32 = 10;
+/// Bytes queued on the connection beyond which a load test's probes are
+/// skipped, as the link isn't keeping up with the offered rate.
+const OFFERED_BACKLOG_BYTES: u32 = 64 * 1024;
+
+/// Runs `probes` handshakes against one server, one after another, on a
+/// connection of its own, in `codec`'s format. Resolves to an array of
+/// latencies, or rejects with an error message.
+fn probe_server(url: &str, codec: Rc<dyn Codec>, probes: u32) -> js_sys::Promise {
+    js_sys::Promise::new(&mut |resolve, reject| {
+        let fail = move |message: &str| {
+            let _ = reject.call1(&JsValue::NULL, &JsValue::from_str(message));
+        };
+        let Ok(socket) = WebSocket::new(url) else {
+            fail("Invalid URL");
+            return;
+        };
+        socket.set_binary_type(BinaryType::Arraybuffer);
+        let request = codec.encode(&LatencyTest::InitialRequest {
+            magic: MAGIC_NUMBER,
+            id: 0,
+        });
+
+        let onopen = {
+            let (socket, request) = (socket.clone(), request.clone());
+            Closure::<dyn FnMut()>::new(move || send_frame(&socket, &request, Transport::Binary))
+        };
+        socket.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+        onopen.forget();
+
+        let onmessage = {
+            let (socket, codec) = (socket.clone(), codec.clone());
+            let latencies = js_sys::Array::new();
+            Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
+                let Some(bytes) = message_bytes(e.data()) else {
+                    return;
+                };
+                let Some((decoded, trailer)) = decode_frame(&*codec, &bytes) else {
+                    return;
+                };
+                let Some(now) = now_ms() else {
+                    return;
+                };
+                match client_step(decoded.into_owned(), now) {
+                    ClientStep::Reply(reply) => {
+                        let mut bytes = codec.encode(&reply);
+                        bytes.extend(trailer);
+                        send_frame(&socket, &bytes, Transport::Binary);
+                    }
+                    ClientStep::Complete { result, .. } => {
+                        latencies.push(&result.latency_ms.into());
+                        if latencies.length() >= probes {
+                            let _ = socket.close();
+                            let _ = resolve.call1(&JsValue::NULL, &latencies);
+                        } else {
+                            send_frame(&socket, &request, Transport::Binary);
+                        }
+                    }
+                    ClientStep::Unexpected(_) => {}
+                }
+            })
+        };
+        socket.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        onmessage.forget();
+
+        // Once the promise has resolved, later rejections are ignored
+        let onerror = {
+            let fail = fail.clone();
+            Closure::<dyn FnMut()>::new(move || fail("Connection failed"))
+        };
+        socket.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+        onerror.forget();
+        let onclose = {
+            let fail = fail.clone();
+            Closure::<dyn FnMut()>::new(move || fail("Connection closed"))
+        };
+        socket.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+        onclose.forget();
+
+        if let Some(window) = web_sys::window() {
+            let timeout = Closure::once_into_js(move || {
+                fail("Timed out");
+                let _ = socket.close();
+            });
+            let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(
+                timeout.unchecked_ref(),
+                ranking::probe_timeout_ms(probes),
+            );
+        }
+    })
+}
+
+/// The finest latency this client can measure, in ms: the resolution of
+/// `performance.now()` (coarsened by some browsers), but never finer than
+/// the 1ms of the handshake's timestamps. Latencies below it read as 0ms or
+/// a multiple of it, so a UI should warn when it's large. Probed the first
+/// time it's asked for (clients never ask), by busy-waiting on the clock for
+/// at most a quarter of a second.
+#[wasm_bindgen]
+pub fn timer_resolution_ms() -> f64 {
+    static RESOLUTION: OnceLock<f64> = OnceLock::new();
+    *RESOLUTION.get_or_init(|| {
+        let probed = resolution::probe_resolution(performance_now, js_sys::Date::now);
+        let resolution_ms = resolution::effective_resolution_ms(probed);
+        if resolution_ms > resolution::TIMESTAMP_RESOLUTION_MS {
+            diag!(WARN, "Clock resolution is {resolution_ms}ms, finer latencies can't be measured");
+        }
+        resolution_ms
+    })
+}
+
+/// Probes each server in turn (`probes` handshakes apiece) and returns them
+/// ranked by median latency, fastest first. Servers that couldn't be
+/// measured are listed last, with an `error`.
+#[wasm_bindgen]
+pub async fn rank_servers(urls: Vec<String>, probes: u32) -> Vec<RankedServer> {
+    let probes = probes.max(1);
+    let mut outcomes = Vec::with_capacity(urls.len());
+    for url in urls {
+        let probed = probe_server(&url, Rc::new(BinaryCodec), probes);
+        let outcome: ProbeOutcome = match JsFuture::from(probed).await {
+            Ok(latencies) => Ok(js_sys::Array::from(&latencies)
+                .iter()
+                .filter_map(|latency| latency.as_f64())
+                .collect()),
+            Err(e) => Err(e.as_string().unwrap_or_else(|| "Probe failed".to_string())),
+        };
+        outcomes.push((url, outcome));
+    }
+    ranking::rank(outcomes)
 }
 
 #[wasm_bindgen]
 impl LatencyClient {
     #[wasm_bindgen(constructor)]
     pub fn new(url: String) -> Self {
+        logging::init_tracing();
+        panics::install_hook(report_client_error);
+        let tab_id = (js_sys::Math::random() * u64::MAX as f64) as u64;
         Self {
-            inner: Rc::new(RefCell::new(LatencyClientInner {
-                status: ConnectionStatus::New,
-                socket: None,
-                url,
-            })),
+            inner: Rc::new(RefCell::new(LatencyClientInner::new(url, tab_id))),
         }
     }
 
+    /// Send frames as base64 text rather than binary. Slower, but survives
+    /// proxies that mangle binary WebSocket frames. WebTransport is always
+    /// binary.
+    #[wasm_bindgen]
+    pub fn set_text_transport(&mut self, enabled: bool) {
+        self.inner.borrow_mut().transport = if enabled {
+            Transport::Text
+        } else {
+            Transport::Binary
+        };
+    }
+
+    /// Connects to the server, retrying (see `set_connect_attempts`) if the
+    /// connection can't be made. Once retries run out, the failure is passed
+    /// to `window.reportClientError`.
     #[wasm_bindgen]
     pub fn connect_socket(&mut self) {
+        self.inner.borrow_mut().connect_failures = Some(0);
+        self.try_connect();
+    }
+
+    fn try_connect(&mut self) {
         match self.connect() {
-            Ok(_) => log("Connection requested."),
-            Err(e) => log(&format!("Error connecting: {:?}", e)),
+            Ok(_) => diag!(INFO, "Connection requested."),
+            Err(WebSocketError::CreationError) => initial_connect_failed(&self.inner),
+            Err(e) => diag!(WARN, "Error connecting: {:?}", e),
         }
     }
 
+    /// The shared secret for a server started with `AUTH_TOKEN`, sent when
+    /// connecting. Browsers can't set headers on a WebSocket, so it goes in
+    /// the URL.
+    #[wasm_bindgen]
+    pub fn set_auth_token(&mut self, token: Option<String>) {
+        self.inner.borrow_mut().auth_token = token.filter(|token| !token.is_empty());
+    }
+
+    /// The server's WebTransport endpoint (`https://host:port/`), and the
+    /// hex SHA-256 of its certificate if self-signed, as advertised at
+    /// `/webtransport`. Where the browser supports it, WebTransport is tried
+    /// before the WebSocket, which remains the fallback. Returns false, and
+    /// leaves WebTransport off, if the hash isn't valid.
+    #[wasm_bindgen]
+    pub fn set_webtransport(
+        &mut self,
+        url: Option<String>,
+        certificate_hash: Option<String>,
+    ) -> bool {
+        let certificate_hash = match certificate_hash.as_deref() {
+            Some(hex) => match webtransport::parse_certificate_hash(hex) {
+                Some(hash) => Some(hash),
+                None => {
+                    diag!(WARN, "Invalid WebTransport certificate hash: {hex}");
+                    self.inner.borrow_mut().webtransport = None;
+                    return false;
+                }
+            },
+            None => None,
+        };
+        self.inner.borrow_mut().webtransport = url.map(|url| WebTransportEndpoint {
+            url,
+            certificate_hash,
+        });
+        true
+    }
+
+    /// Time how long each handshake frame spends crossing the JS/wasm
+    /// boundary (copying in and decoding, encoding and sending), so it can be
+    /// told apart from network latency. Enabling it starts a fresh tally.
+    #[wasm_bindgen]
+    pub fn set_boundary_instrumentation(&mut self, enabled: bool) {
+        let mut inner = self.inner.borrow_mut();
+        inner.instrument_boundary = enabled;
+        inner.boundary = BoundaryOverhead::default();
+    }
+
+    /// How much of each measured latency is JS/wasm boundary overhead, in
+    /// ms, with `set_boundary_instrumentation` on.
+    #[wasm_bindgen]
+    pub fn boundary_overhead_ms(&self) -> Option<f64> {
+        self.inner.borrow().boundary.latency_overhead_ms()
+    }
+
+    /// Mean time to copy in and decode a handshake frame, in ms.
+    #[wasm_bindgen]
+    pub fn boundary_decode_ms(&self) -> Option<f64> {
+        self.inner.borrow().boundary.mean_decode_ms()
+    }
+
+    /// Mean time to encode and send a handshake reply, in ms.
+    #[wasm_bindgen]
+    pub fn boundary_send_ms(&self) -> Option<f64> {
+        self.inner.borrow().boundary.mean_send_ms()
+    }
+
+    /// Connection attempts, including the first, before giving up.
+    #[wasm_bindgen]
+    pub fn set_connect_attempts(&mut self, attempts: u32) {
+        self.inner.borrow_mut().connect_retry.set_attempts(attempts);
+    }
+
+    /// Delay between connection attempts.
+    #[wasm_bindgen]
+    pub fn set_connect_retry_ms(&mut self, delay_ms: u32) {
+        self.inner.borrow_mut().connect_retry.set_delay_ms(delay_ms);
+    }
+
+    /// Idle time before a keepalive is sent to the server. Zero disables
+    /// keepalives.
+    #[wasm_bindgen]
+    pub fn set_keepalive_ms(&mut self, interval_ms: u32) {
+        self.inner.borrow_mut().keepalive.set_interval_ms(interval_ms);
+    }
+
+    /// How long a keepalive may go unanswered before the connection is
+    /// closed as dead.
+    #[wasm_bindgen]
+    pub fn set_keepalive_timeout_ms(&mut self, timeout_ms: u32) {
+        self.inner.borrow_mut().keepalive.set_timeout_ms(timeout_ms);
+    }
+
+    /// Round trip of the most recent keepalive. Not a latency measurement.
+    #[wasm_bindgen]
+    pub fn keepalive_rtt_ms(&self) -> Option<f64> {
+        self.inner.borrow().keepalive.last_rtt_ms().map(|rtt| rtt as f64)
+    }
+
     fn connect(&mut self) -> Result<(), WebSocketError> {
         // Precondition testing
         if self.inner.borrow().url.is_empty() {
@@ -78,94 +1079,64 @@ impl LatencyClient {
         if self.inner.borrow().socket.is_some() {
             return Err(WebSocketError::AlreadyExists);
         }
-        log(&format!("Connecting to: {}", self.inner.borrow().url));
-        let conn_result = WebSocket::new(&self.inner.borrow().url);
+        let webtransport = self.inner.borrow().webtransport.clone();
+        match webtransport.filter(|_| webtransport::is_supported()) {
+            Some(endpoint) => {
+                self.inner.borrow_mut().status = ConnectionStatus::Negotiating;
+                let inner = self.inner.clone();
+                wasm_bindgen_futures::spawn_local(connect_webtransport(inner, endpoint));
+                Ok(())
+            }
+            None => self.connect_websocket(),
+        }
+    }
+
+    fn connect_websocket(&mut self) -> Result<(), WebSocketError> {
+        let url = {
+            let inner = self.inner.borrow();
+            connect_url(&inner, &inner.url)
+        };
+        diag!(INFO, "Connecting to: {}", logging::redact_url(&url));
+        let conn_result = WebSocket::new(&url);
         if conn_result.is_err() {
-            log(&format!("Error connecting: {:?}", conn_result));
+            diag!(WARN, "Error connecting: {:?}", conn_result);
             return Err(WebSocketError::CreationError);
         }
-        self.inner.borrow_mut().socket = Some(conn_result.unwrap());
-        if let Some(socket) = &self.inner.borrow().socket {
-            socket.set_binary_type(BinaryType::Arraybuffer);
+        let socket = conn_result.unwrap();
+        self.inner.borrow_mut().socket = Some(Conduit::WebSocket(socket.clone()));
+        socket.set_binary_type(BinaryType::Arraybuffer);
 
-            // Wire up on_close
-            let inner = self.inner.clone();
-            let onclose_callback = Closure::<dyn FnMut(_)>::new(move |_e: ErrorEvent| {
-                inner.borrow_mut().socket = None;
-                inner.borrow_mut().status = ConnectionStatus::New;
-            });
-            socket.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
-            onclose_callback.forget();
-
-            // Wire up on_error
-            let inner = self.inner.clone();
-            let onerror_callback = Closure::<dyn FnMut(_)>::new(move |e: ErrorEvent| {
-                log(&format!("Error Received: {e:?}"));
-                inner.borrow_mut().socket = None;
-                inner.borrow_mut().status = ConnectionStatus::New;
-            });
-            socket.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
-            onerror_callback.forget();
-
-            // Wire up on_open
-            let inner = self.inner.clone();
-            let onopen_callback = Closure::<dyn FnMut(_)>::new(move |_e: ErrorEvent| {
-                //log("Open Received");
-                inner.borrow_mut().status = ConnectionStatus::Connected;
-            });
-            socket.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
-            onopen_callback.forget();
-
-            // Wire up on message
-            let onmsg_inner = self.inner.clone();
-            let onmessage_callback = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
-                log("Message Received");
-                if let Ok(abuf) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
-                    let array = js_sys::Uint8Array::new(&abuf);
-                    let raw = array.to_vec();
-                    let decoded = LatencyTest::decode(&raw).unwrap();
-                    match decoded {
-                        LatencyTest::FirstReply { magic, server_time } => {
-                            assert_eq!(magic, MAGIC_NUMBER);
-                            let reply = LatencyTest::FirstResponse {
-                                magic: MAGIC_NUMBER,
-                                server_time,
-                                client_time: unix_now_ms(),
-                            };
-                            if let Some(socket) = &onmsg_inner.borrow().socket {
-                                socket.send_with_u8_array(&reply.encode()).unwrap();
-                            }
-                        }
-                        LatencyTest::SecondReply {
-                            magic,
-                            server_time,
-                            client_time,
-                            server_ack_time,
-                        } => {
-                            assert_eq!(magic, MAGIC_NUMBER);
-                            let final_result = LatencyTest::Final {
-                                magic: MAGIC_NUMBER,
-                                server_time,
-                                client_time,
-                                server_ack_time,
-                                client_ack_time: unix_now_ms(),
-                            };
-                            let (average, server, client) = final_result.calculate_latency();
-                            log(&format!(
-                                "Average: {}ms, Server: {}ms, Client: {}ms",
-                                average, server, client
-                            ));
-                            report_latency(average, server, client);
-                        }
-                        _ => {
-                            log(&format!("Received: {:?}", decoded));
-                        }
-                    }
-                }
-            });
-            socket.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
-            onmessage_callback.forget();
-        }
+        // Wire up on_close
+        let inner = self.inner.clone();
+        let onclose_callback = Closure::<dyn FnMut(_)>::new(move |e: CloseEvent| {
+            on_server_close(&inner, e.code())
+        });
+        socket.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+        onclose_callback.forget();
+
+        // Wire up on_error
+        // An error is always followed by a close, which decides what to do
+        let onerror_callback = Closure::<dyn FnMut(_)>::new(move |e: ErrorEvent| {
+            diag!(WARN, "Error Received: {e:?}")
+        });
+        socket.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
+        onerror_callback.forget();
+
+        // Wire up on_open
+        let inner = self.inner.clone();
+        let onopen_callback = Closure::<dyn FnMut(_)>::new(move |_e: ErrorEvent| {
+            on_open(&inner)
+        });
+        socket.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
+        onopen_callback.forget();
+
+        // Wire up on message
+        let inner = self.inner.clone();
+        let onmessage_callback = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
+            on_frame(&inner, || message_bytes(e.data()))
+        });
+        socket.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
+        onmessage_callback.forget();
 
         Ok(())
     }
@@ -177,12 +1148,615 @@ impl LatencyClient {
 
     #[wasm_bindgen]
     pub fn start_latency_run(&self) {
-        let bytes = LatencyTest::InitialRequest {
+        let mut inner = self.inner.borrow_mut();
+        let request = inner.initial_request();
+        if let Some(socket) = &inner.socket {
+            socket.send(&inner.codec.encode(&request), inner.transport);
+        }
+    }
+
+    /// Starts a measurement run using a named profile (`quick`, `thorough`
+    /// or `continuous`), replacing any run in progress.
+    #[wasm_bindgen]
+    pub fn run_profile(&mut self, name: &str) {
+        match Profile::from_name(name) {
+            Some(profile) => self.start_run(profile.params(), None),
+            None => diag!(WARN, "Unknown profile: {name}"),
+        }
+    }
+
+    /// Trades speed for accuracy in one setting: `fast` or `accurate` (see
+    /// `Precision` for exactly what each changes). Sets the outlier
+    /// threshold, as `set_outlier_k`, and the run `run_precision` starts.
+    /// Returns false, changing nothing, for an unknown mode.
+    #[wasm_bindgen]
+    pub fn set_precision(&mut self, mode: &str) -> bool {
+        let Some(precision) = Precision::from_name(mode) else {
+            diag!(WARN, "Unknown precision mode: {mode}");
+            return false;
+        };
+        let mut inner = self.inner.borrow_mut();
+        inner.precision = precision;
+        inner.outlier_k = precision.outlier_k();
+        true
+    }
+
+    /// Feeds a `Final` with these timestamps through the result path as if a
+    /// handshake had just completed: the latency is reported to the page (and
+    /// to the server, if connected) and counts towards any run. For replaying
+    /// recorded handshakes deterministically, without a server. Only built
+    /// with the `debug` feature.
+    #[cfg(feature = "debug")]
+    #[wasm_bindgen]
+    pub fn inject_final(
+        &mut self,
+        server_time: u64,
+        client_time: u64,
+        server_ack_time: u64,
+        client_ack_time: u64,
+    ) -> bool {
+        let timestamps = [server_time, client_time, server_ack_time, client_ack_time];
+        match replay::recorded_final(timestamps.map(u128::from)) {
+            Ok((last, result)) => {
+                let now = client_ack_time.into();
+                on_result(&self.inner, last, result, now);
+                true
+            }
+            Err(e) => {
+                diag!(WARN, "Can't replay handshake: {e}");
+                false
+            }
+        }
+    }
+
+    /// Starts a measurement run in the mode set by `set_precision` (`fast`
+    /// by default), replacing any run in progress.
+    #[wasm_bindgen]
+    pub fn run_precision(&mut self) {
+        let params = self.inner.borrow().precision.params();
+        self.start_run(params, None);
+    }
+
+    /// Probes continuously for `duration_ms`, ending any run in progress,
+    /// then stops and passes the run's mean, jitter, 95th percentile, floor
+    /// and loss (as a fraction) to `window.reportRunSummary`, if the page
+    /// defines it. Each result is reported as usual along the way.
+    #[wasm_bindgen]
+    pub fn run_for(&mut self, duration_ms: u32) {
+        let params = Profile::Continuous.params();
+        self.start_run(params, Some(duration_ms));
+    }
+
+    /// Stops the current measurement run or load test, if any.
+    #[wasm_bindgen]
+    pub fn stop_run(&mut self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.timer = None;
+        inner.run = None;
+        inner.offered = None;
+        inner.offered_timer = None;
+        inner.release_tab_turn();
+    }
+
+    /// Measures latency under an offered load: probes sent at `pps` (rather
+    /// than one at a time) for `duration_s`, ending any run in progress.
+    /// Probes the browser can't send on time, or that would queue behind a
+    /// backed up connection, are skipped; `load_test_report` gives the rate
+    /// achieved, and how latency evolved. Results aren't passed to
+    /// `window.reportLatency` or reported to the server meanwhile.
+    #[wasm_bindgen]
+    pub fn start_load_test(&mut self, pps: u32, duration_s: u32) -> bool {
+        self.stop_run();
+        let (Some(window), Some(now)) = (web_sys::window(), now_ms()) else {
+            return false;
+        };
+        if self.inner.borrow().socket.is_none() {
+            return false;
+        }
+        let inner = self.inner.clone();
+        let tick = Closure::<dyn FnMut()>::new(move || {
+            let mut inner = inner.borrow_mut();
+            let inner = &mut *inner;
+            let (Some(offered), Some(socket), Some(now)) =
+                (inner.offered.as_mut(), inner.socket.clone(), now_ms())
+            else {
+                return;
+            };
+            if offered.is_finished(now) {
+                let report = offered.report(now);
+                diag!(
+                    INFO,
+                    "Load test complete: {:.1} of {}pps achieved",
+                    report.achieved_pps,
+                    report.offered_pps
+                );
+                if let (Some(window), Some(timer)) = (web_sys::window(), &inner.offered_timer) {
+                    window.clear_interval_with_handle(timer.handle);
+                }
+                return;
+            }
+            inner.send_offered_probes(&socket, now);
+        });
+        let tick_ms = (1000 / pps.max(1)).clamp(1, OFFERED_TICK_MS);
+        let handle = window.set_interval_with_callback_and_timeout_and_arguments_0(
+            tick.as_ref().unchecked_ref(),
+            tick_ms as i32,
+        );
+        match handle {
+            Ok(handle) => {
+                let mut inner = self.inner.borrow_mut();
+                inner.offered = Some(OfferedLoad::new(pps, duration_s, now));
+                inner.offered_timer = Some(Timer {
+                    handle,
+                    _tick: tick,
+                });
+                true
+            }
+            Err(e) => {
+                diag!(ERROR, "Unable to schedule probes: {e:?}");
+                false
+            }
+        }
+    }
+
+    /// The current (or last) load test's results so far, until the run is
+    /// stopped.
+    #[wasm_bindgen]
+    pub fn load_test_report(&self) -> Option<LoadTestReport> {
+        let inner = self.inner.borrow();
+        Some(inner.offered.as_ref()?.report(now_ms()?))
+    }
+
+    /// Coordinate with the client in other tabs (over a `BroadcastChannel`),
+    /// so only one tab probes at a time, rather than each contending for the
+    /// link. Runs in the other tabs wait until the probing tab stops.
+    #[wasm_bindgen]
+    pub fn set_tab_coordination(&mut self, enabled: bool) {
+        let mut inner = self.inner.borrow_mut();
+        if !enabled {
+            inner.release_tab_turn();
+            inner.tab_channel = None;
+            return;
+        }
+        if inner.tab_channel.is_some() {
+            return;
+        }
+        let channel = match BroadcastChannel::new(tabs::CHANNEL_NAME) {
+            Ok(channel) => channel,
+            Err(e) => {
+                diag!(WARN, "Unable to coordinate with other tabs: {e:?}");
+                return;
+            }
+        };
+        let onmsg_inner = self.inner.clone();
+        let onmessage = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
+            let message = e.data().as_string();
+            let message = message.as_deref().and_then(TabMessage::decode);
+            if let Some((message, now)) = message.zip(now_ms()) {
+                onmsg_inner.borrow_mut().tabs.receive(message, now);
+            }
+        });
+        channel.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        inner.tab_channel = Some(TabChannel {
+            channel,
+            _onmessage: onmessage,
+        });
+    }
+
+    /// Number of probes in the current run that received no reply in time.
+    #[wasm_bindgen]
+    pub fn lost_probes(&self) -> u32 {
+        self.inner.borrow().run.as_ref().map_or(0, RunState::lost)
+    }
+
+    /// Replies the server numbered (with `SEQUENCE_REPLIES`) that never
+    /// arrived on this connection, as told by gaps in their numbers. Unlike
+    /// `lost_probes`, counted as soon as a later reply arrives. Always zero if
+    /// the server doesn't number its replies.
+    #[wasm_bindgen]
+    pub fn lost_replies(&self) -> u32 {
+        self.inner.borrow().replies.lost()
+    }
+
+    /// Replies that arrived after a higher-numbered one: late, not lost.
+    #[wasm_bindgen]
+    pub fn reordered_replies(&self) -> u32 {
+        self.inner.borrow().replies.reordered()
+    }
+
+    /// Number of probes in the current run that completed, including warmup.
+    #[wasm_bindgen]
+    pub fn completed_probes(&self) -> u32 {
+        self.inner.borrow().run.as_ref().map_or(0, RunState::completed)
+    }
+
+    /// Reject outliers from runs' mean and jitter: results more than `k`
+    /// median absolute deviations from the median of recent results, such
+    /// as a GC pause might cause. They're still reported, and counted by
+    /// `outlier_count`. Zero (the default) keeps every result. Applies from
+    /// the next run.
+    #[wasm_bindgen]
+    pub fn set_outlier_k(&mut self, k: f64) {
+        self.inner.borrow_mut().outlier_k = (k > 0.0).then_some(k);
+    }
+
+    /// Mean latency of the current run, excluding outliers.
+    #[wasm_bindgen]
+    pub fn mean_latency_ms(&self) -> Option<f64> {
+        self.inner.borrow().run.as_ref()?.stats().mean_ms()
+    }
+
+    /// Jitter of the current run (the mean difference between consecutive
+    /// latencies), excluding outliers.
+    #[wasm_bindgen]
+    pub fn jitter_ms(&self) -> Option<f64> {
+        self.inner.borrow().run.as_ref()?.stats().jitter_ms()
+    }
+
+    /// The offset of this machine's clock from the server's (local minus
+    /// server, in ms), where both are synchronized externally, such as by
+    /// PTP or NTP. With it, each handshake's delay is also measured in each
+    /// direction (see `upstream_ms` and `downstream_ms`), rather than only
+    /// as half the round trip. `None` turns this off.
+    #[wasm_bindgen]
+    pub fn set_external_clock_offset(&mut self, offset_ms: Option<f64>) {
+        let mut inner = self.inner.borrow_mut();
+        inner.clock_offset_ms = offset_ms.filter(|offset| offset.is_finite());
+        inner.one_way = None;
+    }
+
+    /// Client to server delay of the latest handshake, with an external
+    /// clock offset set.
+    #[wasm_bindgen]
+    pub fn upstream_ms(&self) -> Option<f64> {
+        self.inner.borrow().one_way.map(|delay| delay.upstream_ms)
+    }
+
+    /// Server to client delay of the latest handshake, with an external
+    /// clock offset set.
+    #[wasm_bindgen]
+    pub fn downstream_ms(&self) -> Option<f64> {
+        self.inner.borrow().one_way.map(|delay| delay.downstream_ms)
+    }
+
+    /// Results in the current run rejected as outliers.
+    #[wasm_bindgen]
+    pub fn outlier_count(&self) -> u32 {
+        self.inner.borrow().run.as_ref().map_or(0, |run| run.stats().outliers())
+    }
+
+    /// Reported results whose server leg the server (started with
+    /// `ECHO_REPORTS`) measured differently: a sign of clock trouble, or of
+    /// timestamps altered in transit. Always zero if the server doesn't echo.
+    #[wasm_bindgen]
+    pub fn report_mismatches(&self) -> u32 {
+        self.inner.borrow().report_mismatches
+    }
+
+    /// Consecutive lost probes before probing pauses.
+    #[wasm_bindgen]
+    pub fn set_breaker_threshold(&mut self, threshold: u32) {
+        self.inner.borrow_mut().breaker.set_failure_threshold(threshold);
+    }
+
+    /// How long probing pauses for, before a single trial probe.
+    #[wasm_bindgen]
+    pub fn set_breaker_cooldown_ms(&mut self, cooldown_ms: u32) {
+        self.inner.borrow_mut().breaker.set_cooldown_ms(cooldown_ms);
+    }
+
+    /// The circuit breaker state: `closed`, `open` or `half-open`.
+    #[wasm_bindgen]
+    pub fn breaker_state(&self) -> String {
+        match self.inner.borrow().breaker.state() {
+            BreakerState::Closed => "closed",
+            BreakerState::Open { .. } => "open",
+            BreakerState::HalfOpen => "half-open",
+        }
+        .to_string()
+    }
+
+    /// Loads the link in one direction (`download` or `upload`) for
+    /// `duration_ms`, while probing continues. Results measured meanwhile are
+    /// reported through `window.reportLoadedLatency` rather than
+    /// `window.reportLatency`. The server only streams a download to a
+    /// session that has completed a handshake, and refuses one asked for
+    /// sooner.
+    #[wasm_bindgen]
+    pub fn start_load(&mut self, direction: &str, duration_ms: u32) -> bool {
+        let Some(direction) = LoadDirection::from_name(direction) else {
+            diag!(WARN, "Unknown load direction: {direction}");
+            return false;
+        };
+        let duration_ms = duration_ms.min(shared_data::MAX_LOAD_DURATION_MS);
+        let Some(now) = now_ms() else {
+            return false;
+        };
+        let mut inner = self.inner.borrow_mut();
+        let Some(socket) = inner.socket.clone() else {
+            return false;
+        };
+        let request = LatencyTest::Load {
             magic: MAGIC_NUMBER,
+            id: 0,
+            direction,
+            duration_ms,
+        };
+        socket.send(&inner.codec.encode(&request), inner.transport);
+        let until = now + duration_ms as u128;
+        inner.load = Some((direction, until));
+        inner.upload_timer = None;
+        if direction == LoadDirection::Upload {
+            inner.upload_timer = start_upload(socket, &*inner.codec, inner.transport, until);
+        }
+        true
+    }
+
+    /// Labels reported results with `key` (such as ISP, region or device).
+    /// Returns false, leaving the metadata unchanged, if this would exceed
+    /// the metadata size limit.
+    #[wasm_bindgen]
+    pub fn set_metadata(&mut self, key: String, value: String) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        let mut metadata = inner.metadata.clone();
+        metadata.insert(key, value);
+        if let Err(e) = check_metadata(&metadata) {
+            diag!(WARN, "Unable to set metadata: {e}");
+            return false;
+        }
+        inner.metadata = metadata;
+        true
+    }
+
+    #[wasm_bindgen]
+    pub fn clear_metadata(&mut self) {
+        self.inner.borrow_mut().metadata.clear();
+    }
+
+    /// Tags reported results with a measurement campaign, so the server can
+    /// group (and summarize) the results of many clients measuring together.
+    /// Only reports carry it; the probes themselves stay small.
+    #[wasm_bindgen]
+    pub fn set_campaign_id(&mut self, campaign_id: Option<u64>) {
+        self.inner.borrow_mut().campaign_id = campaign_id;
+    }
+
+    /// Adapt the probe interval to jitter in subsequent runs: probe less
+    /// often while latency is steady, and more often while it fluctuates.
+    #[wasm_bindgen]
+    pub fn set_adaptive(&mut self, enabled: bool) {
+        self.inner.borrow_mut().adaptive_enabled = enabled;
+    }
+
+    /// Shortest interval between adaptive probes.
+    #[wasm_bindgen]
+    pub fn set_adaptive_min_ms(&mut self, min_ms: u32) {
+        self.inner.borrow_mut().adaptive.min_ms = min_ms;
+    }
+
+    /// Interval between adaptive probes at typical jitter.
+    #[wasm_bindgen]
+    pub fn set_adaptive_base_ms(&mut self, base_ms: u32) {
+        self.inner.borrow_mut().adaptive.base_ms = base_ms;
+    }
+
+    /// Longest interval between adaptive probes.
+    #[wasm_bindgen]
+    pub fn set_adaptive_max_ms(&mut self, max_ms: u32) {
+        self.inner.borrow_mut().adaptive.max_ms = max_ms;
+    }
+
+    fn start_run(&mut self, params: RunParams, duration_ms: Option<u32>) {
+        self.stop_run();
+        let Some(window) = web_sys::window() else {
+            diag!(ERROR, "No window available to schedule probes");
+            return;
+        };
+
+        let inner = self.inner.clone();
+        let tick = Closure::<dyn FnMut()>::new(move || {
+            let mut inner = inner.borrow_mut();
+            let inner = &mut *inner;
+            let Some(run) = inner.run.as_mut() else {
+                return;
+            };
+            let Some(now) = now_ms() else {
+                return;
+            };
+            // A bounded run ends on time, connected or not
+            if !run.end_if_due(now) {
+                if inner.status != ConnectionStatus::Connected {
+                    return;
+                }
+                if run.expire(now) && inner.breaker.record_failure(now) {
+                    diag!(WARN, "Server unreachable, pausing probes");
+                }
+                if !inner.breaker.allow(now) {
+                    return;
+                }
+                if let Some(channel) = &inner.tab_channel {
+                    if !inner.tabs.my_turn(now) {
+                        // Another tab is probing
+                        return;
+                    }
+                    channel.post(TabMessage::Active(inner.tabs.id()));
+                }
+                if run.tick(now) {
+                    let request = inner.initial_request();
+                    if let Some(socket) = &inner.socket {
+                        socket.send(&inner.codec.encode(&request), inner.transport);
+                    }
+                    return;
+                }
+            }
+            if run.is_finished() {
+                diag!(INFO, "Measurement run complete");
+                let summary = run.is_bounded().then(|| run.summary());
+                inner.release_tab_turn();
+                if let (Some(window), Some(timer)) = (web_sys::window(), &inner.timer) {
+                    window.clear_interval_with_handle(timer.handle);
+                }
+                if let Some(s) = summary {
+                    let reported =
+                        report_run_summary(s.mean_ms, s.jitter_ms, s.p95_ms, s.floor_ms, s.loss);
+                    if let Err(e) = reported {
+                        diag!(DEBUG, "Run summary not reported: {e:?}");
+                    }
+                }
+            }
+        });
+        // Adaptive runs tick at the shortest interval, and skip ticks until
+        // the next probe is due
+        let (run, tick_ms) = {
+            let inner = self.inner.borrow();
+            let (run, tick_ms) = if inner.adaptive_enabled {
+                let adaptive = inner.adaptive;
+                (RunState::adaptive(params, adaptive), adaptive.min_ms.max(1))
+            } else {
+                (RunState::new(params), params.interval_ms)
+            };
+            let mut run = run.with_outlier_k(inner.outlier_k);
+            if let (Some(duration_ms), Some(now)) = (duration_ms, now_ms()) {
+                run = run.with_deadline(now + duration_ms as u128);
+            }
+            (run, tick_ms)
+        };
+        let handle = window.set_interval_with_callback_and_timeout_and_arguments_0(
+            tick.as_ref().unchecked_ref(),
+            tick_ms as i32,
+        );
+        match handle {
+            Ok(handle) => {
+                let mut inner = self.inner.borrow_mut();
+                inner.run = Some(run);
+                inner.timer = Some(Timer {
+                    handle,
+                    _tick: tick,
+                });
+            }
+            Err(e) => diag!(ERROR, "Unable to schedule probes: {e:?}"),
+        }
+    }
+}
+
+impl LatencyClient {
+    /// Encodes frames with `codec` rather than the native binary format, to
+    /// talk to a server using the same one.
+    pub fn set_codec(&mut self, codec: Rc<dyn Codec>) {
+        self.inner.borrow_mut().codec = codec;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shared_data::LatencyTestError;
+
+    /// A trivial alternate format: the binary encoding, behind a marker byte.
+    struct MarkedCodec;
+
+    impl Codec for MarkedCodec {
+        fn encode(&self, message: &LatencyTest) -> Vec<u8> {
+            [b"M".as_slice(), &message.encode()].concat()
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<LatencyTest, LatencyTestError> {
+            let binary = bytes.strip_prefix(b"M").ok_or(LatencyTestError::Read)?;
+            LatencyTest::decode(binary)
+        }
+    }
+
+    #[test]
+    fn frames_are_decoded_with_the_clients_codec() {
+        let reply = LatencyTest::FirstReply {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            server_time: 1000,
+        };
+        let mut bytes = MarkedCodec.encode(&reply);
+        bytes.extend([9, 8, 7]);
+        let (decoded, trailer) = decode_frame(&MarkedCodec, &bytes).unwrap();
+        assert_eq!(decoded, LatencyTestRef::Control(reply.clone()));
+        assert_eq!(trailer, &[9, 8, 7]);
+        // Not in the client's format
+        assert!(decode_frame(&MarkedCodec, &reply.encode()).is_none());
+
+        // Numbered replies are binary, around a frame in the codec's format
+        let sequenced = LatencyTest::Sequenced {
+            magic: MAGIC_NUMBER,
+            id: 0,
+            seq: 4,
+            frame: MarkedCodec.encode(&reply),
+        };
+        let bytes = sequenced.encode();
+        let (decoded, _) = decode_frame(&MarkedCodec, &bytes).unwrap();
+        let LatencyTestRef::Control(LatencyTest::Sequenced { frame, .. }) = decoded else {
+            panic!("Expected a Sequenced, got {decoded:?}");
+        };
+        let (decoded, _) = decode_frame(&MarkedCodec, &frame).unwrap();
+        assert_eq!(decoded, LatencyTestRef::Control(reply));
+    }
+
+    #[test]
+    fn replies_are_sent_before_returning() {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let inner = Rc::new(RefCell::new(LatencyClientInner::new(String::new(), 0)));
+        inner.borrow_mut().socket = Some(Conduit::Recorder(sent.clone()));
+        let mut bytes = LatencyTest::FirstReply {
+            magic: MAGIC_NUMBER,
+            id: 7,
+            server_time: 1000,
         }
         .encode();
-        if let Some(socket) = &self.inner.borrow().socket {
-            socket.send_with_u8_array(&bytes).unwrap();
+        bytes.extend([9, 8, 7]);
+        on_frame(&inner, || Some(bytes));
+
+        // Already sent, not left to a task or timer
+        let sent = sent.borrow();
+        assert_eq!(sent.len(), 1);
+        let (response, trailer) = LatencyTest::decode_with_trailer(&sent[0]).unwrap();
+        assert!(matches!(
+            response,
+            LatencyTest::FirstResponse {
+                id: 7,
+                server_time: 1000,
+                ..
+            }
+        ));
+        assert_eq!(trailer, &[9, 8, 7]);
+    }
+
+    #[test]
+    fn load_test_probes_sent_together_are_all_recorded() {
+        // Diagnostics go to `tracing`, as there's no console to log to
+        let _guard = tracing::subscriber::set_default(tracing::subscriber::NoSubscriber::new());
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let socket = Conduit::Recorder(sent.clone());
+        let inner = Rc::new(RefCell::new(LatencyClientInner::new(String::new(), 0)));
+        let now = shared_data::unix_now_ms().unwrap();
+        // Four probes due at once, at 1000pps
+        let start = now - 3;
+        inner.borrow_mut().offered = Some(OfferedLoad::new(1000, 10, start));
+        inner.borrow_mut().send_offered_probes(&socket, start + 3);
+        let ids: Vec<u64> = sent
+            .borrow()
+            .iter()
+            .map(|bytes| LatencyTest::decode(bytes).unwrap().id())
+            .collect();
+        assert_eq!(ids.len(), 4);
+
+        // The server stamps them all in the same millisecond
+        for id in ids {
+            let reply = LatencyTest::SecondReply {
+                magic: MAGIC_NUMBER,
+                id,
+                server_time: now - 20,
+                client_time: now - 10,
+                server_ack_time: now - 5,
+            };
+            on_frame(&inner, || Some(reply.encode()));
         }
+        let report = inner.borrow().offered.as_ref().unwrap().report(now);
+        assert_eq!((report.sent, report.completed, report.unanswered), (4, 4, 0));
     }
 }
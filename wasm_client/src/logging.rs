@@ -0,0 +1,127 @@
+//! Client diagnostics. These go through `tracing`, to a `tracing-wasm`
+//! subscriber installed when the first client is created, so they reach the
+//! browser console with their level (and show up in performance traces).
+//! Until a subscriber exists they fall back to a plain `console.log`.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::level_filters::LevelFilter;
+use tracing::Level;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = console, js_name = log)]
+    fn console_log(s: &str);
+}
+
+/// Filters from quietest to most verbose; `MAX_LEVEL` indexes into this.
+const FILTERS: [LevelFilter; 6] = [
+    LevelFilter::OFF,
+    LevelFilter::ERROR,
+    LevelFilter::WARN,
+    LevelFilter::INFO,
+    LevelFilter::DEBUG,
+    LevelFilter::TRACE,
+];
+
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(3);
+
+/// Sets the most verbose diagnostics the client emits: "off", "error",
+/// "warn", "info" (the default), "debug" or "trace". Returns `false`, leaving
+/// the level alone, if the name isn't recognized.
+#[wasm_bindgen]
+pub fn set_log_level(level: &str) -> bool {
+    let Some(index) = LevelFilter::from_str(level)
+        .ok()
+        .and_then(|filter| FILTERS.iter().position(|f| *f == filter))
+    else {
+        return false;
+    };
+    MAX_LEVEL.store(index, Ordering::Relaxed);
+    true
+}
+
+fn max_level() -> LevelFilter {
+    FILTERS[MAX_LEVEL.load(Ordering::Relaxed)]
+}
+
+pub(crate) fn enabled(level: Level) -> bool {
+    level <= max_level()
+}
+
+/// Installs the `tracing-wasm` subscriber, unless the page already has one.
+/// Filtering is left to `set_log_level`, so the level can change later.
+pub(crate) fn init_tracing() {
+    if tracing::dispatcher::has_been_set() {
+        return;
+    }
+    let config = tracing_wasm::WASMLayerConfigBuilder::new()
+        .set_max_level(Level::TRACE)
+        .build();
+    tracing_wasm::set_as_global_default_with_config(config);
+}
+
+/// Emits a diagnostic; use the `diag!` macro rather than calling this.
+pub(crate) fn emit(level: Level, message: &str) {
+    if !tracing::dispatcher::has_been_set() {
+        console_log(message);
+    } else if level == Level::ERROR {
+        tracing::error!("{message}");
+    } else if level == Level::WARN {
+        tracing::warn!("{message}");
+    } else if level == Level::INFO {
+        tracing::info!("{message}");
+    } else if level == Level::DEBUG {
+        tracing::debug!("{message}");
+    } else {
+        tracing::trace!("{message}");
+    }
+}
+
+/// `diag!(WARN, "format {args}")`: logs at the given `tracing::Level`, if
+/// it's within the configured level.
+macro_rules! diag {
+    ($level:ident, $($arg:tt)*) => {
+        if $crate::logging::enabled(tracing::Level::$level) {
+            $crate::logging::emit(tracing::Level::$level, &format!($($arg)*));
+        }
+    };
+}
+pub(crate) use diag;
+
+/// `url` without its query string, for logging: the query carries the
+/// session and auth tokens, and logs are shared more widely than either.
+pub(crate) fn redact_url(url: &str) -> &str {
+    url.split_once('?').map_or(url, |(base, _)| base)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn log_level_is_configurable() {
+        assert!(enabled(Level::INFO));
+        assert!(!enabled(Level::DEBUG));
+
+        assert!(set_log_level("TRACE"));
+        assert!(enabled(Level::TRACE));
+        assert!(set_log_level("warn"));
+        assert!(enabled(Level::ERROR));
+        assert!(!enabled(Level::INFO));
+        assert!(set_log_level("off"));
+        assert!(!enabled(Level::ERROR));
+
+        assert!(!set_log_level("chatty"));
+        assert!(!enabled(Level::ERROR));
+        assert!(set_log_level("info"));
+    }
+
+    #[test]
+    fn urls_are_logged_without_tokens() {
+        let url = "wss://example.com/ws?session=42&token=s3cret";
+        assert_eq!(redact_url(url), "wss://example.com/ws");
+        assert_eq!(redact_url("ws://localhost/ws"), "ws://localhost/ws");
+    }
+}
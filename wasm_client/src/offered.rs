@@ -0,0 +1,196 @@
+//! Measuring latency under an offered load: probes sent at a fixed rate, in
+//! probes per second, for a set time, rather than one at a time. This shows
+//! how the link copes as the rate is sustained, where queues build up and
+//! latency climbs. `OfferedLoad` only keeps the schedule and the results;
+//! the client sends the probes, on a timer, and feeds it their latencies.
+
+use wasm_bindgen::prelude::*;
+
+/// Most probes sent in one tick. A tick late by more than this many probe
+/// intervals (a throttled background tab, or a busy main thread) skips the
+/// missed probes rather than sending them in a burst, and they count
+/// against the achieved rate.
+const MAX_BURST: u32 = 16;
+/// Results are summarized over windows of this many ms.
+pub const WINDOW_MS: u128 = 1000;
+/// How long after the last probe its reply may still arrive and count.
+pub const DRAIN_MS: u128 = 2000;
+
+/// Probes sent at `pps` for a set duration, and the latencies measured.
+#[derive(Debug)]
+pub struct OfferedLoad {
+    pps: u32,
+    start: u128,
+    duration_ms: u128,
+    /// Probes whose time has come, sent or skipped.
+    scheduled: u64,
+    sent: u64,
+    /// Latencies, by the window they completed in.
+    windows: Vec<Vec<f64>>,
+}
+
+/// How an offered load went: the rate achieved, and latency over time.
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadTestReport {
+    pub offered_pps: u32,
+    /// Probes actually sent per second. Below `offered_pps` if probes had to
+    /// be skipped, because the browser couldn't send them on time or the
+    /// connection was backed up.
+    pub achieved_pps: f64,
+    pub sent: u32,
+    pub completed: u32,
+    /// Probes sent but not (yet) answered.
+    pub unanswered: u32,
+    /// Median latency in each `WINDOW_MS` window, in order; `NaN` for a
+    /// window with no results.
+    pub median_ms: Vec<f64>,
+    /// 95th percentile latency in each window, likewise.
+    pub p95_ms: Vec<f64>,
+}
+
+impl OfferedLoad {
+    pub fn new(pps: u32, duration_s: u32, start: u128) -> Self {
+        let duration_ms = duration_s.max(1) as u128 * 1000;
+        let windows = duration_ms.div_ceil(WINDOW_MS) as usize;
+        Self {
+            pps: pps.max(1),
+            start,
+            duration_ms,
+            scheduled: 0,
+            sent: 0,
+            windows: vec![Vec::new(); windows],
+        }
+    }
+
+    /// Called on every timer tick: the number of probes to send now to keep
+    /// to the rate, including any still owed once the duration is up. If
+    /// `backlogged` (the connection isn't keeping up), the probes due are
+    /// skipped instead.
+    pub fn due(&mut self, now: u128, backlogged: bool) -> u32 {
+        let elapsed = now.saturating_sub(self.start);
+        // Probe k is due k/pps seconds in
+        let total = self.duration_ms * self.pps as u128 / 1000;
+        let target = (elapsed * self.pps as u128 / 1000 + 1).min(total) as u64;
+        let owed = target.saturating_sub(self.scheduled);
+        self.scheduled = self.scheduled.max(target);
+        if backlogged {
+            return 0;
+        }
+        let send = owed.min(MAX_BURST as u64);
+        self.sent += send;
+        send as u32
+    }
+
+    /// Records a latency measured at `now`. Results arriving after the last
+    /// probe count towards the last window.
+    pub fn record(&mut self, now: u128, latency_ms: f64) {
+        let window = (now.saturating_sub(self.start) / WINDOW_MS) as usize;
+        let last = self.windows.len() - 1;
+        self.windows[window.min(last)].push(latency_ms);
+    }
+
+    /// True once every probe has been sent, and their replies have had
+    /// `DRAIN_MS` to arrive.
+    pub fn is_finished(&self, now: u128) -> bool {
+        now.saturating_sub(self.start) >= self.duration_ms + DRAIN_MS
+    }
+
+    /// The load so far, as of `now`.
+    pub fn report(&self, now: u128) -> LoadTestReport {
+        let elapsed_ms = now.saturating_sub(self.start).clamp(1, self.duration_ms);
+        let completed: usize = self.windows.iter().map(Vec::len).sum();
+        let mut median_ms = Vec::with_capacity(self.windows.len());
+        let mut p95_ms = Vec::with_capacity(self.windows.len());
+        for window in &self.windows {
+            let mut sorted = window.clone();
+            sorted.sort_by(f64::total_cmp);
+            median_ms.push(percentile(&sorted, 0.5));
+            p95_ms.push(percentile(&sorted, 0.95));
+        }
+        LoadTestReport {
+            offered_pps: self.pps,
+            achieved_pps: self.sent as f64 * 1000.0 / elapsed_ms as f64,
+            sent: self.sent as u32,
+            completed: completed as u32,
+            unanswered: (self.sent as u32).saturating_sub(completed as u32),
+            median_ms,
+            p95_ms,
+        }
+    }
+}
+
+/// The `p` quantile of `sorted` (nearest rank), or `NaN` if it's empty.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ticks the load every `tick_ms` from `start` until it ends, returning
+    /// the probes sent on each tick.
+    fn run(load: &mut OfferedLoad, start: u128, tick_ms: u128) -> Vec<u32> {
+        let mut sends = Vec::new();
+        let mut now = start;
+        while !load.is_finished(now) {
+            sends.push(load.due(now, false));
+            now += tick_ms;
+        }
+        sends
+    }
+
+    #[test]
+    fn probes_are_sent_at_the_offered_rate() {
+        // 50pps ticked every 10ms: one probe every other tick
+        let mut load = OfferedLoad::new(50, 2, 1000);
+        let sends = run(&mut load, 1000, 10);
+        assert_eq!(sends.iter().sum::<u32>(), 100);
+        assert_eq!(&sends[..6], [1, 0, 1, 0, 1, 0]);
+        assert_eq!(load.report(4000).achieved_pps, 50.0);
+
+        // 1000pps, faster than the ticks: several per tick
+        let mut load = OfferedLoad::new(1000, 1, 0);
+        let sends = run(&mut load, 0, 4);
+        assert_eq!(sends.iter().sum::<u32>(), 1000);
+        assert!(sends[1..250].iter().all(|&sent| sent == 4));
+    }
+
+    #[test]
+    fn an_unsustainable_rate_reports_what_was_achieved() {
+        // Ticks stall for 500ms: the missed probes are skipped, not burst
+        let mut load = OfferedLoad::new(100, 1, 0);
+        assert_eq!(load.due(0, false), 1);
+        assert_eq!(load.due(500, false), MAX_BURST);
+        assert_eq!(load.due(510, false), 1);
+        // A backed up connection skips the probes due
+        assert_eq!(load.due(600, true), 0);
+        assert_eq!(load.due(610, false), 1);
+        let report = load.report(1000);
+        assert_eq!(report.sent, 1 + MAX_BURST + 2);
+        assert_eq!(report.achieved_pps, report.sent as f64);
+        assert!(report.achieved_pps < report.offered_pps as f64);
+    }
+
+    #[test]
+    fn latency_is_summarized_per_window() {
+        let mut load = OfferedLoad::new(10, 3, 0);
+        for latency in 1..=20 {
+            load.record(500, latency as f64);
+        }
+        load.record(2500, 40.0);
+        // A late reply counts towards the last window
+        load.record(4000, 60.0);
+        let report = load.report(5000);
+        assert_eq!(report.completed, 22);
+        assert_eq!(report.median_ms[0], 10.0);
+        assert_eq!(report.p95_ms[0], 19.0);
+        assert!(report.median_ms[1].is_nan());
+        assert_eq!(report.p95_ms[2], 60.0);
+    }
+}
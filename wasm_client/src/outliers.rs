@@ -0,0 +1,138 @@
+//! A run's mean and jitter, optionally with outliers rejected. One GC pause
+//! or scheduler hiccup can produce a wildly high latency that distorts the
+//! mean, so with a threshold `k` set, a sample further than `k` median
+//! absolute deviations (MAD) from the median of the recent samples is set
+//! aside: counted as an outlier, but kept out of the mean and jitter.
+
+/// Recent samples the median and MAD are taken over.
+const MAD_WINDOW: usize = 100;
+
+/// Samples needed before any are judged; until then, all are kept.
+const MIN_SAMPLES: usize = 5;
+
+/// The least deviation the threshold is scaled by. A steady link can give
+/// a MAD of zero, which would otherwise make any change at all an outlier.
+const MIN_MAD_MS: f64 = 0.5;
+
+#[derive(Debug, Default)]
+pub struct RunStats {
+    /// Outlier threshold, in MADs from the median; `None` keeps everything.
+    k: Option<f64>,
+    recent: Vec<f64>,
+    kept: u32,
+    total_ms: f64,
+    last_kept: Option<f64>,
+    total_jitter_ms: f64,
+    outliers: u32,
+}
+
+impl RunStats {
+    pub fn new(k: Option<f64>) -> Self {
+        Self {
+            k,
+            recent: Vec::with_capacity(MAD_WINDOW),
+            ..Self::default()
+        }
+    }
+
+    /// Adds a sample. Returns false if it was rejected as an outlier.
+    pub fn push(&mut self, latency_ms: f64) -> bool {
+        let outlier = self.is_outlier(latency_ms);
+        // Outliers still count towards the median, so a lasting shift in
+        // latency is soon accepted as the new normal
+        if self.recent.len() == MAD_WINDOW {
+            self.recent.remove(0);
+        }
+        self.recent.push(latency_ms);
+        if outlier {
+            self.outliers += 1;
+            return false;
+        }
+        self.kept += 1;
+        self.total_ms += latency_ms;
+        if let Some(last) = self.last_kept {
+            self.total_jitter_ms += (latency_ms - last).abs();
+        }
+        self.last_kept = Some(latency_ms);
+        true
+    }
+
+    fn is_outlier(&self, latency_ms: f64) -> bool {
+        let Some(k) = self.k else {
+            return false;
+        };
+        if self.recent.len() < MIN_SAMPLES {
+            return false;
+        }
+        let center = median(self.recent.clone());
+        let mad = median(self.recent.iter().map(|x| (x - center).abs()).collect());
+        (latency_ms - center).abs() > k * mad.max(MIN_MAD_MS)
+    }
+
+    /// Mean of the samples kept.
+    pub fn mean_ms(&self) -> Option<f64> {
+        (self.kept > 0).then(|| self.total_ms / self.kept as f64)
+    }
+
+    /// Mean absolute difference between consecutive samples kept, as
+    /// `adaptive::jitter_ms`.
+    pub fn jitter_ms(&self) -> Option<f64> {
+        (self.kept > 1).then(|| self.total_jitter_ms / (self.kept - 1) as f64)
+    }
+
+    /// Samples rejected as outliers.
+    pub fn outliers(&self) -> u32 {
+        self.outliers
+    }
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLES: [f64; 8] = [20.0, 21.0, 19.0, 20.0, 22.0, 500.0, 21.0, 20.0];
+
+    #[test]
+    fn outlier_is_counted_but_not_averaged() {
+        let mut stats = RunStats::new(Some(3.0));
+        for sample in SAMPLES {
+            assert_eq!(stats.push(sample), sample != 500.0);
+        }
+        assert_eq!(stats.outliers(), 1);
+        let kept: Vec<f64> = SAMPLES.into_iter().filter(|&s| s != 500.0).collect();
+        let mean = kept.iter().sum::<f64>() / kept.len() as f64;
+        assert_eq!(stats.mean_ms(), Some(mean));
+        assert_eq!(stats.jitter_ms(), crate::adaptive::jitter_ms(&kept));
+    }
+
+    #[test]
+    fn everything_is_kept_without_a_threshold() {
+        let mut stats = RunStats::new(None);
+        for sample in SAMPLES {
+            assert!(stats.push(sample));
+        }
+        assert_eq!(stats.outliers(), 0);
+        assert_eq!(stats.mean_ms(), Some(SAMPLES.iter().sum::<f64>() / 8.0));
+    }
+
+    #[test]
+    fn steady_samples_tolerate_small_changes() {
+        let mut stats = RunStats::new(Some(3.0));
+        for _ in 0..10 {
+            stats.push(20.0);
+        }
+        assert!(stats.push(21.0));
+        assert!(!stats.push(30.0));
+        assert_eq!(stats.mean_ms(), Some(221.0 / 11.0));
+    }
+}
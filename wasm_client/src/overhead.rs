@@ -0,0 +1,93 @@
+//! Accounting for the cost of crossing the JS/wasm boundary: copying each
+//! incoming frame out of its `ArrayBuffer` and decoding it, and encoding and
+//! handing each reply back to the browser. That time is spent in the client,
+//! not on the network, but it falls inside the measured legs. The timings
+//! themselves come from `performance.now()`.
+//!
+//! Of a handshake's two legs, the server's covers decoding `FirstReply` and
+//! sending `FirstResponse`, and the client's covers sending `FirstResponse`
+//! and decoding `SecondReply`. Latency is the mean of the legs, so the
+//! overhead it includes is one decode plus one send.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Timing {
+    count: u32,
+    total_ms: f64,
+}
+
+impl Timing {
+    fn record(&mut self, ms: f64) {
+        self.count += 1;
+        self.total_ms += ms.max(0.0);
+    }
+
+    fn mean_ms(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.total_ms / self.count as f64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BoundaryOverhead {
+    decode: Timing,
+    send: Timing,
+}
+
+impl BoundaryOverhead {
+    /// Time taken to copy in and decode one handshake frame.
+    pub fn record_decode(&mut self, ms: f64) {
+        self.decode.record(ms);
+    }
+
+    /// Time taken to encode and send one handshake reply.
+    pub fn record_send(&mut self, ms: f64) {
+        self.send.record(ms);
+    }
+
+    pub fn mean_decode_ms(&self) -> Option<f64> {
+        self.decode.mean_ms()
+    }
+
+    pub fn mean_send_ms(&self) -> Option<f64> {
+        self.send.mean_ms()
+    }
+
+    /// How much of each measured latency is boundary overhead rather than
+    /// network: one decode plus one send (see the module docs). `None` until
+    /// both have been timed.
+    pub fn latency_overhead_ms(&self) -> Option<f64> {
+        Some(self.mean_decode_ms()? + self.mean_send_ms()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nothing_timed_yet() {
+        let overhead = BoundaryOverhead::default();
+        assert_eq!(overhead.mean_decode_ms(), None);
+        assert_eq!(overhead.latency_overhead_ms(), None);
+    }
+
+    #[test]
+    fn overhead_is_one_decode_and_one_send() {
+        let mut overhead = BoundaryOverhead::default();
+        for ms in [0.5, 0.25, 0.75, 0.5] {
+            overhead.record_decode(ms);
+        }
+        assert_eq!(overhead.latency_overhead_ms(), None);
+        overhead.record_send(0.125);
+        overhead.record_send(0.375);
+        assert_eq!(overhead.mean_decode_ms(), Some(0.5));
+        assert_eq!(overhead.mean_send_ms(), Some(0.25));
+        assert_eq!(overhead.latency_overhead_ms(), Some(0.75));
+    }
+
+    #[test]
+    fn clock_steps_backwards_count_as_zero() {
+        let mut overhead = BoundaryOverhead::default();
+        overhead.record_send(-1.0);
+        assert_eq!(overhead.mean_send_ms(), Some(0.0));
+    }
+}
@@ -0,0 +1,20 @@
+//! Reporting panics in browser callbacks. wasm32 builds abort on panic, so a
+//! panic can't be caught and recovered from: the module is dead, and the page
+//! wedged, until it's reloaded. What can be done is to say so, with the
+//! panic's message and a stack trace on the console, and on the page, so a
+//! panic in the field is seen rather than taken for a stalled test.
+
+use std::panic;
+use std::sync::Once;
+
+/// Installs the panic hook, once: the panic is logged to the console by
+/// `console_error_panic_hook`, then passed to `report`.
+pub fn install_hook(report: fn(&str)) {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        panic::set_hook(Box::new(move |info| {
+            console_error_panic_hook::hook(info);
+            report(&format!("Client panic: {info}"));
+        }));
+    });
+}
@@ -0,0 +1,70 @@
+//! One knob trading speed for accuracy, in place of setting the profile and
+//! outlier threshold separately.
+//!
+//! The protocol itself is the same in both modes: every probe is the full
+//! handshake, timed with the millisecond timestamps the wire format carries.
+//! There is no shorter handshake to fall back on, nor a finer clock to ask
+//! for, so the modes differ only in how many probes are taken and how their
+//! results are filtered.
+
+use crate::profile::Profile;
+use crate::run::RunParams;
+
+/// Outlier threshold used by `Accurate`, in median absolute deviations.
+const ACCURATE_OUTLIER_K: f64 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    /// The `quick` profile (10 probes after 2 warmups, 200ms apart), with
+    /// every result kept.
+    Fast,
+    /// The `thorough` profile (100 probes after 5 warmups, 500ms apart),
+    /// with results more than 3 MADs from the median rejected as outliers.
+    Accurate,
+}
+
+impl Precision {
+    /// Looks up a mode by its (case-insensitive) name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "fast" => Some(Self::Fast),
+            "accurate" => Some(Self::Accurate),
+            _ => None,
+        }
+    }
+
+    pub fn params(self) -> RunParams {
+        match self {
+            Self::Fast => Profile::Quick.params(),
+            Self::Accurate => Profile::Thorough.params(),
+        }
+    }
+
+    /// The outlier threshold for the run's stats; `None` keeps everything.
+    pub fn outlier_k(self) -> Option<f64> {
+        match self {
+            Self::Fast => None,
+            Self::Accurate => Some(ACCURATE_OUTLIER_K),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn modes_configure_runs() {
+        let fast = Precision::from_name("fast").unwrap();
+        assert_eq!(fast.params().burst_count, 10);
+        assert_eq!(fast.params().warmup, 2);
+        assert_eq!(fast.outlier_k(), None);
+
+        let accurate = Precision::from_name("Accurate").unwrap();
+        assert_eq!(accurate.params(), Profile::Thorough.params());
+        assert!(accurate.params().burst_count > fast.params().burst_count);
+        assert_eq!(accurate.outlier_k(), Some(3.0));
+
+        assert_eq!(Precision::from_name("exact"), None);
+    }
+}
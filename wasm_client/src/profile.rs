@@ -0,0 +1,97 @@
+//! Named measurement presets, so end users don't have to tune individual
+//! parameters.
+
+use crate::run::RunParams;
+
+/// A named bundle of measurement parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// A fast check: 2 warmup probes, then 10 probes 200ms apart, each given
+    /// 1s to reply.
+    Quick,
+    /// A careful measurement: 5 warmup probes, then 100 probes 500ms apart,
+    /// each given 2s to reply.
+    Thorough,
+    /// Ongoing monitoring: 1 warmup probe, then a probe every second until
+    /// stopped, each given 2s to reply.
+    Continuous,
+}
+
+impl Profile {
+    /// Looks up a profile by its (case-insensitive) name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "quick" => Some(Self::Quick),
+            "thorough" => Some(Self::Thorough),
+            "continuous" => Some(Self::Continuous),
+            _ => None,
+        }
+    }
+
+    pub fn params(&self) -> RunParams {
+        match self {
+            Self::Quick => RunParams {
+                burst_count: 10,
+                interval_ms: 200,
+                warmup: 2,
+                timeout_ms: 1000,
+            },
+            Self::Thorough => RunParams {
+                burst_count: 100,
+                interval_ms: 500,
+                warmup: 5,
+                timeout_ms: 2000,
+            },
+            Self::Continuous => RunParams {
+                burst_count: 0,
+                interval_ms: 1000,
+                warmup: 1,
+                timeout_ms: 2000,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn profile_names_map_to_params() {
+        let quick = Profile::from_name("quick").unwrap().params();
+        assert_eq!(
+            quick,
+            RunParams {
+                burst_count: 10,
+                interval_ms: 200,
+                warmup: 2,
+                timeout_ms: 1000,
+            }
+        );
+        let thorough = Profile::from_name("Thorough").unwrap().params();
+        assert_eq!(
+            thorough,
+            RunParams {
+                burst_count: 100,
+                interval_ms: 500,
+                warmup: 5,
+                timeout_ms: 2000,
+            }
+        );
+        let continuous = Profile::from_name("CONTINUOUS").unwrap().params();
+        assert_eq!(
+            continuous,
+            RunParams {
+                burst_count: 0,
+                interval_ms: 1000,
+                warmup: 1,
+                timeout_ms: 2000,
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_profile_name() {
+        assert_eq!(Profile::from_name("leisurely"), None);
+    }
+}
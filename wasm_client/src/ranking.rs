@@ -0,0 +1,104 @@
+//! Ranking candidate servers by measured latency, for picking the closest
+//! endpoint. The client probes each candidate; this ranks what it measured.
+
+use wasm_bindgen::prelude::*;
+
+/// How long each probe of a server may take before the server is counted
+/// as failed.
+const PROBE_TIMEOUT_MS: u32 = 2000;
+
+/// How long probing a server with `probes` handshakes may take in all, for
+/// `setTimeout`, which takes an `i32`. A huge `probes` waits as long as it
+/// can, rather than overflowing.
+pub fn probe_timeout_ms(probes: u32) -> i32 {
+    let timeout_ms = probes.saturating_mul(PROBE_TIMEOUT_MS);
+    i32::try_from(timeout_ms).unwrap_or(i32::MAX)
+}
+
+/// The outcome of probing one server: its latencies, or why it failed.
+pub type ProbeOutcome = Result<Vec<f64>, String>;
+
+/// One server's place in the ranking.
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankedServer {
+    pub url: String,
+    /// Median latency over the probes, if the server could be measured.
+    pub latency_ms: Option<f64>,
+    /// Why the server couldn't be measured, if it failed.
+    pub error: Option<String>,
+}
+
+fn median(latencies: &[f64]) -> Option<f64> {
+    if latencies.is_empty() {
+        return None;
+    }
+    let mut sorted = latencies.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        Some((sorted[mid - 1] + sorted[mid]) * 0.5)
+    } else {
+        Some(sorted[mid])
+    }
+}
+
+/// Orders servers by median latency, fastest first. Servers that failed (or
+/// completed no probes) go last, in their original order, with an error.
+pub fn rank(probes: Vec<(String, ProbeOutcome)>) -> Vec<RankedServer> {
+    let mut ranked: Vec<RankedServer> = probes
+        .into_iter()
+        .map(|(url, outcome)| {
+            let (latency_ms, error) = match outcome {
+                Ok(latencies) => match median(&latencies) {
+                    Some(latency) => (Some(latency), None),
+                    None => (None, Some("No probes completed".to_string())),
+                },
+                Err(e) => (None, Some(e)),
+            };
+            RankedServer {
+                url,
+                latency_ms,
+                error,
+            }
+        })
+        .collect();
+    // Stable, so failed servers keep their relative order
+    ranked.sort_by(|a, b| match (a.latency_ms, b.latency_ms) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+    ranked
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fastest_server_first_and_failures_last() {
+        let ranked = rank(vec![
+            ("wss://down".to_string(), Err("Connection failed".to_string())),
+            ("wss://far".to_string(), Ok(vec![80.0, 90.0, 85.0])),
+            ("wss://empty".to_string(), Ok(vec![])),
+            ("wss://near".to_string(), Ok(vec![10.0, 12.0, 200.0, 11.0])),
+        ]);
+        let urls: Vec<&str> = ranked.iter().map(|server| server.url.as_str()).collect();
+        assert_eq!(urls, ["wss://near", "wss://far", "wss://down", "wss://empty"]);
+        // The median shrugs off the one slow probe
+        assert_eq!(ranked[0].latency_ms, Some(11.5));
+        assert_eq!(ranked[1].latency_ms, Some(85.0));
+        assert_eq!(ranked[2].error.as_deref(), Some("Connection failed"));
+        assert_eq!(ranked[3].latency_ms, None);
+        assert!(ranked[3].error.is_some());
+    }
+
+    #[test]
+    fn probe_timeout_never_overflows() {
+        assert_eq!(probe_timeout_ms(3), 6000);
+        assert_eq!(probe_timeout_ms(2_000_000), i32::MAX);
+        assert_eq!(probe_timeout_ms(u32::MAX), i32::MAX);
+    }
+}
@@ -0,0 +1,47 @@
+//! Reacting to the server refusing a frame with a `ProtocolError`, by its
+//! reason code. (An overloaded server sends `Busy` instead, handled with the
+//! other replies.)
+
+use shared_data::ErrorCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// Abandon the probe in flight without counting it as lost, and send it
+    /// again after `after_ms`.
+    Retry { after_ms: u32 },
+    /// Retrying won't help: we sent something the server doesn't accept
+    /// (or a reason this version doesn't know). Only worth logging.
+    Report,
+}
+
+/// What to do about a `ProtocolError` with the raw reason `code`.
+pub fn on_protocol_error(code: u16) -> Refusal {
+    match ErrorCode::from_u16(code) {
+        // The frame was garbled on the way; start the handshake afresh
+        Some(ErrorCode::BadFrame) => Refusal::Retry { after_ms: 0 },
+        Some(ErrorCode::Unexpected | ErrorCode::TooLarge) | None => Refusal::Report,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shared_data::LatencyTest;
+
+    #[test]
+    fn refusals_dispatch_on_their_code() {
+        let reaction = |code: ErrorCode| {
+            let error = LatencyTest::protocol_error(code, code.name());
+            match LatencyTest::decode(&error.encode()).unwrap() {
+                LatencyTest::ProtocolError { code, .. } => on_protocol_error(code),
+                other => panic!("Expected ProtocolError, got {other:?}"),
+            }
+        };
+        assert_eq!(reaction(ErrorCode::BadFrame), Refusal::Retry { after_ms: 0 });
+        assert_eq!(reaction(ErrorCode::Unexpected), Refusal::Report);
+        assert_eq!(reaction(ErrorCode::TooLarge), Refusal::Report);
+        // A reason from a newer server, or a retired one
+        assert_eq!(on_protocol_error(999), Refusal::Report);
+        assert_eq!(on_protocol_error(5), Refusal::Report);
+    }
+}
@@ -0,0 +1,35 @@
+//! Replaying recorded handshakes, for the `debug` feature's `inject_final`:
+//! QA can reproduce an exact latency readout from known timestamps.
+
+use shared_data::{LatencyResult, LatencyTest, LatencyTestError};
+
+/// The `Final` completing a handshake with these timestamps (server, client,
+/// server ack, client ack), and its latency as the client would compute it.
+pub fn recorded_final(
+    [server_time, client_time, server_ack_time, client_ack_time]: [u128; 4],
+) -> Result<(LatencyTest, LatencyResult), LatencyTestError> {
+    let result =
+        LatencyResult::from_timestamps(server_time, client_time, server_ack_time, client_ack_time)?;
+    let last = LatencyTest::builder()
+        .server_time(server_time)
+        .client_time(client_time)
+        .server_ack_time(server_ack_time)
+        .client_ack_time(client_ack_time)
+        .build();
+    Ok((last, result))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recorded_final_reports_its_latency() {
+        let (last, result) = recorded_final([1_000, 50_000, 1_012, 50_018]).unwrap();
+        assert_eq!(result.server_latency_ms, 12.0);
+        assert_eq!(result.client_latency_ms, 18.0);
+        assert_eq!(result.latency_ms, 15.0);
+        assert_eq!(last.calculate_latency().unwrap().latency_ms, result.latency_ms);
+        assert!(recorded_final([1_000, 50_000, 999, 50_018]).is_err());
+    }
+}
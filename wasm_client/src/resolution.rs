@@ -0,0 +1,126 @@
+//! Probing the resolution of the browser's clock. Browsers coarsen
+//! `performance.now()` to blunt timing attacks: to 5µs or 100µs in most, but
+//! to 1ms or much worse (100ms, with fingerprinting protection) in some. A
+//! latency below the resolution can't be measured. The clocks are passed in,
+//! so the tests can simulate a coarse one.
+
+/// The resolution of the handshake's timestamps, which travel as whole
+/// milliseconds: however fine the clock, nothing finer can be measured.
+pub const TIMESTAMP_RESOLUTION_MS: f64 = 1.0;
+
+/// Ticks timed before settling on a resolution.
+const TICKS: usize = 5;
+/// How long to keep probing for more ticks, in ms of the probed clock. A
+/// coarse clock gives up after fewer ticks, but always times at least one.
+const PROBE_BUDGET_MS: f64 = 50.0;
+/// The longest the probe busy-waits, in ms of the wall clock, however
+/// coarse (or stuck) the probed clock.
+const MAX_PROBE_MS: f64 = 250.0;
+
+/// The smallest step `now` was seen to take between two ticks, in ms, or
+/// `None` if it never ticked twice, or couldn't be read. Busy-waits on `now`,
+/// so is only worth doing once, and gives up after `MAX_PROBE_MS` by
+/// `wall_ms`, a second clock that keeps going if `now` doesn't.
+pub fn probe_resolution(
+    mut now: impl FnMut() -> Option<f64>,
+    mut wall_ms: impl FnMut() -> f64,
+) -> Option<f64> {
+    let start = now()?;
+    let deadline = wall_ms() + MAX_PROBE_MS;
+    let mut last = start;
+    // The first change is from an arbitrary point mid-tick, so isn't timed
+    let mut last_tick = None;
+    let mut smallest: Option<f64> = None;
+    let mut ticks = 0;
+    while wall_ms() < deadline {
+        let reading = now()?;
+        if reading <= last {
+            continue;
+        }
+        if let Some(tick) = last_tick {
+            let step: f64 = reading - tick;
+            smallest = Some(smallest.map_or(step, |smallest| smallest.min(step)));
+            ticks += 1;
+        }
+        last = reading;
+        last_tick = Some(reading);
+        let over_budget = reading - start > PROBE_BUDGET_MS;
+        if ticks >= TICKS || (over_budget && smallest.is_some()) {
+            break;
+        }
+    }
+    smallest
+}
+
+/// The finest latency the client can measure, given the probed clock
+/// resolution (if any): never finer than `TIMESTAMP_RESOLUTION_MS`.
+pub fn effective_resolution_ms(probed: Option<f64>) -> f64 {
+    probed.map_or(TIMESTAMP_RESOLUTION_MS, |probed| {
+        probed.max(TIMESTAMP_RESOLUTION_MS)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A clock advancing `step_ms` per reading, reporting whole multiples
+    /// of `resolution_ms`.
+    fn clock(step_ms: f64, resolution_ms: f64) -> impl FnMut() -> Option<f64> {
+        let mut time = 1234.567;
+        move || {
+            time += step_ms;
+            Some((time / resolution_ms).floor() * resolution_ms)
+        }
+    }
+
+    /// A wall clock advancing `step_ms` per reading.
+    fn wall(step_ms: f64) -> impl FnMut() -> f64 {
+        let mut time = 0.0;
+        move || {
+            time += step_ms;
+            time
+        }
+    }
+
+    #[test]
+    fn probes_the_clock_resolution() {
+        for resolution in [0.005, 0.1, 1.0, 100.0] {
+            let probed = probe_resolution(clock(0.001, resolution), wall(0.001)).unwrap();
+            assert!((probed - resolution).abs() < 1e-6, "{resolution}: {probed}");
+        }
+    }
+
+    #[test]
+    fn gives_up_on_a_clock_that_never_ticks() {
+        let mut reads = 0;
+        let stuck = || {
+            reads += 1;
+            Some(5.0)
+        };
+        assert_eq!(probe_resolution(stuck, wall(0.01)), None);
+        // Once the wall clock passes MAX_PROBE_MS, however many reads that is
+        assert!(reads <= (MAX_PROBE_MS / 0.01) as usize + 1);
+    }
+
+    #[test]
+    fn gives_up_on_the_first_failed_read() {
+        let mut reads = 0;
+        let failing = || {
+            reads += 1;
+            (reads < 3).then_some(5.0)
+        };
+        assert_eq!(probe_resolution(failing, wall(0.01)), None);
+        assert_eq!(reads, 3);
+    }
+
+    #[test]
+    fn timestamps_limit_the_effective_resolution() {
+        assert_eq!(
+            effective_resolution_ms(Some(0.005)),
+            TIMESTAMP_RESOLUTION_MS
+        );
+        assert_eq!(effective_resolution_ms(Some(100.0)), 100.0);
+        assert_eq!(effective_resolution_ms(None), TIMESTAMP_RESOLUTION_MS);
+    }
+}
@@ -0,0 +1,431 @@
+//! Scheduling for a measurement run: when each probe is due, and when the
+//! run is over.
+
+use crate::adaptive::{next_interval, AdaptiveParams, JITTER_WINDOW};
+use crate::outliers::RunStats;
+
+/// Parameters controlling a measurement run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunParams {
+    /// Number of probes to measure, after warmup. Zero runs until stopped.
+    pub burst_count: u32,
+    /// Delay between probes, in milliseconds.
+    pub interval_ms: u32,
+    /// Probes sent (and discarded) first, to warm up the connection.
+    pub warmup: u32,
+    /// How long to wait for a reply before counting a probe as lost.
+    pub timeout_ms: u32,
+}
+
+/// Progress through a run. One probe is in flight at a time.
+#[derive(Debug)]
+pub struct RunState {
+    params: RunParams,
+    sent: u32,
+    completed: u32,
+    lost: u32,
+    in_flight_since: Option<u128>,
+    /// If set, probes are spaced by recent jitter rather than on every tick.
+    adaptive: Option<AdaptiveParams>,
+    recent: Vec<f64>,
+    last_sent: Option<u128>,
+    /// Set when the server asks us to back off.
+    paused_until: Option<u128>,
+    /// The reported results' mean and jitter.
+    stats: RunStats,
+    /// Every reported result, for the summary's percentiles. Only collected
+    /// for a run with a deadline, the only kind summarized, so an endless
+    /// run doesn't grow it forever.
+    latencies: Vec<f64>,
+    /// When a duration-bounded run ends.
+    deadline: Option<u128>,
+    ended: bool,
+}
+
+/// A run's results, aggregated once it's over. Latencies are `NaN` if
+/// there were too few results to compute them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunSummary {
+    /// Mean latency, with outliers rejected as for `RunState::stats`.
+    pub mean_ms: f64,
+    pub jitter_ms: f64,
+    pub p95_ms: f64,
+    /// The lowest latency measured.
+    pub floor_ms: f64,
+    pub completed: u32,
+    pub lost: u32,
+    /// The fraction of probes lost, of those answered or lost.
+    pub loss: f64,
+}
+
+impl RunState {
+    pub fn new(params: RunParams) -> Self {
+        Self {
+            params,
+            sent: 0,
+            completed: 0,
+            lost: 0,
+            in_flight_since: None,
+            adaptive: None,
+            recent: Vec::with_capacity(JITTER_WINDOW),
+            last_sent: None,
+            paused_until: None,
+            stats: RunStats::default(),
+            latencies: Vec::new(),
+            deadline: None,
+            ended: false,
+        }
+    }
+
+    /// Ends the run at `deadline` (ms since the epoch, as `tick`'s `now`),
+    /// however many probes it has sent by then.
+    pub fn with_deadline(self, deadline: u128) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+
+    /// Rejects outliers from the run's stats: results further than `k`
+    /// median absolute deviations from the median. See `RunStats`.
+    pub fn with_outlier_k(self, k: Option<f64>) -> Self {
+        Self {
+            stats: RunStats::new(k),
+            ..self
+        }
+    }
+
+    /// A run whose probe interval adapts to jitter. The timer should tick
+    /// at `adaptive.min_ms`; ticks before the next probe is due are skipped.
+    pub fn adaptive(params: RunParams, adaptive: AdaptiveParams) -> Self {
+        Self {
+            adaptive: Some(adaptive),
+            ..Self::new(params)
+        }
+    }
+
+    /// Records a measured latency, for adapting the probe interval.
+    pub fn observe(&mut self, latency_ms: f64) {
+        if self.recent.len() == JITTER_WINDOW {
+            self.recent.remove(0);
+        }
+        self.recent.push(latency_ms);
+    }
+
+    /// The current delay between probes.
+    pub fn interval_ms(&self) -> u32 {
+        match &self.adaptive {
+            Some(adaptive) => next_interval(adaptive, &self.recent),
+            None => self.params.interval_ms,
+        }
+    }
+
+    /// Counts the in-flight probe as lost if it is overdue. Returns true if
+    /// a probe was lost.
+    pub fn expire(&mut self, now: u128) -> bool {
+        match self.in_flight_since {
+            Some(since) if now.saturating_sub(since) >= self.params.timeout_ms as u128 => {
+                self.lost += 1;
+                self.in_flight_since = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The server was too busy to handle the probe in flight. The probe is
+    /// abandoned without counting as lost (and will be sent again), and
+    /// probing pauses for `retry_after_ms`.
+    pub fn busy(&mut self, now: u128, retry_after_ms: u32) {
+        if self.in_flight_since.take().is_some() {
+            self.sent -= 1;
+        }
+        self.paused_until = Some(now + retry_after_ms as u128);
+    }
+
+    /// The connection dropped. The probe in flight is abandoned without
+    /// counting as lost, to be sent again once reconnected, so the run still
+    /// measures its full count of probes.
+    pub fn disconnected(&mut self) {
+        if self.in_flight_since.take().is_some() {
+            self.sent -= 1;
+        }
+    }
+
+    /// Ends the run if its deadline has passed. The probe in flight, if
+    /// any, is abandoned without counting as lost: it had no chance to time
+    /// out, and a reply arriving later isn't reported. Returns true if the
+    /// run has ended on its deadline.
+    pub fn end_if_due(&mut self, now: u128) -> bool {
+        if !self.ended && self.deadline.is_some_and(|deadline| now >= deadline) {
+            self.disconnected();
+            self.ended = true;
+        }
+        self.ended
+    }
+
+    /// Called on every timer tick. Expires an overdue probe, and returns
+    /// true if a new probe should be sent now.
+    pub fn tick(&mut self, now: u128) -> bool {
+        if self.end_if_due(now) {
+            return false;
+        }
+        self.expire(now);
+        if self.in_flight_since.is_some() || self.is_finished() {
+            return false;
+        }
+        if self.paused_until.is_some_and(|until| now < until) {
+            return false;
+        }
+        if let (Some(_), Some(last_sent)) = (self.adaptive, self.last_sent) {
+            if now.saturating_sub(last_sent) < self.interval_ms() as u128 {
+                return false;
+            }
+        }
+        self.sent += 1;
+        self.in_flight_since = Some(now);
+        self.last_sent = Some(now);
+        true
+    }
+
+    /// Called when the in-flight probe completes. Returns true if its result
+    /// should be reported, or false if it was a warmup probe (or had already
+    /// been counted as lost).
+    pub fn complete(&mut self) -> bool {
+        if self.in_flight_since.take().is_none() {
+            return false;
+        }
+        self.completed += 1;
+        self.sent > self.params.warmup
+    }
+
+    /// True once every probe has been sent and accounted for, or the
+    /// deadline has passed.
+    pub fn is_finished(&self) -> bool {
+        self.ended
+            || self.params.burst_count != 0
+            && self.sent >= self.params.warmup + self.params.burst_count
+            && self.in_flight_since.is_none()
+    }
+
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+
+    pub fn completed(&self) -> u32 {
+        self.completed
+    }
+
+    /// Adds a reported result to the run's stats.
+    pub fn record(&mut self, latency_ms: f64) {
+        self.stats.push(latency_ms);
+        if self.is_bounded() {
+            self.latencies.push(latency_ms);
+        }
+    }
+
+    pub fn stats(&self) -> &RunStats {
+        &self.stats
+    }
+
+    /// True if the run ends on a deadline, rather than a count of probes.
+    pub fn is_bounded(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    pub fn summary(&self) -> RunSummary {
+        let mut sorted = self.latencies.clone();
+        sorted.sort_by(f64::total_cmp);
+        // Nearest rank, as `shared_data::LatencySamples::percentile_ms`
+        let rank = (0.95 * sorted.len() as f64).ceil() as usize;
+        let answered = self.completed + self.lost;
+        RunSummary {
+            mean_ms: self.stats.mean_ms().unwrap_or(f64::NAN),
+            jitter_ms: self.stats.jitter_ms().unwrap_or(f64::NAN),
+            p95_ms: sorted.get(rank.saturating_sub(1)).copied().unwrap_or(f64::NAN),
+            floor_ms: sorted.first().copied().unwrap_or(f64::NAN),
+            completed: self.completed,
+            lost: self.lost,
+            loss: if answered == 0 { 0.0 } else { self.lost as f64 / answered as f64 },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PARAMS: RunParams = RunParams {
+        burst_count: 2,
+        interval_ms: 100,
+        warmup: 1,
+        timeout_ms: 250,
+    };
+
+    #[test]
+    fn warmup_probes_are_not_reported() {
+        let mut run = RunState::new(PARAMS);
+        assert!(run.tick(0));
+        assert!(!run.complete());
+        assert!(run.tick(100));
+        assert!(run.complete());
+        assert!(run.tick(200));
+        assert!(run.complete());
+        assert!(run.is_finished());
+        assert!(!run.tick(300));
+        assert_eq!(run.completed(), 3);
+    }
+
+    #[test]
+    fn overdue_probe_is_lost() {
+        let mut run = RunState::new(PARAMS);
+        assert!(run.tick(0));
+        assert!(!run.tick(100));
+        assert!(!run.tick(200));
+        assert!(run.tick(300));
+        assert_eq!(run.lost(), 1);
+        assert!(!run.expire(300));
+        // Only the probe in flight can complete.
+        assert!(run.complete());
+        assert!(!run.complete());
+    }
+
+    #[test]
+    fn busy_server_backs_off_without_loss() {
+        let mut run = RunState::new(PARAMS);
+        assert!(run.tick(0));
+        run.busy(50, 200);
+        assert!(!run.tick(100));
+        assert!(!run.tick(200));
+        // The abandoned probe is retried once the pause is over
+        assert!(run.tick(250));
+        assert_eq!(run.lost(), 0);
+        assert!(!run.complete());
+        assert!(run.tick(350));
+        assert!(run.complete());
+        assert!(run.tick(450));
+        assert!(run.complete());
+        assert!(run.is_finished());
+    }
+
+    #[test]
+    fn interrupted_burst_resumes_after_reconnect() {
+        let params = RunParams {
+            burst_count: 100,
+            ..PARAMS
+        };
+        let mut run = RunState::new(params);
+        let mut now = 0;
+        let mut reported = 0;
+        for _ in 0..40 {
+            assert!(run.tick(now));
+            reported += run.complete() as u32;
+            now += 100;
+        }
+        // Disconnected with a probe in flight
+        assert!(run.tick(now));
+        run.disconnected();
+        now += 5000;
+        while run.tick(now) {
+            reported += run.complete() as u32;
+            now += 100;
+        }
+        assert!(run.is_finished());
+        assert_eq!(reported, 100);
+        assert_eq!(run.completed(), 101);
+        assert_eq!(run.lost(), 0);
+    }
+
+    #[test]
+    fn continuous_run_never_finishes() {
+        let mut run = RunState::new(RunParams {
+            burst_count: 0,
+            ..PARAMS
+        });
+        for i in 0..1000 {
+            assert!(run.tick(i * 100));
+            run.complete();
+        }
+        assert!(!run.is_finished());
+    }
+
+    #[test]
+    fn bounded_run_ends_on_its_deadline() {
+        let mut run = RunState::new(RunParams {
+            burst_count: 0,
+            ..PARAMS
+        })
+        .with_deadline(1050);
+        let mut now = 0;
+        while !run.is_finished() {
+            // The probes sent at 300 and 900 go unanswered
+            if run.tick(now) && now != 300 && now != 900 && run.complete() {
+                run.record(10.0 + (now / 100) as f64);
+            }
+            now += 100;
+        }
+        assert_eq!(now, 1200);
+        // The probe sent at 900 is abandoned, not lost
+        assert!(!run.complete());
+        assert!(!run.tick(2000));
+        let summary = run.summary();
+        assert_eq!(summary.completed, 6);
+        assert_eq!(summary.lost, 1);
+        assert_eq!(summary.loss, 1.0 / 7.0);
+        assert_eq!(summary.mean_ms, 14.8);
+        assert_eq!(summary.floor_ms, 11.0);
+        assert_eq!(summary.p95_ms, 18.0);
+    }
+
+    #[test]
+    fn summary_of_an_empty_run() {
+        let summary = RunState::new(PARAMS).summary();
+        assert!(summary.mean_ms.is_nan() && summary.p95_ms.is_nan());
+        assert_eq!(summary.loss, 0.0);
+    }
+
+    #[test]
+    fn only_bounded_runs_keep_every_latency() {
+        let mut run = RunState::new(PARAMS);
+        for _ in 0..1000 {
+            run.record(10.0);
+        }
+        assert!(run.latencies.is_empty());
+        assert_eq!(run.stats().mean_ms(), Some(10.0));
+    }
+
+    #[test]
+    fn adaptive_run_spaces_probes_by_jitter() {
+        let adaptive = AdaptiveParams {
+            min_ms: 100,
+            base_ms: 1000,
+            max_ms: 4000,
+        };
+        let mut run = RunState::adaptive(
+            RunParams {
+                burst_count: 0,
+                ..PARAMS
+            },
+            adaptive,
+        );
+        assert!(run.tick(0));
+        run.complete();
+        // Base interval until there's enough history
+        assert!(!run.tick(900));
+        assert!(run.tick(1000));
+        run.complete();
+        for _ in 0..5 {
+            run.observe(20.0);
+        }
+        // Steady: back off to the maximum
+        assert_eq!(run.interval_ms(), 4000);
+        assert!(!run.tick(4900));
+        assert!(run.tick(5000));
+        run.complete();
+        run.observe(80.0);
+        run.observe(10.0);
+        // Volatile: probe as fast as allowed
+        assert_eq!(run.interval_ms(), 100);
+        assert!(run.tick(5100));
+    }
+}
@@ -0,0 +1,110 @@
+//! Counting lost replies from their sequence numbers, when the server
+//! numbers them (`SEQUENCE_REPLIES`). A gap in the numbers means replies
+//! were lost, and is counted straight away rather than when a probe times
+//! out; a reply that turns up after a higher number was just late, so it
+//! counts as reordered instead.
+
+use std::collections::BTreeSet;
+
+/// How far behind the highest number seen a missing reply may still turn
+/// up late. Older gaps are settled as lost for good.
+pub const REORDER_WINDOW: u32 = 64;
+
+/// One connection's numbered replies. Numbering restarts with each
+/// connection, and so must this.
+#[derive(Debug, Default)]
+pub struct ReplySequence {
+    /// The number expected next: one past the highest seen.
+    next: u32,
+    /// Numbers skipped within the reorder window, which may yet turn up.
+    missing: BTreeSet<u32>,
+    /// Numbers skipped and given up on.
+    settled_lost: u32,
+    reordered: u32,
+}
+
+/// How a numbered reply arrived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    InOrder,
+    /// After a gap of `skipped` replies, counted as lost.
+    AfterGap { skipped: u32 },
+    /// Late, filling a gap: counted as reordered rather than lost.
+    Late,
+    /// A number already seen (or too old to tell).
+    Duplicate,
+}
+
+impl ReplySequence {
+    /// Records the reply numbered `seq`.
+    pub fn observe(&mut self, seq: u32) -> Arrival {
+        if seq < self.next {
+            if self.missing.remove(&seq) {
+                self.reordered += 1;
+                return Arrival::Late;
+            }
+            return Arrival::Duplicate;
+        }
+        let skipped = seq - self.next;
+        // Only the most recent skipped numbers are worth waiting for
+        let waiting = skipped.min(REORDER_WINDOW);
+        self.settled_lost += skipped - waiting;
+        self.missing.extend(seq - waiting..seq);
+        self.next = seq + 1;
+        let oldest = self.next.saturating_sub(REORDER_WINDOW);
+        let recent = self.missing.split_off(&oldest);
+        self.settled_lost += self.missing.len() as u32;
+        self.missing = recent;
+        match skipped {
+            0 => Arrival::InOrder,
+            skipped => Arrival::AfterGap { skipped },
+        }
+    }
+
+    /// Replies skipped and not (yet) arrived late.
+    pub fn lost(&self) -> u32 {
+        self.settled_lost + self.missing.len() as u32
+    }
+
+    /// Replies that arrived after a higher-numbered one.
+    pub fn reordered(&self) -> u32 {
+        self.reordered
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gaps_are_lost_until_filled() {
+        let mut replies = ReplySequence::default();
+        assert_eq!(replies.observe(0), Arrival::InOrder);
+        assert_eq!(replies.observe(1), Arrival::InOrder);
+        // 2 and 3 lost
+        assert_eq!(replies.observe(4), Arrival::AfterGap { skipped: 2 });
+        assert_eq!(replies.lost(), 2);
+        // 3 was only late
+        assert_eq!(replies.observe(3), Arrival::Late);
+        assert_eq!((replies.lost(), replies.reordered()), (1, 1));
+        assert_eq!(replies.observe(3), Arrival::Duplicate);
+        assert_eq!(replies.observe(5), Arrival::InOrder);
+        assert_eq!((replies.lost(), replies.reordered()), (1, 1));
+    }
+
+    #[test]
+    fn old_gaps_are_settled_as_lost() {
+        let mut replies = ReplySequence::default();
+        replies.observe(0);
+        replies.observe(2);
+        // A huge jump doesn't track every number skipped
+        replies.observe(1_000_000);
+        // 1, and 3 to 999,999
+        assert_eq!(replies.lost(), 999_998);
+        assert!(replies.missing.len() <= REORDER_WINDOW as usize);
+        // Too late to count as reordered
+        assert_eq!(replies.observe(1), Arrival::Duplicate);
+        assert_eq!(replies.observe(999_999), Arrival::Late);
+        assert_eq!(replies.lost(), 999_997);
+    }
+}
@@ -0,0 +1,148 @@
+//! Coordinating measurement between browser tabs, so that several tabs
+//! running the client don't contend for the link and skew each other's
+//! results. Tabs announce over a `BroadcastChannel` while they are probing,
+//! and a tab only probes if no other tab is. This module decides whose turn
+//! it is; the client owns the channel.
+
+use std::collections::HashMap;
+
+/// Name of the `BroadcastChannel` tabs coordinate over.
+pub const CHANNEL_NAME: &str = "wasm_latency";
+
+/// A tab that hasn't announced itself for this long is assumed to have
+/// stopped (or closed without saying so). Probing tabs announce on every
+/// timer tick, so this must be longer than the longest probe interval.
+pub const HEARTBEAT_TIMEOUT_MS: u128 = 10_000;
+
+/// What a tab tells the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TabMessage {
+    /// The tab is probing.
+    Active(u64),
+    /// The tab has stopped probing.
+    Done(u64),
+}
+
+impl TabMessage {
+    /// Encodes the message as `active:<id>` or `done:<id>`.
+    pub fn encode(&self) -> String {
+        match self {
+            Self::Active(id) => format!("active:{id}"),
+            Self::Done(id) => format!("done:{id}"),
+        }
+    }
+
+    pub fn decode(text: &str) -> Option<Self> {
+        let (kind, id) = text.split_once(':')?;
+        let id = id.parse().ok()?;
+        match kind {
+            "active" => Some(Self::Active(id)),
+            "done" => Some(Self::Done(id)),
+            _ => None,
+        }
+    }
+}
+
+/// One tab's view of which tabs are probing.
+#[derive(Debug)]
+pub struct TabCoordinator {
+    id: u64,
+    /// True while this tab holds the turn to probe.
+    active: bool,
+    /// Other probing tabs, and when each was last heard from.
+    peers: HashMap<u64, u128>,
+}
+
+impl TabCoordinator {
+    pub fn new(id: u64) -> Self {
+        Self {
+            id,
+            active: false,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Handles a message from another tab, received at `now`.
+    pub fn receive(&mut self, message: TabMessage, now: u128) {
+        match message {
+            TabMessage::Active(id) if id != self.id => {
+                self.peers.insert(id, now);
+            }
+            TabMessage::Done(id) => {
+                self.peers.remove(&id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether this tab may probe at `now`. A tab takes the turn when no
+    /// other tab is probing, and keeps it until it calls `release`. If two
+    /// tabs take the turn at once, the one with the higher id yields.
+    pub fn my_turn(&mut self, now: u128) -> bool {
+        self.peers
+            .retain(|_, last_seen| now.saturating_sub(*last_seen) < HEARTBEAT_TIMEOUT_MS);
+        self.active = if self.active {
+            self.peers.keys().all(|&peer| peer > self.id)
+        } else {
+            self.peers.is_empty()
+        };
+        self.active
+    }
+
+    /// Gives up the turn, returning true if this tab held it (and so should
+    /// tell the others it's done).
+    pub fn release(&mut self) -> bool {
+        std::mem::take(&mut self.active)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        for message in [TabMessage::Active(42), TabMessage::Done(u64::MAX)] {
+            assert_eq!(TabMessage::decode(&message.encode()), Some(message));
+        }
+        assert_eq!(TabMessage::decode("active:"), None);
+        assert_eq!(TabMessage::decode("paused:1"), None);
+    }
+
+    #[test]
+    fn only_one_tab_probes() {
+        let mut tab = TabCoordinator::new(10);
+        assert!(tab.my_turn(0));
+        // Our own announcements echo back; they don't count
+        tab.receive(TabMessage::Active(10), 0);
+        assert!(tab.my_turn(100));
+
+        // Another tab that started alongside us yields, having a higher id
+        let mut other = TabCoordinator::new(20);
+        assert!(other.my_turn(0));
+        other.receive(TabMessage::Active(10), 100);
+        assert!(!other.my_turn(100));
+        tab.receive(TabMessage::Active(20), 100);
+        assert!(tab.my_turn(200));
+
+        // Once we're done, the other tab takes over
+        assert!(tab.release());
+        other.receive(TabMessage::Done(10), 300);
+        assert!(other.my_turn(300));
+        tab.receive(TabMessage::Active(20), 300);
+        assert!(!tab.my_turn(400));
+        assert!(!tab.release());
+    }
+
+    #[test]
+    fn silent_tabs_time_out() {
+        let mut tab = TabCoordinator::new(10);
+        tab.receive(TabMessage::Active(5), 1000);
+        assert!(!tab.my_turn(1000 + HEARTBEAT_TIMEOUT_MS - 1));
+        assert!(tab.my_turn(1000 + HEARTBEAT_TIMEOUT_MS));
+    }
+}
@@ -0,0 +1,231 @@
+//! WebTransport, used in place of the WebSocket when the server advertises
+//! it (at `/webtransport`) and the browser supports it. Probes are sent as
+//! datagrams, so one lost packet doesn't hold up the probes behind it as it
+//! would over TCP; anything else goes, length-prefixed, on the stream the
+//! server opens when the session starts.
+//!
+//! `web_sys` only has WebTransport behind its unstable APIs flag, so the
+//! little of it needed is bound here instead.
+
+use shared_data::{encode_frame_bytes, LatencyTestRef};
+use std::cell::Cell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    ReadableStream, ReadableStreamDefaultReader, ReadableStreamReadResult, WritableStream,
+    WritableStreamDefaultWriter,
+};
+
+/// How long to wait for a session before falling back to the WebSocket. A
+/// network that blocks UDP would otherwise leave the client waiting out the
+/// browser's own (much longer) handshake timeout.
+const READY_TIMEOUT_MS: i32 = 3000;
+
+#[wasm_bindgen]
+extern "C" {
+    #[derive(Clone)]
+    type WebTransport;
+
+    #[wasm_bindgen(constructor, catch)]
+    fn new(url: &str, options: &JsValue) -> Result<WebTransport, JsValue>;
+
+    #[wasm_bindgen(method, getter)]
+    fn ready(this: &WebTransport) -> js_sys::Promise;
+
+    #[wasm_bindgen(method, getter)]
+    fn closed(this: &WebTransport) -> js_sys::Promise;
+
+    #[wasm_bindgen(method, getter)]
+    fn datagrams(this: &WebTransport) -> DatagramDuplexStream;
+
+    #[wasm_bindgen(method, getter, js_name = incomingBidirectionalStreams)]
+    fn incoming_bidirectional_streams(this: &WebTransport) -> ReadableStream;
+
+    #[wasm_bindgen(method)]
+    fn close(this: &WebTransport);
+
+    type DatagramDuplexStream;
+
+    #[wasm_bindgen(method, getter)]
+    fn readable(this: &DatagramDuplexStream) -> ReadableStream;
+
+    #[wasm_bindgen(method, getter)]
+    fn writable(this: &DatagramDuplexStream) -> WritableStream;
+
+    #[wasm_bindgen(method, getter, js_name = maxDatagramSize)]
+    fn max_datagram_size(this: &DatagramDuplexStream) -> Option<u32>;
+
+    type BidirectionalStream;
+
+    #[wasm_bindgen(method, getter, js_name = readable)]
+    fn stream_readable(this: &BidirectionalStream) -> ReadableStream;
+
+    #[wasm_bindgen(method, getter, js_name = writable)]
+    fn stream_writable(this: &BidirectionalStream) -> WritableStream;
+}
+
+/// Whether this browser has WebTransport at all.
+pub fn is_supported() -> bool {
+    js_sys::Reflect::has(&js_sys::global(), &JsValue::from_str("WebTransport")).unwrap_or(false)
+}
+
+/// Parses the hex certificate hash advertised by the server.
+pub fn parse_certificate_hash(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() != 64 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// An open WebTransport session.
+#[derive(Clone)]
+pub struct WebTransportConduit {
+    transport: WebTransport,
+    datagrams: WritableStreamDefaultWriter,
+    max_datagram_size: usize,
+    stream: WritableStreamDefaultWriter,
+    /// Bytes written to the stream that it hasn't yet taken.
+    buffered: Rc<Cell<u32>>,
+    open: Rc<Cell<bool>>,
+}
+
+/// A new session's conduit, with the incoming datagrams and stream.
+pub struct Opened {
+    pub conduit: WebTransportConduit,
+    pub datagrams: ReadableStream,
+    pub stream: ReadableStream,
+}
+
+/// Opens a session, and waits for the stream the server opens on it. If the
+/// server's certificate is self-signed, `certificate_hash` is its SHA-256.
+pub async fn open(url: &str, certificate_hash: Option<&[u8]>) -> Result<Opened, JsValue> {
+    let options = js_sys::Object::new();
+    if let Some(hash) = certificate_hash {
+        let entry = js_sys::Object::new();
+        js_sys::Reflect::set(&entry, &"algorithm".into(), &"sha-256".into())?;
+        js_sys::Reflect::set(&entry, &"value".into(), &js_sys::Uint8Array::from(hash))?;
+        let hashes = js_sys::Array::of1(&entry);
+        js_sys::Reflect::set(&options, &"serverCertificateHashes".into(), &hashes)?;
+    }
+    let transport = WebTransport::new(url, &options)?;
+    let ready = js_sys::Promise::race(&js_sys::Array::of2(&transport.ready(), &timeout()));
+    if let Err(e) = JsFuture::from(ready).await {
+        transport.close();
+        return Err(e);
+    }
+
+    let incoming = ReadableStreamDefaultReader::new(&transport.incoming_bidirectional_streams())?;
+    let next: ReadableStreamReadResult = JsFuture::from(incoming.read()).await?.unchecked_into();
+    if next.get_done().unwrap_or(true) {
+        transport.close();
+        return Err(JsValue::from_str("Session closed before the server opened its stream"));
+    }
+    let stream: BidirectionalStream = next.get_value().unchecked_into();
+    let datagrams = transport.datagrams();
+    let conduit = WebTransportConduit {
+        datagrams: datagrams.writable().get_writer()?,
+        max_datagram_size: datagrams.max_datagram_size().unwrap_or(0) as usize,
+        stream: stream.stream_writable().get_writer()?,
+        buffered: Rc::new(Cell::new(0)),
+        open: Rc::new(Cell::new(true)),
+        transport,
+    };
+    Ok(Opened {
+        conduit,
+        datagrams: datagrams.readable(),
+        stream: stream.stream_readable(),
+    })
+}
+
+/// A promise rejected after `READY_TIMEOUT_MS`.
+fn timeout() -> js_sys::Promise {
+    js_sys::Promise::new(&mut |_resolve, reject| {
+        if let Some(window) = web_sys::window() {
+            let _ = window
+                .set_timeout_with_callback_and_timeout_and_arguments_0(&reject, READY_TIMEOUT_MS);
+        }
+    })
+}
+
+impl WebTransportConduit {
+    /// Sends an encoded frame: a probe as a datagram if it fits in one, and
+    /// anything else on the stream.
+    pub fn send(&self, bytes: &[u8]) {
+        let probe = LatencyTestRef::decode(bytes).is_ok_and(|frame| frame.kind().is_probe());
+        if probe && bytes.len() <= self.max_datagram_size {
+            let write = self.datagrams.write_with_chunk(&js_sys::Uint8Array::from(bytes));
+            wasm_bindgen_futures::spawn_local(async move {
+                let _ = JsFuture::from(write).await;
+            });
+            return;
+        }
+        let frame = encode_frame_bytes(bytes);
+        let len = frame.len() as u32;
+        let write = self.stream.write_with_chunk(&js_sys::Uint8Array::from(&frame[..]));
+        let buffered = self.buffered.clone();
+        buffered.set(buffered.get() + len);
+        wasm_bindgen_futures::spawn_local(async move {
+            let _ = JsFuture::from(write).await;
+            buffered.set(buffered.get().saturating_sub(len));
+        });
+    }
+
+    pub fn close(&self) {
+        self.open.set(false);
+        self.transport.close();
+    }
+
+    /// Marks the session closed, once the server has gone.
+    pub fn closed(&self) {
+        self.open.set(false);
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.get()
+    }
+
+    /// The code the session was closed with, once it has closed; `None` if
+    /// it didn't close cleanly.
+    pub async fn close_code(&self) -> Option<u16> {
+        let info = JsFuture::from(self.transport.closed()).await.ok()?;
+        let code = js_sys::Reflect::get(&info, &"closeCode".into()).ok()?.as_f64()?;
+        u16::try_from(code as u32).ok()
+    }
+
+    /// Bytes queued on the stream but not yet sent, like a WebSocket's
+    /// `bufferedAmount`.
+    pub fn buffered_amount(&self) -> u32 {
+        self.buffered.get()
+    }
+}
+
+/// Reads `stream` until it ends, passing each chunk to `chunk`.
+pub async fn read_chunks(stream: ReadableStream, mut chunk: impl FnMut(Vec<u8>)) {
+    let Ok(reader) = ReadableStreamDefaultReader::new(&stream) else {
+        return;
+    };
+    while let Ok(next) = JsFuture::from(reader.read()).await {
+        let next: ReadableStreamReadResult = next.unchecked_into();
+        if next.get_done().unwrap_or(true) {
+            break;
+        }
+        chunk(js_sys::Uint8Array::new(&next.get_value()).to_vec());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn certificate_hash_from_hex() {
+        let hash = parse_certificate_hash(&"0a".repeat(32)).unwrap();
+        assert_eq!(hash, vec![10; 32]);
+        assert_eq!(parse_certificate_hash("0a0b"), None);
+        assert_eq!(parse_certificate_hash(&"zz".repeat(32)), None);
+    }
+}